@@ -0,0 +1,100 @@
+mod stress;
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use smoo::discovery::Beacon;
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+use tracing_subscriber::EnvFilter;
+
+/// Tools for testing a running smo-rs server
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Listen for the beacons of servers on the local network
+    Discover {
+        /// Port the servers broadcast their beacons to
+        #[arg(short, long, default_value_t = 1028)]
+        port: u16,
+        /// Seconds to listen for beacons
+        #[arg(short, long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Connect fake clients to a server and report throughput and latency
+    Stress {
+        #[arg(default_value = "127.0.0.1:1027")]
+        server: SocketAddr,
+        /// Amount of fake clients
+        #[arg(short, long, default_value_t = 10)]
+        clients: usize,
+        /// Player packets per second and client, a third as many cap packets are sent
+        #[arg(short, long, default_value_t = 30)]
+        rate: u64,
+        /// Seconds to run the test for
+        #[arg(short, long, default_value_t = 30)]
+        duration: u64,
+        /// Send player and cap packets over udp, if the server initiates the udp handshake
+        #[arg(short, long)]
+        udp: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    match Cli::parse().cmd {
+        Command::Discover { port, timeout } => discover(port, Duration::from_secs(timeout)).await,
+        Command::Stress {
+            server,
+            clients,
+            rate,
+            duration,
+            udp,
+        } => stress::stress(server, clients, rate, Duration::from_secs(duration), udp).await,
+    }
+}
+
+async fn discover(port: u16, duration: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))).await?;
+    println!("Listening for servers on udp port {} for {}s", port, duration.as_secs());
+
+    let deadline = Instant::now() + duration;
+    let mut servers = BTreeMap::new();
+    let mut buff = [0; 1024];
+    while let Ok(result) = timeout_at(deadline, socket.recv_from(&mut buff)).await {
+        let (len, addr) = result?;
+        let beacon = match Beacon::decode(&buff[..len]) {
+            Some(beacon) => beacon,
+            None => {
+                tracing::debug!("Ignoring datagram from {}", addr);
+                continue;
+            }
+        };
+
+        let server = SocketAddr::new(addr.ip(), beacon.port);
+        if servers.insert(server, beacon.clone()).is_none() {
+            println!(
+                "{} {} ({}/{} players)",
+                server, beacon.name, beacon.players, beacon.max_players
+            );
+        }
+    }
+
+    println!("Found {} server(s)", servers.len());
+    Ok(())
+}
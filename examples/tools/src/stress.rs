@@ -0,0 +1,202 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use smoo::{
+    guid::Guid,
+    net::{connection::Connection, udp_conn::UdpConnection, ConnectionType, Packet, PacketData},
+    types::{Quaternion, Vector3},
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    select,
+    time::{interval, sleep, Instant},
+};
+
+/// Packets and latencies of all fake clients
+#[derive(Default)]
+struct Stats {
+    sent: AtomicU64,
+    received: AtomicU64,
+    failed: AtomicU64,
+    /// Milliseconds between sending a player packet and another client receiving it
+    latencies: Mutex<Vec<u32>>,
+}
+
+pub async fn stress(
+    server: SocketAddr,
+    clients: usize,
+    rate: u64,
+    duration: Duration,
+    udp: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    println!("Connecting {} clients to {}, sending {} player packets/s each", clients, server, rate);
+
+    for index in 0..clients {
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fake_client(server, index, rate, udp, start, &stats).await {
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("Client {} failed: {}", index, e);
+            }
+        });
+    }
+
+    let mut report = interval(Duration::from_secs(1));
+    report.tick().await;
+    let (mut last_sent, mut last_received) = (0, 0);
+    while start.elapsed() < duration {
+        report.tick().await;
+        let sent = stats.sent.load(Ordering::Relaxed);
+        let received = stats.received.load(Ordering::Relaxed);
+        let latencies = std::mem::take(&mut *stats.latencies.lock().unwrap());
+        println!(
+            "{:>4}s: sent {:>7}/s, received {:>8}/s, latency {}, {} clients failed",
+            start.elapsed().as_secs(),
+            sent - last_sent,
+            received - last_received,
+            summarize(latencies),
+            stats.failed.load(Ordering::Relaxed),
+        );
+        (last_sent, last_received) = (sent, received);
+    }
+
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "Total: sent {} ({:.0}/s), received {} ({:.0}/s)",
+        last_sent,
+        last_sent as f64 / secs,
+        last_received,
+        last_received as f64 / secs,
+    );
+    Ok(())
+}
+
+/// Average, median and 99th percentile in milliseconds
+fn summarize(mut latencies: Vec<u32>) -> String {
+    if latencies.is_empty() {
+        return "-".to_string();
+    }
+    latencies.sort_unstable();
+    let avg = latencies.iter().map(|l| u64::from(*l)).sum::<u64>() / latencies.len() as u64;
+    let p50 = latencies[latencies.len() / 2];
+    let p99 = latencies[latencies.len() * 99 / 100];
+    format!("avg {}ms p50 {}ms p99 {}ms", avg, p50, p99)
+}
+
+async fn fake_client(
+    server: SocketAddr,
+    index: usize,
+    rate: u64,
+    use_udp: bool,
+    start: Instant,
+    stats: &Stats,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = Guid::new_random();
+
+    let socket = TcpStream::connect(server).await?;
+    socket.set_nodelay(true)?;
+    let mut tcp = Connection::new(socket);
+    let udp_socket = UdpSocket::bind(SocketAddr::new(tcp.socket.get_ref().local_addr()?.ip(), 0)).await?;
+    let udp_port = udp_socket.local_addr()?.port();
+    let mut udp = UdpConnection::new(udp_socket, server.ip());
+    let mut udp_ready = false;
+
+    let connect = PacketData::Connect {
+        c_type: ConnectionType::FirstConnection,
+        max_player: u16::MAX,
+        client_name: format!("Stress{}", index),
+    };
+    tcp.write_packet(&Packet::new(id, connect)).await?;
+    let game = PacketData::Game {
+        is_2d: false,
+        scenario_num: 1,
+        stage: "WaterfallWorldHomeStage".to_string(),
+    };
+    tcp.write_packet(&Packet::new(id, game)).await?;
+
+    // spread the clients over the send interval
+    sleep(Duration::from_millis(index as u64 * 7 % 1000 / rate.max(1))).await;
+    let period = Duration::from_micros(1_000_000 / rate.max(1));
+    let mut player_tick = interval(period);
+    let mut cap_tick = interval(period * 3);
+    let mut frame: u64 = 0;
+    loop {
+        let packet = select! {
+            _ = player_tick.tick() => {
+                frame += 1;
+                player_packet(id, index, frame, start)
+            },
+            _ = cap_tick.tick() => cap_packet(id, index, frame),
+            packet = tcp.read_packet() => {
+                match packet?.data {
+                    PacketData::UdpInit { port } if use_udp => {
+                        udp.set_client_port(port);
+                        tcp.write_packet(&Packet::new(id, PacketData::UdpInit { port: udp_port })).await?;
+                        udp_ready = true;
+                    }
+                    data => record(stats, &data, start),
+                }
+                continue;
+            },
+            packet = udp.read_packet(), if udp_ready => {
+                record(stats, &packet?.data, start);
+                continue;
+            },
+        };
+
+        if udp_ready {
+            udp.write_packet(&packet).await?;
+        } else {
+            tcp.write_packet(&packet).await?;
+        }
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn record(stats: &Stats, data: &PacketData, start: Instant) {
+    stats.received.fetch_add(1, Ordering::Relaxed);
+    if let PacketData::Player { act, sub_act, .. } = data {
+        let sent = u64::from(*act) | u64::from(*sub_act) << 16;
+        let now = start.elapsed().as_millis() as u64;
+        if let Some(latency) = now.checked_sub(sent) {
+            stats.latencies.lock().unwrap().push(latency as u32);
+        }
+    }
+}
+
+/// Player running in circles, carrying the send time in `act` and `sub_act`
+fn player_packet(id: Guid, index: usize, frame: u64, start: Instant) -> Packet {
+    let angle = frame as f32 / 30.0 + index as f32;
+    let sent = start.elapsed().as_millis() as u32;
+    Packet::new(
+        id,
+        PacketData::Player {
+            pos: Vector3::new(angle.cos() * 500.0, 0.0, angle.sin() * 500.0),
+            rot: Quaternion::identity(),
+            animation_blend_weights: [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            act: sent as u16,
+            sub_act: (sent >> 16) as u16,
+        },
+    )
+}
+
+fn cap_packet(id: Guid, index: usize, frame: u64) -> Packet {
+    let angle = frame as f32 / 30.0 + index as f32;
+    Packet::new(
+        id,
+        PacketData::Cap {
+            pos: Vector3::new(angle.cos() * 500.0, 100.0, angle.sin() * 500.0),
+            rot: Quaternion::identity(),
+            cap_out: false,
+            cap_anim: "default".to_string(),
+        },
+    )
+}
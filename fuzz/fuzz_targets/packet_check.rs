@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use smoo::net::{encoding::Decodable, Packet};
+
+// framing of the received bytes, like `Connection::parse_packet`
+fuzz_target!(|data: &[u8]| {
+    let mut buf = Cursor::new(data);
+    if Packet::check(&mut buf).is_ok() {
+        buf.set_position(0);
+        let _ = Packet::decode(&mut buf);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use smoo::net::{
+    encoding::{Decodable, Encodable},
+    Packet,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode(&mut Cursor::new(data)) {
+        // decoded packets are relayed to the other clients
+        let mut buff = BytesMut::new();
+        let _ = packet.encode(&mut buff);
+    }
+});
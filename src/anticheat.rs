@@ -0,0 +1,114 @@
+//! Detection of players moving impossibly fast, configured in `TeleportDetection`
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    audit::{self, AuditEntry},
+    cmds::{Command, ExternalCommand, PlayerCommand, Players},
+    events::ServerEvent,
+    guid::Guid,
+    lobby::Lobby,
+    settings::{TeleportAction, TeleportDetectionSettings},
+    types::Vector3,
+};
+
+/// Shortest time between two positions, packets arriving in bursts would otherwise look like huge speeds
+const MIN_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+
+/// Last position of a player, to detect impossible movement within a stage
+#[derive(Clone, Debug, Default)]
+pub struct MovementTracker {
+    last: Option<(Vector3, Instant, String)>,
+    /// When the player moved impossibly fast within the window
+    flagged: VecDeque<Instant>,
+}
+
+/// Impossible movement of a player
+#[derive(Clone, Debug)]
+pub struct Teleport {
+    pub distance: f32,
+    /// Units per second
+    pub speed: f32,
+    /// Times the player got flagged within the window, including this one
+    pub strikes: usize,
+}
+
+impl MovementTracker {
+    /// Track the position of a player packet, returns the movement if it was impossibly fast
+    pub fn update(&mut self, pos: Vector3, stage: &str, settings: &TeleportDetectionSettings) -> Option<Teleport> {
+        let now = Instant::now();
+        let last = self.last.replace((pos, now, stage.to_string()));
+        // changing stages or scenarios places the player anywhere
+        let (last_pos, last_time, last_stage) = last?;
+        if last_stage != stage {
+            return None;
+        }
+
+        let distance = (pos - last_pos).norm();
+        let speed = distance / now.duration_since(last_time).max(MIN_INTERVAL).as_secs_f32();
+        if distance < settings.min_distance || speed <= settings.max_speed {
+            return None;
+        }
+
+        let window = Duration::from_millis(settings.window_ms);
+        while self.flagged.front().is_some_and(|flagged| now.duration_since(*flagged) > window) {
+            self.flagged.pop_front();
+        }
+        self.flagged.push_back(now);
+        let strikes = self.flagged.len();
+        // start over once acted on
+        if strikes >= settings.strikes {
+            self.flagged.clear();
+        }
+        Some(Teleport { distance, speed, strikes })
+    }
+}
+
+/// Log the teleport and act on it, once the player got flagged often enough
+pub fn report(lobby: &Lobby, id: Guid, name: &str, stage: &str, teleport: Teleport, settings: &TeleportDetectionSettings) {
+    tracing::warn!(
+        "{} moved {:.0} units in {} at {:.0} units/s ({}/{} strikes)",
+        name,
+        teleport.distance,
+        stage,
+        teleport.speed,
+        teleport.strikes,
+        settings.strikes
+    );
+    if teleport.strikes < settings.strikes {
+        return;
+    }
+
+    if settings.action == TeleportAction::Log {
+        return;
+    }
+    lobby.emit(ServerEvent::Teleport {
+        id,
+        name: name.to_string(),
+        stage: stage.to_string(),
+        speed: teleport.speed,
+    });
+
+    if settings.action == TeleportAction::Kick {
+        let lobby = lobby.clone();
+        let name = name.to_string();
+        let reason = format!("Moved at {:.0} units/s in {}", teleport.speed, stage);
+        tracing::warn!("Kicking {}: {}", name, reason);
+        tokio::spawn(async move {
+            let (sender, recv) = oneshot::channel();
+            let kick = ExternalCommand::Player {
+                players: Players::Individual(vec![id]),
+                command: PlayerCommand::Disconnect {},
+            };
+            let _ = lobby.to_coord.send(Command::External(kick, sender)).await;
+            let _ = recv.await;
+            let entry = AuditEntry::new("anticheat", "kick", vec![name], Some(reason));
+            audit::record(&lobby, &entry).await;
+        });
+    }
+}
@@ -0,0 +1,138 @@
+//! Append-only log of the moderation actions and admin commands, one JSON entry per line in `Audit.Filename`
+
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{lobby::Lobby, types::Result};
+
+/// Action of the entries for the commands of the console and the JSON API, the command line is the target
+pub const COMMAND: &str = "command";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// Who did it, e.g. `console` or the JSON API token
+    pub moderator: String,
+    pub action: String,
+    /// Players, addresses, stages, ... the action was applied to
+    pub targets: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Where the moderator connected from, e.g. the address of a JSON API client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    /// Reply or error of a command, shortened to its first line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(moderator: &str, action: &str, targets: Vec<String>, reason: Option<String>) -> Self {
+        Self {
+            timestamp: now(),
+            moderator: moderator.to_string(),
+            action: action.to_string(),
+            targets,
+            reason,
+            address: None,
+            result: None,
+        }
+    }
+
+    pub fn with_address(mut self, address: Option<IpAddr>) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn with_result(mut self, result: &str) -> Self {
+        let line = result.lines().next().unwrap_or_default();
+        self.result = Some(match line.char_indices().nth(MAX_RESULT_LENGTH) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line.to_string(),
+        });
+        self
+    }
+
+    pub fn is_command(&self) -> bool {
+        self.action == COMMAND
+    }
+}
+
+const MAX_RESULT_LENGTH: usize = 100;
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", format_time(self.timestamp), self.moderator)?;
+        if let Some(address) = &self.address {
+            write!(f, " from {}", address)?;
+        }
+        write!(f, ": {} {}", self.action, self.targets.join(", "))?;
+        if let Some(reason) = &self.reason {
+            write!(f, " ({})", reason)?;
+        }
+        if let Some(result) = &self.result {
+            write!(f, " -> {}", result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+pub fn format_time(timestamp: u64) -> String {
+    match Utc.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Append the entry to the audit log, failures are only logged to not fail the action itself
+pub async fn record(lobby: &Lobby, entry: &AuditEntry) {
+    let settings = lobby.settings.read().await.audit.clone();
+    if !settings.enabled {
+        return;
+    }
+    if let Err(e) = append(&settings.filename, entry).await {
+        tracing::warn!("Failed to write to the audit log {}: {}", settings.filename, e);
+    }
+}
+
+async fn append(filename: &str, entry: &AuditEntry) -> Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(filename).await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+/// The last `count` entries of the audit log, oldest first, optionally including the commands
+pub async fn latest(lobby: &Lobby, count: usize, commands: bool) -> Result<Vec<AuditEntry>> {
+    let filename = lobby.settings.read().await.audit.filename.clone();
+    let content = match tokio::fs::read_to_string(&filename).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries: Vec<AuditEntry> = content
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+        .filter(|entry| commands || !entry.is_command())
+        .take(count)
+        .collect();
+    entries.reverse();
+    Ok(entries)
+}
@@ -1,24 +1,33 @@
 use crate::{
     cmds::{ClientCommand, Command, ServerCommand},
+    anticheat::{self, MovementTracker},
+    emotes::{self, EmoteTracker},
+    events::ServerEvent,
     guid::Guid,
     json_api::JsonApi,
-    lobby::{Lobby, LobbyView},
-    net::{connection::Connection, udp_conn::UdpConnection, ConnectionType, GameMode, Packet, PacketData, TagUpdate},
+    lobby::{Lobby, LobbyView, SlotReservation},
+    netstat::ConnectionStats,
+    net::{connection::Connection, udp_conn::{UdpConnection, UdpSenderStatus}, ConnectionType, GameMode, Packet, PacketData, TagUpdate},
     player_holder::ClientChannel,
-    types::{ChannelError, ClientInitError, ErrorSeverity, Result, SMOError, Vector3},
+    relay::{Relay, SendQueue, Throttle},
+    sampling::PacketSampler,
+    settings::{BanEnforcement, DuplicateNames, ModelEnforcement, Settings, StageValidationAction, UnhandledAction},
+    types::{ChannelError, ClientInitError, Costume, ErrorSeverity, Result, SMOError, Vector3},
 };
+use bytes::Bytes;
 use dashmap::mapref::one::{Ref, RefMut};
 use nalgebra::UnitQuaternion;
 use std::{
-    collections::{hash_map::RandomState, BTreeSet},
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpStream, UdpSocket},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
     select,
-    sync::{broadcast, mpsc},
+    sync::mpsc,
 };
 use tracing::Level;
 
@@ -31,12 +40,37 @@ pub struct Client {
     pub udp_conn: UdpConnection,
     pub to_coord: mpsc::Sender<Command>,
     pub from_server: mpsc::Receiver<ClientCommand>,
-    pub send_broadcast: broadcast::Sender<ClientCommand>,
-    pub recv_broadcast: broadcast::Receiver<ClientCommand>,
+    pub send_broadcast: Relay,
+    /// Commands for all clients, see `Relay`
+    pub queue: Arc<SendQueue>,
 
     lobby: Lobby,
+    recv_sampler: PacketSampler,
+    send_sampler: PacketSampler,
+    throttle: Throttle,
+    /// Whether movement is sent over UDP, see `use_udp`
+    udp: bool,
+    keepalive: Option<Duration>,
+    stats: ConnectionStats,
+    next_keepalive: Instant,
+    /// When the udp handshake was last initiated
+    udp_handshake: Instant,
+    /// When the movement of far away players was last forwarded to this player
+    distant: HashMap<(Guid, String), Instant>,
 }
 
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.queue.dropped() > 0 {
+            tracing::debug!("{} didn't keep up with {} movement packets", self.display_name, self.queue.dropped());
+        }
+        self.send_broadcast.unregister(&self.guid, &self.queue);
+    }
+}
+
+/// Packet types of other players that can be hidden from a player with `filter hide`
+pub const FILTERABLE_PACKETS: &[&str] = &["player", "cap", "game", "tag", "costume", "capture"];
+
 #[derive(Clone, Debug)]
 pub struct PlayerData {
     pub ipv4: Option<IpAddr>,
@@ -50,13 +84,47 @@ pub struct PlayerData {
     pub last_costume_packet: Option<Packet>,
     pub last_game_packet: Option<Packet>,
     pub last_player_packet: Option<Packet>,
+    /// Game packet of the stage the player was in before the current one
+    pub previous_game_packet: Option<Packet>,
+    /// Highest scenario seen per stage
+    pub progress: BTreeMap<String, i8>,
+    /// Packet types of other players that aren't relayed to this player
+    pub hidden_packets: BTreeSet<String>,
+    /// Costume waiting for approval, the other players see the default costume instead
+    pub held_costume: Option<Costume>,
+    /// Costume the other players see instead of the real one, set with `costume set`
+    pub forced_costume: Option<Costume>,
+    /// Hidden from the others until the first game packet with a stage
+    pub in_limbo: bool,
+    pub emotes: EmoteTracker,
+    pub movement: MovementTracker,
+    /// Whether movement is currently sent to the player over UDP
+    pub udp: bool,
+    pub connection: ConnectionStats,
     pub disable_shine_sync: bool,
     pub loaded_save: bool,
     pub time: Option<Duration>,
+    pub connected_at: SystemTime,
     pub channel: ClientChannel,
 }
 
 impl PlayerData {
+    /// Stage of the last game packet
+    pub fn last_stage(&self) -> Option<&str> {
+        match &self.last_game_packet {
+            Some(Packet { data: PacketData::Game { stage, .. }, .. }) => Some(stage),
+            _ => None,
+        }
+    }
+
+    /// Position of the last player packet
+    pub fn last_position(&self) -> Option<Vector3> {
+        match &self.last_player_packet {
+            Some(Packet { data: PacketData::Player { pos, .. }, .. }) => Some(*pos),
+            _ => None,
+        }
+    }
+
     fn new(channel: ClientChannel) -> Self {
         Self {
             ipv4: Default::default(),
@@ -70,9 +138,20 @@ impl PlayerData {
             last_costume_packet: Default::default(),
             last_game_packet: Default::default(),
             last_player_packet: Default::default(),
+            previous_game_packet: Default::default(),
+            progress: Default::default(),
+            hidden_packets: Default::default(),
+            held_costume: Default::default(),
+            forced_costume: Default::default(),
+            in_limbo: Default::default(),
+            emotes: Default::default(),
+            movement: Default::default(),
+            udp: Default::default(),
+            connection: Default::default(),
             disable_shine_sync: Default::default(),
             loaded_save: Default::default(),
             time: Default::default(),
+            connected_at: SystemTime::now(),
             channel,
         }
     }
@@ -102,12 +181,47 @@ impl PlayerData {
             },
         ))
     }
+
+    /// Packets that recreate the puppet of this player on another client
+    pub fn create_sync_packets(&self, guid: Guid, max_player: u16) -> Vec<Packet> {
+        let connect_packet = Packet::new(
+            guid,
+            PacketData::Connect {
+                c_type: ConnectionType::FirstConnection,
+                max_player,
+                client_name: self.name.clone(),
+            },
+        );
+
+        let costume_packet = match &self.forced_costume {
+            Some(costume) => Some(Packet::new(guid, PacketData::Costume(costume.clone()))),
+            None => self.last_costume_packet.clone(),
+        };
+
+        [
+            Some(connect_packet),
+            costume_packet,
+            self.last_capture_packet.clone(),
+            self.create_tag_packet(guid),
+            self.last_game_packet.clone(),
+            self.last_player_packet.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
 }
 
 #[derive(Debug)]
 enum ClientEvent {
     Incoming(Packet),
-    Outgoing(ClientCommand),
+    /// With the encoding of packets for all clients
+    Outgoing(ClientCommand, Option<Bytes>),
+    /// Movement held back by the `Throttle` may be forwarded
+    Throttled,
+    Keepalive,
+    /// The client fell too far behind on the commands for it
+    Overflowed,
 }
 
 pub fn get_mario_size(is_2d: bool) -> f32 {
@@ -133,12 +247,20 @@ impl Client {
 
             let result = match event {
                 Ok(ClientEvent::Incoming(p)) => self.handle_packet(p).await,
-                Ok(ClientEvent::Outgoing(c)) => self.handle_command(c).await,
+                Ok(ClientEvent::Outgoing(c, encoded)) => self.handle_command(c, encoded).await,
+                Ok(ClientEvent::Throttled) => self.forward_throttled().await,
+                Ok(ClientEvent::Keepalive) => self.udp_keepalive().await,
+                Ok(ClientEvent::Overflowed) => {
+                    tracing::warn!("Disconnecting {}, who didn't keep up with the commands for them", self.display_name);
+                    self.alive = false;
+                    Ok(())
+                }
                 Err(e) => match e.severity() {
                     ErrorSeverity::ClientFatal => {
                         self.alive = false;
                         break;
                     }
+                    _ if e.is_malformed_packet() => self.malformed_packet(e).await,
                     _ => Err(e),
                 },
             };
@@ -154,6 +276,7 @@ impl Client {
 
     /// Read an event from either the client sockets or server channels
     async fn read_event(&mut self) -> Result<ClientEvent> {
+        let throttled = self.throttle.due();
         let event = select! {
             packet = self.conn.read_packet() => {
                 ClientEvent::Incoming(packet?)
@@ -161,12 +284,30 @@ impl Client {
             udp_packet = self.udp_conn.read_packet() => {
                 ClientEvent::Incoming(udp_packet?)
             },
-            command = self.from_server.recv() => ClientEvent::Outgoing(command.ok_or(ChannelError::RecvChannel)?),
-            command = self.recv_broadcast.recv() => ClientEvent::Outgoing(command?),
+            command = self.from_server.recv() => ClientEvent::Outgoing(command.ok_or(ChannelError::RecvChannel)?, None),
+            outgoing = self.queue.recv() => match outgoing {
+                Some(outgoing) => ClientEvent::Outgoing(outgoing.command, outgoing.encoded),
+                None => ClientEvent::Overflowed,
+            },
+            _ = tokio::time::sleep_until(throttled.unwrap_or_else(Instant::now).into()), if throttled.is_some() => ClientEvent::Throttled,
+            _ = tokio::time::sleep_until(self.next_keepalive.into()), if self.keepalive.is_some() => ClientEvent::Keepalive,
         };
         Ok(event)
     }
 
+    /// Count a packet that failed to decode, disconnecting the client in strict mode once it sent too many
+    async fn malformed_packet(&mut self, error: SMOError) -> Result<()> {
+        self.stats.malformed += 1;
+        self.publish_stats();
+        let malformed = self.lobby.settings.read().await.malformed_packets.clone();
+        if malformed.strict && self.stats.malformed >= malformed.limit {
+            tracing::warn!("Disconnecting {} after {} malformed packets: {}", self.display_name, self.stats.malformed, error);
+            self.alive = false;
+            return Ok(());
+        }
+        Err(error)
+    }
+
     /// Disconnect the player
     pub async fn disconnect(mut self) -> Result<()> {
         tracing::warn!("Client {} disconnected", self.display_name);
@@ -181,18 +322,68 @@ impl Client {
 
     /// Handle any incoming packets from the client
     async fn handle_packet(&mut self, mut packet: Packet) -> Result<()> {
-        match packet.data {
-            PacketData::Player { .. } | PacketData::Cap { .. } => {}
-            _ => tracing::trace!("Handling packet: {}", &packet.data.get_type_name()),
+        if self.stats.receive(&packet) {
+            self.publish_stats();
+        }
+        // the same settings for the whole packet, released before it's forwarded
+        let settings = self.lobby.settings.clone().read_owned().await;
+        if tracing::enabled!(Level::TRACE) {
+            let rates = &settings.logging.trace_sampling;
+            if self.recv_sampler.sample(rates, packet.data.get_type_name()) {
+                tracing::trace!("Handling packet: {}", &packet.data.get_type_name());
+            }
+        }
+
+        if !self.enforce_model_bans(&settings, &mut packet.data) {
+            return Ok(());
+        }
+        if !self.validate_stage(&settings, &packet.data) {
+            return Ok(());
+        }
+        if let PacketData::Costume(costume) = &mut packet.data {
+            self.moderate_costume(&settings, costume);
+        }
+
+        // spectators watch the others without moving a puppet of their own
+        if matches!(packet.data, PacketData::Player { .. } | PacketData::Cap { .. })
+            && settings.spectators.players.contains(&self.guid)
+        {
+            return Ok(());
         }
 
         let send_destination = match &mut packet.data {
             PacketData::Player {
                 ref mut rot,
                 ref mut pos,
+                act,
+                sub_act,
                 ..
             } => {
-                let settings = self.lobby.settings.read().await;
+                if settings.emotes.enabled {
+                    let mut data = self.lobby.players.get_mut(&self.guid).ok_or(SMOError::InvalidID(self.guid))?;
+                    let fired = data.emotes.update(*act, *sub_act, &settings.emotes.triggers);
+                    let name = data.name.clone();
+                    drop(data);
+                    for trigger in fired {
+                        emotes::fire(&self.lobby, self.guid, &name, trigger);
+                    }
+                }
+                if settings.teleport_detection.enabled {
+                    let detection = &settings.teleport_detection;
+                    let mut data = self.lobby.players.get_mut(&self.guid).ok_or(SMOError::InvalidID(self.guid))?;
+                    let stage = match &data.last_game_packet {
+                        Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) => {
+                            format!("{}/{}", stage, scenario_num)
+                        }
+                        _ => String::new(),
+                    };
+                    let teleport = data.movement.update(*pos, &stage, detection);
+                    let name = data.name.clone();
+                    drop(data);
+                    if let Some(teleport) = teleport {
+                        anticheat::report(&self.lobby, self.guid, &name, &stage, teleport, detection);
+                    }
+                }
                 if settings.flip.enabled
                     && settings.flip.pov.is_others_flip()
                     && settings.flip.players.get(&packet.id).is_some()
@@ -203,7 +394,6 @@ impl Client {
                     *pos += get_mario_size(data.is_2d) * Vector3::y();
                     *rot *= rot_quad;
                 }
-                drop(settings);
 
                 let mut data = self.lobby.get_mut_client(&self.guid)?;
                 data.last_player_packet = Some(packet.clone());
@@ -233,13 +423,26 @@ impl Client {
                 data.is_2d = *is_2d;
                 data.scenario = *scenario_num;
                 // reset last_player_packet on stage changes
+                let mut changed_stage = false;
                 if let Some(Packet { data: PacketData::Game { stage: last_stage, .. }, .. }) = &data.last_game_packet {
                     if *stage != *last_stage {
+                        changed_stage = true;
                         data.last_player_packet = None;
+                        data.previous_game_packet = data.last_game_packet.take();
                     }
                 }
+                let reached = data.progress.entry(stage.clone()).or_insert(*scenario_num);
+                *reached = (*reached).max(*scenario_num);
+                let released = data.in_limbo && !stage.is_empty();
                 data.last_game_packet = Some(packet.clone());
                 drop(data);
+                // the distances to everyone else changed
+                if changed_stage {
+                    self.distant.clear();
+                }
+                if released {
+                    self.release_from_limbo(settings.server.max_players);
+                }
                 PacketDestination::Coordinator
             }
             PacketData::Tag {
@@ -286,6 +489,7 @@ impl Client {
                 drop(data);
                 PacketDestination::Coordinator
             }
+            PacketData::UdpInit { .. } if !settings.udp.enabled => PacketDestination::NoSend,
             PacketData::UdpInit { port } => {
                 tracing::debug!(
                     "{} completed udp handshake, attempting hybrid connection",
@@ -295,18 +499,49 @@ impl Client {
                 // Attempt to send some udp data to client
                 let holepunch = Packet::new(self.guid, PacketData::HolePunch);
                 self.udp_conn.write_packet(&holepunch).await?;
+                self.stats.punch_sent();
+                PacketDestination::NoSend
+            }
+            PacketData::HolePunch => {
+                self.stats.punch_answered();
                 PacketDestination::NoSend
             }
-            PacketData::HolePunch => PacketDestination::NoSend,
+            PacketData::Unhandled { tag, data } => match settings.unhandled_packets.action(*tag) {
+                UnhandledAction::Forward => PacketDestination::Broadcast,
+                UnhandledAction::Drop => PacketDestination::NoSend,
+                UnhandledAction::Log => {
+                    tracing::info!("{} sent an unhandled packet of type {}: {}", self.display_name, tag, hex::encode(data));
+                    PacketDestination::NoSend
+                }
+            },
             _ => PacketDestination::Broadcast,
         };
 
-        match send_destination {
+        // the real costume is kept for `costume reset`
+        if let PacketData::Costume(costume) = &mut packet.data {
+            if let Some(forced) = &self.get_player().forced_costume {
+                *costume = forced.clone();
+            }
+        }
+
+        let max_rate = settings.relay.max_update_rate;
+        drop(settings);
+        if let PacketData::Player { .. } | PacketData::Cap { .. } = packet.data {
+            match self.throttle.offer(packet, max_rate) {
+                Some(throttled) => packet = throttled,
+                None => return Ok(()),
+            }
+        }
+        self.forward(packet, send_destination).await
+    }
+
+    async fn forward(&mut self, packet: Packet, destination: PacketDestination) -> Result<()> {
+        match destination {
             PacketDestination::NoSend => {}
             PacketDestination::Broadcast => {
                 let mut packet = packet;
                 packet.resize();
-                self.send_broadcast.send(ClientCommand::Packet(packet))?;
+                self.send_broadcast.send(ClientCommand::Packet(packet));
             }
             PacketDestination::Coordinator => self.to_coord.send(Command::Packet(packet)).await?,
         }
@@ -314,10 +549,178 @@ impl Client {
         Ok(())
     }
 
+    /// Forward the movement the `Throttle` held back
+    async fn forward_throttled(&mut self) -> Result<()> {
+        for packet in self.throttle.take_due() {
+            let destination = match packet.data {
+                PacketData::Player { .. } => PacketDestination::Coordinator,
+                _ => PacketDestination::Broadcast,
+            };
+            self.forward(packet, destination).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply the costume and capture bans, returns `false` if the packet is stripped
+    fn enforce_model_bans(&self, settings: &Settings, data: &mut PacketData) -> bool {
+        let bans = &settings.ban_list;
+        if !bans.enabled {
+            return true;
+        }
+        let strip = bans.model_enforcement == ModelEnforcement::Strip;
+
+        match data {
+            PacketData::Costume(costume) => {
+                let body_banned = bans.costumes.contains(&costume.body_name);
+                let cap_banned = bans.costumes.contains(&costume.cap_name);
+                if !body_banned && !cap_banned {
+                    return true;
+                }
+                tracing::info!("Banned costume {}/{} of {}", costume.body_name, costume.cap_name, self.display_name);
+                if strip {
+                    return false;
+                }
+                let default = Costume::default();
+                if body_banned {
+                    costume.body_name = default.body_name;
+                }
+                if cap_banned {
+                    costume.cap_name = default.cap_name;
+                }
+            }
+            PacketData::Capture { model } if bans.captures.contains(model) => {
+                tracing::info!("Banned capture {} of {}", model, self.display_name);
+                if strip {
+                    return false;
+                }
+                model.clear();
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Check the stage of game packets against the known stages, returns `false` if the packet is dropped
+    fn validate_stage(&mut self, settings: &Settings, data: &PacketData) -> bool {
+        let PacketData::Game { stage, .. } = data else {
+            return true;
+        };
+        let validation = &settings.stage_validation;
+        if validation.allows(stage) {
+            return true;
+        }
+        let action = validation.action;
+
+        tracing::warn!("Unknown stage {:?} from {}", stage, self.display_name);
+        match action {
+            StageValidationAction::Log => true,
+            StageValidationAction::Drop => false,
+            StageValidationAction::Kick => {
+                tracing::warn!("Kicking {} for sending an unknown stage", self.display_name);
+                self.alive = false;
+                false
+            }
+        }
+    }
+
+    /// Show the player to the others, who didn't get any of its packets yet
+    fn release_from_limbo(&mut self, max_players: u16) {
+        let mut data = self.get_player_mut();
+        data.in_limbo = false;
+        let packets = data.create_sync_packets(self.guid, max_players);
+        drop(data);
+        tracing::debug!("Releasing {} from limbo", self.display_name);
+        for packet in packets {
+            self.send_broadcast.send(ClientCommand::Packet(packet));
+        }
+    }
+
+    /// Replace not approved costume names with the default costume, remembering the original one for later approval
+    fn moderate_costume(&mut self, settings: &Settings, costume: &mut Costume) {
+        let moderation = &settings.costume_moderation;
+        let body_allowed = moderation.allows(&costume.body_name);
+        let cap_allowed = moderation.allows(&costume.cap_name);
+
+        let held = (!body_allowed || !cap_allowed).then(|| costume.clone());
+        if let Some(held) = &held {
+            tracing::info!(
+                "Holding costume {}/{} of {} for approval",
+                held.body_name,
+                held.cap_name,
+                self.display_name
+            );
+            let default = Costume::default();
+            if !body_allowed {
+                costume.body_name = default.body_name;
+            }
+            if !cap_allowed {
+                costume.cap_name = default.cap_name;
+            }
+        }
+        self.get_player_mut().held_costume = held;
+    }
+
     /// Handle any commands sent from internal channels
-    async fn handle_command(&mut self, command: ClientCommand) -> Result<()> {
+    async fn handle_command(&mut self, command: ClientCommand, mut encoded: Option<Bytes>) -> Result<()> {
         match command {
             ClientCommand::Packet(mut p) => {
+                // players that left or changed the stage start over
+                if p.id != self.guid && matches!(p.data, PacketData::Disconnect | PacketData::Game { .. }) {
+                    self.distant.retain(|(guid, _), _| *guid != p.id);
+                }
+                if p.id != self.guid && self.get_player().hidden_packets.contains(&p.data.get_type_name()) {
+                    return Ok(());
+                }
+                // the same settings for the whole packet, released before it's sent
+                let settings = self.lobby.settings.clone().read_owned().await;
+                // puppets of ghosts and invisible players still get removed, e.g. when turning into one
+                if p.id != self.guid && !matches!(p.data, PacketData::Disconnect) {
+                    if self.lobby.is_hidden_by(&settings, &p) {
+                        return Ok(());
+                    }
+                    let ghosts = &settings.ghosts;
+                    if !ghosts.see_others && ghosts.players.contains(&self.guid) {
+                        return Ok(());
+                    }
+                }
+                // party members only see the movement of their party
+                if p.id != self.guid
+                    && matches!(p.data, PacketData::Player { .. } | PacketData::Cap { .. } | PacketData::Capture { .. })
+                {
+                    let party = settings.party_of(&self.guid);
+                    if party.is_some() && party != settings.party_of(&p.id) {
+                        return Ok(());
+                    }
+                    let relay = &settings.relay;
+                    if (relay.same_stage_only || relay.interest_radius > 0.0)
+                        && matches!(p.data, PacketData::Player { .. } | PacketData::Cap { .. })
+                    {
+                        let own = self.get_player();
+                        let (own_stage, own_pos) = (own.last_stage().map(str::to_string), own.last_position());
+                        drop(own);
+                        let (same_stage, pos) = match self.lobby.players.get(&p.id) {
+                            Some(sender) => (sender.last_stage() == own_stage.as_deref(), sender.last_position()),
+                            None => (true, None),
+                        };
+                        if relay.same_stage_only && !same_stage {
+                            return Ok(());
+                        }
+                        // far away players only get the occasional update
+                        let distant = matches!((own_pos, pos), (Some(a), Some(b)) if (a - b).norm() > relay.interest_radius);
+                        if same_stage && relay.interest_radius > 0.0 && distant {
+                            if relay.distant_update_rate == 0 {
+                                return Ok(());
+                            }
+                            let interval = Duration::from_secs(1) / relay.distant_update_rate;
+                            let key = (p.id, p.data.get_type_name());
+                            let now = Instant::now();
+                            if self.distant.get(&key).is_some_and(|last| now.duration_since(*last) < interval) {
+                                return Ok(());
+                            }
+                            self.distant.insert(key, now);
+                        }
+                    }
+                }
                 match &mut p.data {
                     // Same pid handling
                     PacketData::Disconnect if p.id == self.guid => {
@@ -331,24 +734,30 @@ impl Client {
                         ref mut pos,
                         ref mut rot,
                         ..
-                    } => {
-                        let settings = self.lobby.settings.read().await;
-                        if settings.flip.enabled
-                            && settings.flip.pov.is_self_flip()
-                            && settings.flip.players.get(&self.guid).is_some()
-                            && settings.flip.players.get(&p.id).is_none()
-                        {
-                            let angle = std::f32::consts::PI;
-                            let rot_quad =
-                                *(UnitQuaternion::from_axis_angle(&Vector3::z_axis(), angle));
-                            let data = self.get_player();
-                            *pos += get_mario_size(data.is_2d) * Vector3::y();
-                            *rot *= rot_quad;
+                    } if settings.flip.enabled
+                        && settings.flip.pov.is_self_flip()
+                        && settings.flip.players.get(&self.guid).is_some()
+                        && settings.flip.players.get(&p.id).is_none() =>
+                    {
+                        let angle = std::f32::consts::PI;
+                        let rot_quad =
+                            *(UnitQuaternion::from_axis_angle(&Vector3::z_axis(), angle));
+                        let data = self.get_player();
+                        *pos += get_mario_size(data.is_2d) * Vector3::y();
+                        *rot *= rot_quad;
+                        encoded = None;
+                    }
+                    PacketData::Game { ref mut scenario_num, .. } => {
+                        if let Some(scenario) = settings.scenario.player_overrides.get(&p.id) {
+                            *scenario_num = *scenario;
+                            encoded = None;
                         }
                     }
                     _ => {}
                 }
-                self.send_packet(&p).await?;
+                self.trace_send(&settings, &p);
+                drop(settings);
+                self.send_encoded(&p, encoded.as_deref()).await?;
             }
             ClientCommand::SelfAddressed(mut p) => {
                 // Update local client data with any outgoing packet data
@@ -383,30 +792,93 @@ impl Client {
 
     /// Send packet to player using either tcp or udp
     pub async fn send_packet(&mut self, packet: &Packet) -> Result<()> {
-        // Packet logging
         if tracing::enabled!(Level::TRACE) {
-            match packet.data {
-                PacketData::Player { .. } | PacketData::Cap { .. } => {}
-                _ => {
-                    tracing::trace!(
-                        "Sending packet: {}->{}",
-                        packet.id,
-                        packet.data.get_type_name()
-                    );
-                }
+            let settings = self.lobby.settings.clone().read_owned().await;
+            self.trace_send(&settings, packet);
+        }
+        self.send_encoded(packet, None).await
+    }
+
+    /// Packet logging
+    fn trace_send(&mut self, settings: &Settings, packet: &Packet) {
+        if tracing::enabled!(Level::TRACE) {
+            let rates = &settings.logging.trace_sampling;
+            if self.send_sampler.sample(rates, packet.data.get_type_name()) {
+                tracing::trace!(
+                    "Sending packet: {}->{}",
+                    packet.id,
+                    packet.data.get_type_name()
+                );
             }
         }
+    }
+
+    /// Send packet to player, reusing its encoding if there is one
+    async fn send_encoded(&mut self, packet: &Packet, encoded: Option<&[u8]>) -> Result<()> {
+        if self.stats.send(packet) {
+            self.publish_stats();
+        }
 
         match packet.data {
             // Use UDP traffic for player and cap if possible
-            PacketData::Player { .. } | PacketData::Cap { .. } if self.udp_conn.is_client_udp() => {
-                self.udp_conn.write_packet(packet).await
-            }
+            PacketData::Player { .. } | PacketData::Cap { .. } if self.use_udp() => match encoded {
+                Some(buff) => self.udp_conn.write_encoded(buff).await,
+                None => self.udp_conn.write_packet(packet).await,
+            },
             // Fallback to tcp otherwise
-            _ => self.conn.write_packet(packet).await,
+            _ => match encoded {
+                Some(buff) => self.conn.write_encoded(buff).await,
+                None => self.conn.write_packet(packet).await,
+            },
         }
     }
 
+    /// Keep the NAT mapping of the client open, re-initiating the udp handshake once the client stays quiet
+    async fn udp_keepalive(&mut self) -> Result<()> {
+        let Some(interval) = self.keepalive else {
+            return Ok(());
+        };
+        self.next_keepalive = Instant::now() + interval;
+        if !matches!(self.udp_conn.send_addr, UdpSenderStatus::Connected(_)) {
+            return Ok(());
+        }
+
+        let last_heard = self.udp_conn.last_recv.max(Some(self.udp_handshake));
+        if last_heard.is_some_and(|last| last.elapsed() > interval * 3) {
+            tracing::debug!("No udp packets from {} in a while, restarting the udp handshake", self.display_name);
+            self.udp_handshake = Instant::now();
+            let port = self.udp_conn.socket.local_addr()?.port();
+            self.conn.write_packet(&Packet::new(Guid::NIL, PacketData::UdpInit { port })).await?;
+        }
+        self.udp_conn.write_packet(&Packet::new(self.guid, PacketData::HolePunch)).await?;
+        self.stats.punch_sent();
+        Ok(())
+    }
+
+    /// Copy the connection statistics into the player data, for `netstat` and the JSON API
+    fn publish_stats(&self) {
+        if let Some(mut data) = self.lobby.players.get_mut(&self.guid) {
+            data.connection = self.stats.clone();
+        }
+    }
+
+    /// Whether to send movement over UDP, noting changes of the transport in the player data
+    fn use_udp(&mut self) -> bool {
+        let udp = self.udp_conn.is_client_udp();
+        if udp != self.udp {
+            self.udp = udp;
+            if udp {
+                tracing::debug!("Sending the movement to {} over udp", self.display_name);
+            } else {
+                tracing::info!("{} stopped sending udp packets, falling back to tcp", self.display_name);
+            }
+            if let Some(mut data) = self.lobby.players.get_mut(&self.guid) {
+                data.udp = udp;
+            }
+        }
+        udp
+    }
+
     /// Readdress packet to come from the same guid as client then send
     pub async fn readdress_and_send(&mut self, p: &mut Packet) -> Result<()> {
         p.id = self.guid;
@@ -415,24 +887,40 @@ impl Client {
 
     /// Perform the initialization and handshake with client then hand off to coordinator
     pub async fn initialize_client(
-        socket: TcpStream,
+        mut conn: Connection,
+        slot: SlotReservation,
         to_coord: mpsc::Sender<Command>,
-        broadcast: broadcast::Sender<ClientCommand>,
+        broadcast: Relay,
         udp_port: u16,
         lobby: Lobby,
     ) -> Result<()> {
         let (to_cli, from_server) = mpsc::channel(10);
-        let tcp_sock_addr = socket.peer_addr().expect("Couldn't get tcp peer address");
+        let tcp_sock_addr = conn.addr;
 
         let l_set = lobby.settings.read().await;
         let max_players = l_set.server.max_players;
-        let start_udp_handshake = l_set.udp.initiate_handshake;
+        let udp_enabled = l_set.udp.enabled;
+        let start_udp_handshake = udp_enabled && l_set.udp.initiate_handshake;
+        let udp_fallback = Some(Duration::from_millis(l_set.udp.fallback_timeout_ms)).filter(|t| !t.is_zero());
+        let udp_keepalive = Some(Duration::from_millis(l_set.udp.keepalive_interval_ms)).filter(|t| !t.is_zero());
+        let queue_capacity = l_set.relay.queue_capacity;
         drop(l_set);
 
-        let mut conn = Connection::new(socket);
-
         tracing::debug!("Waiting for client init");
-        let connect = conn.read_packet().await?;
+        let mut connect = conn.read_packet().await?;
+        if let PacketData::Connect { client_name, .. } = &mut connect.data {
+            let name_settings = lobby.settings.read().await.names.clone();
+            let mut name = name_settings.sanitize(client_name);
+            if name_settings.duplicates == DuplicateNames::Suffix {
+                let names = lobby.names.0.read().await;
+                let is_taken = |n: &str| names.get_by_right(n).is_some_and(|guid| *guid != connect.id);
+                name = name_settings.deduplicate(&name, is_taken);
+            }
+            if name != *client_name {
+                tracing::info!("Renaming {:?} from {} to {}", client_name, tcp_sock_addr, name);
+                *client_name = name;
+            }
+        }
 
         let new_player = match connect.data {
             PacketData::Connect {
@@ -440,12 +928,20 @@ impl Client {
                 ref c_type,
                 ..
             } => {
+                if !connect.id.is_valid() {
+                    tracing::warn!("Client {} tried to connect with a reserved id {}", tcp_sock_addr, connect.id);
+                    return Err(SMOError::ClientInit(ClientInitError::BadHandshake));
+                }
+
                 let settings = lobby.settings.read().await;
                 if settings.ban_list.players.contains(&connect.id) {
                     let identifier = format!("{} ({}/{})", tcp_sock_addr.to_string(), name, connect.id);
                     tracing::warn!("Banned profile tried to connect: {}", identifier);
+                    lobby.emit(ServerEvent::BannedClient { ip: tcp_sock_addr.ip(), name: Some(name.clone()) });
                     tracing::info!("Ignoring player {}", identifier);
-                    Self::ignore_client(conn, identifier).await?;
+                    let enforcement = settings.ban_list.enforcement;
+                    drop(settings);
+                    Self::ignore_client(conn, identifier, enforcement).await?;
                     return Err(SMOError::ClientInit(ClientInitError::BannedID));
                 }
                 drop(settings);
@@ -453,7 +949,7 @@ impl Client {
                 // send server init
                 tracing::debug!("Send server init");
                 conn.write_packet(&Packet::new(
-                    Guid::default(),
+                    Guid::NIL,
                     PacketData::Init { max_players },
                 ))
                 .await?;
@@ -474,9 +970,12 @@ impl Client {
                 // old player data and not create a completely new object.
                 // Because older versions of the mod (below 1.3.0) did not send
                 // all important packets again after a reconnect.
+                // reconnecting clients of older mods don't necessarily send a game packet again
+                let in_limbo = *c_type == ConnectionType::FirstConnection && lobby.settings.read().await.limbo.enabled;
                 let data = PlayerData {
                     name: name.clone(),
                     ipv4: Some(conn.addr.ip()),
+                    in_limbo,
                     ..PlayerData::new(to_cli.clone())
                 };
 
@@ -487,12 +986,13 @@ impl Client {
                 tracing::debug!("Binding udp to: {:?}", local_udp_addr);
 
                 tracing::debug!("setting new udp connection");
-                let udp_conn = UdpConnection::new(udp, tcp_sock_addr.ip());
+                let mut udp_conn = UdpConnection::new(udp, tcp_sock_addr.ip());
+                udp_conn.fallback_after = udp_fallback;
 
                 if start_udp_handshake {
                     tracing::debug!("Starting udp handshake");
                     conn.write_packet(&Packet::new(
-                        Guid::default(),
+                        Guid::NIL,
                         PacketData::UdpInit {
                             port: local_udp_addr.port(),
                         },
@@ -500,12 +1000,12 @@ impl Client {
                     .await?;
                 }
 
-                let recv_broadcast = broadcast.subscribe();
+                let queue = broadcast.register(connect.id, queue_capacity);
 
                 let to_coord = to_coord.clone();
                 tracing::debug!("Created client data");
                 let client = Client {
-                    display_name: name.trim_matches(char::from(0)).to_string(),
+                    display_name: name.clone(),
                     guid: connect.id,
                     alive: true,
                     to_coord,
@@ -513,8 +1013,17 @@ impl Client {
                     conn,
                     udp_conn,
                     send_broadcast: broadcast,
-                    recv_broadcast,
+                    queue,
                     lobby,
+                    recv_sampler: Default::default(),
+                    send_sampler: Default::default(),
+                    throttle: Default::default(),
+                    distant: Default::default(),
+                    udp: false,
+                    keepalive: udp_keepalive,
+                    stats: Default::default(),
+                    next_keepalive: Instant::now(),
+                    udp_handshake: Instant::now(),
                 };
 
                 tracing::debug!("Initialized player");
@@ -524,6 +1033,7 @@ impl Client {
                     data,
                     connect_packet: Box::new(connect),
                     comm: to_cli,
+                    slot,
                 })))
             }
             PacketData::JsonApi { json } => {
@@ -539,14 +1049,43 @@ impl Client {
         Ok(())
     }
 
-    pub async fn ignore_client(mut conn: Connection, mut identifier: String) -> Result<()> {
+    pub async fn ignore_client(mut conn: Connection, mut identifier: String, enforcement: BanEnforcement) -> Result<()> {
+        let crash_after = match enforcement {
+            BanEnforcement::Disconnect => {
+                tracing::info!("Disconnecting ignored player {}", identifier);
+                conn.socket.shutdown().await?;
+                return Ok(());
+            }
+            BanEnforcement::Tarpit { bytes_per_second } => return Self::tarpit(conn, identifier, bytes_per_second).await,
+            BanEnforcement::TimedCrash { seconds } => Some(Duration::from_secs(seconds)),
+            BanEnforcement::CrashOnStage => None,
+        };
+
         // send server init (required to crash ignored players later)
         conn.write_packet(&Packet::new(
-            Guid::default(),
+            Guid::NIL,
             PacketData::Init { max_players: 1 },
         )).await?;
+        let crash_timer = async {
+            match crash_after {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(crash_timer);
+        // the timer can't be polled again once it fired
+        let mut crashed = false;
         loop {
-            match conn.read_packet().await {
+            let packet = tokio::select! {
+                packet = conn.read_packet() => packet,
+                _ = &mut crash_timer, if !crashed => {
+                    crashed = true;
+                    tracing::info!("Crashing ignored player {} after {}s", identifier, crash_after.unwrap_or_default().as_secs());
+                    Self::crash_ignored(&mut conn).await?;
+                    continue;
+                },
+            };
+            match packet {
                 // disconnect
                 Err(_) => { break; },
                 // client init
@@ -556,21 +1095,12 @@ impl Client {
                     tracing::info!("Ignoring player {}", identifier);
                 },
                 // client entered a stage
-                Ok(Packet { data: PacketData::Game { stage, .. }, .. }) => {
+                Ok(Packet { data: PacketData::Game { stage, .. }, .. }) if crash_after.is_none() => {
                     tracing::debug!("{} packet received from {}.", "game", identifier);
                     tracing::info!("Crashing ignored player {} after entering stage {}", identifier, stage);
                     // wait 500ms
                     tokio::time::sleep(Duration::from_millis(500)).await;
-                    // crash player
-                    conn.write_packet(&Packet::new(
-                        Guid::default(),
-                        PacketData::ChangeStage {
-                            id           : "$among$us/SubArea".to_string(),
-                            stage        : "$agogusStage".to_string(),
-                            scenario     : 21,
-                            sub_scenario : 69,
-                        },
-                    )).await?;
+                    Self::crash_ignored(&mut conn).await?;
                 },
                 // ignore all other packages
                 Ok(Packet { data, .. }) => {
@@ -582,6 +1112,29 @@ impl Client {
         Ok(())
     }
 
+    async fn crash_ignored(conn: &mut Connection) -> Result<()> {
+        conn.write_packet(&Packet::new(
+            Guid::NIL,
+            PacketData::ChangeStage {
+                id           : "$among$us/SubArea".to_string(),
+                stage        : "$agogusStage".to_string(),
+                scenario     : 21,
+                sub_scenario : 69,
+            },
+        )).await
+    }
+
+    /// Hold the connection open without answering, only reading a few bytes per second
+    async fn tarpit(mut conn: Connection, identifier: String, bytes_per_second: usize) -> Result<()> {
+        tracing::info!("Tarpitting ignored player {}", identifier);
+        let mut buff = vec![0; bytes_per_second.max(1)];
+        while conn.socket.get_mut().read(&mut buff).await? > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        tracing::info!("Tarpitted player disconnected {}", identifier);
+        Ok(())
+    }
+
     fn get_player(&self) -> Ref<'_, Guid, PlayerData, RandomState> {
         self.lobby
             .players
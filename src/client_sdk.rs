@@ -0,0 +1,174 @@
+//! High level client for the server protocol, for bots, relays and integration tests
+//!
+//! ```no_run
+//! # async fn run() -> smoo::types::Result<()> {
+//! use smoo::{client_sdk::{SmoClient, SmoEvent}, guid::Guid};
+//!
+//! let mut client = SmoClient::connect("127.0.0.1:1027", Guid::new_random(), "Bot").await?;
+//! client.send_game("CapWorldHomeStage", 1, false).await?;
+//! while let Ok(event) = client.next_event().await {
+//!     if let SmoEvent::PlayerConnected { name, .. } = event {
+//!         println!("{} joined", name);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use tokio::{
+    net::{TcpStream, ToSocketAddrs},
+    time::timeout,
+};
+
+use crate::{
+    guid::Guid,
+    net::{connection::Connection, ConnectionType, GameMode, Packet, PacketData, TagUpdate},
+    types::{ClientInitError, Costume, Quaternion, Result, Vector3},
+};
+
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something that happened on the server, as seen by a client
+#[derive(Debug, Clone)]
+pub enum SmoEvent {
+    PlayerConnected { id: Guid, name: String },
+    PlayerDisconnected { id: Guid },
+    PlayerMoved { id: Guid, pos: Vector3, rot: Quaternion },
+    CapMoved { id: Guid, pos: Vector3, rot: Quaternion, cap_out: bool },
+    StageChanged { id: Guid, stage: String, scenario: i8, is_2d: bool },
+    CostumeChanged { id: Guid, costume: Costume },
+    CaptureChanged { id: Guid, model: String },
+    TagChanged { id: Guid, is_it: bool, time: Duration },
+    /// A moon collected by the other players
+    Shine { shine_id: i32 },
+    /// The server wants the client to go to another stage
+    ChangeStage { stage: String, id: String, scenario: i8 },
+    /// Any other packet
+    Other(Packet),
+}
+
+impl From<Packet> for SmoEvent {
+    fn from(packet: Packet) -> Self {
+        let id = packet.id;
+        match packet.data {
+            PacketData::Connect { client_name, .. } => Self::PlayerConnected { id, name: client_name },
+            PacketData::Disconnect => Self::PlayerDisconnected { id },
+            PacketData::Player { pos, rot, .. } => Self::PlayerMoved { id, pos, rot },
+            PacketData::Cap { pos, rot, cap_out, .. } => Self::CapMoved { id, pos, rot, cap_out },
+            PacketData::Game { stage, scenario_num, is_2d } => Self::StageChanged {
+                id,
+                stage,
+                scenario: scenario_num,
+                is_2d,
+            },
+            PacketData::Costume(costume) => Self::CostumeChanged { id, costume },
+            PacketData::Capture { model } => Self::CaptureChanged { id, model },
+            PacketData::Tag { is_it, minutes, seconds, .. } => Self::TagChanged {
+                id,
+                is_it,
+                time: Duration::from_secs(u64::from(minutes) * 60 + u64::from(seconds)),
+            },
+            PacketData::Shine { shine_id, .. } => Self::Shine { shine_id },
+            PacketData::ChangeStage { stage, id, scenario, .. } => Self::ChangeStage { stage, id, scenario },
+            data => Self::Other(Packet { data, ..packet }),
+        }
+    }
+}
+
+/// A connected and initialized client, talking to the server over tcp only
+pub struct SmoClient {
+    pub guid: Guid,
+    /// Maximum amount of players the server told the client about
+    pub max_players: u16,
+    conn: Connection,
+}
+
+impl SmoClient {
+    /// Connect and do the handshake, fails if the server doesn't answer with its init packet
+    pub async fn connect(addr: impl ToSocketAddrs, guid: Guid, name: impl Into<String>) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        socket.set_nodelay(true)?;
+        let mut conn = Connection::new(socket);
+
+        let connect = PacketData::Connect {
+            c_type: ConnectionType::FirstConnection,
+            max_player: u16::MAX,
+            client_name: name.into(),
+        };
+        conn.write_packet(&Packet::new(guid, connect)).await?;
+
+        let init = timeout(INIT_TIMEOUT, conn.read_packet())
+            .await
+            .map_err(|_| ClientInitError::BadHandshake)??;
+        let max_players = match init.data {
+            PacketData::Init { max_players } => max_players,
+            _ => return Err(ClientInitError::BadHandshake.into()),
+        };
+
+        Ok(Self {
+            guid,
+            max_players,
+            conn,
+        })
+    }
+
+    pub async fn send(&mut self, data: PacketData) -> Result<()> {
+        self.conn.write_packet(&Packet::new(self.guid, data)).await
+    }
+
+    pub async fn send_position(&mut self, pos: Vector3, rot: Quaternion) -> Result<()> {
+        self.send(PacketData::Player {
+            pos,
+            rot,
+            animation_blend_weights: [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            act: 0,
+            sub_act: 0,
+        })
+        .await
+    }
+
+    pub async fn send_game(&mut self, stage: impl Into<String>, scenario: i8, is_2d: bool) -> Result<()> {
+        self.send(PacketData::Game {
+            is_2d,
+            scenario_num: scenario,
+            stage: stage.into(),
+        })
+        .await
+    }
+
+    pub async fn send_costume(&mut self, body: impl Into<String>, cap: impl Into<String>) -> Result<()> {
+        self.send(PacketData::Costume(Costume {
+            body_name: body.into(),
+            cap_name: cap.into(),
+        }))
+        .await
+    }
+
+    pub async fn send_tag(&mut self, is_it: bool, time: Duration) -> Result<()> {
+        let secs = time.as_secs();
+        self.send(PacketData::Tag {
+            game_mode: GameMode::HideAndSeek,
+            update_type: TagUpdate::Both,
+            is_it,
+            seconds: (secs % 60) as u8,
+            minutes: (secs / 60) as u16,
+        })
+        .await
+    }
+
+    pub async fn send_shine(&mut self, shine_id: i32) -> Result<()> {
+        self.send(PacketData::Shine { shine_id, is_grand: false }).await
+    }
+
+    /// Wait for the next packet of the server
+    pub async fn next_event(&mut self) -> Result<SmoEvent> {
+        Ok(self.conn.read_packet().await?.into())
+    }
+
+    /// Disconnect gracefully
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.send(PacketData::Disconnect).await
+    }
+}
@@ -11,10 +11,11 @@ use crate::{
     guid::Guid,
     lobby::{Lobby, LobbyView},
     net::Packet,
-    types::{Result, SMOError},
+    npc::Npc,
+    types::{Result, SMOError, Vector3},
 };
 
-use std::{collections::BTreeSet, net::IpAddr};
+use std::{collections::BTreeSet, net::IpAddr, path::PathBuf};
 
 use self::reply::ReplyChannel;
 
@@ -39,6 +40,12 @@ pub enum ExternalCommand {
     Shine {
         command: ShineCommand,
     },
+    Recording {
+        command: RecordingCommand,
+    },
+    Npc {
+        command: NpcCommand,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +72,42 @@ pub enum ShineCommand {
     Clear,
 }
 
+/// Work for the shine sync task, sent by the coordinator
+#[derive(Debug)]
+pub enum ShineRequest {
+    Collected { id: Guid, shine_id: i32, is_grand: bool },
+    /// Sync all players soon, together with any other syncs requested until then
+    Sync,
+    /// Sync all players right away, answering once the shines were sent
+    SyncNow(Option<ReplyChannel<Result<String>>>),
+    /// Sync a single player, e.g. after its shine sync was enabled again
+    SyncPlayer(Guid),
+    /// Forget the collected shines, and with `players` also which shines the players have
+    Clear { players: bool },
+}
+
+#[derive(Debug, Clone)]
+pub enum RecordingCommand {
+    Start { path: Option<PathBuf> },
+    Stop,
+}
+
+#[derive(Debug, Clone)]
+pub enum NpcCommand {
+    Spawn {
+        npc: Npc,
+    },
+    Move {
+        npcs: Vec<Guid>,
+        path: Vec<Vector3>,
+        speed: f32,
+        loop_path: bool,
+    },
+    Despawn {
+        npcs: Vec<Guid>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum Players {
     All,
@@ -79,13 +122,6 @@ impl Players {
         }
     }
 
-    pub fn get_guids(&self, lobby: &Lobby) -> BTreeSet<Guid> {
-        match self {
-            Self::All           => lobby.players.iter().map(|x| *x.key()).collect(),
-            Self::Individual(p) => p.iter().cloned().collect(),
-        }
-    }
-
     pub fn get_ipv4s(&self, lobby: &Lobby) -> BTreeSet<IpAddr> {
         match self {
             Self::All           => lobby.players.iter().filter_map(|x| x.value().ipv4).collect(),
@@ -93,13 +129,6 @@ impl Players {
         }
     }
 
-    pub fn get_names(&self, lobby: &Lobby) -> BTreeSet<String> {
-        match self {
-            Self::All           => lobby.players.iter().map(|x| x.value().name.to_string()).collect(),
-            Self::Individual(p) => lobby.players.iter().filter(|x| p.contains(x.key())).map(|x| x.value().name.to_string()).collect(),
-        }
-    }
-
     pub fn verify(&self, lobby: &LobbyView) -> Result<()> {
         match self {
             Self::All => Ok(()),
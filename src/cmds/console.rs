@@ -1,7 +1,8 @@
-use crate::{guid::Guid, net::GameMode, player_holder::PlayerSelect, settings::FlipPovSettings};
-use std::{convert::Infallible, net::IpAddr, str::FromStr};
+use crate::{guid::Guid, net::GameMode, player_holder::PlayerSelect, settings::FlipPovSettings, types::Vector3};
+use std::{convert::Infallible, net::IpAddr, path::PathBuf, str::FromStr};
 
 use clap::Subcommand;
+use tracing::{level_filters::LevelFilter, Level};
 
 #[derive(Subcommand, Debug, Clone)]
 #[clap(rename_all = "lower")]
@@ -19,12 +20,42 @@ pub enum ConsoleCommand {
         scenario: i8,
         players: Vec<SinglePlayerSelect>,
     },
+    /// Send players to the stage and scenario of another player
+    SendTo {
+        target: SinglePlayerSelect,
+        players: Vec<SinglePlayerSelect>,
+    },
+    #[clap(subcommand)]
+    Warp(WarpCommand),
+    #[clap(subcommand)]
+    Race(RaceCommand),
+    #[clap(subcommand)]
+    Party(PartyCommand),
     #[clap(subcommand)]
     Ban(BanCommand),
     #[clap(subcommand)]
     Unban(UnbanCommand),
     Crash {
         players: Vec<SinglePlayerSelect>,
+        /// Only show who would be affected
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Disconnect players, they are free to join again
+    Kick {
+        players: Vec<SinglePlayerSelect>,
+        #[arg(short, long, num_args = 1..)]
+        reason: Vec<String>,
+    },
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+    /// Names, addresses and connection times of a player by any of its names or its guid
+    History {
+        player: String,
+    },
+    /// Other players that connected from the same addresses as the player
+    Alts {
+        player: String,
     },
     Rejoin {
         players: Vec<SinglePlayerSelect>,
@@ -35,16 +66,116 @@ pub enum ConsoleCommand {
     Tag(TagCommand),
     MaxPlayers {
         player_count: u16,
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Connected players, optionally only those matching all of the filters
+    List {
+        /// Stage name or alias of a home stage, e.g. `metro`
+        #[arg(long)]
+        stage: Option<String>,
+        /// Any stage of the kingdom, e.g. `sand`
+        #[arg(long)]
+        kingdom: Option<String>,
+        /// Seekers in hide and seek
+        #[arg(long)]
+        tagged: bool,
+        #[arg(long = "2d")]
+        is_2d: bool,
+    },
+    /// Everything known about a connected player
+    Info {
+        player: SinglePlayerSelect,
+    },
+    /// Stage, kingdom, scenario and last known position of the players
+    Where {
+        players: Vec<SinglePlayerSelect>,
+    },
+    /// Round trip times, udp loss and traffic of the players
+    Netstat,
+    /// The most recent log lines, optionally only those at least as severe as the level
+    Log {
+        #[arg(default_value_t = 20)]
+        n: usize,
+        level: Option<Level>,
+    },
+    /// Change the log level of a module (e.g. `net::udp_conn trace`) or of everything else (`default`)
+    LogLevel {
+        target: String,
+        level: LevelFilter,
     },
-    List,
     #[clap(subcommand)]
     Flip(FlipCommand),
+    /// Players that only watch, without a visible Mario
+    #[clap(subcommand)]
+    Spectate(SpectateCommand),
+    /// Quietly hide a player from the others, by name or guid, may be offline
+    Ghost {
+        player: String,
+        #[arg(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Watch the others without a puppet of your own, by name or guid, may be offline
+    Invisible {
+        player: String,
+        #[arg(action = clap::ArgAction::Set, value_parser = clap::builder::BoolishValueParser::new())]
+        enabled: bool,
+    },
+    /// Notes about players, by name or guid, may be offline
+    #[clap(subcommand)]
+    Note(NoteCommand),
+    /// Only allow entering the listed stages, while the ban list is enabled
+    #[clap(subcommand)]
+    AllowList(AllowListCommand),
     #[clap(subcommand)]
     Shine(ShineArg),
+    /// Save the session state to restore it for reconnecting players after a restart
+    #[clap(subcommand)]
+    Snapshot(SessionCommand),
     #[clap(subcommand)]
     Udp(UdpCommand),
+    /// Review modded costumes that are held back from the other players
+    #[clap(subcommand)]
+    Costume(CostumeCommand),
+    /// Stop relaying packet types of other players to some players
+    #[clap(subcommand)]
+    Filter(FilterCommand),
+    /// Record the packets passing through the server
+    #[clap(subcommand)]
+    Record(RecordCommand),
+    #[clap(subcommand)]
+    Api(ApiCommand),
+    /// Manage the tokens of the JSON API
+    #[clap(subcommand)]
+    Token(TokenCommand),
+    /// Fake players controlled by the server
+    #[clap(subcommand)]
+    Npc(NpcArg),
     LoadSettings,
+    /// List the additional lobbies or run a command in one of them
+    Lobby {
+        name: Option<String>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
     Restart,
+    /// Run the destructive command shown last, after checking who it affects
+    Confirm,
+}
+
+impl ConsoleCommand {
+    /// Whether the command disconnects players or discards data, then `Some` with its `--dry-run` flag
+    pub fn dry_run(&self) -> Option<bool> {
+        match self {
+            ConsoleCommand::Crash { dry_run, .. }
+            | ConsoleCommand::MaxPlayers { dry_run, .. }
+            | ConsoleCommand::Ban(BanCommand::Player { dry_run, .. })
+            | ConsoleCommand::Ban(BanCommand::Profile { dry_run, .. })
+            | ConsoleCommand::Ban(BanCommand::IP { dry_run, .. })
+            | ConsoleCommand::Shine(ShineArg::Clear { dry_run }) => Some(*dry_run),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -55,12 +186,24 @@ pub enum BanCommand {
     Disable,
     Player {
         players: Vec<SinglePlayerSelect>,
+        #[arg(short, long, num_args = 1..)]
+        reason: Vec<String>,
+        #[arg(long = "dry-run")]
+        dry_run: bool,
     },
     Profile {
         profile_id: Guid,
+        #[arg(short, long, num_args = 1..)]
+        reason: Vec<String>,
+        #[arg(long = "dry-run")]
+        dry_run: bool,
     },
     IP {
         ipv4: IpAddr,
+        #[arg(short, long, num_args = 1..)]
+        reason: Vec<String>,
+        #[arg(long = "dry-run")]
+        dry_run: bool,
     },
     Stage {
         stage: String,
@@ -68,6 +211,14 @@ pub enum BanCommand {
     GameMode {
         game_mode: GameMode,
     },
+    /// Ban a body or cap name
+    Costume {
+        name: String,
+    },
+    /// Ban a capture model
+    Capture {
+        model: String,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -85,12 +236,115 @@ pub enum UnbanCommand {
     GameMode {
         game_mode: GameMode,
     },
+    Costume {
+        name: String,
+    },
+    Capture {
+        model: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum AuditCommand {
+    /// The latest moderation actions of the audit log
+    List {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+    /// The latest entries of the audit log, including every command of the console and the JSON API
+    Tail {
+        #[arg(default_value_t = 20)]
+        count: usize,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum WarpCommand {
+    List,
+    /// Name a stage entrance, the entrance id defaults to the default entrance and the scenario to the current one
+    ///
+    /// The names of the subcommands can't be used.
+    Add {
+        #[arg(short, long)]
+        force: bool,
+        name: String,
+        stage: String,
+        id: Option<String>,
+        scenario: Option<i8>,
+    },
+    Remove {
+        name: String,
+    },
+    /// `warp <name> <players...>` sends the players to the warp
+    #[clap(external_subcommand)]
+    To(Vec<String>),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum RaceCommand {
+    /// Define a route from the start stage along the checkpoints to the finish stage
+    Create {
+        name: String,
+        #[arg(num_args = 2..)]
+        stages: Vec<String>,
+    },
+    /// Send all players to the start and start timing
+    Start {
+        name: String,
+    },
+    /// Finishes of the current race and the best times of the players
+    Standings,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum PartyCommand {
+    List,
+    Create {
+        name: String,
+    },
+    /// Move players by name or guid into the party, they may be offline
+    Add {
+        name: String,
+        #[arg(num_args = 1..)]
+        players: Vec<String>,
+    },
+    /// Take players out of their parties, they see everyone again
+    Remove {
+        #[arg(num_args = 1..)]
+        players: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum AllowListCommand {
+    List,
+    Enable,
+    Disable,
+    /// Allow a stage or all stages of a kingdom alias
+    Add {
+        stage: String,
+    },
+    Remove {
+        stage: String,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
 #[clap(rename_all = "lower")]
 pub enum ScenarioCommand {
     Merge { enabled: Option<bool> },
+    /// Pin all players in the stage to the scenario
+    Lock { stage: String, scenario: i8 },
+    Unlock { stage: String },
+    /// Show the player to the others in the scenario, regardless of the actual one
+    Set { player: SinglePlayerSelect, scenario: i8 },
+    /// Show the actual scenario of the player to the others again
+    Unset { player: SinglePlayerSelect },
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -110,17 +364,39 @@ pub enum TagCommand {
         countdown: u8,
         seekers: Vec<SinglePlayerSelect>,
     },
+    /// Wins and hide times of the rounds started with `tag start`
+    Scoreboard,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum NoteCommand {
+    /// The notes of a player, or of every player with notes
+    List {
+        player: Option<String>,
+    },
+    Add {
+        player: String,
+        #[arg(required = true, trailing_var_arg = true)]
+        text: Vec<String>,
+    },
+    /// Remove all notes of a player
+    Clear {
+        player: String,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
 #[clap(rename_all = "lower")]
 pub enum FlipCommand {
     List,
+    /// Add a player by name or guid, may be offline
     Add {
-        player: Guid,
+        player: String,
     },
+    /// Remove a player by name or guid, may be offline
     Remove {
-        player: Guid,
+        player: String,
     },
     Set {
         #[arg(action = clap::ArgAction::Set)]
@@ -131,11 +407,28 @@ pub enum FlipCommand {
     },
 }
 
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum SpectateCommand {
+    List,
+    /// Add a player by name or guid, may be offline
+    Add {
+        player: String,
+    },
+    /// Remove a player by name or guid, may be offline
+    Remove {
+        player: String,
+    },
+}
+
 #[derive(Subcommand, Debug, Clone)]
 #[clap(rename_all = "lower")]
 pub enum ShineArg {
     List,
-    Clear,
+    Clear {
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
     Sync,
     Send {
         id: i32,
@@ -151,6 +444,33 @@ pub enum ShineArg {
     Exclude {
         id: i32,
     },
+    /// Never sync shines to a player, by name or guid, may be offline
+    ExcludePlayer {
+        player: String,
+    },
+    /// Sync shines to an excluded player again
+    IncludePlayer {
+        player: String,
+    },
+    /// List the server shines the players are missing, grouped by kingdom for a single player
+    Progress {
+        player: Option<SinglePlayerSelect>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum SessionCommand {
+    /// Write the shines, tag state and last packets of every player to the file
+    Save {
+        #[arg(default_value = "./session.json")]
+        path: String,
+    },
+    /// Restore the session from the file, for the players once they reconnect
+    Load {
+        #[arg(default_value = "./session.json")]
+        path: String,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -165,6 +485,161 @@ pub enum UdpCommand {
     },
 }
 
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum CostumeCommand {
+    /// List the held back costume names and who is wearing them
+    Pending,
+    /// List the approved costume names
+    List,
+    Approve {
+        name: String,
+    },
+    Revoke {
+        name: String,
+    },
+    /// Show the players to everyone in the given costume, until `costume reset`
+    Set {
+        body: String,
+        cap: String,
+        players: Vec<SinglePlayerSelect>,
+    },
+    /// Show the real costumes of the players again
+    Reset {
+        players: Vec<SinglePlayerSelect>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum FilterCommand {
+    List,
+    Hide {
+        packet_type: String,
+        players: Vec<SinglePlayerSelect>,
+    },
+    Show {
+        packet_type: String,
+        players: Vec<SinglePlayerSelect>,
+    },
+    Clear {
+        players: Vec<SinglePlayerSelect>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum RecordCommand {
+    Start {
+        path: Option<PathBuf>,
+    },
+    Stop,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum ApiCommand {
+    /// Ip addresses blocked for too many invalid requests
+    #[clap(subcommand)]
+    Blocks(ApiBlocksCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum ApiBlocksCommand {
+    List,
+    /// Unblock an ip address, or all of them
+    Clear {
+        ip: Option<IpAddr>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum TokenCommand {
+    List,
+    /// Add a token with the given permissions, generates a random token without --token
+    Add {
+        #[arg(long)]
+        token: Option<String>,
+        permissions: Vec<String>,
+    },
+    /// Remove a token, given in full or by a unique prefix
+    Remove {
+        token: String,
+    },
+    Grant {
+        token: String,
+        #[arg(required = true)]
+        permissions: Vec<String>,
+    },
+    Revoke {
+        token: String,
+        #[arg(required = true)]
+        permissions: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+#[clap(rename_all = "lower")]
+pub enum NpcArg {
+    List,
+    /// Spawn an NPC where a player is standing, or at the given stage and position
+    Spawn {
+        name: String,
+        #[arg(long)]
+        at: Option<SinglePlayerSelect>,
+        #[arg(long)]
+        stage: Option<String>,
+        #[arg(long, allow_hyphen_values = true)]
+        scenario: Option<i8>,
+        #[arg(long, allow_hyphen_values = true)]
+        pos: Option<Position>,
+        #[arg(long)]
+        body: Option<String>,
+        #[arg(long)]
+        cap: Option<String>,
+    },
+    /// Walk NPCs along the given points
+    Move {
+        /// Name or guid of the NPC, or * for all
+        npc: String,
+        #[arg(required = true, allow_hyphen_values = true)]
+        points: Vec<Position>,
+        /// Units per second
+        #[arg(short, long, default_value_t = 600.0)]
+        speed: f32,
+        /// Keep walking the path in a loop
+        #[arg(short, long = "loop")]
+        loop_path: bool,
+    },
+    Despawn {
+        /// Names or guids of the NPCs, or * for all
+        #[arg(required = true)]
+        npcs: Vec<String>,
+    },
+}
+
+/// Position given as `x,y,z`
+#[derive(Debug, Clone, Copy)]
+pub struct Position(pub Vector3);
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let coords = s
+            .split(',')
+            .map(|c| c.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid coordinate in {}: {}", s, e))?;
+        match coords[..] {
+            [x, y, z] => Ok(Self(Vector3::new(x, y, z))),
+            _ => Err(format!("Expected a position as x,y,z, got {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SinglePlayerSelect {
     Player(String),
@@ -203,11 +678,7 @@ impl From<&[SinglePlayerSelect]> for PlayerSelect<String> {
         match modifier {
             Some(SinglePlayerSelect::AllPlayers) => PlayerSelect::AllPlayers,
             Some(SinglePlayerSelect::Negate) => {
-                let players: Vec<_> = players
-                    .into_iter()
-                    .skip(1)
-                    .map(SinglePlayerSelect::to_string)
-                    .collect();
+                let players = split_selectors(&players[1..]);
 
                 if players.is_empty() {
                     PlayerSelect::AllPlayers
@@ -215,14 +686,12 @@ impl From<&[SinglePlayerSelect]> for PlayerSelect<String> {
                     PlayerSelect::ExcludePlayers(players)
                 }
             }
-            _ => {
-                let players = players
-                    .into_iter()
-                    .map(SinglePlayerSelect::to_string)
-                    .collect();
-
-                PlayerSelect::SelectPlayers(players)
-            }
+            _ => PlayerSelect::SelectPlayers(split_selectors(players)),
         }
     }
 }
+
+/// The selectors as given, comma separated ones are only split by `PlayerSelect::into_guid_select` once the names are known
+fn split_selectors(players: &[SinglePlayerSelect]) -> Vec<String> {
+    players.iter().map(SinglePlayerSelect::to_string).collect()
+}
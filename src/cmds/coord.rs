@@ -1,6 +1,7 @@
 use crate::{
     client::{Client, PlayerData},
     guid::Guid,
+    lobby::SlotReservation,
     net::Packet,
 };
 
@@ -15,6 +16,7 @@ pub enum ServerCommand {
         data: PlayerData,
         connect_packet: Box<Packet>,
         comm: mpsc::Sender<ClientCommand>,
+        slot: SlotReservation,
     },
     DisconnectPlayer {
         guid: Guid,
@@ -1,18 +1,40 @@
 use crate::{
+    audit::{self, AuditEntry},
+    client::FILTERABLE_PACKETS,
     cmds::{
-        console::{BanCommand, FlipCommand, ScenarioCommand, ShineArg, TagCommand, UdpCommand, UnbanCommand},
-        Command, ConsoleCommand, ExternalCommand, PlayerCommand, ServerWideCommand, ShineCommand,
+        console::{
+            AllowListCommand, ApiBlocksCommand, AuditCommand, ApiCommand, BanCommand, CostumeCommand, FilterCommand, FlipCommand, NoteCommand, NpcArg, PartyCommand, RaceCommand, RecordCommand, ScenarioCommand, ShineArg,
+            SessionCommand, SinglePlayerSelect, SpectateCommand, TagCommand, TokenCommand, UdpCommand, UnbanCommand,
+            WarpCommand,
+        },
+        ClientCommand, Command, ConsoleCommand, ExternalCommand, NpcCommand, PlayerCommand, Players, RecordingCommand,
+        ServerWideCommand, ShineCommand,
     },
     guid::Guid,
+    json_api::BlockClients,
     lobby::LobbyView,
-    net::GameMode,
-    player_holder::PlayerSelect,
-    settings::{load_settings, save_settings},
+    logbuffer,
+    loglevel,
+    net::{GameMode, Packet, PacketData},
+    npc::Npc,
+    output,
+    player_holder::{display_name, resolve_offline, PlayerSelect},
+    race::{self, Race},
+    readline::ConsoleInput,
+    server::request_exit,
+    session::SessionSnapshot,
+    settings::{reload_settings, save_settings, BanDetails, Settings, Warp},
+    shines::Shines,
     stages::Stages,
-    types::{Result, SMOError},
+    types::{Costume, Result, SMOError},
 };
 use clap::Parser;
-use std::{io::Write, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::ErrorKind,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{select, sync::oneshot};
 
 // Call this console
@@ -24,19 +46,77 @@ pub struct Cli {
 
 pub struct Console {
     view: LobbyView,
+    lobbies: BTreeMap<String, LobbyView>,
+    /// Who issued the commands, for the audit log
+    origin: String,
+    /// Where they connected from, for remote admins
+    address: Option<IpAddr>,
+    /// Destructive commands wait for `confirm`, only on the console of the server
+    interactive: bool,
+    /// The destructive command waiting for `confirm`
+    pending: Option<ConsoleCommand>,
+    confirmed: bool,
 }
 
 impl Console {
     pub fn new(view: LobbyView) -> Self {
-        Self { view }
+        Self {
+            view,
+            lobbies: Default::default(),
+            origin: "console".to_string(),
+            address: None,
+            interactive: false,
+            pending: None,
+            confirmed: false,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: String) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_address(mut self, address: IpAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Make other lobbies reachable through `lobby <name> <command>`
+    pub fn with_lobbies(mut self, lobbies: BTreeMap<String, LobbyView>) -> Self {
+        self.lobbies = lobbies;
+        self
     }
 
     pub async fn loop_read_commands(mut self) -> Result<()> {
+        let mut input = ConsoleInput::take()?;
+        let result = self.read_commands(&mut input).await;
+        // the server after a restart keeps reading from the same thread
+        input.release();
+        result
+    }
+
+    async fn read_commands(&mut self, input: &mut ConsoleInput) -> Result<()> {
+        let lobby = self.view.get_lobby().clone();
+        self.interactive = true;
         loop {
             // let command_result = parse_command(&mut to_coord).await;
             let command_result = select! {
-                result = Console::read_input()=> {
-                    result
+                result = input.next(&lobby) => {
+                    match result {
+                        Ok(Some(line)) => Ok(line),
+                        Ok(None) => continue,
+                        Err(SMOError::ServerShutdown) => {
+                            // no new prompt, it would leave the terminal in raw mode when the process exits
+                            request_exit();
+                            self.view.get_server_send().send(ServerWideCommand::Shutdown)?;
+                            break Ok(());
+                        }
+                        Err(SMOError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                            tracing::info!("Console input closed");
+                            break Ok(());
+                        }
+                        Err(e) => Err(e),
+                    }
                 },
                 exit_cmd = self.view.get_server_recv().recv() => {
                     match exit_cmd? {
@@ -47,20 +127,98 @@ impl Console {
             };
 
             if let Err(e) = &command_result {
-                println!("{}", e);
+                output::error(&e.to_string());
                 continue;
             }
 
-            let command_result = self.process_command(command_result.unwrap()).await;
+            let (line, cli) = command_result.unwrap();
+            let command_result = self.run_audited(&line, cli).await;
 
             match command_result {
-                Ok(s) => println!("{}", s),
-                Err(e) => println!("Error processing command: {}", e),
+                Ok(s) => output::reply(&s),
+                Err(e) => output::error(&format!("Error processing command: {}", e)),
+            }
+        }
+    }
+
+    /// Process a command, forwarding `lobby <name> <command>` to the console of that lobby
+    pub async fn dispatch_command(&mut self, cli: Cli) -> Result<String> {
+        // anything but `confirm` discards the waiting command
+        let cmd = match cli.cmd {
+            ConsoleCommand::Confirm => {
+                self.confirmed = true;
+                self.pending
+                    .take()
+                    .ok_or_else(|| SMOError::InvalidConsoleArg("No command to confirm".to_string()))?
+            }
+            cmd => {
+                self.confirmed = false;
+                self.pending = None;
+                cmd
+            }
+        };
+
+        match cmd {
+            ConsoleCommand::Lobby {
+                name: Some(name),
+                command,
+            } if !command.is_empty() => {
+                let view = self.lobbies.get(&name).ok_or_else(|| {
+                    SMOError::InvalidConsoleArg(format!("Unknown lobby {}", name))
+                })?;
+                let cli = Cli::try_parse_from(std::iter::once("> ".to_string()).chain(command.iter().cloned()))?;
+                if let ConsoleCommand::Restart = cli.cmd {
+                    // lobbies are (re)created by the main server
+                    return Err(SMOError::InvalidConsoleArg(
+                        "Lobbies are restarted together with the main server".to_string(),
+                    ));
+                }
+                let mut console = Console::new(view.clone()).with_origin(self.origin.clone());
+                console.address = self.address;
+                console.interactive = self.interactive;
+                console.confirmed = self.confirmed;
+                let result = console.process_command(cli).await;
+                if console.pending.is_some() {
+                    self.pending = Some(ConsoleCommand::Lobby { name: Some(name), command });
+                }
+                result
             }
+            cmd => self.process_command(Cli { cmd }).await,
         }
     }
 
+    /// Process a command of an admin, recording it with its result in the audit log
+    pub async fn run_audited(&mut self, line: &str, cli: Cli) -> Result<String> {
+        let result = self.dispatch_command(cli).await;
+        let entry = AuditEntry::new(&self.origin, audit::COMMAND, vec![redact_command(line)], None)
+            .with_address(self.address);
+        let settings = self.view.get_lobby().settings.read().await;
+        let entry = match &result {
+            Ok(reply) => entry.with_result(&redact_tokens(reply, &settings)),
+            Err(e) => entry.with_result(&format!("Error: {}", e)),
+        };
+        drop(settings);
+        audit::record(self.view.get_lobby(), &entry).await;
+        result
+    }
+
     pub async fn process_command(&mut self, cli: Cli) -> Result<String> {
+        if let Some(dry_run) = cli.cmd.dry_run() {
+            let affected = self.affected_by(&cli.cmd).await?;
+            if dry_run {
+                return Ok(match affected {
+                    Some(affected) => format!("Dry run, {}", affected),
+                    None => "Dry run, nothing would be affected".to_string(),
+                });
+            }
+            if let Some(affected) = affected {
+                if self.interactive && !self.confirmed {
+                    self.pending = Some(cli.cmd);
+                    return Ok(format!("{}\nType `confirm` to proceed", affected));
+                }
+            }
+        }
+
         let reply_str = match cli.cmd {
             ConsoleCommand::SendAll { force, stage } => {
                 let players: PlayerSelect<Guid> = PlayerSelect::AllPlayers;
@@ -120,6 +278,36 @@ impl Console {
                 .await?;
                 format!("Sent players to {}:{}", stage, scenario)
             }
+            ConsoleCommand::SendTo { target, players } => {
+                let target = self.single_player(target).await?;
+                let (name, stage, scenario) = {
+                    let target = self.view.get_lobby().get_client(&target)?;
+                    match &target.last_game_packet {
+                        Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) if !stage.is_empty() => {
+                            (target.name.clone(), stage.clone(), *scenario_num)
+                        }
+                        _ => {
+                            return Err(SMOError::InvalidConsoleArg(format!(
+                                "The stage of {} is unknown.",
+                                target.name
+                            )))
+                        }
+                    }
+                };
+
+                let players: PlayerSelect<String> = (&players[..]).into();
+                let players = players.into_guid_vec(&self.view).await?;
+                self.request_comm(ExternalCommand::Player {
+                    players,
+                    command: PlayerCommand::Send {
+                        stage: stage.clone(),
+                        id: "".to_string(),
+                        scenario,
+                    },
+                })
+                .await?;
+                format!("Sent players to {} in {}:{}", name, stage, scenario)
+            }
             ConsoleCommand::Ban(subcmd) => match subcmd {
                 BanCommand::List => {
                     let settings = self.view.get_lobby().settings.read().await;
@@ -131,6 +319,7 @@ impl Console {
                         for ip in settings.ban_list.ip_addresses.iter() {
                             list.push("\n- ".to_string());
                             list.push(ip.to_string());
+                            list.push(describe_ban(&settings, &ip.to_string()));
                         }
                     }
                     if !settings.ban_list.players.is_empty() {
@@ -138,6 +327,7 @@ impl Console {
                         for guid in settings.ban_list.players.iter() {
                             list.push("\n- ".to_string());
                             list.push(guid.to_string());
+                            list.push(describe_ban(&settings, &guid.to_string()));
                         }
                     }
                     if !settings.ban_list.stages.is_empty() {
@@ -154,6 +344,20 @@ impl Console {
                             list.push(GameMode::from_i8(*game_mode).to_string());
                         }
                     }
+                    if !settings.ban_list.costumes.is_empty() {
+                        list.push("\nBanned costumes:".to_string());
+                        for costume in settings.ban_list.costumes.iter() {
+                            list.push("\n- ".to_string());
+                            list.push(costume.to_string());
+                        }
+                    }
+                    if !settings.ban_list.captures.is_empty() {
+                        list.push("\nBanned captures:".to_string());
+                        for capture in settings.ban_list.captures.iter() {
+                            list.push("\n- ".to_string());
+                            list.push(capture.to_string());
+                        }
+                    }
                     list.join("")
                 },
                 BanCommand::Enable => {
@@ -174,15 +378,22 @@ impl Console {
 
                     "BanList disabled.".to_string()
                 },
-                BanCommand::Player { players } => {
+                BanCommand::Player { players, reason, .. } => {
+                    let details = self.ban_details(reason_arg(reason));
+                    let reason = details.reason.clone();
                     let players: PlayerSelect<String> = (&players[..]).into();
-                    let players = players.into_guid_vec(&self.view).await?;
+                    let guids = players.into_offline_guids(&self.view).await?;
+                    let mut names = Vec::with_capacity(guids.len());
+                    for guid in &guids {
+                        names.push(display_name(&self.view, guid).await);
+                    }
 
-                    // get player data for banned players
+                    // get player data for banned players, ips are only known while connected
                     let lobby = &self.view.get_lobby();
-                    let guids = players.get_guids(lobby);
+                    let connected = guids.iter().filter(|g| lobby.players.contains_key(g)).copied().collect();
+                    let players = Players::Individual(connected);
+                    let guids = guids.into_iter().collect();
                     let ips   = players.get_ipv4s(lobby);
-                    let names = players.get_names(lobby);
 
                     // update settings
                     let mut settings = self.view.get_mut_settings().write().await;
@@ -198,6 +409,10 @@ impl Console {
                         .union(&guids)
                         .copied()
                         .collect();
+                    let banned = guids.iter().map(Guid::to_string).chain(ips.iter().map(IpAddr::to_string));
+                    for banned in banned {
+                        settings.ban_list.details.insert(banned, details.clone());
+                    }
                     save_settings(&settings)?;
                     drop(settings);
 
@@ -207,9 +422,12 @@ impl Console {
                         command : PlayerCommand::Crash {},
                     }).await?;
 
-                    "Banned players: ".to_string() + &Vec::from_iter(names).join(", ")
+                    self.audit("ban player", names.clone(), reason).await;
+                    "Banned players: ".to_string() + &names.join(", ")
                 },
-                BanCommand::Profile { profile_id } => {
+                BanCommand::Profile { profile_id, reason, .. } => {
+                    let details = self.ban_details(reason_arg(reason));
+                    let reason = details.reason.clone();
                     // get connected players
                     let lobby = &self.view.get_lobby();
                     let guids: Vec<Guid> = lobby.players.iter().filter(|x| x.key() == &profile_id).map(|x| *x.key()).collect();
@@ -219,6 +437,7 @@ impl Console {
                     // update settings
                     let mut settings = self.view.get_mut_settings().write().await;
                     settings.ban_list.players.insert(profile_id);
+                    settings.ban_list.details.insert(profile_id.to_string(), details.clone());
                     save_settings(&settings)?;
                     drop(settings);
 
@@ -228,9 +447,12 @@ impl Console {
                         command : PlayerCommand::Crash {},
                     }).await?;
 
+                    self.audit("ban profile", vec![profile_id.to_string()], reason).await;
                     "Banned profile: ".to_string() + &profile_id.to_string()
                 },
-                BanCommand::IP { ipv4 } => {
+                BanCommand::IP { ipv4, reason, .. } => {
+                    let details = self.ban_details(reason_arg(reason));
+                    let reason = details.reason.clone();
                     // get connected players
                     let lobby = &self.view.get_lobby();
                     let guids: Vec<Guid> = lobby.players.iter().filter(|x| x.value().ipv4 == Some(ipv4)).map(|x| *x.key()).collect();
@@ -240,6 +462,7 @@ impl Console {
                     // update settings
                     let mut settings = self.view.get_mut_settings().write().await;
                     settings.ban_list.ip_addresses.insert(ipv4);
+                    settings.ban_list.details.insert(ipv4.to_string(), details.clone());
                     save_settings(&settings)?;
                     drop(settings);
 
@@ -249,6 +472,7 @@ impl Console {
                         command : PlayerCommand::Crash {},
                     }).await?;
 
+                    self.audit("ban ip", vec![ipv4.to_string()], reason).await;
                     "Banned ip: ".to_string() + &ipv4.to_string()
                 },
                 BanCommand::Stage { stage } => {
@@ -265,6 +489,7 @@ impl Console {
                         save_settings(&settings)?;
                         drop(settings);
 
+                        self.audit("ban stage", stages.clone(), None).await;
                         "Banned stages: ".to_string() + &stages.join(", ")
                     }
                 },
@@ -275,26 +500,272 @@ impl Console {
                     save_settings(&settings)?;
                     drop(settings);
 
+                    self.audit("ban gamemode", vec![game_mode.to_string()], None).await;
                     "Banned gamemode: ".to_string() + &game_mode.to_string()
                 },
+                BanCommand::Costume { name } => {
+                    // update settings
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.ban_list.costumes.insert(name.clone());
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    self.audit("ban costume", vec![name.clone()], None).await;
+                    "Banned costume: ".to_string() + &name
+                },
+                BanCommand::Capture { model } => {
+                    // update settings
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.ban_list.captures.insert(model.clone());
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    self.audit("ban capture", vec![model.clone()], None).await;
+                    "Banned capture: ".to_string() + &model
+                },
+            },
+            ConsoleCommand::Warp(subcmd) => match subcmd {
+                WarpCommand::List => {
+                    let settings = self.view.get_lobby().settings.read().await;
+                    let mut list = vec![format!("Warps: {}", settings.warps.len())];
+                    for (name, warp) in settings.warps.iter() {
+                        list.push(format!("- {}: {} {}:{}", name, warp.stage, warp.id, warp.scenario));
+                    }
+                    list.join("\n")
+                }
+                WarpCommand::Add { force, name, stage, id, scenario } => {
+                    // `warp <name>` would run the subcommand instead
+                    if ["list", "add", "remove", "help"].contains(&name.as_str()) {
+                        return Err(SMOError::InvalidConsoleArg(format!("Warps can't be named {}", name)));
+                    }
+                    let stage = match (Stages::input2stage(&stage), force) {
+                        (Some(s), _) => s,
+                        (None, true) => stage,
+                        (None, false) => return Err(SMOError::InvalidConsoleArg("Invalid stage name.".to_string())),
+                    };
+                    let warp = Warp {
+                        stage,
+                        id: id.unwrap_or_default(),
+                        scenario: scenario.unwrap_or(-1),
+                    };
+                    let reply = format!("Added warp {} to {} {}:{}", name, warp.stage, warp.id, warp.scenario);
+
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.warps.insert(name, warp);
+                    save_settings(&settings)?;
+                    reply
+                }
+                WarpCommand::Remove { name } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    if settings.warps.remove(&name).is_none() {
+                        return Err(SMOError::InvalidConsoleArg(format!("Unknown warp {}", name)));
+                    }
+                    save_settings(&settings)?;
+                    format!("Removed warp {}", name)
+                }
+                WarpCommand::To(args) => {
+                    let (name, players) = args.split_first().expect("External subcommand without a name");
+                    let warp = self.view.get_lobby().settings.read().await.warps.get(name).cloned();
+                    let warp = warp.ok_or_else(|| SMOError::InvalidConsoleArg(format!("Unknown warp {}", name)))?;
+                    let players = players
+                        .iter()
+                        .map(|p| p.parse())
+                        .collect::<std::result::Result<Vec<SinglePlayerSelect>, _>>()
+                        .expect("Infallible player selection");
+
+                    let players: PlayerSelect<String> = (&players[..]).into();
+                    let players = players.into_guid_vec(&self.view).await?;
+                    self.request_comm(ExternalCommand::Player {
+                        players,
+                        command: PlayerCommand::Send {
+                            stage: warp.stage,
+                            id: warp.id,
+                            scenario: warp.scenario,
+                        },
+                    })
+                    .await?;
+                    format!("Sent players to warp {}", name)
+                }
+            },
+            ConsoleCommand::Party(subcmd) => match subcmd {
+                PartyCommand::List => {
+                    let parties = self.view.get_lobby().settings.read().await.parties.clone();
+                    let mut list = vec![format!("Parties: {}", parties.len())];
+                    for (name, members) in parties.iter() {
+                        let mut names = Vec::with_capacity(members.len());
+                        for member in members {
+                            names.push(display_name(&self.view, member).await);
+                        }
+                        list.push(format!("- {}: {}", name, names.join(", ")));
+                    }
+                    list.join("\n")
+                }
+                PartyCommand::Create { name } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    if settings.parties.contains_key(&name) {
+                        return Err(SMOError::InvalidConsoleArg(format!("Party {} already exists", name)));
+                    }
+                    settings.parties.insert(name.clone(), Default::default());
+                    save_settings(&settings)?;
+                    format!("Created party {}", name)
+                }
+                PartyCommand::Add { name, players } => {
+                    let mut guids = Vec::with_capacity(players.len());
+                    for player in &players {
+                        guids.push(resolve_offline(&self.view, player).await?);
+                    }
+
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    if !settings.parties.contains_key(&name) {
+                        return Err(SMOError::InvalidConsoleArg(format!("Unknown party {}", name)));
+                    }
+                    // a player is in one party at most
+                    for members in settings.parties.values_mut() {
+                        members.retain(|m| !guids.contains(m));
+                    }
+                    settings.parties.entry(name.clone()).or_default().extend(guids);
+                    save_settings(&settings)?;
+                    format!("Added {} to party {}", players.join(", "), name)
+                }
+                PartyCommand::Remove { players } => {
+                    let mut guids = Vec::with_capacity(players.len());
+                    for player in &players {
+                        guids.push(resolve_offline(&self.view, player).await?);
+                    }
+
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    for members in settings.parties.values_mut() {
+                        members.retain(|m| !guids.contains(m));
+                    }
+                    save_settings(&settings)?;
+                    format!("Removed {} from their parties", players.join(", "))
+                }
+            },
+            ConsoleCommand::Race(subcmd) => match subcmd {
+                RaceCommand::Create { name, stages } => {
+                    let route = stages
+                        .iter()
+                        .map(|s| {
+                            Stages::input2stage(s)
+                                .ok_or_else(|| SMOError::InvalidConsoleArg(format!("Invalid stage name {}", s)))
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let reply = format!("Created race {}: {}", name, route.join(" -> "));
+
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.races.insert(name, route);
+                    save_settings(&settings)?;
+                    reply
+                }
+                RaceCommand::Start { name } => {
+                    let route = self.view.get_lobby().settings.read().await.races.get(&name).cloned();
+                    let route = route.ok_or_else(|| SMOError::InvalidConsoleArg(format!("Unknown race {}", name)))?;
+                    let start = route[0].clone();
+
+                    let players: PlayerSelect<Guid> = PlayerSelect::AllPlayers;
+                    let players = players.into_guid_vec(&self.view)?;
+                    self.request_comm(ExternalCommand::Player {
+                        players,
+                        command: PlayerCommand::Send {
+                            stage: start.clone(),
+                            id: "".to_string(),
+                            scenario: -1,
+                        },
+                    })
+                    .await?;
+                    *self.view.get_lobby().race.write().await = Some(Race::new(&name, route));
+                    format!("Started race {} at {}", name, start)
+                }
+                RaceCommand::Standings => {
+                    let lobby = self.view.get_lobby();
+                    let race = lobby.race.read().await.clone();
+                    let race = race.ok_or_else(|| SMOError::InvalidConsoleArg("No race was started".to_string()))?;
+
+                    let mut list = vec![format!("Race {}, {} since the start:", race.name, race::format_time(race.started.elapsed()))];
+                    for (place, (_, name, time)) in race.finished.iter().enumerate() {
+                        list.push(format!("{}. {} {}", place + 1, name, race::format_time(*time)));
+                    }
+                    for (id, reached) in race.racing() {
+                        let checkpoints = race.route.len() - 2;
+                        let name = display_name(&self.view, &id).await;
+                        list.push(format!("- {} at checkpoint {}/{}", name, reached, checkpoints));
+                    }
+
+                    list.push("Best times:".to_string());
+                    for (place, (name, time)) in lobby.stats.races.read().await.standings(&race.name).iter().enumerate() {
+                        list.push(format!("{}. {} {}", place + 1, name, race::format_time(*time)));
+                    }
+                    list.join("\n")
+                }
+            },
+            ConsoleCommand::AllowList(subcmd) => match subcmd {
+                AllowListCommand::List => {
+                    let settings = self.view.get_lobby().settings.read().await;
+                    let mut list = vec![format!(
+                        "Stage allow list: {}",
+                        if settings.ban_list.allow_list { "enabled" } else { "disabled" }
+                    )];
+                    for stage in settings.ban_list.allowed_stages.iter() {
+                        list.push(format!("- {}", stage));
+                    }
+                    list.join("\n")
+                }
+                AllowListCommand::Enable | AllowListCommand::Disable => {
+                    let enabled = matches!(subcmd, AllowListCommand::Enable);
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.ban_list.allow_list = enabled;
+                    save_settings(&settings)?;
+                    let warning = if enabled && !settings.ban_list.enabled {
+                        ", but the ban list is disabled"
+                    } else {
+                        ""
+                    };
+                    drop(settings);
+
+                    format!("Stage allow list {}{}", if enabled { "enabled" } else { "disabled" }, warning)
+                }
+                AllowListCommand::Add { ref stage } | AllowListCommand::Remove { ref stage } => {
+                    if Stages::input2stage(stage).is_none() {
+                        return Err(SMOError::InvalidConsoleArg("Invalid stage name.".to_string()));
+                    }
+                    let stages = Stages::stages_by_input(stage);
+                    let add = matches!(subcmd, AllowListCommand::Add { .. });
+
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    for s in stages.iter() {
+                        if add {
+                            settings.ban_list.allowed_stages.insert(s.to_string());
+                        } else {
+                            settings.ban_list.allowed_stages.remove(s);
+                        }
+                    }
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    format!("{} stages: {}", if add { "Allowed" } else { "Disallowed" }, stages.join(", "))
+                }
             },
             ConsoleCommand::Unban(subcmd) => match subcmd {
                 UnbanCommand::Profile { profile_id } => {
                     // update settings
                     let mut settings = self.view.get_mut_settings().write().await;
                     settings.ban_list.players.remove(&profile_id);
+                    settings.ban_list.details.remove(&profile_id.to_string());
                     save_settings(&settings)?;
                     drop(settings);
 
+                    self.audit("unban profile", vec![profile_id.to_string()], None).await;
                     "Unbanned profile: ".to_string() + &profile_id.to_string()
                 },
                 UnbanCommand::IP { ipv4 } => {
                     // update settings
                     let mut settings = self.view.get_mut_settings().write().await;
                     settings.ban_list.ip_addresses.remove(&ipv4);
+                    settings.ban_list.details.remove(&ipv4.to_string());
                     save_settings(&settings)?;
                     drop(settings);
 
+                    self.audit("unban ip", vec![ipv4.to_string()], None).await;
                     "Unbanned ip: ".to_string() + &ipv4.to_string()
                 },
                 UnbanCommand::Stage { stage } => {
@@ -311,6 +782,7 @@ impl Console {
                         save_settings(&settings)?;
                         drop(settings);
 
+                        self.audit("unban stage", stages.clone(), None).await;
                         "Unbanned stages: ".to_string() + &stages.join(", ")
                     }
                 },
@@ -321,18 +793,108 @@ impl Console {
                     save_settings(&settings)?;
                     drop(settings);
 
+                    self.audit("unban gamemode", vec![game_mode.to_string()], None).await;
                     "Unbanned gamemode: ".to_string() + &game_mode.to_string()
                 },
+                UnbanCommand::Costume { name } => {
+                    // update settings
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.ban_list.costumes.remove(&name);
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    self.audit("unban costume", vec![name.clone()], None).await;
+                    "Unbanned costume: ".to_string() + &name
+                },
+                UnbanCommand::Capture { model } => {
+                    // update settings
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.ban_list.captures.remove(&model);
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    self.audit("unban capture", vec![model.clone()], None).await;
+                    "Unbanned capture: ".to_string() + &model
+                },
             },
-            ConsoleCommand::Crash { players } => {
+            ConsoleCommand::Crash { players, .. } => {
                 let players: PlayerSelect<String> = (&players[..]).into();
                 let players = players.into_guid_vec(&self.view).await?;
+                let mut names = Vec::new();
+                for guid in players.clone().flatten(self.view.get_lobby())? {
+                    names.push(display_name(&self.view, &guid).await);
+                }
+
+                let reply = self
+                    .request_comm(ExternalCommand::Player {
+                        players,
+                        command: PlayerCommand::Crash {},
+                    })
+                    .await?;
+                self.audit("crash", names, None).await;
+                reply
+            }
+            ConsoleCommand::Kick { players, reason } => {
+                let players: PlayerSelect<String> = (&players[..]).into();
+                let guids = players.into_guid_vec(&self.view).await?.flatten(self.view.get_lobby())?;
+                if guids.is_empty() {
+                    return Err(SMOError::InvalidConsoleArg("No players selected".to_string()));
+                }
+                let names: Vec<_> = guids
+                    .iter()
+                    .filter_map(|guid| self.view.get_lobby().get_client(guid).ok().map(|p| p.name.clone()))
+                    .collect();
+                let reason = reason_arg(reason);
 
                 self.request_comm(ExternalCommand::Player {
-                    players,
-                    command: PlayerCommand::Crash {},
+                    players: Players::Individual(guids),
+                    command: PlayerCommand::Disconnect {},
                 })
-                .await?
+                .await?;
+
+                match &reason {
+                    Some(reason) => tracing::info!("Kicked {}: {}", names.join(", "), reason),
+                    None => tracing::info!("Kicked {}", names.join(", ")),
+                }
+                self.audit("kick", names.clone(), reason).await;
+                format!("Kicked players: {}", names.join(", "))
+            }
+            ConsoleCommand::Audit(AuditCommand::List { count }) => self.audit_log(count, false).await?,
+            ConsoleCommand::Audit(AuditCommand::Tail { count }) => self.audit_log(count, true).await?,
+            ConsoleCommand::History { player } => {
+                let guid = player.parse::<Guid>().ok();
+                let seen = self.view.get_lobby().seen.0.read().await;
+                let matches: Vec<_> = seen
+                    .iter()
+                    .filter(|(g, p)| guid == Some(**g) || p.names.contains(&player))
+                    .collect();
+                if matches.is_empty() {
+                    return Err(SMOError::InvalidConsoleArg(format!("Unknown player {}", player)));
+                }
+
+                let mut list = Vec::new();
+                for (guid, seen) in matches {
+                    list.push(format!("{} ({}):", seen.name, guid));
+                    list.push(format!("\tNames: {}", seen.names.iter().cloned().collect::<Vec<_>>().join(", ")));
+                    list.push(format!("\tIPs: {}", seen.ips.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")));
+                    list.push(format!("\tFirst seen: {}", audit::format_time(seen.first_seen)));
+                    list.push(format!("\tLast seen: {}", audit::format_time(seen.last_seen)));
+                }
+                list.join("\n")
+            }
+            ConsoleCommand::Alts { player } => {
+                let guid = resolve_offline(&self.view, &player).await?;
+                let alts = self.view.get_lobby().seen.alts(&guid).await;
+                if alts.is_empty() {
+                    return Ok(format!("No other players connected from the addresses of {}", player));
+                }
+
+                let mut list = vec![format!("Players that share addresses with {}:", player)];
+                for (alt, ips) in alts {
+                    let ips: Vec<_> = ips.iter().map(IpAddr::to_string).collect();
+                    list.push(format!("- {} ({}): {}", display_name(&self.view, &alt).await, alt, ips.join(", ")));
+                }
+                list.join("\n")
             }
             ConsoleCommand::Rejoin { players } => {
                 let players: PlayerSelect<String> = (&players[..]).into();
@@ -365,6 +927,49 @@ impl Console {
                         format!("Scenario merging is {}", is_enabled)
                     }
                 },
+                ScenarioCommand::Lock { stage, scenario } => {
+                    let stage = Stages::input2stage(&stage)
+                        .ok_or_else(|| SMOError::InvalidConsoleArg(format!("Invalid stage name {}", stage)))?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.scenario.locks.insert(stage.clone(), scenario);
+                    save_settings(&settings)?;
+                    drop(settings);
+                    format!("Locked {} to scenario {}", stage, scenario)
+                }
+                ScenarioCommand::Unlock { stage } => {
+                    let stage = Stages::input2stage(&stage).unwrap_or(stage);
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let removed = settings.scenario.locks.remove(&stage).is_some();
+                    save_settings(&settings)?;
+                    drop(settings);
+                    if removed {
+                        format!("Unlocked the scenario of {}", stage)
+                    } else {
+                        format!("The scenario of {} isn't locked", stage)
+                    }
+                }
+                ScenarioCommand::Set { player, scenario } => {
+                    let guid = self.single_player(player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.scenario.player_overrides.insert(guid, scenario);
+                    save_settings(&settings)?;
+                    drop(settings);
+                    let name = self.resend_game_packet(guid).await?;
+                    format!("Showing {} in scenario {}", name, scenario)
+                }
+                ScenarioCommand::Unset { player } => {
+                    let guid = self.single_player(player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let removed = settings.scenario.player_overrides.remove(&guid).is_some();
+                    save_settings(&settings)?;
+                    drop(settings);
+                    let name = self.resend_game_packet(guid).await?;
+                    if removed {
+                        format!("Showing the actual scenario of {}", name)
+                    } else {
+                        format!("The scenario of {} isn't overridden", name)
+                    }
+                }
             },
             ConsoleCommand::Tag(tag) => match tag {
                 TagCommand::Time {
@@ -420,22 +1025,54 @@ impl Console {
                     })
                     .await?;
 
-                    self.request_comm(ExternalCommand::Player {
-                        players: hiders,
+                    let reply = self.request_comm(ExternalCommand::Player {
+                        players: hiders.clone(),
                         command: PlayerCommand::Tag {
                             time: Some((0, 0)),
                             is_seeking: Some(false),
                         },
                     })
-                    .await?
+                    .await?;
+                    let lobby = self.view.get_lobby();
+                    *lobby.tag_round.write().await = Some(SystemTime::now());
+                    lobby.tag_scores.write().await.start_round(hiders.flatten(lobby)?);
+                    reply
+                }
+                TagCommand::Scoreboard => {
+                    let scores = self.view.get_lobby().tag_scores.read().await;
+                    let mut list = vec![format!("Tag rounds: {}", scores.rounds)];
+                    if scores.is_running() {
+                        list.push(format!("Hiders left in the current round: {}", scores.hiding.len()));
+                    }
+                    for (place, score) in scores.standings().iter().enumerate() {
+                        list.push(format!(
+                            "{}. {}: {} wins, hidden for {}s in {} rounds",
+                            place + 1,
+                            score.name,
+                            score.wins,
+                            score.hide_time,
+                            score.rounds
+                        ));
+                    }
+                    list.join("\n")
                 }
             },
-            ConsoleCommand::MaxPlayers { player_count } => {
+            ConsoleCommand::MaxPlayers { player_count, .. } => {
                 let mut settings = self.view.get_mut_settings().write().await;
                 settings.server.max_players = player_count;
                 save_settings(&settings)?;
                 drop(settings);
 
+                // the connected players keep the limit of their init packet, which is only a problem if it's lowered below them
+                let connected = self.view.get_lobby().players.len();
+                if player_count as usize >= connected {
+                    return Ok(format!(
+                        "Max players set to {}, applies to newly connecting players",
+                        player_count
+                    ));
+                }
+
+                tracing::info!("Reconnecting {} players for the lower max players of {}", connected, player_count);
                 let players: PlayerSelect<Guid> = PlayerSelect::AllPlayers;
                 let players = players.into_guid_vec(&self.view)?;
 
@@ -445,20 +1082,183 @@ impl Console {
                 })
                 .await?
             }
-            ConsoleCommand::List => {
-                let players: Vec<_> = self
-                    .view
-                    .get_lobby()
+            ConsoleCommand::List { stage, kingdom, tagged, is_2d } => {
+                let stage = match stage {
+                    Some(stage) => Some(Stages::input2stage(&stage).ok_or_else(|| {
+                        SMOError::InvalidConsoleArg(format!("Unknown stage {}", stage))
+                    })?),
+                    None => None,
+                };
+                let kingdom = match kingdom {
+                    Some(kingdom) => Some(
+                        Stages::kingdom_stages(&kingdom)
+                            .ok_or_else(|| SMOError::InvalidConsoleArg(format!("Unknown kingdom {}", kingdom)))?,
+                    ),
+                    None => None,
+                };
+
+                let filtered = stage.is_some() || kingdom.is_some() || tagged || is_2d;
+
+                let lobby = self.view.get_lobby();
+                let players: Vec<_> = lobby
                     .names
                     .0
                     .read()
                     .await
                     .iter()
-                    .map(|x| format!("{} ({})", x.0, x.1))
+                    .filter_map(|x| {
+                        let player = lobby.players.get(x.0);
+                        let matches = match &player {
+                            Some(player) => {
+                                let current = player.last_stage().map(ToString::to_string);
+                                stage.as_ref().is_none_or(|stage| current.as_ref() == Some(stage))
+                                    && kingdom.as_ref().is_none_or(|stages| current.as_ref().is_some_and(|s| stages.contains(s)))
+                                    && (!tagged || player.is_seeking == Some(true))
+                                    && (!is_2d || player.is_2d)
+                            }
+                            None => !filtered,
+                        };
+                        let udp = player.is_some_and(|p| p.udp);
+                        matches.then(|| format!("{} ({}, {})", x.0, x.1, if udp { "udp" } else { "tcp" }))
+                    })
                     .collect();
 
                 format!("List: \n\t{}", players.join("\n\t"))
             }
+            ConsoleCommand::Netstat => {
+                let mut players: Vec<_> = self
+                    .view
+                    .get_lobby()
+                    .players
+                    .iter()
+                    .map(|p| format!("{} ({}): {}", p.name, if p.udp { "udp" } else { "tcp" }, p.connection))
+                    .collect();
+                players.sort();
+                format!("Connections: \n\t{}", players.join("\n\t"))
+            }
+            ConsoleCommand::Info { player } => {
+                let guid = self.single_player(player).await?;
+                let player = self.view.get_lobby().get_client(&guid)?;
+                let (stage, scenario) = match &player.last_game_packet {
+                    Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) => (Some(stage.clone()), *scenario_num),
+                    _ => (None, player.scenario),
+                };
+                let costume = match &player.last_costume_packet {
+                    Some(Packet { data: PacketData::Costume(costume), .. }) => format!("{} / {}", costume.body_name, costume.cap_name),
+                    _ => "unknown".to_string(),
+                };
+                let capture = match &player.last_capture_packet {
+                    Some(Packet { data: PacketData::Capture { model }, .. }) if !model.is_empty() => model.clone(),
+                    _ => "none".to_string(),
+                };
+                let tag = match player.is_seeking {
+                    Some(true) => "seeking",
+                    Some(false) => "hiding",
+                    None => "not playing",
+                };
+                let connected = player.connected_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+                let online = player.connected_at.elapsed().unwrap_or_default().as_secs();
+
+                let info = [
+                    format!("{} ({}):", player.name, guid),
+                    format!("\tIP: {}", player.ipv4.map_or("unknown".to_string(), |ip| ip.to_string())),
+                    format!("\tConnection: {}, {}", if player.udp { "udp" } else { "tcp" }, player.connection),
+                    format!(
+                        "\tStage: {} (scenario {}, {})",
+                        stage.as_deref().unwrap_or("unknown"),
+                        scenario,
+                        stage.as_deref().and_then(Stages::stage2kingdom).unwrap_or_else(|| "unknown kingdom".to_string())
+                    ),
+                    format!("\t2D: {}", player.is_2d),
+                    format!("\tCostume: {}", costume),
+                    format!("\tCapture: {}", capture),
+                    format!("\tMoons: {}", player.shine_sync.len()),
+                    format!("\tGame mode: {}, tag: {}", player.game_mode, tag),
+                    format!("\tConnected: {} ({}m {}s ago)", audit::format_time(connected), online / 60, online % 60),
+                ];
+                info.join("\n")
+            }
+            ConsoleCommand::Where { players } => {
+                let players: PlayerSelect<String> = (&players[..]).into();
+                let guids = players.into_guid_vec(&self.view).await?.flatten(self.view.get_lobby())?;
+                if guids.is_empty() {
+                    return Err(SMOError::InvalidConsoleArg("No players selected".to_string()));
+                }
+
+                let mut list = Vec::with_capacity(guids.len());
+                for guid in guids {
+                    let player = self.view.get_lobby().get_client(&guid)?;
+                    let location = match &player.last_game_packet {
+                        Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) => format!(
+                            "{} in {} (scenario {})",
+                            Stages::stage2kingdom(stage).unwrap_or_else(|| "unknown kingdom".to_string()),
+                            stage,
+                            scenario_num
+                        ),
+                        _ => "unknown stage".to_string(),
+                    };
+                    let position = match player.last_position() {
+                        Some(pos) => format!("at {:.0}, {:.0}, {:.0}", pos.x, pos.y, pos.z),
+                        None => "at an unknown position".to_string(),
+                    };
+                    list.push(format!("{}: {} {}", player.name, location, position));
+                }
+                list.sort();
+                format!("Locations: \n\t{}", list.join("\n\t"))
+            }
+            ConsoleCommand::Log { n, level } => {
+                let lines = logbuffer::recent(n, level);
+                if lines.is_empty() {
+                    "No log lines".to_string()
+                } else {
+                    format!("Log: \n\t{}", lines.join("\n\t"))
+                }
+            }
+            ConsoleCommand::LogLevel { target, level } => {
+                let filter = loglevel::set(&target, level)?;
+                format!("Log filter: {}", filter)
+            }
+            ConsoleCommand::Note(note) => match note {
+                NoteCommand::List { player } => {
+                    let notes = self.view.get_lobby().settings.read().await.notes.clone();
+                    let notes = match player {
+                        Some(player) => {
+                            let guid = resolve_offline(&self.view, &player).await?;
+                            notes.into_iter().filter(|(g, _)| *g == guid).collect()
+                        }
+                        None => notes,
+                    };
+                    let mut lines = Vec::new();
+                    for (guid, notes) in notes {
+                        lines.push(format!("{} ({}):", display_name(&self.view, &guid).await, guid));
+                        lines.extend(notes.iter().map(|note| format!("\t{}", note)));
+                    }
+                    if lines.is_empty() {
+                        "No notes".to_string()
+                    } else {
+                        lines.join("\n")
+                    }
+                }
+                NoteCommand::Add { player, text } => {
+                    let player = resolve_offline(&self.view, &player).await?;
+                    let note = format!("{} {}: {}", audit::format_time(audit::now()), self.origin, text.join(" "));
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.notes.entry(player).or_default().push(note);
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    format!("Added a note to {}", display_name(&self.view, &player).await)
+                }
+                NoteCommand::Clear { player } => {
+                    let player = resolve_offline(&self.view, &player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let removed = settings.notes.remove(&player).map_or(0, |notes| notes.len());
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    format!("Removed {} notes of {}", removed, display_name(&self.view, &player).await)
+                }
+            },
             ConsoleCommand::Flip(flip) => match flip {
                 FlipCommand::List => {
                     let settings = self.view.get_mut_settings().write().await;
@@ -472,6 +1272,7 @@ impl Console {
                     format!("User ids: {}", &player_str.join(", "))
                 }
                 FlipCommand::Add { player } => {
+                    let player = resolve_offline(&self.view, &player).await?;
                     let mut settings = self.view.get_mut_settings().write().await;
                     settings.flip.players.insert(player);
                     save_settings(&settings)?;
@@ -479,6 +1280,7 @@ impl Console {
                     format!("Added {} to flipped players", player)
                 }
                 FlipCommand::Remove { player } => {
+                    let player = resolve_offline(&self.view, &player).await?;
                     let mut settings = self.view.get_mut_settings().write().await;
                     let was_removed = settings.flip.players.remove(&player);
                     save_settings(&settings)?;
@@ -505,6 +1307,80 @@ impl Console {
                     format!("Point of view set to {}", value)
                 }
             },
+            ConsoleCommand::Ghost { player, enabled } => {
+                let guid = resolve_offline(&self.view, &player).await?;
+                let mut settings = self.view.get_mut_settings().write().await;
+                let changed = if enabled {
+                    settings.ghosts.players.insert(guid)
+                } else {
+                    settings.ghosts.players.remove(&guid)
+                };
+                save_settings(&settings)?;
+                drop(settings);
+
+                if changed {
+                    self.show_puppet(guid, !enabled).await;
+                }
+                let name = display_name(&self.view, &guid).await;
+                match (enabled, changed) {
+                    (true, true) => format!("{} is a ghost now", name),
+                    (true, false) => format!("{} is already a ghost", name),
+                    (false, true) => format!("{} isn't a ghost anymore", name),
+                    (false, false) => format!("{} isn't a ghost", name),
+                }
+            }
+            ConsoleCommand::Invisible { player, enabled } => {
+                let guid = resolve_offline(&self.view, &player).await?;
+                let mut settings = self.view.get_mut_settings().write().await;
+                let changed = if enabled {
+                    settings.invisible.players.insert(guid)
+                } else {
+                    settings.invisible.players.remove(&guid)
+                };
+                save_settings(&settings)?;
+                drop(settings);
+
+                if changed {
+                    self.show_puppet(guid, !enabled).await;
+                }
+                let name = display_name(&self.view, &guid).await;
+                match (enabled, changed) {
+                    (true, true) => format!("{} is invisible now", name),
+                    (true, false) => format!("{} is already invisible", name),
+                    (false, true) => format!("{} is visible again", name),
+                    (false, false) => format!("{} isn't invisible", name),
+                }
+            }
+            ConsoleCommand::Spectate(spectate) => match spectate {
+                SpectateCommand::List => {
+                    let players = self.view.get_lobby().settings.read().await.spectators.players.clone();
+                    let mut names = Vec::with_capacity(players.len());
+                    for guid in &players {
+                        names.push(display_name(&self.view, guid).await);
+                    }
+                    format!("Spectators: {}", names.join(", "))
+                }
+                SpectateCommand::Add { player } => {
+                    let guid = resolve_offline(&self.view, &player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.spectators.players.insert(guid);
+                    save_settings(&settings)?;
+                    drop(settings);
+                    format!("Added {} to the spectators", display_name(&self.view, &guid).await)
+                }
+                SpectateCommand::Remove { player } => {
+                    let guid = resolve_offline(&self.view, &player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let was_removed = settings.spectators.players.remove(&guid);
+                    save_settings(&settings)?;
+                    drop(settings);
+                    let name = display_name(&self.view, &guid).await;
+                    match was_removed {
+                        true => format!("Removed {} from the spectators", name),
+                        false => format!("{} wasn't a spectator", name),
+                    }
+                }
+            },
             ConsoleCommand::Shine(shine) => match shine {
                 ShineArg::List => {
                     let mut out = "Shines: ".to_string();
@@ -512,7 +1388,7 @@ impl Console {
                     let shines = self.view.get_lobby().shines.read().await;
                     out += &shines
                         .iter()
-                        .map(ToString::to_string)
+                        .map(|id| Shines::describe(*id))
                         .collect::<Vec<_>>()
                         .join(", ");
 
@@ -521,14 +1397,14 @@ impl Console {
                         out += "\nExcluded Shines: ";
                         out += &settings.shines.excluded
                             .iter()
-                            .map(ToString::to_string)
+                            .map(|id| Shines::describe(*id))
                             .collect::<Vec<_>>()
                             .join(", ");
                     }
 
                     out
                 }
-                ShineArg::Clear => {
+                ShineArg::Clear { .. } => {
                     self.request_comm(ExternalCommand::Shine {
                         command: ShineCommand::Clear,
                     })
@@ -568,7 +1444,7 @@ impl Console {
                     save_settings(&settings)?;
                     drop(settings);
 
-                    format!("No longer exclude shine {} from syncing", id)
+                    format!("No longer exclude shine {} from syncing", Shines::describe(id))
                 }
                 ShineArg::Exclude { id } => {
                     let mut settings = self.view.get_mut_settings().write().await;
@@ -576,7 +1452,85 @@ impl Console {
                     save_settings(&settings)?;
                     drop(settings);
 
-                    format!("Exclude shine {} from syncing", id)
+                    format!("Exclude shine {} from syncing", Shines::describe(id))
+                }
+                ShineArg::ExcludePlayer { player } => {
+                    let player = resolve_offline(&self.view, &player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.shines.excluded_players.insert(player);
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    format!("Exclude {} from shine syncing", display_name(&self.view, &player).await)
+                }
+                ShineArg::IncludePlayer { player } => {
+                    let player = resolve_offline(&self.view, &player).await?;
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let was_removed = settings.shines.excluded_players.remove(&player);
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    let name = display_name(&self.view, &player).await;
+                    match was_removed {
+                        true => format!("No longer exclude {} from shine syncing", name),
+                        false => format!("{} wasn't excluded from shine syncing", name),
+                    }
+                }
+                ShineArg::Progress { player } => {
+                    let lobby = self.view.get_lobby();
+                    let guids = match player {
+                        Some(player) => {
+                            let players: PlayerSelect<String> = (&[player][..]).into();
+                            players.into_guid_vec(&self.view).await?.flatten(lobby)?
+                        }
+                        None => lobby.players.iter().map(|p| *p.key()).collect(),
+                    };
+                    let detailed = guids.len() == 1;
+
+                    let shines = lobby.shines.read().await;
+                    let mut out = Vec::with_capacity(guids.len());
+                    for guid in guids {
+                        let player = lobby.get_client(&guid)?;
+                        let missing = Shines::missing(&shines, &player.shine_sync);
+                        let count: usize = missing.values().map(Vec::len).sum();
+                        out.push(format!("{}: missing {} of {} shines", player.name, count, shines.len()));
+                        if detailed {
+                            for (kingdom, ids) in missing {
+                                // the kingdom is already shown in front of them
+                                let ids: Vec<_> = ids
+                                    .into_iter()
+                                    .map(|id| match Shines::info(id) {
+                                        Some(info) => format!("{} ({})", id, info.name),
+                                        None => id.to_string(),
+                                    })
+                                    .collect();
+                                out.push(format!("  {} ({}): {}", kingdom, ids.len(), ids.join(", ")));
+                            }
+                        }
+                    }
+                    if out.is_empty() {
+                        "No players connected".to_string()
+                    } else {
+                        out.join("\n")
+                    }
+                }
+            },
+            ConsoleCommand::Snapshot(session) => match session {
+                SessionCommand::Save { path } => {
+                    let snapshot = SessionSnapshot::capture(self.view.get_lobby()).await?;
+                    snapshot.save(&path).await?;
+                    format!(
+                        "Saved {} players and {} shines to {}",
+                        snapshot.players.len(),
+                        snapshot.shines.len(),
+                        path
+                    )
+                }
+                SessionCommand::Load { path } => {
+                    let snapshot = SessionSnapshot::load(&path).await?;
+                    let shines = snapshot.shines.len();
+                    let restored = snapshot.restore(self.view.get_lobby()).await?;
+                    format!("Loaded {} shines and {} players from {}", shines, restored, path)
                 }
             },
             ConsoleCommand::Udp(udpcmd) => match udpcmd {
@@ -594,23 +1548,523 @@ impl Console {
                     .to_string()
                 }
             },
+            ConsoleCommand::Costume(costume) => match costume {
+                CostumeCommand::Pending => {
+                    let settings = self.view.get_lobby().settings.read().await;
+                    let moderation = &settings.costume_moderation;
+                    let mut pending: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+                    for player in self.view.get_lobby().players.iter() {
+                        if let Some(costume) = &player.held_costume {
+                            for name in [&costume.body_name, &costume.cap_name] {
+                                if !moderation.allows(name) {
+                                    pending.entry(name.clone()).or_default().insert(player.name.clone());
+                                }
+                            }
+                        }
+                    }
+                    if pending.is_empty() {
+                        "No costumes are waiting for approval".to_string()
+                    } else {
+                        pending
+                            .into_iter()
+                            .map(|(name, players)| format!("{} (worn by {})", name, Vec::from_iter(players).join(", ")))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    }
+                }
+                CostumeCommand::List => {
+                    let settings = self.view.get_lobby().settings.read().await;
+                    let approved: Vec<_> = settings.costume_moderation.approved.iter().cloned().collect();
+                    format!("Approved costumes: {}", approved.join(", "))
+                }
+                CostumeCommand::Approve { name } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    settings.costume_moderation.approved.insert(name.clone());
+                    save_settings(&settings)?;
+                    drop(settings);
+
+                    let released = self.release_costumes().await?;
+                    format!("Approved costume {}, released the costumes of {} players", name, released)
+                }
+                CostumeCommand::Revoke { name } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let was_removed = settings.costume_moderation.approved.remove(&name);
+                    save_settings(&settings)?;
+                    drop(settings);
+                    match was_removed {
+                        true => format!("Revoked approval of costume {}, it will be held back on the next costume change", name),
+                        false => format!("Costume {} wasn't approved", name),
+                    }
+                }
+                CostumeCommand::Set { body, cap, players } => {
+                    let costume = Costume { body_name: body, cap_name: cap };
+                    let names = self.force_costumes(&players, Some(costume.clone())).await?;
+                    format!("Showing {} in {}/{}", names.join(", "), costume.body_name, costume.cap_name)
+                }
+                CostumeCommand::Reset { players } => {
+                    let names = self.force_costumes(&players, None).await?;
+                    format!("Showing the real costumes of {}", names.join(", "))
+                }
+            },
+            ConsoleCommand::Filter(filter) => match filter {
+                FilterCommand::List => {
+                    let mut out = Vec::new();
+                    for player in self.view.get_lobby().players.iter() {
+                        if !player.hidden_packets.is_empty() {
+                            let hidden: Vec<_> = player.hidden_packets.iter().cloned().collect();
+                            out.push(format!("{}: {}", player.name, hidden.join(", ")));
+                        }
+                    }
+                    if out.is_empty() {
+                        "No packets are hidden from any player".to_string()
+                    } else {
+                        out.join("\n")
+                    }
+                }
+                FilterCommand::Hide { packet_type, players } => {
+                    check_filterable(&packet_type)?;
+                    let names = self.update_filters(&players, |hidden| { hidden.insert(packet_type.clone()); }).await?;
+                    format!("Hiding {} packets from: {}", packet_type, names.join(", "))
+                }
+                FilterCommand::Show { packet_type, players } => {
+                    check_filterable(&packet_type)?;
+                    let names = self.update_filters(&players, |hidden| { hidden.remove(&packet_type); }).await?;
+                    format!("Showing {} packets to: {}", packet_type, names.join(", "))
+                }
+                FilterCommand::Clear { players } => {
+                    let names = self.update_filters(&players, BTreeSet::clear).await?;
+                    format!("Cleared packet filters of: {}", names.join(", "))
+                }
+            },
+            ConsoleCommand::Record(record) => {
+                let command = match record {
+                    RecordCommand::Start { path } => RecordingCommand::Start { path },
+                    RecordCommand::Stop => RecordingCommand::Stop,
+                };
+                self.request_comm(ExternalCommand::Recording { command }).await?
+            }
+            ConsoleCommand::Api(ApiCommand::Blocks(blocks)) => match blocks {
+                ApiBlocksCommand::List => {
+                    let blocked: Vec<_> = BlockClients::list()
+                        .await
+                        .into_iter()
+                        .map(|(ip, since)| {
+                            let minutes = since.elapsed().unwrap_or_default().as_secs() / 60;
+                            format!("{} (blocked {} minutes ago)", ip, minutes)
+                        })
+                        .collect();
+                    if blocked.is_empty() {
+                        "No blocked api clients".to_string()
+                    } else {
+                        blocked.join("\n")
+                    }
+                }
+                ApiBlocksCommand::Clear { ip } => {
+                    let settings = self.view.get_lobby().settings.read().await.json_api.blocking.clone();
+                    let cleared = BlockClients::clear(ip, &settings).await;
+                    format!("Unblocked {} api clients", cleared)
+                }
+            },
+            ConsoleCommand::Token(token) => match token {
+                TokenCommand::List => {
+                    let settings = self.view.get_lobby().settings.read().await;
+                    let tokens: Vec<_> = settings
+                        .json_api
+                        .tokens
+                        .iter()
+                        .map(|(token, permissions)| {
+                            let permissions: Vec<_> = permissions.iter().cloned().collect();
+                            format!("{}: {}", mask_token(token), permissions.join(", "))
+                        })
+                        .collect();
+                    if tokens.is_empty() {
+                        "No tokens configured".to_string()
+                    } else {
+                        tokens.join("\n")
+                    }
+                }
+                TokenCommand::Add { token, permissions } => {
+                    let token = token.unwrap_or_else(|| hex::encode(Guid::new_random().id));
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    if settings.json_api.tokens.contains_key(&token) {
+                        return Err(SMOError::InvalidConsoleArg("Token already exists".to_string()));
+                    }
+                    settings.json_api.tokens.insert(token.clone(), permissions.into_iter().collect());
+                    save_settings(&settings)?;
+                    format!("Added token {}", token)
+                }
+                TokenCommand::Remove { token } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let token = resolve_token(&settings, &token)?;
+                    settings.json_api.tokens.remove(&token);
+                    save_settings(&settings)?;
+                    format!("Removed token {}", mask_token(&token))
+                }
+                TokenCommand::Grant { token, permissions } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let token = resolve_token(&settings, &token)?;
+                    settings.json_api.tokens.entry(token.clone()).or_default().extend(permissions.iter().cloned());
+                    save_settings(&settings)?;
+                    format!("Granted {} to token {}", permissions.join(", "), mask_token(&token))
+                }
+                TokenCommand::Revoke { token, permissions } => {
+                    let mut settings = self.view.get_mut_settings().write().await;
+                    let token = resolve_token(&settings, &token)?;
+                    let granted = settings.json_api.tokens.entry(token.clone()).or_default();
+                    for permission in &permissions {
+                        granted.remove(permission);
+                    }
+                    save_settings(&settings)?;
+                    format!("Revoked {} from token {}", permissions.join(", "), mask_token(&token))
+                }
+            },
+            ConsoleCommand::Npc(npc) => match npc {
+                NpcArg::List => {
+                    let npcs: Vec<_> = self
+                        .view
+                        .get_lobby()
+                        .npcs
+                        .iter()
+                        .map(|npc| {
+                            let walking = if npc.path.is_empty() { "" } else { ", walking" };
+                            format!(
+                                "{} ({}) in {} at {:.0},{:.0},{:.0}{}",
+                                npc.name, npc.key(), npc.stage, npc.pos.x, npc.pos.y, npc.pos.z, walking
+                            )
+                        })
+                        .collect();
+                    if npcs.is_empty() {
+                        "No NPCs spawned".to_string()
+                    } else {
+                        npcs.join("\n")
+                    }
+                }
+                NpcArg::Spawn { name, at, stage, scenario, pos, body, cap } => {
+                    if self.view.get_lobby().npcs.iter().any(|npc| npc.name == name) {
+                        return Err(SMOError::InvalidConsoleArg(format!("An NPC named {} already exists", name)));
+                    }
+                    let mut npc = match at {
+                        Some(player) => self.npc_at(name, player).await?,
+                        None => Npc::new(name, Costume::default(), String::new(), -1, Default::default()),
+                    };
+                    if let Some(stage) = stage {
+                        npc.stage = Stages::input2stage(&stage).unwrap_or(stage);
+                    }
+                    if let Some(scenario) = scenario {
+                        npc.scenario = scenario;
+                    }
+                    if let Some(pos) = pos {
+                        npc.pos = pos.0;
+                    }
+                    if let Some(body) = body {
+                        npc.costume.body_name = body;
+                    }
+                    if let Some(cap) = cap {
+                        npc.costume.cap_name = cap;
+                    }
+                    if npc.stage.is_empty() {
+                        return Err(SMOError::InvalidConsoleArg(
+                            "Either spawn the NPC at a player or give it a stage".to_string(),
+                        ));
+                    }
+                    self.request_comm(ExternalCommand::Npc { command: NpcCommand::Spawn { npc } }).await?
+                }
+                NpcArg::Move { npc, points, speed, loop_path } => {
+                    let npcs = self.resolve_npcs(&[npc])?;
+                    let path = points.into_iter().map(|p| p.0).collect();
+                    self.request_comm(ExternalCommand::Npc {
+                        command: NpcCommand::Move { npcs, path, speed, loop_path },
+                    })
+                    .await?
+                }
+                NpcArg::Despawn { npcs } => {
+                    let npcs = self.resolve_npcs(&npcs)?;
+                    self.request_comm(ExternalCommand::Npc { command: NpcCommand::Despawn { npcs } }).await?
+                }
+            },
             ConsoleCommand::LoadSettings => {
                 let mut settings = self.view.get_mut_settings().write().await;
-                let new_settings = load_settings()?;
+                let new_settings = reload_settings(&settings)?;
+                if new_settings.lobby.is_none() {
+                    logbuffer::set_capacity(new_settings.logging.buffer_lines);
+                    Stages::set_custom_aliases(&new_settings.stage_aliases);
+                    Shines::reload(&new_settings.shines);
+                }
                 *settings = new_settings;
                 "Loaded settings.json".to_string()
             }
+            ConsoleCommand::Lobby { name, command } => {
+                if !command.is_empty() {
+                    return Err(SMOError::InvalidConsoleArg(
+                        "Lobby commands can only be run from the main console".to_string(),
+                    ));
+                }
+
+                let mut lobbies = Vec::new();
+                for (lobby_name, view) in self.lobbies.iter() {
+                    if name.as_ref().is_some_and(|n| n != lobby_name) {
+                        continue;
+                    }
+                    let lobby = view.get_lobby();
+                    let port = lobby.settings.read().await.server.port;
+                    lobbies.push(format!("{} (port {}): {} players", lobby_name, port, lobby.players.len()));
+                }
+
+                if lobbies.is_empty() {
+                    "No lobbies found".to_string()
+                } else {
+                    format!("Lobbies: \n\t{}", lobbies.join("\n\t"))
+                }
+            }
             ConsoleCommand::Restart => {
                 self.view
                     .get_server_send()
                     .send(ServerWideCommand::Shutdown)?;
                 "Restarting server".to_string()
             }
+            ConsoleCommand::Confirm => {
+                return Err(SMOError::InvalidConsoleArg("No command to confirm".to_string()));
+            }
         };
 
         Ok(reply_str)
     }
 
+    /// Show the held back costumes that are fully approved now to the other players, returns how many were released
+    async fn release_costumes(&mut self) -> Result<usize> {
+        let settings = self.view.get_lobby().settings.read().await;
+        let moderation = &settings.costume_moderation;
+        let mut released = Vec::new();
+        for mut player in self.view.get_lobby().players.iter_mut() {
+            let approved = player
+                .held_costume
+                .as_ref()
+                .is_some_and(|c| moderation.allows(&c.body_name) && moderation.allows(&c.cap_name));
+            if approved {
+                let costume = player.held_costume.take().unwrap();
+                let packet = Packet::new(*player.key(), PacketData::Costume(costume));
+                player.last_costume_packet = Some(packet.clone());
+                released.push(packet);
+            }
+        }
+        drop(settings);
+
+        let count = released.len();
+        for packet in released {
+            self.view.get_lobby().to_coord.send(Command::Packet(packet)).await?;
+        }
+        Ok(count)
+    }
+
+    /// Show the selected players in a costume, or in their real one without it, returns their names
+    async fn force_costumes(&mut self, players: &[SinglePlayerSelect], costume: Option<Costume>) -> Result<Vec<String>> {
+        let players: PlayerSelect<String> = players.into();
+        let guids = players.into_guid_vec(&self.view).await?.flatten(self.view.get_lobby())?;
+        let mut names = Vec::with_capacity(guids.len());
+        let mut packets = Vec::with_capacity(guids.len());
+        for guid in guids {
+            let mut player = self.view.get_mut_client(&guid)?;
+            player.forced_costume = costume.clone();
+            let packet = match &costume {
+                Some(costume) => Some(Packet::new(guid, PacketData::Costume(costume.clone()))),
+                None => player.last_costume_packet.clone(),
+            };
+            names.push(player.name.clone());
+            drop(player);
+            packets.extend(packet);
+        }
+        for packet in packets {
+            self.view.get_lobby().to_coord.send(Command::Packet(packet)).await?;
+        }
+        Ok(names)
+    }
+
+    /// Change the hidden packet types of the selected players, returns their names
+    async fn update_filters(
+        &mut self,
+        players: &[SinglePlayerSelect],
+        update: impl Fn(&mut BTreeSet<String>),
+    ) -> Result<Vec<String>> {
+        let players: PlayerSelect<String> = players.into();
+        let guids = players.into_guid_vec(&self.view).await?.flatten(self.view.get_lobby())?;
+        let mut names = Vec::with_capacity(guids.len());
+        for guid in guids {
+            let mut player = self.view.get_mut_client(&guid)?;
+            update(&mut player.hidden_packets);
+            names.push(player.name.clone());
+        }
+        Ok(names)
+    }
+
+    async fn audit(&self, action: &str, targets: Vec<String>, reason: Option<String>) {
+        let entry = AuditEntry::new(&self.origin, action, targets, reason).with_address(self.address);
+        audit::record(self.view.get_lobby(), &entry).await;
+    }
+
+    /// What a destructive command would do, `None` if it wouldn't affect anyone
+    async fn affected_by(&self, cmd: &ConsoleCommand) -> Result<Option<String>> {
+        let lobby = self.view.get_lobby();
+        let connected_names = |guids: &[Guid]| -> Vec<String> {
+            guids
+                .iter()
+                .filter_map(|guid| lobby.players.get(guid).map(|p| p.name.clone()))
+                .collect()
+        };
+
+        let affected = match cmd {
+            ConsoleCommand::Crash { players, .. } => {
+                let players: PlayerSelect<String> = (&players[..]).into();
+                let guids = players.into_guid_vec(&self.view).await?.flatten(lobby)?;
+                let names = connected_names(&guids);
+                format!("crash would disconnect {} players: {}", names.len(), names.join(", "))
+            }
+            ConsoleCommand::Ban(BanCommand::Player { players, .. }) => {
+                let players: PlayerSelect<String> = (&players[..]).into();
+                let guids = players.into_offline_guids(&self.view).await?;
+                let mut names = Vec::with_capacity(guids.len());
+                for guid in &guids {
+                    names.push(display_name(&self.view, guid).await);
+                }
+                let connected: Vec<_> = guids.iter().filter(|g| lobby.players.contains_key(g)).copied().collect();
+                let ips = Players::Individual(connected.clone()).get_ipv4s(lobby);
+                format!(
+                    "ban would ban {} players: {}, and their ip addresses: {}, disconnecting: {}",
+                    names.len(),
+                    names.join(", "),
+                    ips.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", "),
+                    connected_names(&connected).join(", ")
+                )
+            }
+            ConsoleCommand::Ban(BanCommand::Profile { profile_id, .. }) => {
+                format!(
+                    "ban would ban profile {}, disconnecting: {}",
+                    profile_id,
+                    connected_names(&[*profile_id]).join(", ")
+                )
+            }
+            ConsoleCommand::Ban(BanCommand::IP { ipv4, .. }) => {
+                let guids: Vec<Guid> = lobby.players.iter().filter(|x| x.value().ipv4 == Some(*ipv4)).map(|x| *x.key()).collect();
+                format!("ban would ban ip {}, disconnecting: {}", ipv4, connected_names(&guids).join(", "))
+            }
+            ConsoleCommand::Shine(ShineArg::Clear { .. }) => {
+                let shines = lobby.shines.read().await.len();
+                format!("shine clear would discard {} collected shines", shines)
+            }
+            ConsoleCommand::MaxPlayers { player_count, .. } => {
+                let connected = lobby.players.len();
+                if *player_count as usize >= connected {
+                    return Ok(None);
+                }
+                format!(
+                    "maxplayers {} would reconnect all {} players",
+                    player_count, connected
+                )
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(affected))
+    }
+
+    async fn audit_log(&self, count: usize, commands: bool) -> Result<String> {
+        let entries = audit::latest(self.view.get_lobby(), count, commands).await?;
+        let mut list = vec![format!("Audit log ({} entries):", entries.len())];
+        list.extend(entries.iter().map(AuditEntry::to_string));
+        Ok(list.join("\n"))
+    }
+
+    fn ban_details(&self, reason: Option<String>) -> BanDetails {
+        BanDetails {
+            reason,
+            moderator: self.origin.clone(),
+            timestamp: audit::now(),
+        }
+    }
+
+    /// Guid of the selected player, fails unless exactly one player matches
+    async fn single_player(&self, player: SinglePlayerSelect) -> Result<Guid> {
+        let players: PlayerSelect<String> = (&[player][..]).into();
+        let guids = players.into_guid_vec(&self.view).await?.flatten(self.view.get_lobby())?;
+        match guids[..] {
+            [guid] => Ok(guid),
+            _ => Err(SMOError::InvalidConsoleArg("Expected exactly one player".to_string())),
+        }
+    }
+
+    /// Send the last game packet of the player to the others again, for a changed scenario override, returns its name
+    async fn resend_game_packet(&self, guid: Guid) -> Result<String> {
+        let player = self.view.get_lobby().get_client(&guid)?;
+        let (name, packet) = (player.name.clone(), player.last_game_packet.clone());
+        drop(player);
+        self.send_to_others(guid, packet.into_iter().collect()).await;
+        Ok(name)
+    }
+
+    /// Remove or restore the puppet of a connected player for all other players
+    async fn show_puppet(&self, guid: Guid, visible: bool) {
+        let max_players = self.view.get_lobby().settings.read().await.server.max_players;
+        let packets = match self.view.get_lobby().players.get(&guid) {
+            Some(player) if visible => player.create_sync_packets(guid, max_players),
+            Some(_) => vec![Packet::new(guid, PacketData::Disconnect)],
+            None => return,
+        };
+        self.send_to_others(guid, packets).await;
+    }
+
+    /// Send packets of a player to all other players, without going through the coordinator
+    async fn send_to_others(&self, guid: Guid, packets: Vec<Packet>) {
+        let channels: Vec<_> = self
+            .view
+            .get_lobby()
+            .players
+            .iter()
+            .filter(|other| *other.key() != guid)
+            .map(|other| other.channel.clone())
+            .collect();
+        for channel in channels {
+            for packet in &packets {
+                let _ = channel.send(ClientCommand::Packet(packet.clone())).await;
+            }
+        }
+    }
+
+    /// Create an NPC with the stage, position and costume of a player
+    async fn npc_at(&self, name: String, player: SinglePlayerSelect) -> Result<Npc> {
+        let guid = self.single_player(player).await?;
+        let player = self.view.get_lobby().get_client(&guid)?;
+        let costume = match &player.last_costume_packet {
+            Some(Packet { data: PacketData::Costume(costume), .. }) => costume.clone(),
+            _ => Costume::default(),
+        };
+        let mut npc = Npc::new(name, costume, String::new(), -1, Default::default());
+        if let Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) = &player.last_game_packet {
+            npc.stage = stage.clone();
+            npc.scenario = *scenario_num;
+        }
+        if let Some(Packet { data: PacketData::Player { pos, rot, .. }, .. }) = &player.last_player_packet {
+            npc.pos = *pos;
+            npc.rot = *rot;
+        }
+        Ok(npc)
+    }
+
+    /// Guids of the NPCs with the given names or guids, `*` selects all of them
+    fn resolve_npcs(&self, selectors: &[String]) -> Result<Vec<Guid>> {
+        let npcs = &self.view.get_lobby().npcs;
+        if selectors.iter().any(|s| s == "*") {
+            return Ok(npcs.iter().map(|npc| *npc.key()).collect());
+        }
+        selectors
+            .iter()
+            .map(|selector| {
+                let guid = selector.parse::<Guid>().ok();
+                npcs.iter()
+                    .find(|npc| &npc.name == selector || guid == Some(*npc.key()))
+                    .map(|npc| *npc.key())
+                    .ok_or_else(|| SMOError::InvalidConsoleArg(format!("Unknown NPC {}", selector)))
+            })
+            .collect()
+    }
+
     pub async fn request_comm(&self, command: ExternalCommand) -> Result<String> {
         let (sender, recv) = oneshot::channel();
 
@@ -625,21 +2079,82 @@ impl Console {
 
         Ok(reply_str)
     }
+}
+
+/// Words of a `--reason` argument
+fn reason_arg(reason: Vec<String>) -> Option<String> {
+    Some(reason.join(" ")).filter(|r| !r.is_empty())
+}
 
-    pub async fn read_input() -> Result<Cli> {
-        let task = tokio::task::spawn_blocking(|| async { Self::get_input() });
-        let cli: Cli = tokio::join!(task).0?.await?;
-        Ok(cli)
+/// Reason, moderator and date of a ban, if known
+fn describe_ban(settings: &Settings, banned: &str) -> String {
+    match settings.ban_list.details.get(banned) {
+        Some(details) => format!(
+            " ({}, by {} on {})",
+            details.reason.as_deref().unwrap_or("no reason"),
+            details.moderator,
+            audit::format_time(details.timestamp)
+        ),
+        None => String::new(),
+    }
+}
+
+/// Full token for the given token or unique prefix of a token
+fn resolve_token(settings: &Settings, token: &str) -> Result<String> {
+    let tokens = &settings.json_api.tokens;
+    if tokens.contains_key(token) {
+        return Ok(token.to_string());
+    }
+    let matches: Vec<_> = tokens.keys().filter(|t| t.starts_with(token)).collect();
+    match matches[..] {
+        [matched] => Ok(matched.clone()),
+        [] => Err(SMOError::InvalidConsoleArg(format!("Unknown token {}", mask_token(token)))),
+        _ => Err(SMOError::InvalidConsoleArg(format!("Token prefix {} is ambiguous", mask_token(token)))),
     }
+}
 
-    pub fn get_input() -> Result<Cli> {
-        let mut input = "> ".to_string();
+/// Only the start of a token, to not leak it into logs
+pub fn mask_token(token: &str) -> String {
+    let prefix: String = token.chars().take(4).collect();
+    format!("{}...", prefix)
+}
 
-        std::io::stdout().flush()?;
-        std::io::stdin().read_line(&mut input)?;
-        let input = input.trim().split(' ');
-        let cli = Cli::try_parse_from(input)?;
+/// The command line with the tokens of `token` subcommands and `--token` masked
+pub fn redact_command(line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let mut masked = Vec::with_capacity(words.len());
+    for (i, word) in words.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| words[i]);
+        let before_previous = i.checked_sub(2).map(|i| words[i]);
+        let is_token = previous == Some("--token")
+            || (before_previous == Some("token") && matches!(previous, Some("remove" | "grant" | "revoke")));
+        match word.strip_prefix("--token=") {
+            Some(token) => masked.push(format!("--token={}", mask_token(token))),
+            None if is_token => masked.push(mask_token(word)),
+            None => masked.push(word.to_string()),
+        }
+    }
+    masked.join(" ")
+}
+
+/// The text with every configured token masked
+pub fn redact_tokens(text: &str, settings: &Settings) -> String {
+    settings
+        .json_api
+        .tokens
+        .keys()
+        .filter(|token| !token.is_empty())
+        .fold(text.to_string(), |text, token| text.replace(token.as_str(), &mask_token(token)))
+}
 
-        Ok(cli)
+fn check_filterable(packet_type: &str) -> Result<()> {
+    if FILTERABLE_PACKETS.contains(&packet_type) {
+        Ok(())
+    } else {
+        Err(SMOError::InvalidConsoleArg(format!(
+            "Invalid packet type {}, valid types are: {}",
+            packet_type,
+            FILTERABLE_PACKETS.join(", ")
+        )))
     }
 }
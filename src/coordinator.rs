@@ -1,47 +1,182 @@
 use crate::{
+    client::PlayerData,
+    console::{Cli, Console},
     cmds::{
-        ClientCommand, Command, ExternalCommand, PlayerCommand, Players, ServerCommand,
-        ShineCommand,
+        console::{SinglePlayerSelect, TagCommand},
+        ClientCommand, ConsoleCommand, Command, ExternalCommand, NpcCommand, PlayerCommand, Players,
+        RecordingCommand, ServerCommand, ServerWideCommand, ShineCommand, ShineRequest,
     },
+    events::ServerEvent,
     guid::Guid,
     lobby::{Lobby, LobbyView},
+    message,
     net::{ConnectionType, GameMode, Packet, PacketData, TagUpdate},
     player_holder::ClientChannel,
-    types::Result,
+    race::{self, RaceProgress},
+    recorder::Recorder,
+    relay::Relay,
+    settings::PersistShine,
+    shine_sync::ShineSync,
+    stages::Stages,
+    systemd::Watchdog,
+    types::{Costume, Result, SMOError},
 };
 
-use std::{collections::BTreeSet, sync::Arc, time::Duration};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     fs::File,
     io::AsyncWriteExt,
-    sync::{broadcast, mpsc, oneshot, RwLock},
+    select,
+    sync::{
+        broadcast::error::RecvError,
+        mpsc, oneshot, Mutex, RwLock,
+    },
+    time::interval,
 };
 use tracing::{info_span, Instrument};
 
 pub type SyncShineBag = Arc<RwLock<ShineBag>>;
-pub type ShineBag = BTreeSet<i32>;
 
+/// Collected shines, remembering which of them are grand moons (multi moons), so they sync as such
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ShineBag {
+    shines: BTreeSet<i32>,
+    #[serde(default)]
+    grand: BTreeSet<i32>,
+}
+
+impl ShineBag {
+    /// Returns `true` if the shine wasn't collected before
+    pub fn insert(&mut self, shine_id: i32, is_grand: bool) -> bool {
+        if is_grand {
+            self.grand.insert(shine_id);
+        }
+        self.shines.insert(shine_id)
+    }
+
+    pub fn is_grand(&self, shine_id: i32) -> bool {
+        self.grand.contains(&shine_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.shines.clear();
+        self.grand.clear();
+    }
+
+    pub fn extend(&mut self, other: ShineBag) {
+        self.shines.extend(other.shines);
+        self.grand.extend(other.grand);
+    }
+}
+
+impl From<BTreeSet<i32>> for ShineBag {
+    fn from(shines: BTreeSet<i32>) -> Self {
+        Self { shines, grand: BTreeSet::new() }
+    }
+}
+
+/// The ids of the shines
+impl Deref for ShineBag {
+    type Target = BTreeSet<i32>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shines
+    }
+}
+
+lazy_static! {
+    /// Keeps concurrent saves from writing the same temporary file
+    static ref SAVE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Version of the moons.json format written by `save_shines`
+///
+/// Version 2 added the grand moons, older versions only have regular ones.
+pub const SHINE_FORMAT_VERSION: u32 = 2;
+
+/// Versioned envelope around the persisted shine bag
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PersistedShines {
+    pub version: u32,
+    /// Seconds since the unix epoch when the file was written
+    pub timestamp: u64,
+    /// Shines excluded from syncing at the time of saving
+    pub excluded: BTreeSet<i32>,
+    #[serde(flatten)]
+    pub shines: ShineBag,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PersistedShinesFormat {
+    Versioned(PersistedShines),
+    /// Version 0: a bare array of shine ids
+    Legacy(BTreeSet<i32>),
+}
+
+/// Applies the packets and commands that change the lobby, one after the other.
+///
+/// Of the planned split into tasks only the shines got their own one (`ShineSync`), the player lifecycle,
+/// game modes and moderation deliberately stay here: they decide on a game packet before it's forwarded,
+/// e.g. sending a player back from a banned or full stage, so they have to see the packets in order.
+/// They only read and update the lobby, files are saved in spawned tasks.
 pub struct Coordinator {
     lobby: Lobby,
     pub from_clients: mpsc::Receiver<Command>,
-    pub cli_broadcast: broadcast::Sender<ClientCommand>,
+    pub cli_broadcast: Relay,
+    /// Gated stages every player was ready for at some point, they stay unlocked
+    unlocked_stages: BTreeSet<String>,
+    recorder: Option<Recorder>,
+    to_shine_sync: mpsc::Sender<ShineRequest>,
+    /// Spawned once the coordinator runs
+    shine_sync: Option<ShineSync>,
 }
 
 impl Coordinator {
     pub fn new(
         lobby: Lobby,
         from_clients: mpsc::Receiver<Command>,
-        cli_broadcast: broadcast::Sender<ClientCommand>,
+        cli_broadcast: Relay,
     ) -> Self {
+        let (shine_sync, to_shine_sync) = ShineSync::new(lobby.clone());
         Coordinator {
             lobby,
             from_clients,
             cli_broadcast,
+            unlocked_stages: Default::default(),
+            recorder: None,
+            to_shine_sync,
+            shine_sync: Some(shine_sync),
         }
     }
+    /// Run the shine task separately, to wait for its final save on shutdown
+    pub fn take_shine_sync(&mut self) -> Option<ShineSync> {
+        self.shine_sync.take()
+    }
+
     pub async fn handle_commands(mut self) -> Result<()> {
+        let mut watchdog = Watchdog::default();
+        if let Some(shine_sync) = self.shine_sync.take() {
+            tokio::spawn(shine_sync.run());
+        }
         loop {
-            let cmd = self.from_clients.recv().await;
+            let cmd = select! {
+                cmd = self.from_clients.recv() => cmd,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = watchdog.keepalive() => continue,
+            };
             if let Some(c) = cmd {
                 let result = self.handle_command(c).await;
                 match result {
@@ -65,23 +200,18 @@ impl Coordinator {
                 ServerCommand::DisconnectPlayer { guid } => self.disconnect_player(guid).await?,
             },
             Command::Packet(packet) => {
+                self.record(&packet).await;
                 match &packet.data {
                     PacketData::Costume(_) => {
-                        self.sync_all_shines().await?;
+                        self.to_shine_sync.send(ShineRequest::Sync).await?;
                     }
-                    PacketData::Shine { shine_id, .. } => {
-                        let settings = self.lobby.settings.read().await;
-                        let is_excluded = settings.shines.excluded.contains(shine_id);
-                        drop(settings);
-
-                        if is_excluded {
-                            tracing::info!("Got moon {shine_id} (excluded)");
-                        } else {
-                            self.lobby.shines.write().await.insert(*shine_id);
-                            tracing::info!("Got moon {shine_id}");
-                            self.sync_all_shines().await?;
-                        }
-
+                    PacketData::Shine { shine_id, is_grand } => {
+                        let request = ShineRequest::Collected {
+                            id: packet.id,
+                            shine_id: *shine_id,
+                            is_grand: *is_grand,
+                        };
+                        self.to_shine_sync.send(request).await?;
                         return Ok(true);
                     }
                     PacketData::Game {
@@ -93,10 +223,15 @@ impl Coordinator {
 
                         // entering a banned stage?
                         let settings = self.lobby.settings.read().await;
-                        let is_stage_banned = settings.ban_list.enabled && settings.ban_list.stages.contains(stage);
+                        let is_stage_banned = settings.ban_list.is_stage_banned(stage);
                         drop(settings);
                         if is_stage_banned {
                             tracing::warn!("Crashing player for entering banned stage {}.", stage);
+                            self.lobby.emit(ServerEvent::StageBanned {
+                                id: packet.id,
+                                name: self.lobby.get_client(&packet.id)?.name.clone(),
+                                stage: stage.clone(),
+                            });
                             // crash player in 500ms
                             tokio::spawn({
                                 let to_coord = self.lobby.to_coord.clone();
@@ -118,6 +253,31 @@ impl Coordinator {
                             return Ok(true);
                         }
 
+                        // entering a stage without the required costume?
+                        if let Some((stage, scenario)) = self.costume_violation(&packet.id, stage).await? {
+                            tracing::warn!("Sending player back to {} for entering a stage without the required costume.", stage);
+                            self.send_back(packet.id, stage, scenario);
+                            return Ok(true);
+                        }
+
+                        // entering a stage that isn't unlocked for everyone yet?
+                        self.update_progression().await;
+                        if let Some((stage, scenario)) = self.progression_violation(&packet.id, stage).await? {
+                            tracing::warn!("Sending player back to {} for entering a stage that is still locked.", stage);
+                            self.send_back(packet.id, stage, scenario);
+                            return Ok(true);
+                        }
+
+                        // entering a stage that is already full?
+                        if let Some((stage, scenario)) = self.capacity_violation(&packet.id, stage).await? {
+                            tracing::warn!("Sending player back to {} for entering a full stage.", stage);
+                            self.roll_back_stage(&packet.id)?;
+                            self.send_back(packet.id, stage, scenario);
+                            return Ok(true);
+                        }
+
+                        self.race_progress(packet.id, stage).await;
+
                         // player is on a new save file before entering Cascade kingdom
                         let is_shine_sync_disabled = self.lobby.get_client(&packet.id)?.disable_shine_sync;
                         if (stage == "CapWorldHomeStage" || stage == "CapWorldTowerStage") && *scenario_num == 1 {
@@ -132,49 +292,35 @@ impl Coordinator {
                                 // clear collected shines remembered by the server
                                 let clear_on_new_saves = self.lobby.settings.read().await.shines.clear_on_new_saves;
                                 if clear_on_new_saves {
-                                    self.lobby.shines.write().await.clear();
-                                    self.persist_shines().await;
+                                    self.to_shine_sync.send(ShineRequest::Clear { players: false }).await?;
                                     tracing::info!("Cleared server memory of collected moons");
                                 }
                             }
                         } else if is_shine_sync_disabled {
                             tracing::info!("Player {} entered Cascade or later with moon sync disabled, enabling moon sync again", self.lobby.get_client(&packet.id)?.name);
                             let mut lobby = LobbyView::new(&self.lobby);
+                            let to_shine_sync = self.to_shine_sync.clone();
                             tokio::spawn(async move {
                                 // sleep to prevent sending it too early (just a safety measure that is likely not necessary)
                                 tokio::time::sleep(Duration::from_millis(2000)).await;
                                 // enable shine sync again for this player
                                 lobby.get_mut_client(&packet.id)?.value_mut().disable_shine_sync = false;
                                 // sync shines to player
-                                let shine_sync_enabled = lobby.get_lobby().settings.read().await.shines.enabled;
-                                if shine_sync_enabled {
-                                    let server_shines = lobby.get_lobby().shines.clone();
-
-                                    let player = lobby.get_lobby().get_client(&packet.id)?;
-                                    let player_channel = player.channel.clone();
-                                    let excluded_shines = &lobby.get_lobby().settings.read().await.shines.excluded;
-                                    let player_shines = player.shine_sync.union(&excluded_shines).copied().collect();
-                                    drop(player);
-
-                                    let result = client_sync_shines(
-                                        player_channel,
-                                        server_shines,
-                                        &packet.id,
-                                        &player_shines,
-                                    )
-                                    .await;
-                                    if let Err(e) = result {
-                                        tracing::warn!("Initial shine sync failed: {e}")
-                                    }
-                                }
+                                to_shine_sync.send(ShineRequest::SyncPlayer(packet.id)).await?;
                                 Ok(()) as Result<()>
                             });
                         }
                         tracing::debug!("Changing scenarios: {} {}", scenario_num, stage);
 
-                        let merge_scenario =
-                            self.lobby.settings.read().await.scenario.merge_enabled;
-                        if merge_scenario {
+                        let settings = self.lobby.settings.read().await;
+                        let lock = settings.scenario.locks.get(stage).copied();
+                        let merge_scenario = settings.scenario.merge_enabled;
+                        drop(settings);
+                        if let Some(locked) = lock {
+                            if *scenario_num != locked {
+                                self.lock_scenario(&packet, locked).await?;
+                            }
+                        } else if merge_scenario {
                             self.merge_scenario(&packet).await?;
                         }
                     }
@@ -205,11 +351,23 @@ impl Coordinator {
                             });
                             return Ok(true);
                         }
+
+                        let tagged = matches!(
+                            packet.data,
+                            PacketData::Tag { update_type: TagUpdate::State | TagUpdate::Both, is_it: true, .. }
+                        );
+                        if tagged && self.lobby.tag_scores.write().await.tag(packet.id) {
+                            self.end_tag_round().await;
+                        }
                     }
                     _ => {}
                 };
                 self.broadcast(&ClientCommand::Packet(packet))?;
             }
+            // answered by the shine task once the players are synced
+            Command::External(ExternalCommand::Shine { command: ShineCommand::Sync }, reply) => {
+                self.to_shine_sync.send(ShineRequest::SyncNow(Some(reply))).await?;
+            }
             Command::External(cmd, reply) => {
                 let result = self.handle_external_cmd(cmd).await;
                 reply.send(result).expect("Reply channel failed");
@@ -233,7 +391,7 @@ impl Coordinator {
                         scenario,
                         sub_scenario: 0,
                     };
-                    let packet = Packet::new(Guid::default(), data);
+                    let packet = Packet::new(Guid::NIL, data);
                     let cmd = ClientCommand::SelfAddressed(packet);
                     self.send_players(&players, &cmd).await?;
                     "Sent players".to_string()
@@ -252,7 +410,7 @@ impl Coordinator {
                         scenario     : 21,
                         sub_scenario : 69, // invalid id
                     };
-                    let packet = Packet::new(Guid::default(), data);
+                    let packet = Packet::new(Guid::NIL, data);
                     let cmd = ClientCommand::SelfAddressed(packet);
                     self.send_players(&players, &cmd).await?;
                     "Crashed players".to_string()
@@ -267,7 +425,7 @@ impl Coordinator {
                             minutes,
                             seconds,
                         };
-                        let packet = Packet::new(Guid::default(), tag_packet);
+                        let packet = Packet::new(Guid::NIL, tag_packet);
                         self.send_players(&players, &ClientCommand::SelfAddressed(packet)).await?;
                     }
 
@@ -279,7 +437,7 @@ impl Coordinator {
                             minutes: 0,
                             seconds: 0,
                         };
-                        let packet = Packet::new(Guid::default(), tag_packet);
+                        let packet = Packet::new(Guid::NIL, tag_packet);
                         self.send_players(&players, &ClientCommand::SelfAddressed(packet)).await?;
                     }
                     "Updated tag status".to_string()
@@ -287,48 +445,229 @@ impl Coordinator {
                 PlayerCommand::SendShine { id } => {
                     let shine_packet = PacketData::Shine {
                         shine_id: id,
-                        is_grand: false,
+                        is_grand: self.lobby.shines.read().await.is_grand(id),
                     };
-                    let packet = Packet::new(Guid::default(), shine_packet);
+                    let packet = Packet::new(Guid::NIL, shine_packet);
                     self.send_players(&players, &ClientCommand::SelfAddressed(packet))
                         .await?;
                     "Sent player shine".to_string()
                 }
             },
             ExternalCommand::Shine { command } => match command {
-                ShineCommand::Sync => {
-                    self.sync_all_shines().await?;
-                    format!("Synced shine bags")
-                }
+                ShineCommand::Sync => unreachable!(),
                 ShineCommand::Clear => {
-                    self.lobby.shines.write().await.clear();
-                    let players = &self.lobby.players;
-                    for mut player in players.iter_mut() {
-                        player.value_mut().shine_sync.clear();
-                    }
+                    self.to_shine_sync.send(ShineRequest::Clear { players: true }).await?;
                     format!("Shines cleared")
                 }
             },
+            ExternalCommand::Recording { command } => match command {
+                RecordingCommand::Start { path } => {
+                    if let Some(recorder) = &self.recorder {
+                        return Err(SMOError::InvalidConsoleArg(format!(
+                            "Already recording to {}",
+                            recorder.path().display()
+                        )));
+                    }
+                    let path = match path {
+                        Some(path) => path,
+                        None => {
+                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                            let directory = self.lobby.settings.read().await.recording.directory.clone();
+                            directory.join(format!("recording-{}.smor", timestamp))
+                        }
+                    };
+                    let recorder = Recorder::create(path).await?;
+                    let reply = format!("Recording to {}", recorder.path().display());
+                    self.recorder = Some(recorder);
+                    reply
+                }
+                RecordingCommand::Stop => match self.recorder.take() {
+                    Some(recorder) => {
+                        let path = recorder.path().display().to_string();
+                        let packets = recorder.finish().await?;
+                        format!("Recorded {} packets to {}", packets, path)
+                    }
+                    None => return Err(SMOError::InvalidConsoleArg("Not recording".to_string())),
+                },
+            },
+            ExternalCommand::Npc { command } => match command {
+                NpcCommand::Spawn { npc } => {
+                    let guid = Guid::new_random();
+                    let max_player = self.lobby.settings.read().await.server.max_players;
+                    for packet in npc.create_sync_packets(guid, max_player) {
+                        self.broadcast(&ClientCommand::Packet(packet))?;
+                    }
+                    let reply = format!("Spawned NPC {} ({})", npc.name, guid);
+                    self.lobby.npcs.insert(guid, npc);
+                    reply
+                }
+                NpcCommand::Move { npcs, path, speed, loop_path } => {
+                    for guid in &npcs {
+                        let mut npc = self.lobby.npcs.get_mut(guid).ok_or(SMOError::InvalidID(*guid))?;
+                        npc.path = path.iter().copied().collect();
+                        npc.speed = speed;
+                        npc.loop_path = loop_path;
+                    }
+                    format!("Moving {} NPCs", npcs.len())
+                }
+                NpcCommand::Despawn { npcs } => {
+                    let mut names = Vec::new();
+                    for guid in npcs {
+                        if let Some((guid, npc)) = self.lobby.npcs.remove(&guid) {
+                            self.broadcast(&ClientCommand::Packet(Packet::new(guid, PacketData::Disconnect)))?;
+                            names.push(npc.name);
+                        }
+                    }
+                    format!("Despawned NPCs: {}", names.join(", "))
+                }
+            },
         };
         Ok(out_str)
     }
 
     async fn merge_scenario(&self, packet: &Packet) -> Result<()> {
         tracing::debug!("Merging scenario");
-        self.cli_broadcast
-            .send(ClientCommand::SelfAddressed(packet.clone()))?;
+        let (stage, scenario) = match &packet.data {
+            PacketData::Game { stage, scenario_num, .. } => (stage, *scenario_num),
+            _ => unreachable!(),
+        };
+
+        let kingdom = Stages::stage2alias(stage);
+        let settings = self.lobby.settings.read().await;
+        let threshold = kingdom.as_ref().and_then(|k| settings.scenario.merge_thresholds.get(k)).copied();
+        let overrides = &settings.scenario.protection_overrides;
+        let above_threshold = threshold.is_some_and(|t| scenario >= t);
+        if !above_threshold && overrides.is_empty() {
+            drop(settings);
+            self.cli_broadcast.send(ClientCommand::SelfAddressed(packet.clone()));
+            return Ok(());
+        }
+
+        // don't push players into scenarios they haven't reached on their save yet
+        let channels: Vec<_> = self
+            .lobby
+            .players
+            .iter()
+            .filter(|player| {
+                let protected = match overrides.get(player.key()) {
+                    Some(protected) => *protected,
+                    None => threshold.is_some_and(|t| {
+                        let reached = player
+                            .progress
+                            .iter()
+                            .any(|(own_stage, reached)| *reached >= t && Stages::stage2alias(own_stage) == kingdom);
+                        scenario >= t && !reached
+                    }),
+                };
+                if protected {
+                    tracing::debug!("Not merging scenario {} of {} into {}", scenario, stage, player.name);
+                }
+                !protected
+            })
+            .map(|player| player.channel.clone())
+            .collect();
+        drop(settings);
+
+        for channel in channels {
+            channel.send(ClientCommand::SelfAddressed(packet.clone())).await?;
+        }
         Ok(())
     }
 
-    async fn persist_shines(&self) {
-        let settings = self.lobby.settings.read().await;
-        if settings.persist_shines.enabled {
-            let filename = settings.persist_shines.filename.clone();
-            let shines = self.lobby.shines.clone();
+    /// Score the round, announce the winner and start the next round if configured
+    async fn end_tag_round(&self) {
+        let players: Vec<_> = self
+            .lobby
+            .players
+            .iter()
+            .map(|p| (*p.key(), p.name.clone(), p.time.unwrap_or_default()))
+            .collect();
+        let mut scores = self.lobby.tag_scores.write().await;
+        let winner = scores.finish_round(&players);
+        let round = scores.rounds;
+        let first_tagged = scores.tagged.first().copied();
+        drop(scores);
+        *self.lobby.tag_round.write().await = None;
+
+        let name = winner.and_then(|id| players.iter().find(|p| p.0 == id).map(|p| p.1.clone()));
+        match &name {
+            Some(name) => tracing::info!("All hiders got tagged, {} wins round {}", name, round),
+            None => tracing::info!("All hiders got tagged in round {}", round),
+        }
+        self.lobby.emit(ServerEvent::TagRoundEnd { round, winner, name });
+
+        let settings = self.lobby.settings.read().await.tag.clone();
+        let seeker = first_tagged.and_then(|id| players.iter().find(|p| p.0 == id).map(|p| p.1.clone()));
+        if let (true, Some(seeker)) = (settings.auto_restart, seeker) {
+            tracing::info!("Starting the next tag round in {}s with {} as seeker", settings.countdown, seeker);
+            let cli = Cli {
+                cmd: ConsoleCommand::Tag(TagCommand::Start {
+                    countdown: settings.countdown,
+                    seekers: vec![SinglePlayerSelect::Player(seeker)],
+                }),
+            };
+            let view = LobbyView::new(&self.lobby);
+            // the command waits on the coordinator
             tokio::spawn(async move {
-                let result = save_shines(filename, shines).await;
+                let result = Console::new(view).with_origin("tag".to_string()).process_command(cli).await;
                 if let Err(e) = result {
-                    tracing::error!("Error saving shines: {}", e);
+                    tracing::warn!("Failed to start the next tag round: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn race_progress(&self, id: Guid, stage: &str) {
+        let mut race = self.lobby.race.write().await;
+        let Some(race) = race.as_mut() else {
+            return;
+        };
+        let Some(name) = self.lobby.players.get(&id).map(|p| p.name.clone()) else {
+            return;
+        };
+
+        match race.enter(id, &name, stage) {
+            Some(RaceProgress::Checkpoint(reached)) => {
+                tracing::info!("{} reached checkpoint {} of race {}", name, reached, race.name);
+            }
+            Some(RaceProgress::Finished { place, time }) => {
+                tracing::info!("{} finished race {} as #{} in {}", name, race.name, place, race::format_time(time));
+                let best = self.lobby.stats.races.write().await.record(&race.name, id, &name, time);
+                if best {
+                    tracing::info!("New best time of {} in race {}", name, race.name);
+                }
+                self.lobby.emit(ServerEvent::RaceFinished {
+                    id,
+                    name,
+                    race: race.name.clone(),
+                    place,
+                    time_ms: time.as_millis() as u64,
+                });
+            }
+            None => {}
+        }
+    }
+
+    /// Pin the player to the locked scenario of its stage
+    async fn lock_scenario(&self, packet: &Packet, locked: i8) -> Result<()> {
+        let mut pinned = packet.clone();
+        if let PacketData::Game { scenario_num, stage, .. } = &mut pinned.data {
+            tracing::debug!("Locking {} of {} to scenario {}", stage, packet.id, locked);
+            *scenario_num = locked;
+        }
+        let channel = self.lobby.get_client(&packet.id)?.channel.clone();
+        channel.send(ClientCommand::SelfAddressed(pinned)).await?;
+        Ok(())
+    }
+
+    async fn save_history(&self) {
+        let settings = self.lobby.settings.read().await;
+        if settings.history.enabled {
+            let filename = settings.history.filename.clone();
+            let seen = self.lobby.seen.clone();
+            tokio::spawn(async move {
+                if let Err(e) = seen.save(&filename).await {
+                    tracing::error!("Error saving the player history to {}: {}", filename, e);
                 }
             });
         }
@@ -350,13 +689,14 @@ impl Coordinator {
     }
 
     async fn add_client(&mut self, cmd: ServerCommand) -> Result<()> {
-        let (cli, packet, data, comm) = match cmd {
+        let (cli, packet, mut data, comm, slot) = match cmd {
             ServerCommand::NewPlayer {
                 cli,
                 connect_packet,
                 data,
                 comm,
-            } => (cli, connect_packet, data, comm),
+                slot,
+            } => (cli, connect_packet, data, comm, slot),
             _ => unreachable!(),
         };
 
@@ -368,11 +708,17 @@ impl Coordinator {
             _ => unreachable!(),
         };
         let id = cli.guid;
+        if let Some((_, state)) = self.lobby.replicated.remove(&id) {
+            state.restore(&mut data);
+        }
 
+        self.lobby.seen.record(id, client_name, data.ipv4).await;
+        self.save_history().await;
         let mut names = self.lobby.names.0.write().await;
         names.insert(id, client_name.clone());
         self.lobby.players.insert(id, data);
         drop(names);
+        drop(slot);
 
         let name = cli.display_name.clone();
         tracing::info!("New client connected: {} ({})", &name, cli.guid);
@@ -384,9 +730,145 @@ impl Coordinator {
             self.disconnect_player(id).await?;
             return Err(e);
         }
+        self.lobby.emit(ServerEvent::PlayerJoin {
+            id,
+            name: self.lobby.get_client(&id)?.name.clone(),
+        });
         Ok(())
     }
 
+    /// Stage and scenario to send the player back to, if the player isn't wearing a costume allowed in the stage
+    async fn costume_violation(&self, guid: &Guid, stage: &str) -> Result<Option<(String, i8)>> {
+        let settings = self.lobby.settings.read().await;
+        let rules = &settings.costume_stages;
+        let rule = match rules.rules.get(stage) {
+            Some(rule) if rules.enabled => rule,
+            _ => return Ok(None),
+        };
+
+        let player = self.lobby.get_client(guid)?;
+        let costume = match &player.last_costume_packet {
+            Some(Packet { data: PacketData::Costume(costume), .. }) => costume.clone(),
+            _ => Costume::default(),
+        };
+        if rule.allows(&costume.body_name, &costume.cap_name) {
+            return Ok(None);
+        }
+
+        Ok(Some(return_target(&player, stage, &rules.fallback_stage)))
+    }
+
+    /// Where to send the player back to, if the stage is still locked
+    async fn progression_violation(&self, guid: &Guid, stage: &str) -> Result<Option<(String, i8)>> {
+        let settings = self.lobby.settings.read().await;
+        let progression = &settings.progression;
+        if !progression.enabled || !progression.gates.contains_key(stage) || self.unlocked_stages.contains(stage) {
+            return Ok(None);
+        }
+
+        let player = self.lobby.get_client(guid)?;
+        Ok(Some(return_target(&player, stage, &progression.fallback_stage)))
+    }
+
+    /// Where to send the player back to, if the stage already holds its maximum amount of players
+    async fn capacity_violation(&self, guid: &Guid, stage: &str) -> Result<Option<(String, i8)>> {
+        let settings = self.lobby.settings.read().await;
+        let capacity = &settings.stage_capacity;
+        let max_players = match capacity.stages.get(stage) {
+            Some(max_players) if capacity.enabled => *max_players,
+            _ => return Ok(None),
+        };
+
+        let others = self
+            .lobby
+            .players
+            .iter()
+            .filter(|p| p.key() != guid)
+            .filter(|p| matches!(&p.last_game_packet, Some(Packet { data: PacketData::Game { stage: s, .. }, .. }) if s == stage))
+            .count();
+        if others < max_players {
+            return Ok(None);
+        }
+
+        let player = self.lobby.get_client(guid)?;
+        self.lobby.emit(ServerEvent::StageFull {
+            id: *guid,
+            name: player.name.clone(),
+            stage: stage.to_string(),
+        });
+        let target = return_target(&player, stage, &capacity.fallback_stage);
+        let channel = player.channel.clone();
+        drop(player);
+        let max_player = settings.server.max_players;
+        drop(settings);
+        message::send(&channel, &format!("{} is full", stage), max_player).await?;
+        Ok(Some(target))
+    }
+
+    /// Unlock the gated stages whose prerequisite every connected player reached
+    async fn update_progression(&mut self) {
+        let settings = self.lobby.settings.read().await;
+        let progression = &settings.progression;
+        if !progression.enabled || self.lobby.players.is_empty() {
+            return;
+        }
+
+        for (stage, gate) in &progression.gates {
+            if self.unlocked_stages.contains(stage) {
+                continue;
+            }
+            let is_ready = self.lobby.players.iter().all(|p| {
+                p.progress
+                    .get(&gate.stage)
+                    .is_some_and(|scenario| *scenario >= gate.scenario)
+            });
+            if is_ready {
+                tracing::info!("All players reached {} {}, unlocking {}", gate.stage, gate.scenario, stage);
+                self.unlocked_stages.insert(stage.clone());
+                self.lobby.emit(ServerEvent::StageUnlocked { stage: stage.clone() });
+            }
+        }
+    }
+
+    /// Add the packet to the running recording, stopping it if the file can't be written
+    async fn record(&mut self, packet: &Packet) {
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let settings = self.lobby.settings.read().await;
+        if let Err(e) = recorder.record(packet, &settings.recording.sampling).await {
+            tracing::error!("Stopped recording to {}: {}", recorder.path().display(), e);
+            self.recorder = None;
+        }
+    }
+
+    /// Send the player to another stage in 500ms
+    /// Forget the stage the player gets sent back from, for them to not count as being in it
+    fn roll_back_stage(&mut self, guid: &Guid) -> Result<()> {
+        let mut player = self.lobby.get_mut_client(guid)?;
+        player.last_game_packet = player.previous_game_packet.clone();
+        Ok(())
+    }
+
+    fn send_back(&self, guid: Guid, stage: String, scenario: i8) {
+        let to_coord = self.lobby.to_coord.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let (sender, recv) = oneshot::channel();
+            let _ = to_coord
+                .send(Command::External(
+                    ExternalCommand::Player {
+                        players: Players::Individual(vec![guid]),
+                        command: PlayerCommand::Send { stage, id: "".to_string(), scenario },
+                    },
+                    sender,
+                ))
+                .await;
+            recv.await
+        });
+    }
+
     async fn setup_player(&mut self, comm: ClientChannel, packet: Packet) -> Result<()> {
         tracing::debug!(
             "Setting up player ({}) with {} other players",
@@ -400,40 +882,28 @@ impl Coordinator {
 
         // Sync other players to the new player
         for other_ref in self.lobby.players.iter() {
-            let other_id  = other_ref.key();
-            let other_cli = other_ref.value();
-
-            let connect_packet = Packet::new(
-                *other_id,
-                PacketData::Connect {
-                    c_type: ConnectionType::FirstConnection,
-                    max_player,
-                    client_name: other_cli.name.clone(),
-                },
-            );
-
-            let packets = [
-                Some(connect_packet),
-                other_cli.last_costume_packet.clone(),
-                other_cli.last_capture_packet.clone(),
-                other_cli.create_tag_packet(*other_id),
-                other_cli.last_game_packet.clone(),
-                other_cli.last_player_packet.clone(),
-            ];
-
-            for packet in packets {
-                if let Some(p) = packet {
-                    comm.send(ClientCommand::Packet(p)).await?;
-                }
+            let packets = other_ref.value().create_sync_packets(*other_ref.key(), max_player);
+            for p in packets {
+                comm.send(ClientCommand::Packet(p)).await?;
             }
         }
+        let npc_packets: Vec<_> = self
+            .lobby
+            .npcs
+            .iter()
+            .flat_map(|npc| npc.create_sync_packets(*npc.key(), max_player))
+            .collect();
+        for p in npc_packets {
+            comm.send(ClientCommand::Packet(p)).await?;
+        }
 
         let client_id = packet.id;
-        let conn_type = match packet.data {
+        let (conn_type, name) = match &packet.data {
             PacketData::Connect {
                 c_type,
+                client_name,
                 ..
-            } => c_type,
+            } => (*c_type, client_name.clone()),
             _ => unreachable!(),
         };
 
@@ -460,11 +930,48 @@ impl Coordinator {
                     model: "".to_string(),
                 },
             )))?;
+
+            self.welcome(&comm, client_id, &name).await?;
         }
 
         Ok(())
     }
 
+    async fn welcome(&self, comm: &ClientChannel, id: Guid, name: &str) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let welcome = settings.welcome.clone();
+        let max_player = settings.server.max_players;
+        drop(settings);
+        if !welcome.message.is_empty() {
+            let message = welcome.message.replace("{name}", name);
+            message::send(comm, &message, max_player).await?;
+            self.lobby.emit(ServerEvent::Welcome { id, name: name.to_string(), message });
+        }
+
+        if let Some(is_seeking) = welcome.is_seeking {
+            let data = PacketData::Tag {
+                game_mode: GameMode::Legacy,
+                update_type: TagUpdate::State,
+                is_it: is_seeking,
+                minutes: 0,
+                seconds: 0,
+            };
+            comm.send(ClientCommand::SelfAddressed(Packet::new(Guid::NIL, data))).await?;
+        }
+
+        if let Some(warp) = welcome.warp {
+            tracing::info!("Sending new player {} to {}", name, warp.stage);
+            let data = PacketData::ChangeStage {
+                stage: warp.stage,
+                id: warp.id,
+                scenario: warp.scenario,
+                sub_scenario: 0,
+            };
+            comm.send(ClientCommand::SelfAddressed(Packet::new(Guid::NIL, data))).await?;
+        }
+        Ok(())
+    }
+
     async fn disconnect_player(&mut self, guid: Guid) -> Result<()> {
         tracing::info!("Disconnecting player {}", guid);
         // TODO: do not remove the player, but mark it as disconnected, so that
@@ -472,46 +979,28 @@ impl Coordinator {
         if let Some((guid, data)) = self.lobby.players.remove(&guid) {
             // let name = &data.read().await.name;
             self.lobby.names.0.write().await.remove_by_left(&guid);
+            self.lobby.emit(ServerEvent::PlayerLeave {
+                id: guid,
+                name: data.name.clone(),
+            });
             let packet = Packet::new(guid, PacketData::Disconnect);
             self.broadcast(&ClientCommand::Packet(packet.clone()))?;
             let disconnect = ClientCommand::Packet(packet);
             data.channel.send(disconnect).await?;
         }
 
-        Ok(())
-    }
-
-    async fn sync_all_shines(&mut self) -> Result<()> {
-        let settings = self.lobby.settings.read().await;
-        if !settings.shines.enabled {
-            return Ok(());
-        }
-
-        let excluded_shines = &settings.shines.excluded;
-
-        for player_ref in self.lobby.players.iter() {
-            let player = player_ref.value();
-            let player_shines = player.shine_sync.union(&excluded_shines).copied().collect();
-            let server_shines = self.lobby.shines.clone();
-            let sender_guid = Guid::default();
-
-            if player.disable_shine_sync {
-                continue;
-            }
-
-            client_sync_shines(
-                player.channel.clone(),
-                server_shines,
-                &sender_guid,
-                &player_shines,
-            )
-            .await?;
+        // the remaining players might all be ready now
+        self.update_progression().await;
+        // or all tagged
+        if self.lobby.tag_scores.write().await.leave(guid) {
+            self.end_tag_round().await;
         }
         Ok(())
     }
 
     fn broadcast(&self, cmd: &ClientCommand) -> Result<()> {
-        self.cli_broadcast.send(cmd.clone())?;
+        self.cli_broadcast.send(cmd.clone());
+        self.lobby.stats.count_broadcast();
         Ok(())
     }
 
@@ -520,45 +1009,124 @@ impl Coordinator {
         for guid in guids {
             let _ = self.disconnect_player(guid).await;
         }
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.finish().await {
+                tracing::warn!("Failed to finish the recording: {}", e);
+            }
+        }
     }
 }
 
-async fn client_sync_shines(
-    to_client: ClientChannel,
-    shine_bag: SyncShineBag,
-    guid: &Guid,
-    client_shines: &ShineBag,
-) -> Result<()> {
-    // let client = player.read().await;
-    let server_shines = shine_bag.read().await;
-    let mismatch = server_shines.difference(&client_shines);
-
-    for shine_id in mismatch {
-        to_client
-            .send(ClientCommand::SelfAddressed(Packet::new(
-                *guid,
-                PacketData::Shine {
-                    shine_id: *shine_id,
-                    is_grand: false,
-                },
-            )))
-            .await?;
+/// Write the shines to a temporary file first and swap it in, so a crash can't leave a truncated file behind
+pub async fn save_shines(settings: &PersistShine, shines: ShineBag, excluded: BTreeSet<i32>) -> Result<()> {
+    let _guard = SAVE_LOCK.lock().await;
+    let persisted = PersistedShines {
+        version: SHINE_FORMAT_VERSION,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        excluded,
+        shines,
+    };
+    let json_str = serde_json::to_string(&persisted)?;
+
+    let filename = &settings.filename;
+    let temp = format!("{}.tmp", filename);
+    let mut file = File::create(&temp).await?;
+    file.write_all(json_str.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if settings.backups > 0 && tokio::fs::metadata(filename).await.is_ok() {
+        for i in (1..settings.backups).rev() {
+            match tokio::fs::rename(format!("{}.{}", filename, i), format!("{}.{}", filename, i + 1)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        tokio::fs::copy(filename, format!("{}.1", filename)).await?;
     }
+    tokio::fs::rename(&temp, filename).await?;
+
     Ok(())
 }
 
-async fn save_shines(filename: String, shines: SyncShineBag) -> Result<()> {
-    let shines = shines.read().await;
-    let json_str = serde_json::to_string(&shines.clone())?;
-    let mut file = File::open(filename).await?;
-    file.write_all(json_str.as_bytes()).await?;
+/// Save changed shines periodically and once more when the server shuts down
+pub async fn autosave_shines(mut lobby: Lobby) -> Result<()> {
+    let settings = lobby.settings.read().await.persist_shines.clone();
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mut ticks = match settings.autosave_interval {
+        0 => None,
+        seconds => Some(interval(Duration::from_secs(seconds))),
+    };
+    let mut saved = lobby.shines.read().await.clone();
+    loop {
+        let shutdown = select! {
+            _ = async { ticks.as_mut().unwrap().tick().await }, if ticks.is_some() => false,
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => true,
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        };
 
+        let shines = lobby.shines.read().await.clone();
+        if shines != saved {
+            let settings = lobby.settings.read().await;
+            let (persist, excluded) = (settings.persist_shines.clone(), settings.shines.excluded.clone());
+            drop(settings);
+            match save_shines(&persist, shines.clone(), excluded).await {
+                Ok(()) => saved = shines,
+                Err(e) => tracing::error!("Error saving shines: {}", e),
+            }
+        }
+        if shutdown {
+            break;
+        }
+    }
     Ok(())
 }
 
-pub fn load_shines(filename: &str) -> Result<ShineBag> {
+/// The persisted shines, with the shines that were excluded at the time of saving
+pub fn load_shines(filename: &str) -> Result<PersistedShines> {
     let file = std::fs::File::open(filename)?;
-    let shines = serde_json::from_reader(file)?;
+    let persisted = match serde_json::from_reader(file)? {
+        PersistedShinesFormat::Versioned(persisted) => {
+            if persisted.version > SHINE_FORMAT_VERSION {
+                tracing::warn!(
+                    "{} was written by a newer server (format version {}), some data may be ignored",
+                    filename,
+                    persisted.version
+                );
+            }
+            persisted
+        }
+        PersistedShinesFormat::Legacy(shines) => {
+            tracing::info!(
+                "Migrating {} from the unversioned format, it will be rewritten on the next save",
+                filename
+            );
+            PersistedShines {
+                version: 0,
+                timestamp: 0,
+                excluded: Default::default(),
+                shines: shines.into(),
+            }
+        }
+    };
+
+    Ok(persisted)
+}
 
-    Ok(shines)
+/// The previous stage of the player, or the fallback stage if it's unknown
+fn return_target(player: &PlayerData, stage: &str, fallback_stage: &str) -> (String, i8) {
+    match &player.previous_game_packet {
+        Some(Packet { data: PacketData::Game { stage: previous, scenario_num, .. }, .. }) if previous != stage => {
+            (previous.clone(), *scenario_num)
+        }
+        _ => (fallback_stage.to_string(), -1),
+    }
 }
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{cmds::ServerWideCommand, events::ServerEvent, lobby::Lobby, race, shines::Shines, types::Result};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct WebhookMessage {
+    content: String,
+}
+
+/// Posts join/leave, moon and ban notifications to a Discord webhook, no bot token needed
+pub struct DiscordNotifier {
+    lobby: Lobby,
+    events: broadcast::Receiver<ServerEvent>,
+}
+
+impl DiscordNotifier {
+    pub fn new(lobby: Lobby) -> Self {
+        Self {
+            events: lobby.events.subscribe(),
+            lobby,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        if self.lobby.settings.read().await.discord.webhook_url.is_none() {
+            return Ok(());
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(anyhow::Error::from)?;
+
+        loop {
+            let event = select! {
+                event = self.events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(amount)) => {
+                        tracing::warn!("Discord notifier skipped {} events", amount);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            let settings = self.lobby.settings.read().await.discord.clone();
+            let url = match settings.webhook_url {
+                Some(url) => url,
+                None => continue,
+            };
+            if !settings.webhook_events.is_empty() && !settings.webhook_events.contains(event.name()) {
+                continue;
+            }
+            let content = match format_event(&event) {
+                Some(content) => content,
+                None => continue,
+            };
+
+            let client = client.clone();
+            tokio::spawn(async move {
+                let result = client
+                    .post(&url)
+                    .json(&WebhookMessage { content })
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    tracing::warn!("Discord notification for {} failed: {}", event.name(), e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn format_event(event: &ServerEvent) -> Option<String> {
+    let content = match event {
+        ServerEvent::PlayerJoin { name, .. } => format!("**{}** joined the server", name),
+        ServerEvent::PlayerLeave { name, .. } => format!("**{}** left the server", name),
+        ServerEvent::MoonCollected { name, shine_id, .. } => format!("**{}** collected moon {}", name, Shines::describe(*shine_id)),
+        ServerEvent::StageBanned { name, stage, .. } => format!("**{}** got kicked out of banned stage {}", name, stage),
+        ServerEvent::StageFull { name, stage, .. } => format!("**{}** got sent back from full stage {}", name, stage),
+        ServerEvent::StageUnlocked { stage } => format!("Everyone is ready, {} is unlocked now", stage),
+        ServerEvent::BannedClient { name: Some(name), .. } => format!("Banned player **{}** tried to join", name),
+        ServerEvent::BannedClient { name: None, .. } => "A banned ip address tried to join".to_string(),
+        ServerEvent::Emote { name, trigger, .. } => format!("**{}** triggered {}", name, trigger),
+        ServerEvent::Welcome { message, .. } => message.clone(),
+        ServerEvent::TagRoundEnd { round, name: Some(name), .. } => {
+            format!("Everyone got tagged, **{}** wins round {}", name, round)
+        }
+        ServerEvent::TagRoundEnd { round, name: None, .. } => format!("Everyone got tagged in round {}", round),
+        ServerEvent::RaceFinished { name, race, place, time_ms, .. } => format!(
+            "**{}** finished {} as #{} in {}",
+            name,
+            race,
+            place,
+            race::format_time(Duration::from_millis(*time_ms))
+        ),
+        ServerEvent::Teleport { name, stage, speed, .. } => {
+            format!("**{}** is suspected of teleporting in {} at {:.0} units/s", name, stage, speed)
+        }
+        ServerEvent::ServerStart | ServerEvent::ServerStop => return None,
+    };
+    Some(content)
+}
@@ -0,0 +1,80 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::broadcast::error::RecvError,
+    time::{interval, Duration},
+};
+
+use crate::{cmds::ServerWideCommand, lobby::Lobby, types::Result};
+
+/// Prefix of every beacon datagram, followed by the JSON encoded beacon
+pub const BEACON_MAGIC: &[u8] = b"SMOO";
+
+/// Announcement of a running server on the local network
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Beacon {
+    pub name: String,
+    pub port: u16,
+    pub players: usize,
+    pub max_players: u16,
+}
+
+impl Beacon {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buff = BEACON_MAGIC.to_vec();
+        serde_json::to_writer(&mut buff, self)?;
+        Ok(buff)
+    }
+
+    /// Returns `None` for datagrams that are not beacons
+    #[allow(dead_code)] // used by the tools, not the server binary
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let json = data.strip_prefix(BEACON_MAGIC)?;
+        serde_json::from_slice(json).ok()
+    }
+}
+
+/// Periodically broadcast a beacon until the server shuts down
+pub async fn announce(mut lobby: Lobby) -> Result<()> {
+    let settings = lobby.settings.read().await;
+    let discovery = settings.discovery.clone();
+    drop(settings);
+
+    if !discovery.enabled {
+        return Ok(());
+    }
+
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.set_broadcast(true)?;
+    let target = SocketAddr::from((Ipv4Addr::BROADCAST, discovery.port));
+    tracing::info!("Announcing server on udp port {}", discovery.port);
+
+    let mut timer = interval(Duration::from_secs(discovery.interval.max(1)));
+    loop {
+        select! {
+            _ = timer.tick() => {},
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        }
+
+        let settings = lobby.settings.read().await;
+        let beacon = Beacon {
+            name: settings.discovery.name.clone(),
+            port: settings.server.port,
+            players: lobby.players.len(),
+            max_players: settings.server.max_players,
+        };
+        drop(settings);
+
+        if let Err(e) = socket.send_to(&beacon.encode()?, target).await {
+            tracing::debug!("Failed to send discovery beacon: {}", e);
+        }
+    }
+    Ok(())
+}
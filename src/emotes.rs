@@ -0,0 +1,76 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+
+use crate::{
+    console::{Cli, Console},
+    events::ServerEvent,
+    guid::Guid,
+    lobby::{Lobby, LobbyView},
+    settings::EmoteTrigger,
+};
+
+/// Recently started animations of a player, to detect repeated emotes
+#[derive(Clone, Debug, Default)]
+pub struct EmoteTracker {
+    last_act: Option<(u16, u16)>,
+    /// When the animation of a trigger was started within its window
+    performed: BTreeMap<String, VecDeque<Instant>>,
+}
+
+impl EmoteTracker {
+    /// Track the animation of a player packet, returns the triggers that fired
+    pub fn update(&mut self, act: u16, sub_act: u16, triggers: &[EmoteTrigger]) -> Vec<EmoteTrigger> {
+        // only count the start of an animation, not every packet while it plays
+        if self.last_act == Some((act, sub_act)) {
+            return Vec::new();
+        }
+        self.last_act = Some((act, sub_act));
+
+        let now = Instant::now();
+        let mut fired = Vec::new();
+        for trigger in triggers.iter().filter(|t| t.matches(act, sub_act)) {
+            let window = Duration::from_millis(trigger.window_ms);
+            let performed = self.performed.entry(trigger.name.clone()).or_default();
+            while performed.front().is_some_and(|start| now.duration_since(*start) > window) {
+                performed.pop_front();
+            }
+            performed.push_back(now);
+            if performed.len() >= trigger.count {
+                performed.clear();
+                fired.push(trigger.clone());
+            }
+        }
+        fired
+    }
+}
+
+/// Announce the emote and run the console command of the trigger
+pub fn fire(lobby: &Lobby, id: Guid, name: &str, trigger: EmoteTrigger) {
+    tracing::info!("{} triggered emote {}", name, trigger.name);
+    lobby.emit(ServerEvent::Emote {
+        id,
+        name: name.to_string(),
+        trigger: trigger.name.clone(),
+    });
+
+    let command = match trigger.command {
+        Some(command) => command.replace("{name}", name),
+        None => return,
+    };
+    // the command might have to wait on the client that triggered it
+    let view = LobbyView::new(lobby);
+    tokio::spawn(async move {
+        let result = match Cli::try_parse_from(format!("> {}", command.trim()).split(' ')) {
+            Ok(cli) => Console::new(view).process_command(cli).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(reply) => tracing::debug!("Emote {}: {}", trigger.name, reply),
+            Err(e) => tracing::warn!("Command of emote {} failed: {}", trigger.name, e),
+        }
+    });
+}
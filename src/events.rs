@@ -0,0 +1,67 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::guid::Guid;
+
+/// Notable things happening on the server, for notifiers like webhooks
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "Event", rename_all = "PascalCase")]
+pub enum ServerEvent {
+    ServerStart,
+    ServerStop,
+    #[serde(rename_all = "PascalCase")]
+    PlayerJoin { id: Guid, name: String },
+    #[serde(rename_all = "PascalCase")]
+    PlayerLeave { id: Guid, name: String },
+    #[serde(rename_all = "PascalCase")]
+    MoonCollected { id: Guid, name: String, shine_id: i32 },
+    #[serde(rename_all = "PascalCase")]
+    StageBanned { id: Guid, name: String, stage: String },
+    /// A player got sent back, because the stage already holds its maximum amount of players
+    #[serde(rename_all = "PascalCase")]
+    StageFull { id: Guid, name: String, stage: String },
+    /// A locked stage got unlocked, because every player reached its prerequisite
+    #[serde(rename_all = "PascalCase")]
+    StageUnlocked { stage: String },
+    /// A banned ip address or profile tried to connect
+    #[serde(rename_all = "PascalCase")]
+    BannedClient { ip: IpAddr, name: Option<String> },
+    /// A player performed the animation of an emote trigger
+    #[serde(rename_all = "PascalCase")]
+    Emote { id: Guid, name: String, trigger: String },
+    /// Greeting of a player that joined, from `Welcome.Message`
+    #[serde(rename_all = "PascalCase")]
+    Welcome { id: Guid, name: String, message: String },
+    /// A player moved impossibly fast too often, see `TeleportDetection`
+    #[serde(rename_all = "PascalCase")]
+    Teleport { id: Guid, name: String, stage: String, speed: f32 },
+    /// All hiders of a `tag start` round got tagged, the last one wins
+    #[serde(rename_all = "PascalCase")]
+    TagRoundEnd { round: u32, winner: Option<Guid>, name: Option<String> },
+    /// A player reached the finish of the race
+    #[serde(rename_all = "PascalCase")]
+    RaceFinished { id: Guid, name: String, race: String, place: usize, time_ms: u64 },
+}
+
+impl ServerEvent {
+    /// Name used to configure notifications for this event
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ServerStart => "ServerStart",
+            Self::ServerStop => "ServerStop",
+            Self::PlayerJoin { .. } => "PlayerJoin",
+            Self::PlayerLeave { .. } => "PlayerLeave",
+            Self::MoonCollected { .. } => "MoonCollected",
+            Self::StageBanned { .. } => "StageBanned",
+            Self::StageFull { .. } => "StageFull",
+            Self::StageUnlocked { .. } => "StageUnlocked",
+            Self::BannedClient { .. } => "BannedClient",
+            Self::Emote { .. } => "Emote",
+            Self::Welcome { .. } => "Welcome",
+            Self::Teleport { .. } => "Teleport",
+            Self::RaceFinished { .. } => "RaceFinished",
+            Self::TagRoundEnd { .. } => "TagRoundEnd",
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::broadcast::error::RecvError,
+    time::{interval, sleep, timeout, Instant},
+};
+
+use crate::{
+    client::PlayerData,
+    cmds::{ClientCommand, ServerWideCommand},
+    coordinator::ShineBag,
+    guid::Guid,
+    lobby::Lobby,
+    net::{auth, connection::Connection, Packet, PacketData},
+    relay::Relay,
+    settings::FailoverRole,
+    types::Result,
+};
+
+/// Packet type of the heartbeats the primary sends to its standby
+const HEARTBEAT_TAG: u16 = 0x4246;
+/// Packet type of the handshake packets of a replication connection
+const HELLO_TAG: u16 = 0x4648;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Last known state of a player on the primary server or in a loaded session snapshot
+#[derive(Debug, Default)]
+pub struct ReplicatedPlayer {
+    pub costume: Option<Packet>,
+    pub capture: Option<Packet>,
+    pub game: Option<Packet>,
+    pub player: Option<Packet>,
+    pub scenario: Option<i8>,
+    pub is_seeking: Option<bool>,
+    pub time: Option<Duration>,
+}
+
+impl ReplicatedPlayer {
+    /// Fill in the packets a reconnecting player hasn't sent to this server yet
+    pub fn restore(self, data: &mut PlayerData) {
+        data.last_costume_packet = data.last_costume_packet.take().or(self.costume);
+        data.last_capture_packet = data.last_capture_packet.take().or(self.capture);
+        data.last_game_packet = data.last_game_packet.take().or(self.game);
+        data.last_player_packet = data.last_player_packet.take().or(self.player);
+        data.scenario = self.scenario.unwrap_or(data.scenario);
+        data.is_seeking = data.is_seeking.or(self.is_seeking);
+        data.time = data.time.or(self.time);
+    }
+}
+
+/// Replicates the state of a primary server to a standby server, which takes over once the primary is gone
+pub struct Failover {
+    lobby: Lobby,
+    cli_broadcast: Relay,
+}
+
+impl Failover {
+    pub fn new(lobby: Lobby, cli_broadcast: Relay) -> Self {
+        Self {
+            lobby,
+            cli_broadcast,
+        }
+    }
+
+    /// Accept standby servers on the replication port, if this is a primary server
+    pub async fn run_primary(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let failover = settings.failover.clone();
+        let addr = SocketAddr::new(settings.server.address.primary(), failover.port);
+        drop(settings);
+
+        if !failover.enabled || failover.role != FailoverRole::Primary {
+            return Ok(());
+        }
+        if failover.secret.is_empty() {
+            tracing::warn!("No failover secret set, any server can replicate the state of this one");
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Accepting standby servers on {}", listener.local_addr()?);
+
+        loop {
+            let (socket, addr) = select! {
+                conn = listener.accept() => conn?,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            tracing::info!("Standby server connected from {}", addr);
+            let replication = Failover::new(self.lobby.clone(), self.cli_broadcast.clone());
+            tokio::spawn(async move {
+                if let Err(e) = replication.replicate(socket).await {
+                    tracing::warn!("Replication to standby {} stopped: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn replicate(mut self, socket: TcpStream) -> Result<()> {
+        socket.set_nodelay(true)?;
+        let mut conn = Connection::new(socket);
+        let secret = self.lobby.settings.read().await.failover.secret.clone();
+        auth::authenticate(&mut conn, HELLO_TAG, &secret).await?;
+        let mut cli_recv = self.cli_broadcast.subscribe();
+        let mut sent_shines = ShineBag::default();
+
+        let packets = self.lobby.sync_packets().await;
+        for packet in packets {
+            conn.write_packet(&packet).await?;
+        }
+
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+        loop {
+            select! {
+                _ = heartbeat.tick() => {
+                    let shines = self.lobby.shines.read().await.clone();
+                    for &shine_id in shines.difference(&sent_shines) {
+                        let shine = PacketData::Shine { shine_id, is_grand: shines.is_grand(shine_id) };
+                        conn.write_packet(&Packet::new(Guid::NIL, shine)).await?;
+                    }
+                    sent_shines = shines;
+
+                    let beat = PacketData::Unhandled { tag: HEARTBEAT_TAG, data: vec![] };
+                    conn.write_packet(&Packet::new(Guid::NIL, beat)).await?;
+                },
+                cmd = cli_recv.recv() => match cmd {
+                    Ok(ClientCommand::Packet(packet)) if is_replicated(&packet.data) && !self.lobby.is_hidden(&packet).await => {
+                        conn.write_packet(&packet).await?;
+                    }
+                    Ok(_) => {},
+                    Err(RecvError::Lagged(amount)) => {
+                        tracing::warn!("Replication skipped {} packets", amount);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(()),
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+    }
+
+    /// Replicate the state of the primary until it is gone, returns `false` if the server shut down before that
+    ///
+    /// Returns `true` right away, if this isn't a standby server.
+    pub async fn wait_for_takeover(self) -> Result<bool> {
+        let settings = self.lobby.settings.read().await;
+        let failover = settings.failover.clone();
+        drop(settings);
+
+        if !failover.enabled || failover.role != FailoverRole::Standby {
+            return Ok(true);
+        }
+
+        let grace = Duration::from_secs(failover.timeout.max(1));
+        let mut deadline = Instant::now() + grace;
+        let mut server_recv = self.lobby.lobby_broadcast.subscribe();
+        tracing::info!("Standing by for primary server {}", failover.primary);
+
+        while Instant::now() < deadline {
+            let attempt = async {
+                if let Ok(Ok(socket)) = timeout(RECONNECT_DELAY, TcpStream::connect(&failover.primary)).await {
+                    tracing::info!("Replicating primary server {}", failover.primary);
+                    if let Err(e) = self.receive(socket, grace, &mut deadline).await {
+                        tracing::warn!("Lost primary server {}: {}", failover.primary, e);
+                    }
+                }
+                sleep(RECONNECT_DELAY).await;
+            };
+            select! {
+                _ = attempt => {},
+                cmd = server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(false),
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+
+        tracing::warn!(
+            "No contact to primary server for {}s, taking over with {} shines and {} players",
+            grace.as_secs(),
+            self.lobby.shines.read().await.len(),
+            self.lobby.replicated.len(),
+        );
+        Ok(true)
+    }
+
+    async fn receive(&self, socket: TcpStream, grace: Duration, deadline: &mut Instant) -> Result<()> {
+        let mut conn = Connection::new(socket);
+        let secret = self.lobby.settings.read().await.failover.secret.clone();
+        auth::authenticate(&mut conn, HELLO_TAG, &secret).await?;
+        let mut connected = HashSet::new();
+
+        while let Ok(packet) = timeout(grace, conn.read_packet()).await {
+            let packet = packet?;
+            *deadline = Instant::now() + grace;
+
+            match packet.data {
+                PacketData::Shine { shine_id, is_grand } => {
+                    self.lobby.shines.write().await.insert(shine_id, is_grand);
+                }
+                PacketData::Connect { .. } => {
+                    connected.insert(packet.id);
+                    self.lobby.replicated.entry(packet.id).or_default();
+                }
+                PacketData::Disconnect => {
+                    connected.remove(&packet.id);
+                    self.lobby.replicated.remove(&packet.id);
+                }
+                _ if !connected.contains(&packet.id) => {}
+                PacketData::Costume(_) => self.store(packet, |p| &mut p.costume),
+                PacketData::Capture { .. } => self.store(packet, |p| &mut p.capture),
+                PacketData::Game { .. } => self.store(packet, |p| &mut p.game),
+                PacketData::Player { .. } => self.store(packet, |p| &mut p.player),
+                _ => {}
+            }
+        }
+        tracing::warn!("Primary server stopped sending heartbeats");
+        Ok(())
+    }
+
+    fn store(&self, packet: Packet, slot: fn(&mut ReplicatedPlayer) -> &mut Option<Packet>) {
+        let mut player = self.lobby.replicated.entry(packet.id).or_default();
+        *slot(&mut player) = Some(packet);
+    }
+}
+
+fn is_replicated(data: &PacketData) -> bool {
+    matches!(
+        data,
+        PacketData::Connect { .. }
+            | PacketData::Disconnect
+            | PacketData::Costume(_)
+            | PacketData::Capture { .. }
+            | PacketData::Game { .. }
+            | PacketData::Player { .. }
+    )
+}
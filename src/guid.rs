@@ -1,6 +1,7 @@
 use std::{fmt::Display, str::FromStr};
 
 use hex::FromHex;
+use ring::rand::{SecureRandom, SystemRandom};
 
 use serde::{Deserialize, Serialize};
 
@@ -12,6 +13,46 @@ pub struct Guid {
     pub id: [u8; 16],
 }
 
+impl Guid {
+    /// Guid of packets that originate from the server itself
+    pub const NIL: Guid = Guid { id: [0; 16] };
+    /// Reserved, never handed out to a player
+    pub const MAX: Guid = Guid { id: [0xff; 16] };
+
+    /// Generate a fresh random (version 4) Guid, e.g. for players simulated by the server
+    pub fn new_random() -> Self {
+        let mut id = [0; 16];
+        SystemRandom::new().fill(&mut id).expect("Failed to generate a random Guid");
+        id[6] = (id[6] & 0x0f) | 0x40;
+        id[8] = (id[8] & 0x3f) | 0x80;
+
+        let guid = Guid { id };
+        if guid.is_valid() {
+            guid
+        } else {
+            Self::new_random()
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+
+    pub fn is_reserved(&self) -> bool {
+        self.is_nil() || *self == Self::MAX
+    }
+
+    /// Whether a player may use this Guid
+    pub fn is_valid(&self) -> bool {
+        !self.is_reserved()
+    }
+
+    /// Short form for log output, only the first 4 bytes
+    pub fn compact(&self) -> String {
+        hex::encode(&self.id[..4])
+    }
+}
+
 impl TryFrom<&str> for Guid {
     type Error = EncodingError;
 
@@ -1,38 +1,140 @@
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 
+use crate::settings::ApiBlockSettings;
+use crate::types::Result;
+
 lazy_static! {
-    static ref FAILURES: RwLock<HashMap<IpAddr, u8>> = RwLock::new(HashMap::new());
+    static ref FAILURES: RwLock<HashMap<IpAddr, FailedClient>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Default)]
+struct FailedClient {
+    failures: u8,
+    /// Seconds since the unix epoch when the client got blocked
+    blocked_at: Option<u64>,
 }
 
-const MAX_TRIES: u8 = 5;
+/// Blocked clients as written to the persist file, mapping to when they got blocked
+#[derive(Default, Serialize, Deserialize)]
+struct PersistedBlocks(BTreeMap<IpAddr, u64>);
 
 pub struct BlockClients {}
 
 impl BlockClients {
-    pub async fn is_blocked(addr: &SocketAddr) -> bool {
-        match FAILURES.read().await.get(&addr.ip()) {
-            Some(failures) => failures >= &MAX_TRIES,
-            None => false,
+    /// Restore the blocks of the last run
+    pub async fn load(settings: &ApiBlockSettings) -> Result<()> {
+        let filename = match &settings.persist_filename {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        let blocks: PersistedBlocks = match std::fs::File::open(filename) {
+            Ok(file) => serde_json::from_reader(file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut clients = FAILURES.write().await;
+        for (ip, blocked_at) in blocks.0 {
+            if !is_expired(settings, blocked_at) {
+                let client = FailedClient {
+                    failures: settings.max_failures,
+                    blocked_at: Some(blocked_at),
+                };
+                clients.insert(ip, client);
+            }
         }
+        Ok(())
     }
 
-    pub async fn fail(addr: &SocketAddr) {
+    pub async fn is_blocked(addr: &SocketAddr, settings: &ApiBlockSettings) -> bool {
         let ip = addr.ip();
-        let failures: u8 = *FAILURES
-            .write()
-            .await
-            .entry(ip)
-            .and_modify(|x| *x += 1)
-            .or_insert(1);
-        if failures >= MAX_TRIES {
+        if settings.whitelist.contains(&ip) {
+            return false;
+        }
+        let blocked_at = match FAILURES.read().await.get(&ip) {
+            Some(FailedClient { blocked_at: Some(blocked_at), .. }) => *blocked_at,
+            _ => return false,
+        };
+        if !is_expired(settings, blocked_at) {
+            return true;
+        }
+
+        tracing::info!("Block of client {} expired", ip);
+        FAILURES.write().await.remove(&ip);
+        false
+    }
+
+    pub async fn fail(addr: &SocketAddr, settings: &ApiBlockSettings) {
+        let ip = addr.ip();
+        if settings.whitelist.contains(&ip) {
+            return;
+        }
+        let mut clients = FAILURES.write().await;
+        let client = clients.entry(ip).or_default();
+        client.failures = client.failures.saturating_add(1);
+        if client.failures >= settings.max_failures && client.blocked_at.is_none() {
             tracing::warn!("Block client {} because of too many failed requests.", ip);
+            client.blocked_at = Some(now());
+            persist(&clients, settings).await;
         }
     }
 
     pub async fn redeem(addr: &SocketAddr) {
         FAILURES.write().await.remove(&addr.ip());
     }
+
+    /// Blocked ip addresses and when they got blocked
+    pub async fn list() -> Vec<(IpAddr, SystemTime)> {
+        let clients = FAILURES.read().await;
+        let mut blocked: Vec<_> = clients
+            .iter()
+            .filter_map(|(ip, client)| Some((*ip, UNIX_EPOCH + std::time::Duration::from_secs(client.blocked_at?))))
+            .collect();
+        blocked.sort();
+        blocked
+    }
+
+    /// Unblock one or all ip addresses, returns how many got unblocked
+    pub async fn clear(ip: Option<IpAddr>, settings: &ApiBlockSettings) -> usize {
+        let mut clients = FAILURES.write().await;
+        let cleared = match ip {
+            Some(ip) => clients.remove(&ip).into_iter().filter(|c| c.blocked_at.is_some()).count(),
+            None => clients.drain().filter(|(_, c)| c.blocked_at.is_some()).count(),
+        };
+        persist(&clients, settings).await;
+        cleared
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn is_expired(settings: &ApiBlockSettings, blocked_at: u64) -> bool {
+    settings.block_seconds != 0 && now().saturating_sub(blocked_at) >= settings.block_seconds
+}
+
+async fn persist(clients: &HashMap<IpAddr, FailedClient>, settings: &ApiBlockSettings) {
+    let filename = match &settings.persist_filename {
+        Some(filename) => filename,
+        None => return,
+    };
+    let blocks = PersistedBlocks(
+        clients
+            .iter()
+            .filter_map(|(ip, client)| Some((*ip, client.blocked_at?)))
+            .collect(),
+    );
+    let result = match serde_json::to_string(&blocks) {
+        Ok(json) => tokio::fs::write(filename, json).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        tracing::error!("Error saving the blocked api clients: {}", e);
+    }
 }
@@ -1,7 +1,9 @@
+use std::net::IpAddr;
+
 use clap::Parser;
 use serde::Serialize;
 
-use crate::console::{Cli, Console};
+use crate::console::{mask_token, redact_command, redact_tokens, Cli, Console};
 use crate::lobby::LobbyView;
 
 #[derive(Serialize)]
@@ -14,11 +16,12 @@ pub(in crate::json_api) struct JsonApiCommands {
 impl JsonApiCommands {
     pub async fn process(
         view: &LobbyView,
-        token: &String,
+        token: &str,
         data: &Option<String>,
+        address: IpAddr,
     ) -> JsonApiCommands {
         let settings = view.get_lobby().settings.read().await;
-        let permissions = &settings.json_api.tokens[token];
+        let permissions = &settings.json_api.permissions(token);
 
         // no permission in general
         if !permissions.contains("Commands") {
@@ -45,11 +48,16 @@ impl JsonApiCommands {
             ));
         }
 
-        let cmd: String = input.trim().split(' ').collect::<Vec<_>>()[0].to_string();
+        let args: Vec<&str> = input.split_whitespace().collect();
+        let cmd = args.first().copied().unwrap_or_default();
 
-        // no specific permissions
+        // no specific permissions, either for the whole command or only its subcommand (e.g. `Commands/ban/list`)
         let perm = format!("Commands/{}", cmd);
-        if !permissions.contains(&perm) {
+        let sub_perm = args.get(1).map(|sub| format!("{}/{}", perm, sub));
+        let is_permitted = permissions.contains("Commands/*")
+            || permissions.contains(&perm)
+            || sub_perm.as_ref().is_some_and(|p| permissions.contains(p));
+        if !is_permitted {
             return JsonApiCommands::result(
                 format!("Error: Missing {} permission.", perm).to_string(),
             );
@@ -58,13 +66,15 @@ impl JsonApiCommands {
         drop(settings);
 
         // execute command
-        tracing::info!("{}", input.trim());
-        let mut console = Console::new(view.clone());
-        let parsed = Cli::try_parse_from(format!("> {}", input.trim()).split(' '));
+        tracing::info!("{}", redact_command(input));
+        let mut console = Console::new(view.clone())
+            .with_origin(format!("api:{}", mask_token(token)))
+            .with_address(address);
+        let parsed = Cli::try_parse_from(std::iter::once(">").chain(args));
         match parsed {
-            Ok(cli) => match console.process_command(cli).await {
+            Ok(cli) => match console.run_audited(input, cli).await {
                 Ok(res) => {
-                    tracing::info!("{}", res);
+                    tracing::info!("{}", redact_tokens(&res, &*view.get_lobby().settings.read().await));
                     return JsonApiCommands::result(res);
                 }
                 Err(error) => {
@@ -72,10 +82,11 @@ impl JsonApiCommands {
                     return JsonApiCommands::result(format!("{}", error).to_string());
                 }
             },
-            _ => {
-                tracing::warn!("Invalid Command: {}", input.trim());
+            Err(error) => {
+                tracing::warn!("Invalid Command: {}", redact_command(input));
+                // clap explains what is wrong with the arguments
                 return JsonApiCommands::result(
-                    format!("Error: Invalid Command - {}", input.trim()).to_string(),
+                    format!("Error: Invalid Command - {}\n{}", input.trim(), error.render()).to_string(),
                 );
             }
         }
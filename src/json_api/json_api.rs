@@ -5,7 +5,7 @@ use serde_json::{from_str, json, Value};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 
-use crate::json_api::{BlockClients, JsonApiCommands, JsonApiStatus};
+use crate::json_api::{BlockClients, JsonApiCommands, JsonApiMap, JsonApiStatus, StatusQuery};
 use crate::lobby::LobbyView;
 use crate::types::Result;
 
@@ -20,11 +20,15 @@ impl JsonApi {
         let enabled  = settings.json_api.enabled;
         let tcp_port = settings.server.port;
         let api_port = settings.json_api.port;
+        let blocking = settings.json_api.blocking.clone();
         drop(settings);
 
         if !enabled {
             return Ok(None);
         }
+        if let Err(e) = BlockClients::load(&blocking).await {
+            tracing::warn!("Failed to load the blocked api clients: {}", e);
+        }
 
         if api_port == tcp_port {
             return Ok(None);
@@ -84,49 +88,58 @@ impl JsonApi {
             return Ok(());
         }
 
-        if BlockClients::is_blocked(&addr).await {
+        if !settings.json_api.allows(&addr.ip()) {
+            tracing::debug!("Rejected api request from {}, it's not in the allowed ips", addr.ip());
+            return Ok(());
+        }
+
+        if BlockClients::is_blocked(&addr, &settings.json_api.blocking).await {
             tracing::info!("Rejected blocked client {}", addr.ip());
             return Ok(());
         }
 
         if !from_api_port && settings.json_api.port != settings.server.port {
             tracing::warn!("{} is using the normal port {} instead of the separated API port {}", addr.ip(), settings.server.port, settings.json_api.port);
-            BlockClients::fail(&addr).await;
+            BlockClients::fail(&addr, &settings.json_api.blocking).await;
             return Ok(());
         }
 
+        if let Some(target) = json_str.strip_prefix("GET ").and_then(|request| request.split_whitespace().next()) {
+            drop(settings);
+            let response = JsonApiMap::respond(&view, &addr, target).await;
+            return JsonApi::respond(&mut socket, response).await;
+        }
+
         tracing::debug!("request: {}", json_str);
-        let packet: JsonApiPacket = match from_str(&json_str) {
-            Ok(p) => p,
-            Err(_) => {
-                tracing::warn!("Invalid request from {}", addr.ip());
-                BlockClients::fail(&addr).await;
-                return Ok(());
-            }
+        let Some(req) = JsonApiRequest::parse(&json_str) else {
+            tracing::warn!("Invalid request from {}", addr.ip());
+            BlockClients::fail(&addr, &settings.json_api.blocking).await;
+            return Ok(());
         };
 
-        let req: JsonApiRequest = packet.request;
-
         if !["Status", "Command", "Permissions"].contains(&&*req.kind) {
             tracing::warn!("Invalid Type from {}", addr.ip());
-            BlockClients::fail(&addr).await;
+            BlockClients::fail(&addr, &settings.json_api.blocking).await;
             return Ok(());
         }
 
         if !settings.json_api.tokens.contains_key(&req.token) {
             tracing::warn!("Invalid Token from {}", addr.ip());
-            BlockClients::fail(&addr).await;
+            BlockClients::fail(&addr, &settings.json_api.blocking).await;
             return Ok(());
         }
 
         let response: Value = match req.kind.as_str() {
-            "Status" => json!(JsonApiStatus::create(&view, &req.token).await),
+            "Status" => match StatusQuery::parse(&req.data) {
+                Ok(query) => json!(JsonApiStatus::create(&view, &req.token, &query).await),
+                Err(error) => json!({ "Error": error }),
+            },
             "Permissions" => json!({
-                "Permissions": settings.json_api.tokens[&req.token],
+                "Permissions": settings.json_api.permissions(&req.token),
             }),
             "Command" => {
                 drop(settings);
-                json!(JsonApiCommands::process(&view, &req.token, &req.data).await)
+                json!(JsonApiCommands::process(&view, &req.token, &req.data, addr.ip()).await)
             }
             _ => json!({
                 "Error": ([req.kind, " is not implemented yet".to_string()].join("")),
@@ -138,8 +151,8 @@ impl JsonApi {
     }
 
     async fn respond(socket: &mut BufWriter<TcpStream>, response_str: String) -> Result<()> {
-        // TODO Repeat write until all bytes are sent
-        let _ = socket.write(response_str.as_bytes()).await?;
+        // large responses don't fit into a single write
+        socket.write_all(response_str.as_bytes()).await?;
         socket.flush().await?;
         tracing::trace!("response: {}", response_str);
         Ok(())
@@ -163,3 +176,19 @@ struct JsonApiPacket {
     #[serde(rename = "API_JSON_REQUEST")]
     request: JsonApiRequest,
 }
+
+impl JsonApiRequest {
+    fn parse(json_str: &str) -> Option<Self> {
+        from_str::<JsonApiPacket>(json_str).ok().map(|packet| packet.request)
+    }
+}
+
+/// Parse a request like `handle` does, and the query of status requests, for the fuzz targets
+#[cfg(fuzzing)]
+pub fn parse_request(json_str: &str) -> std::result::Result<(), String> {
+    let req = JsonApiRequest::parse(json_str).ok_or("Invalid request")?;
+    if req.kind == "Status" {
+        StatusQuery::parse(&req.data)?;
+    }
+    Ok(())
+}
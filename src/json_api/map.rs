@@ -0,0 +1,40 @@
+use std::net::SocketAddr;
+
+use crate::json_api::BlockClients;
+use crate::lobby::LobbyView;
+
+const MAP_PAGE: &str = include_str!("map.html");
+
+/// Live map of the players, served at `/map?token=...` to tokens with the `Map` permission
+pub(in crate::json_api) struct JsonApiMap {}
+
+impl JsonApiMap {
+    /// HTTP response to a `GET` request, the page streams the positions from the `WebSocket` bridge
+    pub async fn respond(view: &LobbyView, addr: &SocketAddr, target: &str) -> String {
+        let settings = view.get_lobby().settings.read().await;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        if !settings.json_api.map || !settings.websocket.enabled || path != "/map" {
+            return http_response("404 Not Found", "");
+        }
+
+        let token = query.split('&').find_map(|param| param.strip_prefix("token="));
+        if !token.is_some_and(|token| settings.json_api.permissions(token).contains("Map")) {
+            tracing::warn!("Invalid map token from {}", addr.ip());
+            BlockClients::fail(addr, &settings.json_api.blocking).await;
+            return http_response("401 Unauthorized", "");
+        }
+
+        BlockClients::redeem(addr).await;
+        let page = MAP_PAGE.replace("{{WEBSOCKET_PORT}}", &settings.websocket.port.to_string());
+        http_response("200 OK", &page)
+    }
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
@@ -1,13 +1,21 @@
 mod block_clients;
 mod commands;
 mod json_api;
+mod map;
 mod status;
 mod status_player;
+mod status_server;
+mod status_shines;
 mod status_settings;
 
-pub(in crate::json_api) use block_clients::*;
+pub(crate) use block_clients::*;
 pub(in crate::json_api) use commands::*;
 pub(crate) use json_api::*;
+#[cfg(fuzzing)]
+pub use json_api::parse_request;
+pub(in crate::json_api) use map::*;
 pub(in crate::json_api) use status::*;
 pub(in crate::json_api) use status_player::*;
+pub(in crate::json_api) use status_server::*;
+pub(in crate::json_api) use status_shines::*;
 pub(in crate::json_api) use status_settings::*;
@@ -1,24 +1,162 @@
+use std::collections::BTreeSet;
+
 use serde::Serialize;
 use serde_json::Value;
+use tracing::Level;
 
-use crate::json_api::{JsonApiStatusPlayer, JsonApiStatusSettings};
+use crate::json_api::{JsonApiStatusPlayer, JsonApiStatusServer, JsonApiStatusSettings, JsonApiStatusShine};
 use crate::lobby::LobbyView;
+use crate::logbuffer;
+use crate::tag::TagScore;
 
 #[derive(Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub(in crate::json_api) struct JsonApiStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<JsonApiStatusServer>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     players: Option<Vec<JsonApiStatusPlayer>>,
 
+    /// Amount of players before paging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_players: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shines: Option<Vec<JsonApiStatusShine>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     settings: Option<Value>,
+
+    /// Hide and seek scores, most wins first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<Vec<TagScore>>,
+
+    /// Most recent log lines, oldest first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<Vec<String>>,
 }
 
 impl JsonApiStatus {
-    pub async fn create(view: &LobbyView, token: &String) -> JsonApiStatus {
+    pub async fn create(view: &LobbyView, token: &str, query: &StatusQuery) -> JsonApiStatus {
+        let players = JsonApiStatusPlayer::create(view, token, query).await;
         JsonApiStatus {
-            players: JsonApiStatusPlayer::create(view, token).await,
+            server: JsonApiStatusServer::create(view, token).await,
+            total_players: players.as_ref().map(|_| view.get_lobby().players.len()),
+            players,
+            shines: JsonApiStatusShine::create(view, token).await,
             settings: JsonApiStatusSettings::create(view, token).await,
+            tag: tag_scores(view, token).await,
+            log: log_lines(view, token, query).await,
+        }
+    }
+}
+
+async fn tag_scores(view: &LobbyView, token: &str) -> Option<Vec<TagScore>> {
+    let lobby = view.get_lobby();
+    if !lobby.settings.read().await.json_api.permissions(token).contains("Status/Tag") {
+        return None;
+    }
+    Some(lobby.tag_scores.read().await.standings())
+}
+
+async fn log_lines(view: &LobbyView, token: &str, query: &StatusQuery) -> Option<Vec<String>> {
+    if !view.get_lobby().settings.read().await.json_api.permissions(token).contains("Status/Log") {
+        return None;
+    }
+    Some(logbuffer::recent(query.log_lines, query.log_level))
+}
+
+/// Page of players and their fields to include, given as `Data` of the request, e.g. `Offset=20&Limit=10&Fields=Name,Stage`
+#[derive(Debug)]
+pub(in crate::json_api) struct StatusQuery {
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Player fields to include, all permitted ones if missing
+    pub fields: Option<BTreeSet<String>>,
+    pub log_lines: usize,
+    /// Only include log lines at least as severe
+    pub log_level: Option<Level>,
+}
+
+impl Default for StatusQuery {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: None,
+            fields: None,
+            log_lines: 50,
+            log_level: None,
+        }
+    }
+}
+
+impl StatusQuery {
+    pub fn parse(data: &Option<String>) -> Result<Self, String> {
+        let mut query = Self::default();
+        let data = match data.as_deref().map(str::trim) {
+            Some(data) if !data.is_empty() => data,
+            _ => return Ok(query),
+        };
+
+        for param in data.split('&') {
+            let (key, value) = param.split_once('=').ok_or_else(|| format!("Invalid parameter {}", param))?;
+            match key {
+                "Offset" => query.offset = value.parse().map_err(|_| format!("Invalid offset {}", value))?,
+                "Limit" => query.limit = Some(value.parse().map_err(|_| format!("Invalid limit {}", value))?),
+                "Fields" => query.fields = Some(value.split(',').map(ToString::to_string).collect()),
+                "LogLines" => query.log_lines = value.parse().map_err(|_| format!("Invalid log lines {}", value))?,
+                "LogLevel" => query.log_level = Some(value.parse().map_err(|_| format!("Invalid log level {}", value))?),
+                _ => return Err(format!("Unknown parameter {}", key)),
+            }
+        }
+        Ok(query)
+    }
+
+    /// Whether to include the player field, if permitted
+    pub fn includes(&self, field: &str) -> bool {
+        match &self.fields {
+            Some(fields) => fields.contains(field),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(data: &str) -> Result<StatusQuery, String> {
+        StatusQuery::parse(&Some(data.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        for query in [StatusQuery::parse(&None).unwrap(), parse("").unwrap(), parse("  ").unwrap()] {
+            assert_eq!(query.offset, 0);
+            assert_eq!(query.limit, None);
+            assert_eq!(query.log_lines, 50);
+            assert_eq!(query.log_level, None);
+            assert!(query.includes("Name"));
+        }
+    }
+
+    #[test]
+    fn parameters() {
+        let query = parse("Offset=20&Limit=10&Fields=Name,Stage&LogLines=5&LogLevel=warn").unwrap();
+        assert_eq!(query.offset, 20);
+        assert_eq!(query.limit, Some(10));
+        assert_eq!(query.log_lines, 5);
+        assert_eq!(query.log_level, Some(Level::WARN));
+        assert!(query.includes("Name"));
+        assert!(query.includes("Stage"));
+        assert!(!query.includes("Costume"));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for data in ["Offset", "Offset=-1", "Limit=x", "LogLevel=loud", "Page=1", "Offset=1&"] {
+            assert!(parse(data).is_err(), "{}", data);
         }
     }
 }
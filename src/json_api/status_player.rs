@@ -1,8 +1,12 @@
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 
+use crate::json_api::StatusQuery;
 use crate::lobby::LobbyView;
 use crate::net::{GameMode, Packet, PacketData};
+use crate::player_holder::alts;
+use crate::shines::Shines;
 use crate::stages::Stages;
 
 #[derive(Serialize)]
@@ -46,32 +50,62 @@ pub(in crate::json_api) struct JsonApiStatusPlayer {
 
     #[serde(skip_serializing_if = "Option::is_none", rename = "IPv4")]
     ipv4: Option<IpAddr>,
+
+    /// Server shines the player is missing, by kingdom
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missing_shines: Option<BTreeMap<String, Vec<i32>>>,
+
+    /// IDs of other players that connected from the same addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alts: Option<Vec<String>>,
+
+    /// `UDP` or `TCP`, whichever the movement of the other players is sent over
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transport: Option<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<JsonApiStatusPlayerConnection>,
 }
 
 impl JsonApiStatusPlayer {
-    pub async fn create(view: &LobbyView, token: &String) -> Option<Vec<JsonApiStatusPlayer>> {
-        let permissions = &view.get_lobby().settings.read().await.json_api.tokens[token];
+    pub async fn create(view: &LobbyView, token: &str, query: &StatusQuery) -> Option<Vec<JsonApiStatusPlayer>> {
+        let permissions = &view.get_lobby().settings.read().await.json_api.permissions(token);
 
         if !permissions.contains("Status/Players") {
             return None;
         }
 
-        let id_perm       = permissions.contains("Status/Players/ID");
-        let name_perm     = permissions.contains("Status/Players/Name");
-        let gamemode_perm = permissions.contains("Status/Players/GameMode");
-        let kingdom_perm  = permissions.contains("Status/Players/Kingdom");
-        let stage_perm    = permissions.contains("Status/Players/Stage");
-        let scenario_perm = permissions.contains("Status/Players/Scenario");
-        let costume_perm  = permissions.contains("Status/Players/Costume");
-        let capture_perm  = permissions.contains("Status/Players/Capture");
-        let position_perm = permissions.contains("Status/Players/Position");
-        let rotation_perm = permissions.contains("Status/Players/Rotation");
-        let is2d_perm     = permissions.contains("Status/Players/Is2D");
-        let ipv4_perm     = permissions.contains("Status/Players/IPv4");
-        let tagged_perm   = permissions.contains("Status/Players/Tagged");
+        let id_perm       = permissions.contains("Status/Players/ID")       && query.includes("ID");
+        let name_perm     = permissions.contains("Status/Players/Name")     && query.includes("Name");
+        let gamemode_perm = permissions.contains("Status/Players/GameMode") && query.includes("GameMode");
+        let kingdom_perm  = permissions.contains("Status/Players/Kingdom")  && query.includes("Kingdom");
+        let stage_perm    = permissions.contains("Status/Players/Stage")    && query.includes("Stage");
+        let scenario_perm = permissions.contains("Status/Players/Scenario") && query.includes("Scenario");
+        let costume_perm  = permissions.contains("Status/Players/Costume")  && query.includes("Costume");
+        let capture_perm  = permissions.contains("Status/Players/Capture")  && query.includes("Capture");
+        let position_perm = permissions.contains("Status/Players/Position") && query.includes("Position");
+        let rotation_perm = permissions.contains("Status/Players/Rotation") && query.includes("Rotation");
+        let is2d_perm     = permissions.contains("Status/Players/Is2D")     && query.includes("Is2D");
+        let ipv4_perm     = permissions.contains("Status/Players/IPv4")     && query.includes("IPv4");
+        let tagged_perm   = permissions.contains("Status/Players/Tagged")   && query.includes("Tagged");
+        let missing_perm  = permissions.contains("Status/Players/MissingShines") && query.includes("MissingShines");
+        let alts_perm     = permissions.contains("Status/Players/Alts")     && query.includes("Alts");
+        let transport_perm = permissions.contains("Status/Players/Transport") && query.includes("Transport");
+        let connection_perm = permissions.contains("Status/Players/Connection") && query.includes("Connection");
+        let shines = view.get_lobby().shines.read().await.clone();
+        let seen = view.get_lobby().seen.0.read().await;
+
+        // sorted, so pages stay stable between requests
+        let mut guids: Vec<_> = view.get_lobby().players.iter().map(|p| *p.key()).collect();
+        guids.sort();
+        let page = guids.into_iter().skip(query.offset).take(query.limit.unwrap_or(usize::MAX));
 
         let mut players: Vec<JsonApiStatusPlayer> = Vec::new();
-        for client_ref in view.get_lobby().players.iter() {
+        for guid in page {
+            let client_ref = match view.get_lobby().players.get(&guid) {
+                Some(client_ref) => client_ref,
+                None => continue,
+            };
             let profile_id = client_ref.key();
 
             let id = id_perm.then(|| profile_id.to_string());
@@ -182,6 +216,25 @@ impl JsonApiStatusPlayer {
 
             let tagged = tagged_perm.then_some(client.is_seeking).flatten();
 
+            let missing_shines = missing_perm.then(|| Shines::missing(&shines, &client.shine_sync));
+
+            let alts = alts_perm.then(|| alts(&seen, profile_id).keys().map(ToString::to_string).collect());
+
+            let transport = transport_perm.then_some(if client.udp { "UDP" } else { "TCP" });
+
+            let connection = connection_perm.then(|| {
+                let stats = &client.connection;
+                JsonApiStatusPlayerConnection {
+                    rtt_ms: stats.rtt.map(|rtt| rtt.as_millis() as u64),
+                    udp_loss: stats.udp_loss(),
+                    packets_in: stats.received.packets_per_sec,
+                    packets_out: stats.sent.packets_per_sec,
+                    bytes_in: stats.received.bytes_per_sec,
+                    bytes_out: stats.sent.bytes_per_sec,
+                    malformed: stats.malformed,
+                }
+            });
+
             let player = JsonApiStatusPlayer {
                 id,
                 name,
@@ -196,6 +249,10 @@ impl JsonApiStatusPlayer {
                 is_2d,
                 tagged,
                 ipv4,
+                missing_shines,
+                alts,
+                transport,
+                connection,
             };
             players.push(player);
         }
@@ -203,6 +260,21 @@ impl JsonApiStatusPlayer {
     }
 }
 
+/// Rates of the last second
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonApiStatusPlayerConnection {
+    rtt_ms: Option<u64>,
+    /// Share of the unanswered hole punches, from 0 to 1
+    udp_loss: Option<f32>,
+    packets_in: f32,
+    packets_out: f32,
+    bytes_in: f32,
+    bytes_out: f32,
+    /// Received packets that failed to decode, since connecting
+    malformed: u64,
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "PascalCase")]
 struct JsonApiStatusPlayerCostume {
@@ -0,0 +1,48 @@
+use serde::Serialize;
+
+use crate::lobby::LobbyView;
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(in crate::json_api) struct JsonApiStatusServer {
+    version: &'static str,
+    /// Seconds since the server started
+    uptime: u64,
+    players: usize,
+    max_players: u16,
+    /// Amount of connections waiting for a free slot
+    queued: usize,
+    shines: usize,
+    /// Most played game mode
+    game_mode: String,
+    /// Amount of lobby snapshots taken
+    tick: u64,
+    /// Amount of packets and commands broadcasted to all clients
+    broadcasts: u64,
+}
+
+impl JsonApiStatusServer {
+    pub async fn create(view: &LobbyView, token: &str) -> Option<JsonApiStatusServer> {
+        let lobby = view.get_lobby();
+        let settings = lobby.settings.read().await;
+
+        if !settings.json_api.permissions(token).contains("Status/Server") {
+            return None;
+        }
+
+        let max_players = settings.server.max_players;
+        drop(settings);
+
+        Some(JsonApiStatusServer {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime: lobby.stats.uptime().as_secs(),
+            players: lobby.players.len(),
+            max_players,
+            queued: lobby.queue.read().await.len(),
+            shines: lobby.shines.read().await.len(),
+            game_mode: lobby.game_mode().to_string(),
+            tick: lobby.snapshots.latest().tick,
+            broadcasts: lobby.stats.broadcasts(),
+        })
+    }
+}
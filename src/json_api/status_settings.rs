@@ -5,10 +5,10 @@ use crate::lobby::LobbyView;
 pub(in crate::json_api) struct JsonApiStatusSettings {}
 
 impl JsonApiStatusSettings {
-    pub async fn create(view: &LobbyView, token: &String) -> Option<Value> {
+    pub async fn create(view: &LobbyView, token: &str) -> Option<Value> {
         let settings = view.get_lobby().settings.read().await;
 
-        let permissions: Vec<String> = settings.json_api.tokens[token]
+        let permissions: Vec<String> = settings.json_api.permissions(token)
             .iter()
             .filter(|s| s.len() > 16 && &s[..16] == "Status/Settings/")
             .map(|s| s[16..].to_string())
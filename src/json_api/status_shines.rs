@@ -0,0 +1,44 @@
+use serde::Serialize;
+
+use crate::lobby::LobbyView;
+use crate::shines::Shines;
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(in crate::json_api) struct JsonApiStatusShine {
+    #[serde(rename = "ID")]
+    id: i32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kingdom: Option<String>,
+
+    grand: bool,
+}
+
+impl JsonApiStatusShine {
+    pub async fn create(view: &LobbyView, token: &str) -> Option<Vec<JsonApiStatusShine>> {
+        let lobby = view.get_lobby();
+        if !lobby.settings.read().await.json_api.permissions(token).contains("Status/Shines") {
+            return None;
+        }
+
+        let shines = lobby.shines.read().await;
+        Some(
+            shines
+                .iter()
+                .map(|&id| {
+                    let info = Shines::info(id);
+                    JsonApiStatusShine {
+                        id,
+                        name: info.as_ref().map(|i| i.name.clone()),
+                        kingdom: info.and_then(|i| i.kingdom),
+                        grand: shines.is_grand(id),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
@@ -1,15 +1,50 @@
+pub mod anticheat;
+pub mod audit;
 pub mod client;
+pub mod client_sdk;
 pub mod cmds;
 pub mod console;
 pub mod coordinator;
+pub mod discovery;
+pub mod discord;
+pub mod emotes;
+pub mod events;
+pub mod failover;
 pub mod guid;
 pub mod json_api;
+pub mod link;
 pub mod listener;
+pub mod logbuffer;
+pub mod loglevel;
 pub mod lobby;
+pub mod master_server;
+pub mod message;
+pub mod mirror;
 pub mod net;
+pub mod netstat;
+pub mod npc;
+pub mod output;
+pub mod overlay;
 pub mod player_holder;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod race;
+pub mod rcon;
+pub mod readline;
+pub mod relay;
+pub mod recorder;
+pub mod sampling;
 pub mod server;
+pub mod scheduler;
+pub mod session;
 pub mod settings;
+pub mod shine_sync;
+pub mod shines;
+pub mod snapshot;
 pub mod stages;
+pub mod systemd;
+pub mod tag;
 pub mod test;
 pub mod types;
+pub mod webhooks;
+pub mod websocket;
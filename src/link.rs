@@ -0,0 +1,306 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bimap::BiMap;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::broadcast::error::RecvError,
+    time::sleep,
+};
+
+use crate::{
+    cmds::{ClientCommand, ServerWideCommand},
+    guid::Guid,
+    lobby::Lobby,
+    net::{auth, connection::Connection, Packet, PacketData},
+    relay::Relay,
+    settings::LinkSettings,
+    types::Result,
+};
+
+/// Packet type of the handshake packets on a link
+const LINK_HELLO_TAG: u16 = 0x4b4c;
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Mirrors the players of this server to linked servers and the players of the linked servers into this one
+///
+/// Only packets of players connected to this server get forwarded, so packets never travel back to where they came from.
+#[derive(Clone)]
+pub struct Link {
+    lobby: Lobby,
+    cli_broadcast: Relay,
+    /// Local guids of the players of the linked servers, by the index of the link and their guid on that server
+    guids: Arc<Mutex<BiMap<(usize, Guid), Guid>>>,
+}
+
+/// Last known state of a player on a linked server
+#[derive(Default)]
+struct RemotePuppet {
+    connect: Option<Packet>,
+    costume: Option<Packet>,
+    capture: Option<Packet>,
+    tag: Option<Packet>,
+    game: Option<Packet>,
+    player: Option<Packet>,
+}
+
+impl RemotePuppet {
+    fn packets(&self) -> impl Iterator<Item = &Packet> {
+        [
+            &self.connect,
+            &self.costume,
+            &self.capture,
+            &self.tag,
+            &self.game,
+            &self.player,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+impl Link {
+    pub fn new(lobby: Lobby, cli_broadcast: Relay) -> Self {
+        Self {
+            lobby,
+            cli_broadcast,
+            guids: Default::default(),
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let link = settings.link.clone();
+        let address = settings.server.address.primary();
+        drop(settings);
+
+        if !link.enabled {
+            return Ok(());
+        }
+        if link.secret.is_empty() {
+            tracing::warn!("No link secret set, any server can link to this one");
+        }
+
+        for (index, peer) in link.peers.iter().enumerate() {
+            let link = self.clone();
+            let peer = peer.clone();
+            tokio::spawn(async move { link.connect_peer(peer, index).await });
+        }
+
+        if link.port != 0 {
+            self.listen(SocketAddr::new(address, link.port), link).await?;
+        }
+        Ok(())
+    }
+
+    async fn listen(mut self, addr: SocketAddr, settings: LinkSettings) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Accepting server links on {}", listener.local_addr()?);
+
+        let mut index = settings.peers.len();
+        loop {
+            let (socket, addr) = select! {
+                conn = listener.accept() => conn?,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            tracing::info!("Incoming server link from {}", addr);
+            let link = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = link.handle_link(socket, index).await {
+                    tracing::warn!("Server link to {} failed: {}", addr, e);
+                }
+            });
+            index += 1;
+        }
+    }
+
+    async fn connect_peer(mut self, peer: String, index: usize) {
+        loop {
+            match TcpStream::connect(&peer).await {
+                Ok(socket) => {
+                    tracing::info!("Linked to server {}", peer);
+                    match self.clone().handle_link(socket, index).await {
+                        Ok(true) => return,
+                        Ok(false) => tracing::info!("Server link to {} closed", peer),
+                        Err(e) => tracing::warn!("Server link to {} failed: {}", peer, e),
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to link to server {}: {}", peer, e),
+            }
+
+            select! {
+                _ = sleep(RECONNECT_DELAY) => {},
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return,
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+    }
+
+    /// Relay packets over an established link, returns `true` if the server is shutting down
+    async fn handle_link(mut self, socket: TcpStream, index: usize) -> Result<bool> {
+        socket.set_nodelay(true)?;
+        let mut conn = Connection::new(socket);
+        let secret = self.lobby.settings.read().await.link.secret.clone();
+        auth::authenticate(&mut conn, LINK_HELLO_TAG, &secret).await?;
+
+        let mut remote = HashMap::new();
+        let result = self.relay(&mut conn, &mut remote, index).await;
+
+        // remove the puppets of the linked server again
+        for guid in remote.into_keys() {
+            self.forget_guid(&guid);
+            self.cli_broadcast.send(ClientCommand::Packet(Packet::new(guid, PacketData::Disconnect)));
+        }
+        result
+    }
+
+    async fn relay(
+        &mut self,
+        conn: &mut Connection,
+        remote: &mut HashMap<Guid, RemotePuppet>,
+        index: usize,
+    ) -> Result<bool> {
+        let mut cli_recv = self.cli_broadcast.subscribe();
+        let mut forwarded = HashSet::new();
+
+        // sync the players that are already connected
+        let packets = self.lobby.sync_packets().await;
+        for packet in packets {
+            forwarded.insert(packet.id);
+            conn.write_packet(&packet).await?;
+        }
+
+        loop {
+            select! {
+                packet = conn.read_packet() => self.handle_remote(remote, packet?, index),
+                cmd = cli_recv.recv() => match cmd {
+                    Ok(ClientCommand::Packet(packet)) => {
+                        if !self.is_forwarded(&mut forwarded, &packet) || self.lobby.is_hidden(&packet).await {
+                            continue;
+                        }
+                        if matches!(packet.data, PacketData::Connect { .. }) {
+                            self.sync_remote(remote, &packet.id).await?;
+                        }
+                        conn.write_packet(&packet).await?;
+                    }
+                    Ok(ClientCommand::SelfAddressed(_)) => {}
+                    Err(RecvError::Lagged(amount)) => {
+                        tracing::warn!("Server link skipped {} packets", amount);
+                    }
+                    Err(RecvError::Closed) => return Ok(false),
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(true),
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+    }
+
+    /// Forward only packets of local players, puppets of linked servers are never in the player list
+    fn is_forwarded(&self, forwarded: &mut HashSet<Guid>, packet: &Packet) -> bool {
+        match packet.data {
+            PacketData::Disconnect => forwarded.remove(&packet.id),
+            PacketData::Connect { .. }
+            | PacketData::Costume(_)
+            | PacketData::Capture { .. }
+            | PacketData::Tag { .. }
+            | PacketData::Game { .. }
+            | PacketData::Player { .. }
+            | PacketData::Cap { .. } => {
+                let local = self.lobby.players.contains_key(&packet.id);
+                if local {
+                    forwarded.insert(packet.id);
+                }
+                local
+            }
+            _ => false,
+        }
+    }
+
+    fn handle_remote(&self, remote: &mut HashMap<Guid, RemotePuppet>, mut packet: Packet, index: usize) {
+        packet.id = match packet.data {
+            PacketData::Connect { .. } => self.local_guid(index, packet.id),
+            _ => match self.guids.lock().expect("Link guids lock poisoned").get_by_left(&(index, packet.id)) {
+                Some(guid) => *guid,
+                None => return,
+            },
+        };
+
+        let slot = match packet.data {
+            PacketData::Connect { .. } => &mut remote.entry(packet.id).or_default().connect,
+            PacketData::Disconnect => {
+                self.forget_guid(&packet.id);
+                if remote.remove(&packet.id).is_none() {
+                    return;
+                }
+                self.broadcast(packet);
+                return;
+            }
+            // the puppet has to be connected first
+            _ if !remote.contains_key(&packet.id) => return,
+            PacketData::Costume(_) => &mut remote.get_mut(&packet.id).unwrap().costume,
+            PacketData::Capture { .. } => &mut remote.get_mut(&packet.id).unwrap().capture,
+            PacketData::Tag { .. } => &mut remote.get_mut(&packet.id).unwrap().tag,
+            PacketData::Game { .. } => &mut remote.get_mut(&packet.id).unwrap().game,
+            PacketData::Player { .. } => &mut remote.get_mut(&packet.id).unwrap().player,
+            PacketData::Cap { .. } => {
+                self.broadcast(packet);
+                return;
+            }
+            _ => return,
+        };
+        *slot = Some(packet.clone());
+        self.broadcast(packet);
+    }
+
+    /// Show the puppets of the linked server to a newly connected local player
+    async fn sync_remote(&self, remote: &HashMap<Guid, RemotePuppet>, guid: &Guid) -> Result<()> {
+        let channel = match self.lobby.players.get(guid) {
+            Some(player) => player.channel.clone(),
+            None => return Ok(()),
+        };
+        for packet in remote.values().flat_map(RemotePuppet::packets) {
+            channel.send(ClientCommand::Packet(packet.clone())).await?;
+        }
+        Ok(())
+    }
+
+    fn broadcast(&self, mut packet: Packet) {
+        packet.resize();
+        // no local clients connected
+        self.cli_broadcast.send(ClientCommand::Packet(packet));
+    }
+
+    /// Local guid of a player of a linked server, a newly allocated one for players that aren't known yet,
+    /// which clashes with neither the local players nor the players of the other links
+    fn local_guid(&self, index: usize, guid: Guid) -> Guid {
+        let mut guids = self.guids.lock().expect("Link guids lock poisoned");
+        if let Some(local) = guids.get_by_left(&(index, guid)) {
+            return *local;
+        }
+        let local = loop {
+            let local = Guid::new_random();
+            if !guids.contains_right(&local) && !self.lobby.players.contains_key(&local) {
+                break local;
+            }
+        };
+        guids.insert((index, guid), local);
+        local
+    }
+
+    fn forget_guid(&self, local: &Guid) {
+        self.guids.lock().expect("Link guids lock poisoned").remove_by_right(local);
+    }
+}
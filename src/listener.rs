@@ -1,44 +1,62 @@
 use crate::{
-    cmds::{ClientCommand, ServerWideCommand},
-    lobby::Lobby,
-    net::connection::Connection,
+    cmds::{Command, ServerWideCommand},
+    events::ServerEvent,
+    lobby::{Lobby, SlotReservation},
+    net::{connection::Connection, encoding::Encodable, Packet, PacketData, MAX_PACKET_SIZE},
+    relay::Relay,
+    settings::BanEnforcement,
+    systemd,
     types::Result,
 };
-use std::net::SocketAddr;
-use tokio::{net::TcpListener, select, sync::broadcast};
+use bytes::BytesMut;
+use futures::future::select_all;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{mem::discriminant, net::SocketAddr, time::Duration};
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::{broadcast, mpsc},
+    time::sleep,
+};
 
 use crate::client::Client;
 
 pub struct Listener {
-    pub cli_broadcast: broadcast::Sender<ClientCommand>,
+    pub cli_broadcast: Relay,
     pub server_broadcast: broadcast::Receiver<ServerWideCommand>,
-    pub tcp_bind_addr: SocketAddr,
+    /// The first one is the main address, there is at least one
+    pub tcp_bind_addrs: Vec<SocketAddr>,
     pub udp_port_addrs: Option<(u16, u16)>,
-    pub listener: Option<TcpListener>,
+    pub listeners: Vec<TcpListener>,
     pub lobby: Lobby,
 }
 
 impl Listener {
     pub async fn bind_address(&mut self) -> Result<()> {
-        let listener = TcpListener::bind(self.tcp_bind_addr).await?;
-        self.tcp_bind_addr = listener.local_addr().unwrap();
-        self.listener = Some(listener);
+        for bind_addr in self.tcp_bind_addrs.iter_mut() {
+            let listener = bind_tcp(*bind_addr)?;
+            *bind_addr = listener.local_addr().unwrap();
+            self.listeners.push(listener);
+        }
         Ok(())
     }
 
     pub async fn listen_for_clients(mut self) -> Result<()> {
-        if self.listener.is_none() {
+        if self.listeners.is_empty() {
             self.bind_address().await?;
         }
-        let listener = self.listener.unwrap();
-        tracing::info!("Binding tcp port to {}", self.tcp_bind_addr);
+        let listeners = std::mem::take(&mut self.listeners);
+        for bind_addr in &self.tcp_bind_addrs {
+            tracing::info!("Binding tcp port to {}", bind_addr);
+        }
+        systemd::notify_ready();
 
         let udp_port_data = self.udp_port_addrs.unwrap_or((0, 1));
         let mut udp_offset = 0;
 
         loop {
             let (socket, addr) = select! {
-                conn = listener.accept() => {
+                (conn, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))) => {
                     conn?
                 }
                 serv_cmd = self.server_broadcast.recv() => {
@@ -53,26 +71,55 @@ impl Listener {
             socket.set_nodelay(true)?;
 
             // Fast fail any banned ips before resource allocation
-            {
+            let slot = {
                 let settings = self.lobby.settings.read().await;
                 let banned_ips = &settings.ban_list.ip_addresses;
 
                 if banned_ips.contains(&addr.ip()) {
                     tracing::warn!("Banned ip tried to connect: {}", addr.to_string());
+                    self.lobby.emit(ServerEvent::BannedClient { ip: addr.ip(), name: None });
+                    let enforcement = settings.ban_list.enforcement;
                     tokio::spawn(async move {
-                        Client::ignore_client(Connection::new(socket), addr.to_string()).await
+                        Client::ignore_client(Connection::new(socket), addr.to_string(), enforcement).await
                     });
                     continue;
                 }
 
-                if settings.server.max_players as usize <= self.lobby.players.len() {
-                    tracing::warn!("Connection attempt with too many players from {}", addr.to_string());
+                let slot = self.lobby.reserve_slot(settings.server.max_players as usize);
+                let queue = &settings.queue;
+                if (slot.is_none() || !self.lobby.queue.read().await.is_empty())
+                    && queue.enabled
+                    && self.lobby.queue.read().await.len() < queue.max_length
+                {
+                    drop(slot);
+                    let interval = Duration::from_millis(queue.check_interval_ms.max(1));
+                    self.lobby.queue.write().await.push_back(addr);
+                    let to_coord = self.lobby.to_coord.clone();
+                    let udp_port = udp_port_data.0 + udp_offset;
+                    let broadcast = self.cli_broadcast.clone();
+                    udp_offset += 1;
+                    udp_offset %= udp_port_data.1;
+                    let lobby = self.lobby.clone();
                     tokio::spawn(async move {
-                        Client::ignore_client(Connection::new(socket), addr.to_string()).await
+                        let mut conn = Connection::new(socket);
+                        if let Some(slot) = wait_in_queue(&mut conn, addr, interval, &lobby).await {
+                            admit(conn, slot, to_coord, broadcast, udp_port, lobby).await;
+                        }
                     });
                     continue;
                 }
-            }
+
+                match slot {
+                    Some(slot) => slot,
+                    None => {
+                        tracing::warn!("Connection attempt with too many players from {}", addr.to_string());
+                        tokio::spawn(async move {
+                            Client::ignore_client(Connection::new(socket), addr.to_string(), BanEnforcement::CrashOnStage).await
+                        });
+                        continue;
+                    }
+                }
+            };
 
             let to_coord = self.lobby.to_coord.clone();
             let udp_port = udp_port_data.0 + udp_offset;
@@ -83,13 +130,98 @@ impl Listener {
             tracing::debug!("New client attempting to connect");
 
             let lobby = self.lobby.clone();
-            tokio::spawn(async move {
-                let cli_result = Client::initialize_client(socket, to_coord, broadcast, udp_port, lobby).await;
+            tokio::spawn(admit(Connection::new(socket), slot, to_coord, broadcast, udp_port, lobby));
+        }
+    }
+}
 
-                if let Err(e) = cli_result {
-                    tracing::warn!("Client failed to begin: {}", e)
+async fn admit(
+    conn: Connection,
+    slot: SlotReservation,
+    to_coord: mpsc::Sender<Command>,
+    broadcast: Relay,
+    udp_port: u16,
+    lobby: Lobby,
+) {
+    let cli_result = Client::initialize_client(conn, slot, to_coord, broadcast, udp_port, lobby).await;
+
+    if let Err(e) = cli_result {
+        tracing::warn!("Client failed to begin: {}", e)
+    }
+}
+
+/// Hold the connection until it's first in line and a slot is free, nothing if it was closed or the server shut down
+///
+/// The client already sent its connect packet and waits for the init, which is only answered once admitted.
+/// Reading on while waiting notices closed connections.
+async fn wait_in_queue(conn: &mut Connection, addr: SocketAddr, interval: Duration, lobby: &Lobby) -> Option<SlotReservation> {
+    let mut server_recv = lobby.lobby_broadcast.subscribe();
+    let mut last_position = None;
+    let mut held = Vec::new();
+    let admitted = loop {
+        let position = lobby.queue.read().await.iter().position(|a| *a == addr);
+        let Some(position) = position else {
+            break None;
+        };
+        let max_players = lobby.settings.read().await.server.max_players as usize;
+        if position == 0 {
+            if let Some(slot) = lobby.reserve_slot(max_players) {
+                tracing::info!("Admitting {} from the queue", addr);
+                break Some(slot);
+            }
+        }
+        if last_position != Some(position) {
+            tracing::info!("{} is waiting for a free slot at position {} of the queue", addr, position + 1);
+            last_position = Some(position);
+        }
+
+        select! {
+            _ = sleep(interval) => {},
+            packet = conn.read_packet() => match packet {
+                Ok(packet) => {
+                    // only the latest state of the player is kept, but every collected moon
+                    if !matches!(packet.data, PacketData::Shine { .. }) {
+                        held.retain(|p: &Packet| discriminant(&p.data) != discriminant(&packet.data));
+                    }
+                    held.push(packet);
+                }
+                Err(_) => {
+                    tracing::info!("{} left the queue", addr);
+                    break None;
                 }
-            });
+            },
+            cmd = server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(broadcast::error::RecvError::Closed) => break None,
+                Err(broadcast::error::RecvError::Lagged(_)) => {},
+            },
         }
+    };
+
+    let mut queue = lobby.queue.write().await;
+    queue.retain(|a| *a != addr);
+    drop(queue);
+
+    // parsed again once admitted, in front of anything only partially received
+    let mut buff = BytesMut::with_capacity(held.len() * MAX_PACKET_SIZE + conn.buff.len());
+    for packet in &held {
+        if packet.encode(&mut buff).is_err() {
+            return None;
+        }
+    }
+    buff.extend_from_slice(&conn.buff);
+    conn.buff = buff;
+    admitted
+}
+
+/// Bind a tcp listener, IPv6 ones only accept IPv6 so that `::` can be bound next to `0.0.0.0`
+fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
     }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
 }
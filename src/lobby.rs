@@ -1,18 +1,33 @@
-use std::{collections::hash_map::RandomState, sync::Arc};
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
 
 use dashmap::{
     mapref::one::{Ref, RefMut},
     DashMap,
 };
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, RwLock};
 
 use crate::{
     client::PlayerData,
     cmds::{Command, ServerWideCommand},
     coordinator::SyncShineBag,
+    events::ServerEvent,
+    failover::ReplicatedPlayer,
     guid::Guid,
-    player_holder::NameMap,
-    settings::SyncSettings,
+    net::{GameMode, Packet, PacketData},
+    npc::Npc,
+    player_holder::{NameMap, SeenMap},
+    race::{Leaderboard, Race},
+    settings::{Settings, SyncSettings},
+    snapshot::Snapshots,
+    tag::TagScoreboard,
     types::{Result, SMOError},
 };
 
@@ -24,10 +39,29 @@ pub struct Lobby {
     pub players: PlayerMap,
     pub shines: SyncShineBag,
     pub names: NameMap,
+    /// Players that connected before, to target them while they are offline
+    pub seen: SeenMap,
+    /// When the current round of hide and seek was started with `tag start`
+    pub tag_round: Arc<RwLock<Option<SystemTime>>>,
+    pub tag_scores: Arc<RwLock<TagScoreboard>>,
+    /// Player state received from a primary server, restored when the players reconnect
+    pub replicated: Arc<DashMap<Guid, ReplicatedPlayer>>,
+    /// Fake players spawned with `npc spawn`
+    pub npcs: Arc<DashMap<Guid, Npc>>,
+    /// Connections waiting for a free slot while the server is full, first in line first
+    pub queue: Arc<RwLock<VecDeque<SocketAddr>>>,
+    /// Slots of connections that were admitted, but aren't in the players yet
+    pub reserved_slots: Arc<AtomicUsize>,
+    /// Race started with `race start`
+    pub race: Arc<RwLock<Option<Race>>>,
+    /// Periodic snapshots of the lobby state, for consumers that don't need every packet
+    pub snapshots: Snapshots,
+    pub stats: ServerStats,
 
     pub to_coord: mpsc::Sender<Command>,
     pub server_recv: broadcast::Receiver<ServerWideCommand>,
     pub lobby_broadcast: broadcast::Sender<ServerWideCommand>,
+    pub events: broadcast::Sender<ServerEvent>,
 }
 
 impl Lobby {
@@ -41,10 +75,80 @@ impl Lobby {
             players: Default::default(),
             shines: Default::default(),
             names: Default::default(),
+            seen: Default::default(),
+            tag_round: Default::default(),
+            tag_scores: Default::default(),
+            replicated: Default::default(),
+            npcs: Default::default(),
+            queue: Default::default(),
+            reserved_slots: Default::default(),
+            race: Default::default(),
+            snapshots: Default::default(),
+            stats: Default::default(),
             to_coord,
             server_recv: lobby_broadcast.subscribe(),
             lobby_broadcast,
+            events: broadcast::channel(100).0,
+        }
+    }
+
+    /// Notify everyone interested in server events, nobody listening is fine
+    pub fn emit(&self, event: ServerEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// The game mode most players are currently playing
+    pub fn game_mode(&self) -> GameMode {
+        let mut modes: HashMap<u8, usize> = HashMap::new();
+        for player in self.players.iter() {
+            *modes.entry(player.game_mode as u8).or_default() += 1;
+        }
+        modes
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(mode, _)| GameMode::from_u8(mode))
+            .unwrap_or(GameMode::None)
+    }
+
+    /// Whether the packet is kept from the others, for ghosts, invisible players and those in limbo.
+    /// Disconnects still go out, e.g. to remove the puppet of a player that just turned into a ghost.
+    pub async fn is_hidden(&self, packet: &Packet) -> bool {
+        self.is_hidden_by(&*self.settings.read().await, packet)
+    }
+
+    /// Like `is_hidden`, with settings that are already read
+    pub fn is_hidden_by(&self, settings: &Settings, packet: &Packet) -> bool {
+        if matches!(packet.data, PacketData::Disconnect) {
+            return false;
+        }
+        if self.players.get(&packet.id).is_some_and(|sender| sender.in_limbo) {
+            return true;
         }
+        settings.ghosts.players.contains(&packet.id) || settings.invisible.players.contains(&packet.id)
+    }
+
+    /// Packets that show the connected players from scratch, without the hidden ones
+    pub async fn sync_packets(&self) -> Vec<Packet> {
+        let max_player = self.settings.read().await.server.max_players;
+        let packets: Vec<_> = self
+            .players
+            .iter()
+            .flat_map(|p| p.value().create_sync_packets(*p.key(), max_player))
+            .collect();
+        let mut visible = Vec::with_capacity(packets.len());
+        for packet in packets {
+            if !self.is_hidden(&packet).await {
+                visible.push(packet);
+            }
+        }
+        visible
+    }
+
+    /// Reserve a player slot, if the connected players and the other reservations leave one free
+    pub fn reserve_slot(&self, max_players: usize) -> Option<SlotReservation> {
+        let reserved = self.reserved_slots.fetch_add(1, Ordering::SeqCst) + 1;
+        let reservation = SlotReservation(self.reserved_slots.clone());
+        (self.players.len() + reserved <= max_players).then_some(reservation)
     }
 
     pub fn get_client<'a>(&'a self, id: &Guid) -> Result<Ref<'a, Guid, PlayerData, RandomState>> {
@@ -59,6 +163,16 @@ impl Lobby {
     }
 }
 
+/// A player slot, held from admitting a connection until the coordinator added the player
+#[derive(Debug)]
+pub struct SlotReservation(Arc<AtomicUsize>);
+
+impl Drop for SlotReservation {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl Clone for Lobby {
     fn clone(&self) -> Self {
         Self {
@@ -66,13 +180,58 @@ impl Clone for Lobby {
             players: self.players.clone(),
             shines: self.shines.clone(),
             names: self.names.clone(),
+            seen: self.seen.clone(),
+            tag_round: self.tag_round.clone(),
+            tag_scores: self.tag_scores.clone(),
+            replicated: self.replicated.clone(),
+            npcs: self.npcs.clone(),
+            queue: self.queue.clone(),
+            reserved_slots: self.reserved_slots.clone(),
+            race: self.race.clone(),
+            snapshots: self.snapshots.clone(),
+            stats: self.stats.clone(),
             to_coord: self.to_coord.clone(),
             server_recv: self.lobby_broadcast.subscribe(),
             lobby_broadcast: self.lobby_broadcast.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Counters of the running server
+#[derive(Clone, Debug)]
+pub struct ServerStats {
+    started: Instant,
+    broadcasts: Arc<AtomicU64>,
+    /// Best times of the races since the start
+    pub races: Arc<RwLock<Leaderboard>>,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            broadcasts: Default::default(),
+            races: Default::default(),
         }
     }
 }
 
+impl ServerStats {
+    pub fn uptime(&self) -> std::time::Duration {
+        self.started.elapsed()
+    }
+
+    /// Amount of packets and commands broadcasted to all clients
+    pub fn broadcasts(&self) -> u64 {
+        self.broadcasts.load(Ordering::Relaxed)
+    }
+
+    pub fn count_broadcast(&self) {
+        self.broadcasts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Clone)]
 pub struct LobbyView {
     lobby: Lobby,
@@ -0,0 +1,102 @@
+//! The most recent log lines, kept in memory for `log` and the `Status/Log` of the JSON API
+
+use std::{collections::VecDeque, io::Write, sync::Mutex};
+
+use lazy_static::lazy_static;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+lazy_static! {
+    static ref LINES: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+        lines: VecDeque::new(),
+        capacity: DEFAULT_CAPACITY,
+    });
+}
+
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+struct LogBuffer {
+    lines: VecDeque<(Level, String)>,
+    capacity: usize,
+}
+
+/// Keep this many lines from now on, dropping the oldest ones beyond it
+pub fn set_capacity(capacity: usize) {
+    let mut buffer = LINES.lock().expect("Log buffer lock poisoned");
+    buffer.capacity = capacity;
+    let excess = buffer.lines.len().saturating_sub(capacity);
+    buffer.lines.drain(..excess);
+}
+
+/// The last `n` lines, optionally only those at least as severe as `level`, oldest first
+pub fn recent(n: usize, level: Option<Level>) -> Vec<String> {
+    let buffer = LINES.lock().expect("Log buffer lock poisoned");
+    let mut lines: Vec<_> = buffer
+        .lines
+        .iter()
+        .rev()
+        .filter(|(l, _)| level.is_none_or(|level| *l <= level))
+        .take(n)
+        .map(|(_, line)| line.clone())
+        .collect();
+    lines.reverse();
+    lines
+}
+
+fn push(level: Level, line: String) {
+    let mut buffer = LINES.lock().expect("Log buffer lock poisoned");
+    if buffer.capacity == 0 {
+        return;
+    }
+    if buffer.lines.len() >= buffer.capacity {
+        buffer.lines.pop_front();
+    }
+    buffer.lines.push_back((level, line));
+}
+
+/// Writer for the log lines, storing each line in the buffer when it's dropped
+pub struct BufferWriter {
+    level: Level,
+    buffer: Vec<u8>,
+}
+
+impl Write for BufferWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BufferWriter {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let line = String::from_utf8_lossy(&self.buffer).trim_end().to_string();
+            push(self.level, line);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MakeBufferWriter;
+
+impl<'a> MakeWriter<'a> for MakeBufferWriter {
+    type Writer = BufferWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        BufferWriter {
+            level: Level::INFO,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        BufferWriter {
+            level: *meta.level(),
+            buffer: Vec::new(),
+        }
+    }
+}
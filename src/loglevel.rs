@@ -0,0 +1,84 @@
+//! Log filter of the running server, changed with `loglevel`
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    reload, Registry,
+};
+
+use crate::types::Result;
+
+lazy_static! {
+    static ref FILTER: Mutex<Option<LogFilter>> = Mutex::new(None);
+}
+
+struct LogFilter {
+    /// Directives from `--log-level` or `RUST_LOG`
+    base: String,
+    /// Levels set with `loglevel`, by target
+    overrides: BTreeMap<String, LevelFilter>,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    fn build(&self) -> EnvFilter {
+        let mut directives = vec![self.base.clone()];
+        directives.extend(self.overrides.iter().map(|(target, level)| match target.as_str() {
+            "" => level.to_string(),
+            target => format!("{}={}", target, level),
+        }));
+        env_filter(&directives.join(","))
+    }
+}
+
+fn env_filter(directives: &str) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(directives)
+}
+
+/// Filter of the log output, based on the directives of `--log-level`, or `RUST_LOG` without it
+pub fn filter(directives: Option<&str>) -> reload::Layer<EnvFilter, Registry> {
+    let base = match directives {
+        Some(directives) => directives.to_string(),
+        None => std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default(),
+    };
+    let (layer, handle) = reload::Layer::new(env_filter(&base));
+    *FILTER.lock().expect("Log filter lock poisoned") = Some(LogFilter {
+        base,
+        overrides: BTreeMap::new(),
+        handle,
+    });
+    layer
+}
+
+/// Change the level of a target, `default` for everything without a more specific directive.
+///
+/// Modules of the server don't need the crate prefix (e.g. `net::udp_conn`), other crates are prefixed with `::` (e.g. `::tokio`).
+pub fn set(target: &str, level: LevelFilter) -> Result<String> {
+    let mut guard = FILTER.lock().expect("Log filter lock poisoned");
+    let filter = guard
+        .as_mut()
+        .ok_or_else(|| anyhow::anyhow!("The log filter can't be changed"))?;
+
+    let krate = module_path!().split("::").next().unwrap_or_default();
+    let target = match target {
+        "default" => String::new(),
+        target if target == krate || target.starts_with(&format!("{}::", krate)) => target.to_string(),
+        target => match target.strip_prefix("::") {
+            Some(other) => other.to_string(),
+            None => format!("{}::{}", krate, target),
+        },
+    };
+    filter.overrides.insert(target, level);
+
+    let env_filter = filter.build();
+    let description = env_filter.to_string();
+    filter
+        .handle
+        .reload(env_filter)
+        .map_err(|e| anyhow::anyhow!("Failed to change the log filter: {}", e))?;
+    Ok(description)
+}
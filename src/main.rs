@@ -1,37 +1,137 @@
+mod anticheat;
+mod audit;
 mod client;
 mod cmds;
 mod console;
 mod coordinator;
+mod discovery;
+mod discord;
+mod emotes;
+mod events;
+mod failover;
 mod guid;
 mod json_api;
+mod link;
 mod listener;
+mod logbuffer;
+mod loglevel;
 mod lobby;
+mod master_server;
+mod message;
+mod mirror;
 mod net;
+mod netstat;
+mod npc;
+mod output;
+mod overlay;
 mod player_holder;
+#[cfg(feature = "plugins")]
+mod plugin;
+mod race;
+mod rcon;
+mod readline;
+mod relay;
+mod recorder;
+mod sampling;
 mod server;
+mod scheduler;
+mod session;
 mod settings;
+mod shine_sync;
+mod shines;
+mod snapshot;
 mod stages;
+mod systemd;
+mod tag;
 mod types;
+mod webhooks;
+mod websocket;
 
 use crate::types::Result;
 
-use server::Server;
-use settings::{load_settings, save_settings};
-use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use std::path::PathBuf;
+
+use clap::Parser;
+use cmds::ServerWideCommand;
+use lobby::LobbyView;
+use logbuffer::MakeBufferWriter;
+use output::ConsoleWriter;
+use server::{exit_requested, Server};
+use settings::{load_lobby_settings, BindAddress, load_settings, save_settings, set_settings_path, Overrides};
+use stages::Stages;
+use tracing::{info_span, Instrument};
+use tracing_subscriber::{fmt, prelude::*};
 use types::SMOError;
 
+/// Super Mario Odyssey: Online server
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Path to the settings file
+    #[arg(short, long, default_value = "./settings.json")]
+    config: PathBuf,
+
+    /// Override the TCP port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Override the address to bind to, or a comma separated list of them
+    #[arg(short, long)]
+    bind: Option<BindAddress>,
+
+    /// Override the maximum number of players
+    #[arg(short, long)]
+    max_players: Option<u16>,
+
+    /// Don't read commands from stdin
+    #[arg(long)]
+    no_console: bool,
+
+    /// Log filter (e.g. `debug` or `smo_rs::client=trace`), takes precedence over RUST_LOG
+    #[arg(short, long)]
+    log_level: Option<String>,
+}
+
+impl Args {
+    /// The command line overrides of the loaded settings
+    fn overrides(&self) -> Overrides {
+        Overrides::new(self.port, self.bind.clone(), self.max_players)
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_env();
+    let args = Args::parse();
+    setup_env(&args);
+    set_settings_path(args.config.clone());
     loop {
         tracing::info!("Creating server");
-        let server = create_server();
+        let mut server = create_server(&args);
+
+        let mut lobby_views = Vec::new();
+        for (name, lobby) in create_lobbies(&server).await {
+            tracing::info!("Starting lobby {} on port {}", name, lobby.get_bind_addr().port());
+            let view = LobbyView::new(&lobby.lobby);
+            server.lobbies.insert(name.clone(), view.clone());
+            lobby_views.push(view);
+            tokio::spawn(lobby.spawn_full_server().instrument(info_span!("lobby", name)));
+        }
+
         tracing::info!("Starting server");
-        server.spawn_full_server().await?
+        let result = server.spawn_full_server().await;
+
+        for mut view in lobby_views {
+            let _ = view.get_server_send().send(ServerWideCommand::Shutdown);
+        }
+        result?;
+        if exit_requested() {
+            tracing::info!("Exiting");
+            return Ok(());
+        }
     }
 }
 
-fn setup_env() {
+fn setup_env(args: &Args) {
     // TODO Remove tihs debug panic option
     let default_panic = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| {
@@ -39,12 +139,19 @@ fn setup_env() {
         std::process::exit(1);
     }));
 
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy())
-        .init();
+    // filtered per layer, so the filter doesn't hide the task instrumentation from tokio-console
+    let output = fmt::layer()
+        .with_ansi(output::colors())
+        .with_writer(ConsoleWriter::default())
+        .and_then(fmt::layer().with_ansi(false).with_writer(MakeBufferWriter))
+        .with_filter(loglevel::filter(args.log_level.as_deref()));
+    let subscriber = tracing_subscriber::registry().with(output);
+    #[cfg(feature = "console-subscriber")]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+    subscriber.init();
 }
 
-fn create_server() -> Server {
+fn create_server(args: &Args) -> Server {
     let settings = load_settings();
     let settings = match settings {
         Ok(s) => s,
@@ -60,7 +167,32 @@ fn create_server() -> Server {
 
     save_settings(&settings).expect("Failed to save config");
 
-    Server::build_server(settings)
+    let mut settings = settings;
+    args.overrides().apply(&mut settings);
+    logbuffer::set_capacity(settings.logging.buffer_lines);
+    Stages::set_custom_aliases(&settings.stage_aliases);
+
+    let mut server = Server::build_server(settings);
+    server.console_enabled = !args.no_console;
+    server
+}
+
+/// Create a server for every additional lobby configured in the main settings
+async fn create_lobbies(server: &Server) -> Vec<(String, Server)> {
+    let settings = server.lobby.settings.read().await;
+    let mut lobbies = Vec::new();
+    for (name, path) in settings.lobbies.iter() {
+        match load_lobby_settings(&settings, name, path) {
+            Ok(lobby_settings) => {
+                let mut lobby = Server::build_server(lobby_settings);
+                // only the main server reads from stdin, use `lobby <name> <command>` instead
+                lobby.console_enabled = false;
+                lobbies.push((name.clone(), lobby));
+            }
+            Err(e) => tracing::error!("Failed to load settings for lobby {}: {}", name, e),
+        }
+    }
+    lobbies
 }
 
 #[cfg(test)]
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::{select, sync::broadcast::error::RecvError, time::sleep};
+
+use crate::{cmds::ServerWideCommand, lobby::Lobby, types::Result};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Heartbeat posted to the master server
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Heartbeat {
+    pub name: String,
+    pub port: u16,
+    pub players: usize,
+    pub max_players: u16,
+    pub game_mode: String,
+}
+
+/// Periodically post a heartbeat to the master server until the server shuts down
+pub async fn announce(mut lobby: Lobby) -> Result<()> {
+    let settings = lobby.settings.read().await;
+    let master = settings.master_server.clone();
+    drop(settings);
+
+    if !master.enabled {
+        return Ok(());
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(anyhow::Error::from)?;
+    let interval = Duration::from_secs(master.interval.max(1));
+    let mut failures = 0;
+    tracing::info!("Announcing server to master server {}", master.url);
+
+    loop {
+        let heartbeat = create_heartbeat(&lobby).await;
+        let result = client
+            .post(&master.url)
+            .json(&heartbeat)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        // back off exponentially while the master server can't be reached
+        let delay = match result {
+            Ok(_) => {
+                if failures > 0 {
+                    tracing::info!("Reached master server again after {} failed attempts", failures);
+                }
+                failures = 0;
+                interval
+            }
+            Err(e) => {
+                failures += 1;
+                let delay = interval.saturating_mul(1 << failures.min(16)).min(MAX_BACKOFF);
+                tracing::warn!(
+                    "Failed to announce to master server ({} attempts), retrying in {}s: {}",
+                    failures,
+                    delay.as_secs(),
+                    e
+                );
+                delay
+            }
+        };
+
+        select! {
+            _ = sleep(delay) => {},
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => {},
+            },
+        }
+    }
+    Ok(())
+}
+
+async fn create_heartbeat(lobby: &Lobby) -> Heartbeat {
+    let settings = lobby.settings.read().await;
+
+    Heartbeat {
+        name: settings.master_server.name.clone(),
+        port: settings.server.port,
+        players: lobby.players.len(),
+        max_players: settings.server.max_players,
+        game_mode: lobby.game_mode().to_string(),
+    }
+}
@@ -0,0 +1,65 @@
+//! Messages for single players in the game
+//!
+//! The protocol has no chat, so a message shows up as the names of fake players in the player list for a while.
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::{
+    cmds::ClientCommand,
+    guid::Guid,
+    net::{ConnectionType, Packet, PacketData, CLIENT_NAME_SIZE},
+    player_holder::ClientChannel,
+    types::Result,
+};
+
+/// How long a message stays in the player list
+const MESSAGE_DURATION: Duration = Duration::from_secs(10);
+
+/// Show the message to the player, until it is removed again after `MESSAGE_DURATION`
+pub async fn send(channel: &ClientChannel, text: &str, max_player: u16) -> Result<()> {
+    let lines: Vec<_> = lines(text).into_iter().map(|line| (Guid::new_random(), line)).collect();
+    for (guid, line) in &lines {
+        let data = PacketData::Connect {
+            c_type: ConnectionType::FirstConnection,
+            max_player,
+            client_name: line.clone(),
+        };
+        channel.send(ClientCommand::Packet(Packet::new(*guid, data))).await?;
+    }
+
+    let channel = channel.clone();
+    tokio::spawn(async move {
+        sleep(MESSAGE_DURATION).await;
+        for (guid, _) in lines {
+            let _ = channel.send(ClientCommand::Packet(Packet::new(guid, PacketData::Disconnect))).await;
+        }
+    });
+    Ok(())
+}
+
+/// The words of the text packed into lines that fit into a player name, longer words get cut
+fn lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + word.len() <= CLIENT_NAME_SIZE => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => {
+                let mut rest = word;
+                while !rest.is_empty() {
+                    let mut end = rest.len().min(CLIENT_NAME_SIZE);
+                    while !rest.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    lines.push(rest[..end].to_string());
+                    rest = &rest[end..];
+                }
+            }
+        }
+    }
+    lines
+}
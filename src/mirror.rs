@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::broadcast::error::RecvError,
+    time::sleep,
+};
+
+use crate::{
+    cmds::{ClientCommand, ServerWideCommand},
+    lobby::Lobby,
+    net::connection::Connection,
+    relay::Relay,
+    types::Result,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Streams every relayed packet to a secondary server, without ever slowing down the clients
+pub struct Mirror {
+    lobby: Lobby,
+    cli_broadcast: Relay,
+}
+
+impl Mirror {
+    pub fn new(lobby: Lobby, cli_broadcast: Relay) -> Self {
+        Self {
+            lobby,
+            cli_broadcast,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let mirror = settings.mirror.clone();
+        drop(settings);
+
+        if !mirror.enabled {
+            return Ok(());
+        }
+
+        let target = format!("{}:{}", mirror.host, mirror.port);
+        loop {
+            match TcpStream::connect(&target).await {
+                Ok(socket) => {
+                    tracing::info!("Mirroring packets to {}", target);
+                    match self.stream(socket).await {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => tracing::info!("Mirror {} closed", target),
+                        Err(e) => tracing::warn!("Mirror {} failed: {}", target, e),
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to connect to mirror {}: {}", target, e),
+            }
+
+            select! {
+                _ = sleep(RECONNECT_DELAY) => {},
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(()),
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+    }
+
+    /// Returns `true` if the server is shutting down
+    async fn stream(&mut self, socket: TcpStream) -> Result<bool> {
+        socket.set_nodelay(true)?;
+        let mut conn = Connection::new(socket);
+        let mut cli_recv = self.cli_broadcast.subscribe();
+
+        // bring the mirror up to date with the players that are already connected
+        let packets = self.lobby.sync_packets().await;
+        for packet in packets {
+            conn.write_packet(&packet).await?;
+        }
+
+        loop {
+            select! {
+                cmd = cli_recv.recv() => match cmd {
+                    Ok(ClientCommand::Packet(packet)) if !self.lobby.is_hidden(&packet).await => {
+                        conn.write_packet(&packet).await?
+                    }
+                    Ok(_) => {},
+                    // a slow mirror only loses packets, the clients don't wait on it
+                    Err(RecvError::Lagged(amount)) => {
+                        tracing::debug!("Mirror skipped {} packets", amount);
+                    }
+                    Err(RecvError::Closed) => return Ok(false),
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(true),
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+    }
+}
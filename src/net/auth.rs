@@ -0,0 +1,60 @@
+//! Challenge-response handshake of server to server connections, proving that both sides know the shared secret
+//! without sending it
+
+use std::time::Duration;
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::time::timeout;
+
+use super::{connection::Connection, Packet, PacketData};
+use crate::{
+    guid::Guid,
+    types::{ClientInitError, Result},
+};
+
+const NONCE_SIZE: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Swap nonces with the other side and answer with an HMAC of its nonce, keyed with the secret
+///
+/// Both sides send the same packets, so it doesn't matter which one connected.
+pub async fn authenticate(conn: &mut Connection, tag: u16, secret: &str) -> Result<()> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("Failed to generate a nonce"))?;
+    send(conn, tag, nonce.to_vec()).await?;
+    let peer_nonce = receive(conn, tag).await?;
+    // a reflected nonce would let the other side pass our own answer off as its own
+    if peer_nonce.len() != NONCE_SIZE || peer_nonce == nonce {
+        return Err(ClientInitError::BadHandshake.into());
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    send(conn, tag, sign(&key, &peer_nonce, &nonce)).await?;
+    let proof = receive(conn, tag).await?;
+    hmac::verify(&key, &[nonce.as_slice(), &peer_nonce].concat(), &proof)
+        .map_err(|_| ClientInitError::BadHandshake.into())
+}
+
+/// The answer to `challenge`, bound to the own nonce so that it can't be used in the other direction
+fn sign(key: &hmac::Key, challenge: &[u8], nonce: &[u8]) -> Vec<u8> {
+    hmac::sign(key, &[challenge, nonce].concat()).as_ref().to_vec()
+}
+
+async fn send(conn: &mut Connection, tag: u16, data: Vec<u8>) -> Result<()> {
+    conn.write_packet(&Packet::new(Guid::NIL, PacketData::Unhandled { tag, data })).await
+}
+
+async fn receive(conn: &mut Connection, tag: u16) -> Result<Vec<u8>> {
+    let packet = timeout(HANDSHAKE_TIMEOUT, conn.read_packet())
+        .await
+        .map_err(|_| ClientInitError::BadHandshake)??;
+    match packet.data {
+        PacketData::Unhandled { tag: t, data } if t == tag => Ok(data),
+        _ => Err(ClientInitError::BadHandshake.into()),
+    }
+}
@@ -36,10 +36,11 @@ impl Connection {
 
                 buf.set_position(0);
 
-                let packet = Packet::decode(&mut buf)?;
+                // malformed packets are dropped, instead of failing on them again
+                let packet = Packet::decode(&mut buf);
                 self.buff.advance(len);
 
-                Ok(Some(packet))
+                Ok(Some(packet?))
             }
             Err(EncodingError::NotEnoughData) => Ok(None),
             Err(e) => Err(e.into()),
@@ -72,11 +73,12 @@ impl Connection {
     pub async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
         let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
         packet.encode(&mut buff)?;
-        let mut amount = 0;
-        while amount < buff.len() {
-            let last_write = self.socket.write(&buff[..]).await?;
-            amount += last_write;
-        }
+        self.write_encoded(&buff).await
+    }
+
+    /// Send an already encoded packet
+    pub async fn write_encoded(&mut self, buff: &[u8]) -> Result<()> {
+        self.socket.write_all(buff).await?;
         self.socket.flush().await?;
         Ok(())
     }
@@ -17,7 +17,7 @@ const CAP_ANIM_SIZE: usize = 0x30;
 const STAGE_GAME_NAME_SIZE: usize = 0x40;
 const STAGE_CHANGE_NAME_SIZE: usize = 0x30;
 const STAGE_ID_SIZE: usize = 0x10;
-const CLIENT_NAME_SIZE: usize = COSTUME_NAME_SIZE;
+pub const CLIENT_NAME_SIZE: usize = COSTUME_NAME_SIZE;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Packet {
@@ -244,6 +244,10 @@ where
         if p_type != 0x5453 && buf.remaining() < p_size.into() {
             return Err(EncodingError::NotEnoughData);
         }
+        // the data is read by its layout, regardless of the announced size
+        if (p_size as usize) < min_data_size(p_type) {
+            return Err(EncodingError::SmallerThanLayout);
+        }
 
         let data = match p_type {
             1 => PacketData::Init {
@@ -280,7 +284,7 @@ where
                 let game_mode = GameMode::from_u8((both & 0b11110000) >> 4);
                 let update_type = (both & 0b1111) as u8;
                 match (game_mode, update_type) {
-                    (GameMode::HideAndSeek, _) | (GameMode::Sardines, _) | (GameMode::Legacy, 3) => PacketData::Tag {
+                    (GameMode::HideAndSeek, _) | (GameMode::Sardines, _) | (GameMode::Legacy, 3) if p_size >= 5 => PacketData::Tag {
                         game_mode,
                         update_type: match update_type {
                             1 => TagUpdate::Time,
@@ -338,7 +342,7 @@ where
             14 => PacketData::HolePunch {},
             0x5453 => {
                 let t_size = p_size;
-                p_size = total_size as u16;
+                p_size = total_size.try_into()?;
                 PacketData::JsonApi {
                     json: [
                         std::str::from_utf8(&id)?.to_string(),
@@ -500,6 +504,24 @@ fn str_to_sized_array<const N: usize>(s: &str) -> [u8; N] {
     bytes
 }
 
+/// Size of the fixed layout of a packet type, `GameMode` packets need at least the game mode
+fn min_data_size(p_type: u16) -> usize {
+    match p_type {
+        1 => 2,
+        2 => 0x38,
+        3 => 29 + CAP_ANIM_SIZE,
+        4 => 2 + STAGE_GAME_NAME_SIZE,
+        5 => 1,
+        6 => 6 + CLIENT_NAME_SIZE,
+        8 => COSTUME_NAME_SIZE * 2,
+        9 => 5,
+        10 => COSTUME_NAME_SIZE,
+        11 => STAGE_ID_SIZE + STAGE_CHANGE_NAME_SIZE + 2,
+        13 => 2,
+        _ => 0,
+    }
+}
+
 fn buf_size_to_string(buf: &mut impl Buf, size: usize) -> Result<String> {
     Ok(std::str::from_utf8(&buf.copy_to_bytes(size)[..])?
         .trim_matches(char::from(0))
@@ -560,4 +582,14 @@ mod test {
             p.encode(&mut buff).map(|_| Packet::decode(&mut buff).map(|de_p| de_p == p).unwrap_or(false)).unwrap_or(false)
         }
     }
+
+    #[test]
+    fn size_smaller_than_layout() {
+        let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+        buff.put_slice(&[0; 16]);
+        buff.put_u16_le(2);
+        buff.put_u16_le(4);
+        buff.put_slice(&[0; 4]);
+        assert!(matches!(Packet::decode(&mut Cursor::new(buff)), Err(EncodingError::SmallerThanLayout)));
+    }
 }
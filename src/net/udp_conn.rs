@@ -1,6 +1,7 @@
 use std::{
     io::Cursor,
     net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
 };
 
 use bytes::{Buf, BufMut, BytesMut};
@@ -22,6 +23,9 @@ pub struct UdpConnection {
     pub buff: BytesMut,
     pub send_addr: UdpSenderStatus,
     pub has_recv_data: bool,
+    pub last_recv: Option<Instant>,
+    /// How long the client may send nothing over UDP before packets go over TCP again
+    pub fallback_after: Option<Duration>,
 }
 
 impl UdpConnection {
@@ -31,6 +35,8 @@ impl UdpConnection {
             buff: BytesMut::with_capacity(1024),
             send_addr: UdpSenderStatus::Pending(addr),
             has_recv_data: false,
+            last_recv: None,
+            fallback_after: None,
         }
     }
 
@@ -40,6 +46,8 @@ impl UdpConnection {
             buff: BytesMut::with_capacity(1024),
             send_addr: UdpSenderStatus::Connected(addr),
             has_recv_data: false,
+            last_recv: None,
+            fallback_after: None,
         }
     }
 
@@ -51,10 +59,11 @@ impl UdpConnection {
 
                 buf.set_position(0);
 
-                let packet = Packet::decode(&mut buf)?;
+                // malformed packets are dropped, instead of failing on them again
+                let packet = Packet::decode(&mut buf);
                 self.buff.advance(len);
 
-                Ok(Some(packet))
+                Ok(Some(packet?))
             }
             Err(EncodingError::NotEnoughData) => Ok(None),
             Err(e) => Err(e.into()),
@@ -62,7 +71,11 @@ impl UdpConnection {
     }
 
     pub fn is_client_udp(&self) -> bool {
-        matches!(self.send_addr, UdpSenderStatus::Connected(_) if self.has_recv_data)
+        let recent = match (self.last_recv, self.fallback_after) {
+            (Some(last), Some(timeout)) => last.elapsed() < timeout,
+            _ => true,
+        };
+        matches!(self.send_addr, UdpSenderStatus::Connected(_) if self.has_recv_data && recent)
     }
 
     pub fn set_client_port(&mut self, port: u16) {
@@ -93,6 +106,7 @@ impl UdpConnection {
             if addr == expected_addr {
                 self.buff.put_slice(&buff[..read_amount]);
                 self.has_recv_data = true;
+                self.last_recv = Some(Instant::now());
             }
         } else {
             // Never resolve as connection isnt ready
@@ -103,13 +117,17 @@ impl UdpConnection {
     }
 
     pub async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
-        if let UdpSenderStatus::Connected(send_addr) = self.send_addr {
-            let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
-            packet.encode(&mut buff)?;
+        let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+        packet.encode(&mut buff)?;
+        self.write_encoded(&buff).await
+    }
 
+    /// Send an already encoded packet
+    pub async fn write_encoded(&mut self, buff: &[u8]) -> Result<()> {
+        if let UdpSenderStatus::Connected(send_addr) = self.send_addr {
             let mut amount = 0;
             while amount < buff.len() {
-                let last_write = self.socket.send_to(&buff[..], send_addr).await;
+                let last_write = self.socket.send_to(buff, send_addr).await;
                 amount += last_write.unwrap();
             }
             Ok(())
@@ -0,0 +1,106 @@
+//! Connection quality of the clients, shown with `netstat`
+
+use std::time::{Duration, Instant};
+
+use crate::net::Packet;
+
+/// Size of the id, type and size in front of the packet data
+const HEADER_SIZE: usize = 20;
+
+/// Rates of a client, measured over windows of about a second
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    /// Round trip time of the last hole punch the client answered
+    pub rtt: Option<Duration>,
+    pub received: Traffic,
+    pub sent: Traffic,
+    /// Received packets that failed to decode
+    pub malformed: u64,
+    punches_sent: u64,
+    punches_answered: u64,
+    punch_sent_at: Option<Instant>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    pub packets_per_sec: f32,
+    pub bytes_per_sec: f32,
+    window: Option<Instant>,
+    packets: u64,
+    bytes: u64,
+}
+
+impl Traffic {
+    /// Count a packet, returns whether a window was completed
+    fn count(&mut self, packet: &Packet) -> bool {
+        let now = Instant::now();
+        let started = *self.window.get_or_insert(now);
+        self.packets += 1;
+        self.bytes += (HEADER_SIZE + packet.data_size as usize) as u64;
+
+        let elapsed = now.duration_since(started);
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+        self.packets_per_sec = self.packets as f32 / elapsed.as_secs_f32();
+        self.bytes_per_sec = self.bytes as f32 / elapsed.as_secs_f32();
+        self.window = Some(now);
+        self.packets = 0;
+        self.bytes = 0;
+        true
+    }
+}
+
+impl ConnectionStats {
+    /// Count a packet of the client, returns whether the rates changed
+    pub fn receive(&mut self, packet: &Packet) -> bool {
+        self.received.count(packet)
+    }
+
+    /// Count a packet for the client, returns whether the rates changed
+    pub fn send(&mut self, packet: &Packet) -> bool {
+        self.sent.count(packet)
+    }
+
+    pub fn punch_sent(&mut self) {
+        self.punches_sent += 1;
+        self.punch_sent_at = Some(Instant::now());
+    }
+
+    /// The client answered a hole punch over udp
+    pub fn punch_answered(&mut self) {
+        if let Some(sent) = self.punch_sent_at.take() {
+            self.punches_answered += 1;
+            self.rtt = Some(sent.elapsed());
+        }
+    }
+
+    /// Share of the hole punches that weren't answered, unknown until the client answered one
+    pub fn udp_loss(&self) -> Option<f32> {
+        (self.punches_answered > 0).then(|| 1.0 - self.punches_answered as f32 / self.punches_sent as f32)
+    }
+}
+
+impl std::fmt::Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {}ms", rtt.as_millis())?,
+            None => write!(f, "rtt unknown")?,
+        }
+        if let Some(loss) = self.udp_loss() {
+            write!(f, ", udp loss {:.0}%", loss * 100.0)?;
+        }
+        write!(
+            f,
+            ", in {:.0} packets/s ({:.1} KB/s), out {:.0} packets/s ({:.1} KB/s)",
+            self.received.packets_per_sec,
+            self.received.bytes_per_sec / 1000.0,
+            self.sent.packets_per_sec,
+            self.sent.bytes_per_sec / 1000.0
+        )?;
+        if self.malformed > 0 {
+            write!(f, ", {} malformed", self.malformed)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::VecDeque, time::Duration};
+
+use nalgebra::UnitQuaternion;
+use tokio::{
+    select,
+    sync::broadcast::error::RecvError,
+    time::interval,
+};
+
+use crate::{
+    cmds::{ClientCommand, ServerWideCommand},
+    guid::Guid,
+    lobby::Lobby,
+    net::{ConnectionType, Packet, PacketData},
+    relay::Relay,
+    types::{Costume, Quaternion, Result, Vector3},
+};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Fake player controlled by the server
+#[derive(Debug, Clone)]
+pub struct Npc {
+    pub name: String,
+    pub costume: Costume,
+    pub stage: String,
+    pub scenario: i8,
+    pub pos: Vector3,
+    pub rot: Quaternion,
+    /// Points the NPC still walks to, in order
+    pub path: VecDeque<Vector3>,
+    /// Append every reached point to the end of the path again
+    pub loop_path: bool,
+    /// Units per second
+    pub speed: f32,
+}
+
+impl Npc {
+    pub fn new(name: String, costume: Costume, stage: String, scenario: i8, pos: Vector3) -> Self {
+        Self {
+            name,
+            costume,
+            stage,
+            scenario,
+            pos,
+            rot: Quaternion::identity(),
+            path: VecDeque::new(),
+            loop_path: false,
+            speed: 0.0,
+        }
+    }
+
+    /// Packets that show the NPC to a client
+    pub fn create_sync_packets(&self, guid: Guid, max_player: u16) -> Vec<Packet> {
+        vec![
+            Packet::new(
+                guid,
+                PacketData::Connect {
+                    c_type: ConnectionType::FirstConnection,
+                    max_player,
+                    client_name: self.name.clone(),
+                },
+            ),
+            Packet::new(guid, PacketData::Costume(self.costume.clone())),
+            Packet::new(
+                guid,
+                PacketData::Game {
+                    is_2d: false,
+                    scenario_num: self.scenario,
+                    stage: self.stage.clone(),
+                },
+            ),
+            self.create_player_packet(guid),
+        ]
+    }
+
+    pub fn create_player_packet(&self, guid: Guid) -> Packet {
+        Packet::new(
+            guid,
+            PacketData::Player {
+                pos: self.pos,
+                rot: self.rot,
+                animation_blend_weights: [0.0; 6],
+                act: 0,
+                sub_act: 0,
+            },
+        )
+    }
+
+    /// Walk along the path for `seconds`, returns `false` if the NPC is standing still
+    fn advance(&mut self, seconds: f32) -> bool {
+        let mut distance = self.speed * seconds;
+        if self.path.is_empty() || distance <= 0.0 {
+            return false;
+        }
+
+        // every point is reached at most once per tick, even on a looping path
+        for _ in 0..self.path.len() {
+            let target = match self.path.front() {
+                Some(target) => *target,
+                None => break,
+            };
+            let delta = target - self.pos;
+            let remaining = delta.norm();
+
+            // face the walking direction
+            if delta.x != 0.0 || delta.z != 0.0 {
+                let yaw = delta.x.atan2(delta.z);
+                self.rot = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), yaw).into_inner();
+            }
+
+            if remaining > distance {
+                self.pos += delta * (distance / remaining);
+                break;
+            }
+            self.pos = target;
+            distance -= remaining;
+            self.path.pop_front();
+            if self.loop_path {
+                self.path.push_back(target);
+            }
+        }
+        true
+    }
+}
+
+/// Moves the NPCs of a lobby along their paths
+pub struct NpcDriver {
+    lobby: Lobby,
+    cli_broadcast: Relay,
+}
+
+impl NpcDriver {
+    pub fn new(lobby: Lobby, cli_broadcast: Relay) -> Self {
+        Self {
+            lobby,
+            cli_broadcast,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let mut ticks = interval(TICK_INTERVAL);
+        loop {
+            select! {
+                _ = ticks.tick() => self.tick(),
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => {},
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&self) {
+        let mut packets = Vec::new();
+        for mut npc in self.lobby.npcs.iter_mut() {
+            if npc.advance(TICK_INTERVAL.as_secs_f32()) {
+                packets.push(npc.create_player_packet(*npc.key()));
+            }
+        }
+        for packet in packets {
+            // no clients connected
+            self.cli_broadcast.send(ClientCommand::Packet(packet));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Output of the console, colored and with timestamps like the log lines
+//!
+//! While a prompt is shown, everything is printed through the line editor, which keeps the prompt
+//! and the partially typed line intact below the output.
+
+use std::{
+    io::{IsTerminal, Write},
+    sync::Mutex,
+};
+
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use rustyline::ExternalPrinter;
+use tracing_subscriber::fmt::MakeWriter;
+
+lazy_static! {
+    static ref PRINTER: Mutex<Option<Box<dyn ExternalPrinter + Send>>> = Mutex::new(None);
+    static ref COLORS: bool = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+}
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+
+/// Whether stdout is a terminal that should get colored output
+pub fn colors() -> bool {
+    *COLORS
+}
+
+/// Print through the printer of the line editor from now on
+pub fn set_printer(printer: impl ExternalPrinter + Send + 'static) {
+    *PRINTER.lock().expect("Console printer lock poisoned") = Some(Box::new(printer));
+}
+
+/// Reply to a console command
+pub fn reply(text: &str) {
+    print(&styled(GREEN, text));
+}
+
+/// Failed console command
+pub fn error(text: &str) {
+    print(&styled(RED, text));
+}
+
+fn styled(color: &str, text: &str) -> String {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+    let text = text.trim_end();
+    if colors() {
+        format!("{}{}{} {}{}{}\n", DIM, timestamp, RESET, color, text, RESET)
+    } else {
+        format!("{} {}\n", timestamp, text)
+    }
+}
+
+fn print(text: &str) {
+    let mut printer = PRINTER.lock().expect("Console printer lock poisoned");
+    if let Some(printer) = printer.as_mut() {
+        if printer.print(text.to_string()).is_ok() {
+            return;
+        }
+    }
+    drop(printer);
+
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// Writer for the log lines, printing each line at once when it's dropped
+#[derive(Default)]
+pub struct ConsoleWriter {
+    buffer: Vec<u8>,
+}
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleWriter {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            print(&String::from_utf8_lossy(&self.buffer));
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for ConsoleWriter {
+    type Writer = ConsoleWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ConsoleWriter::default()
+    }
+}
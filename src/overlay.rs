@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{broadcast::error::RecvError, Mutex},
+    time::{timeout, Instant},
+};
+
+use crate::{cmds::ServerWideCommand, lobby::Lobby, settings::OverlaySettings, types::Result};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Public state for stream overlays, nothing that would need a token of the JSON API
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct OverlayStatus {
+    /// Seconds since the unix epoch
+    pub server_time: u64,
+    pub players: usize,
+    pub max_players: u16,
+    /// Seconds since the last `tag start`
+    pub round_time: Option<u64>,
+    pub timers: Vec<OverlayTimer>,
+}
+
+/// Hide and seek timer of a player
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct OverlayTimer {
+    pub name: String,
+    pub seconds: u64,
+    pub is_seeking: bool,
+}
+
+/// Serves the [`OverlayStatus`] as JSON over HTTP, rate limited per ip address
+pub struct Overlay {
+    lobby: Lobby,
+    cache: Arc<Mutex<Option<(Instant, String)>>>,
+    requests: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+impl Overlay {
+    pub fn new(lobby: Lobby) -> Self {
+        Self {
+            lobby,
+            cache: Default::default(),
+            requests: Default::default(),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let overlay = settings.overlay.clone();
+        let addr = SocketAddr::new(settings.server.address.primary(), overlay.port);
+        drop(settings);
+
+        if !overlay.enabled {
+            return Ok(());
+        }
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Serving overlay status on http://{}/", listener.local_addr()?);
+
+        loop {
+            let (socket, addr) = select! {
+                conn = listener.accept() => conn?,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            let overlay = Self {
+                lobby: self.lobby.clone(),
+                cache: self.cache.clone(),
+                requests: self.requests.clone(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = timeout(REQUEST_TIMEOUT, overlay.handle(socket, addr)).await {
+                    tracing::debug!("Overlay request of {} timed out: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut socket: TcpStream, addr: SocketAddr) {
+        let settings = self.lobby.settings.read().await.overlay.clone();
+        let mut buff = [0; 2048];
+        let read = match socket.read(&mut buff).await {
+            Ok(read) => read,
+            Err(_) => return,
+        };
+
+        let request = String::from_utf8_lossy(&buff[..read]);
+        let response = match self.respond(&settings, &request, addr.ip()).await {
+            Ok(body) => http_response("200 OK", &body),
+            Err(status) => http_response(status, ""),
+        };
+        let _ = socket.write_all(response.as_bytes()).await;
+        let _ = socket.shutdown().await;
+    }
+
+    async fn respond(&self, settings: &OverlaySettings, request: &str, ip: IpAddr) -> std::result::Result<String, &'static str> {
+        // e.g. `GET /?token=abc HTTP/1.1`
+        let target = match request.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", target, ..] => target,
+            _ => return Err("405 Method Not Allowed"),
+        };
+        if let Some(token) = &settings.token {
+            let query = target.split_once('?').map(|(_, query)| query).unwrap_or("");
+            if !query.split('&').any(|param| param.strip_prefix("token=") == Some(token)) {
+                return Err("401 Unauthorized");
+            }
+        }
+
+        let now = Instant::now();
+        let min_interval = Duration::from_millis(settings.min_interval_ms);
+        let mut requests = self.requests.lock().await;
+        requests.retain(|_, last| now.duration_since(*last) < min_interval);
+        if requests.insert(ip, now).is_some() {
+            return Err("429 Too Many Requests");
+        }
+        drop(requests);
+
+        let mut cache = self.cache.lock().await;
+        match &*cache {
+            Some((created, body)) if now.duration_since(*created) < Duration::from_millis(settings.cache_ms) => {
+                Ok(body.clone())
+            }
+            _ => {
+                let body = serde_json::to_string(&self.create_status().await).map_err(|_| "500 Internal Server Error")?;
+                *cache = Some((now, body.clone()));
+                Ok(body)
+            }
+        }
+    }
+
+    async fn create_status(&self) -> OverlayStatus {
+        let now = SystemTime::now();
+        let round_started = *self.lobby.tag_round.read().await;
+        let snapshot = self.lobby.snapshots.latest();
+        let timers = snapshot
+            .players
+            .values()
+            .filter_map(|p| {
+                Some(OverlayTimer {
+                    name: p.name.clone(),
+                    seconds: p.time?,
+                    is_seeking: p.is_seeking.unwrap_or(false),
+                })
+            })
+            .collect();
+
+        OverlayStatus {
+            server_time: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            players: snapshot.players.len(),
+            max_players: self.lobby.settings.read().await.server.max_players,
+            round_time: round_started.and_then(|start| now.duration_since(start).ok()).map(|d| d.as_secs()),
+            timers,
+        }
+    }
+}
+
+fn http_response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
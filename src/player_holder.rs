@@ -1,13 +1,18 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{ops::Not, sync::Arc};
 
 use bimap::BiMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 use crate::cmds::Players;
-use crate::lobby::LobbyView;
-use crate::{cmds::ClientCommand, guid::Guid, types::Result};
+use crate::lobby::{Lobby, LobbyView};
+use crate::stages::Stages;
+use crate::{cmds::ClientCommand, guid::Guid, types::{matches_wildcard, Result, SMOError}};
 
 pub(crate) type ClientChannel = mpsc::Sender<ClientCommand>;
 
@@ -64,21 +69,11 @@ impl PlayerSelect<String> {
             PlayerSelect::AllPlayers => PlayerSelect::AllPlayers,
             PlayerSelect::SelectPlayers(p) => {
                 let names = lobby.get_lobby().names.0.read().await;
-                PlayerSelect::SelectPlayers(
-                    p.iter()
-                        .filter_map(|s| names.get_by_right(s))
-                        .map(|g| *g)
-                        .collect(),
-                )
+                PlayerSelect::SelectPlayers(resolve_all(lobby.get_lobby(), &names, &p)?)
             }
             PlayerSelect::ExcludePlayers(p) => {
                 let names = lobby.get_lobby().names.0.read().await;
-                PlayerSelect::ExcludePlayers(
-                    p.iter()
-                        .filter_map(|s| names.get_by_right(s))
-                        .map(|g| *g)
-                        .collect(),
-                )
+                PlayerSelect::ExcludePlayers(resolve_all(lobby.get_lobby(), &names, &p)?)
             }
         };
 
@@ -89,6 +84,230 @@ impl PlayerSelect<String> {
         let guid_select = self.into_guid_select(lobby).await?;
         guid_select.into_guid_vec(lobby)
     }
+
+    /// Like `into_guid_vec`, but selected players may also be offline, given by their guid, a previously seen name or a pattern of names
+    ///
+    /// Only meant for commands that don't need to reach the players. Negated and `@` selectors only select connected players.
+    pub async fn into_offline_guids(self, lobby: &LobbyView) -> Result<Vec<Guid>> {
+        match self {
+            PlayerSelect::SelectPlayers(p) => {
+                let names = lobby.get_lobby().names.0.read().await;
+                let selectors = split_all(&names, &p);
+                let may_be_offline = selectors.iter().all(|s| names.contains_right(s) || !s.starts_with(['!', '@']));
+                drop(names);
+                if !may_be_offline {
+                    let select = PlayerSelect::SelectPlayers(selectors);
+                    return select.into_guid_vec(lobby).await?.flatten(lobby.get_lobby());
+                }
+                let mut guids = Vec::with_capacity(selectors.len());
+                for player in selectors {
+                    for guid in resolve_offline_all(lobby, &player).await? {
+                        if !guids.contains(&guid) {
+                            guids.push(guid);
+                        }
+                    }
+                }
+                Ok(guids)
+            }
+            select => select.into_guid_vec(lobby).await?.flatten(lobby.get_lobby()),
+        }
+    }
+}
+
+/// Shortest guid prefix to select a player with, shorter hex strings are more likely a name prefix
+const MIN_GUID_PREFIX: usize = 4;
+
+/// Connected players selected by any of the selectors, without duplicates and those of negated selectors (`!Name`).
+/// Only negated selectors select everyone else.
+fn resolve_all(lobby: &Lobby, names: &BiMap<Guid, String>, selectors: &[String]) -> Result<Vec<Guid>> {
+    let mut guids = Vec::new();
+    let mut excluded = Vec::new();
+    let mut only_excluded = true;
+    for selector in split_all(names, selectors) {
+        // the exact name of a player wins over the selector syntax, e.g. for a player called `!Bob`
+        if let Some(guid) = names.get_by_right(&selector) {
+            only_excluded = false;
+            if !guids.contains(guid) {
+                guids.push(*guid);
+            }
+            continue;
+        }
+        let (negated, selector) = match selector.strip_prefix('!') {
+            Some(selector) => (true, selector),
+            None => (false, selector.as_str()),
+        };
+        let selected = match selector.strip_prefix('@') {
+            Some(filter) => resolve_filter(lobby, filter)?,
+            None => resolve_connected(names, selector)?,
+        };
+        if negated {
+            excluded.extend(selected);
+            continue;
+        }
+        only_excluded = false;
+        for guid in selected {
+            if !guids.contains(&guid) {
+                guids.push(guid);
+            }
+        }
+    }
+    if only_excluded && !excluded.is_empty() {
+        guids = names.left_values().copied().collect();
+    }
+    guids.retain(|guid| !excluded.contains(guid));
+    Ok(guids)
+}
+
+/// Comma separated selectors as separate ones, unless the whole selector is the exact name of a connected player
+fn split_all(names: &BiMap<Guid, String>, selectors: &[String]) -> Vec<String> {
+    selectors
+        .iter()
+        .flat_map(|selector| {
+            if names.contains_right(selector) {
+                vec![selector.clone()]
+            } else {
+                selector.split(',').filter(|s| !s.is_empty()).map(ToString::to_string).collect()
+            }
+        })
+        .collect()
+}
+
+/// Connected players of `@tagged`, `@2d`, `@stage:<stage>` or `@kingdom:<kingdom>`, without the `@`
+fn resolve_filter(lobby: &Lobby, filter: &str) -> Result<Vec<Guid>> {
+    let stages = match filter.split_once(':') {
+        Some(("stage", stage)) => {
+            let stages = Stages::stages_by_input(stage);
+            if stages.is_empty() {
+                return Err(SMOError::InvalidConsoleArg(format!("Unknown stage {}", stage)));
+            }
+            Some(stages)
+        }
+        Some(("kingdom", kingdom)) => Some(
+            Stages::kingdom_stages(kingdom)
+                .ok_or_else(|| SMOError::InvalidConsoleArg(format!("Unknown kingdom {}", kingdom)))?,
+        ),
+        _ if ["tagged", "2d"].contains(&filter) => None,
+        _ => return Err(SMOError::InvalidConsoleArg(format!("Unknown selector @{}", filter))),
+    };
+    let guids = lobby
+        .players
+        .iter()
+        .filter(|player| match (filter, &stages) {
+            ("tagged", _) => player.is_seeking == Some(true),
+            ("2d", _) => player.is_2d,
+            (_, Some(stages)) => player.last_stage().is_some_and(|stage| stages.iter().any(|s| s == stage)),
+            _ => false,
+        })
+        .map(|player| *player.key())
+        .collect();
+    Ok(guids)
+}
+
+/// Connected players selected by the exact name, a `*` wildcard pattern of names, a guid,
+/// or else the unique prefix of a name or guid (ignoring the case)
+fn resolve_connected(names: &BiMap<Guid, String>, selector: &str) -> Result<Vec<Guid>> {
+    if let Some(guid) = names.get_by_right(selector) {
+        return Ok(vec![*guid]);
+    }
+    let candidates: Vec<_> = names.iter().map(|(guid, name)| (*guid, name.as_str())).collect();
+    resolve_pattern(&candidates, selector)
+}
+
+/// Candidates selected by a `*` wildcard pattern of names, a guid, or else the unique prefix of a name or guid
+fn resolve_pattern(candidates: &[(Guid, &str)], selector: &str) -> Result<Vec<Guid>> {
+    let lower = selector.to_lowercase();
+    if selector.contains('*') {
+        let guids: Vec<_> = candidates
+            .iter()
+            .filter(|(_, name)| matches_wildcard(&lower, &name.to_lowercase()))
+            .map(|(guid, _)| *guid)
+            .collect();
+        if guids.is_empty() {
+            return Err(SMOError::InvalidConsoleArg(format!("No player matches {}", selector)));
+        }
+        return Ok(guids);
+    }
+    if let Ok(guid) = selector.parse::<Guid>() {
+        if candidates.iter().any(|(g, _)| *g == guid) {
+            return Ok(vec![guid]);
+        }
+    }
+
+    let hex = lower.replace('-', "");
+    let is_guid_prefix = hex.len() >= MIN_GUID_PREFIX && hex.chars().all(|c| c.is_ascii_hexdigit());
+    let matches: Vec<_> = candidates
+        .iter()
+        .filter(|(guid, name)| {
+            name.to_lowercase().starts_with(&lower)
+                || (is_guid_prefix && guid.to_string().replace('-', "").starts_with(&hex))
+        })
+        .map(|(guid, name)| (*guid, name.to_string()))
+        .collect();
+    match matches[..] {
+        [(guid, _)] => Ok(vec![guid]),
+        [] => Err(SMOError::InvalidConsoleArg(format!("Unknown player {}", selector))),
+        _ => Err(ambiguous(selector, matches)),
+    }
+}
+
+fn ambiguous(selector: &str, mut matches: Vec<(Guid, String)>) -> SMOError {
+    matches.sort_by(|a, b| a.1.cmp(&b.1));
+    let names: Vec<_> = matches.iter().map(|(guid, name)| format!("{} ({})", name, guid)).collect();
+    SMOError::InvalidConsoleArg(format!("{} matches multiple players: {}", selector, names.join(", ")))
+}
+
+/// Resolve the name of a connected player, a guid or the name of a player that was connected before,
+/// or else a pattern or prefix of a single connected or previously seen player
+pub async fn resolve_offline(lobby: &LobbyView, player: &str) -> Result<Guid> {
+    let guids = resolve_offline_all(lobby, player).await?;
+    if let [guid] = guids[..] {
+        return Ok(guid);
+    }
+    let mut matches = Vec::with_capacity(guids.len());
+    for guid in guids {
+        matches.push((guid, display_name(lobby, &guid).await));
+    }
+    Err(ambiguous(player, matches))
+}
+
+/// Like `resolve_offline`, but a wildcard pattern selects all matching players
+pub async fn resolve_offline_all(lobby: &LobbyView, player: &str) -> Result<Vec<Guid>> {
+    let lobby = lobby.get_lobby();
+    let names = lobby.names.0.read().await;
+    if let Some(guid) = names.get_by_right(player) {
+        return Ok(vec![*guid]);
+    }
+    if let Ok(guid) = player.parse::<Guid>() {
+        return Ok(vec![guid]);
+    }
+    let seen = lobby.seen.0.read().await;
+    let offline = seen
+        .iter()
+        .filter(|(_, p)| p.name == player || p.names.contains(player))
+        .max_by_key(|(_, p)| p.last_seen)
+        .map(|(guid, _)| *guid);
+    if let Some(guid) = offline {
+        return Ok(vec![guid]);
+    }
+    let mut candidates: Vec<_> = names.iter().map(|(guid, name)| (*guid, name.as_str())).collect();
+    candidates.extend(
+        seen.iter()
+            .filter(|(guid, _)| !names.contains_left(guid))
+            .map(|(guid, p)| (*guid, p.name.as_str())),
+    );
+    resolve_pattern(&candidates, player)
+}
+
+/// Name of a connected or previously seen player, the guid if the player is unknown
+pub async fn display_name(lobby: &LobbyView, guid: &Guid) -> String {
+    let lobby = lobby.get_lobby();
+    if let Some(name) = lobby.names.0.read().await.get_by_left(guid) {
+        return name.clone();
+    }
+    match lobby.seen.0.read().await.get(guid) {
+        Some(player) => player.name.clone(),
+        None => guid.to_string(),
+    }
 }
 
 
@@ -100,3 +319,206 @@ impl From<Vec<Guid>> for PlayerSelect<Guid> {
 
 #[derive(Default, Clone, Debug)]
 pub struct NameMap(pub Arc<RwLock<BiMap<Guid, String>>>);
+
+/// A player that connected to this lobby before
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SeenPlayer {
+    /// The latest name of the player
+    pub name: String,
+    /// Every name and address the player connected with
+    pub names: BTreeSet<String>,
+    pub ips: BTreeSet<IpAddr>,
+    /// Seconds since the unix epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Other players that connected from the same addresses, with the shared addresses
+pub fn alts(seen: &BTreeMap<Guid, SeenPlayer>, guid: &Guid) -> BTreeMap<Guid, BTreeSet<IpAddr>> {
+    let ips = match seen.get(guid) {
+        Some(player) => &player.ips,
+        None => return Default::default(),
+    };
+    seen.iter()
+        .filter(|(other, _)| *other != guid)
+        .map(|(other, player)| (*other, player.ips.intersection(ips).copied().collect::<BTreeSet<_>>()))
+        .filter(|(_, shared)| !shared.is_empty())
+        .collect()
+}
+
+lazy_static! {
+    /// Keeps concurrent saves from writing the same temporary file
+    static ref SAVE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct SeenMap(pub Arc<RwLock<BTreeMap<Guid, SeenPlayer>>>);
+
+impl SeenMap {
+    pub async fn record(&self, guid: Guid, name: &str, ip: Option<IpAddr>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let mut seen = self.0.write().await;
+        let player = seen.entry(guid).or_insert_with(|| SeenPlayer {
+            first_seen: now,
+            ..Default::default()
+        });
+        player.name = name.to_string();
+        player.names.insert(name.to_string());
+        player.ips.extend(ip);
+        player.last_seen = now;
+    }
+
+    pub async fn alts(&self, guid: &Guid) -> BTreeMap<Guid, BTreeSet<IpAddr>> {
+        alts(&*self.0.read().await, guid)
+    }
+
+    /// Players of a previous run from the history file, a missing file is fine
+    pub fn load(filename: &str) -> Result<Self> {
+        let seen: BTreeMap<Guid, SeenPlayer> = match std::fs::File::open(filename) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self(Arc::new(RwLock::new(seen))))
+    }
+
+    /// Write to a temporary file first and swap it in, so a crash can't leave a truncated file behind
+    pub async fn save(&self, filename: &str) -> Result<()> {
+        let _guard = SAVE_LOCK.lock().await;
+        let json = serde_json::to_string(&*self.0.read().await)?;
+        let temp = format!("{}.tmp", filename);
+        tokio::fs::write(&temp, json).await?;
+        tokio::fs::rename(&temp, filename).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cmds::console::SinglePlayerSelect;
+    use crate::settings::Settings;
+    use tokio::sync::broadcast;
+
+    fn guid(n: u8) -> Guid {
+        Guid::from([n; 16])
+    }
+
+    fn names() -> BiMap<Guid, String> {
+        ["Mario", "Luigi", "A,B", "!C", "Peach", "Peaches"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (guid(i as u8 + 1), name.to_string()))
+            .collect()
+    }
+
+    fn lobby() -> Lobby {
+        let settings = Arc::new(RwLock::new(Settings::default()));
+        Lobby::new(settings, mpsc::channel(1).0, broadcast::channel(1).0)
+    }
+
+    fn strings(selectors: &[&str]) -> Vec<String> {
+        selectors.iter().map(ToString::to_string).collect()
+    }
+
+    fn select(args: &[&str]) -> PlayerSelect<String> {
+        let players: Vec<SinglePlayerSelect> = args.iter().map(|arg| arg.parse().unwrap()).collect();
+        PlayerSelect::from(&players[..])
+    }
+
+    fn error(result: Result<Vec<Guid>>) -> String {
+        match result {
+            Err(SMOError::InvalidConsoleArg(msg)) => msg,
+            other => panic!("expected an invalid argument, got {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn parse_selectors() {
+        assert!(matches!(select(&["*"]), PlayerSelect::AllPlayers));
+        assert!(matches!(select(&["*", "Mario"]), PlayerSelect::AllPlayers));
+        assert!(matches!(select(&["!"]), PlayerSelect::AllPlayers));
+        assert!(matches!(select(&["!", "Mario", "Luigi"]), PlayerSelect::ExcludePlayers(p) if p == ["Mario", "Luigi"]));
+        assert!(matches!(select(&["Mario", "!Luigi"]), PlayerSelect::SelectPlayers(p) if p == ["Mario", "!Luigi"]));
+        assert!(matches!(select(&[]), PlayerSelect::SelectPlayers(p) if p.is_empty()));
+    }
+
+    #[test]
+    fn split_at_commas() {
+        let names = names();
+        assert_eq!(split_all(&names, &strings(&["Mario,Luigi", "Peach"])), ["Mario", "Luigi", "Peach"]);
+        assert_eq!(split_all(&names, &strings(&["A,B", "Mario,,"])), ["A,B", "Mario"]);
+    }
+
+    #[test]
+    fn resolve_names() {
+        let names = names();
+        assert_eq!(resolve_connected(&names, "Mario").unwrap(), [guid(1)]);
+        assert_eq!(resolve_connected(&names, "!C").unwrap(), [guid(4)]);
+        assert_eq!(resolve_connected(&names, "Peach").unwrap(), [guid(5)]);
+        assert_eq!(resolve_connected(&names, "lui").unwrap(), [guid(2)]);
+        assert_eq!(resolve_connected(&names, "peachE").unwrap(), [guid(6)]);
+        assert_eq!(error(resolve_connected(&names, "Bowser")), "Unknown player Bowser");
+
+        let msg = error(resolve_connected(&names, "pea"));
+        assert!(msg.starts_with("pea matches multiple players: Peach ("), "{}", msg);
+        assert!(msg.contains(", Peaches ("), "{}", msg);
+    }
+
+    #[test]
+    fn resolve_wildcards() {
+        let names = names();
+        let mut guids = resolve_connected(&names, "*i*").unwrap();
+        guids.sort();
+        assert_eq!(guids, [guid(1), guid(2)]);
+        let mut guids = resolve_connected(&names, "PEACH*").unwrap();
+        guids.sort();
+        assert_eq!(guids, [guid(5), guid(6)]);
+        assert_eq!(error(resolve_connected(&names, "Bow*")), "No player matches Bow*");
+    }
+
+    #[test]
+    fn resolve_guids() {
+        let names = names();
+        let mario = guid(1).to_string();
+        assert_eq!(resolve_connected(&names, &mario).unwrap(), [guid(1)]);
+        assert_eq!(resolve_connected(&names, "0202").unwrap(), [guid(2)]);
+        assert_eq!(resolve_connected(&names, "0303-03").unwrap(), [guid(3)]);
+        // too short for a guid prefix
+        assert_eq!(error(resolve_connected(&names, "020")), "Unknown player 020");
+        // unknown guids don't select anyone
+        assert_eq!(error(resolve_connected(&names, &guid(9).to_string())), format!("Unknown player {}", guid(9)));
+    }
+
+    #[test]
+    fn resolve_offline_candidates() {
+        let candidates = [(guid(1), "Mario"), (guid(7), "Toad"), (guid(8), "Toadette")];
+        assert_eq!(resolve_pattern(&candidates, "Toad*").unwrap(), [guid(7), guid(8)]);
+        assert_eq!(resolve_pattern(&candidates, "toadE").unwrap(), [guid(8)]);
+        assert!(error(resolve_pattern(&candidates, "Toa")).starts_with("Toa matches multiple players"));
+    }
+
+    #[test]
+    fn resolve_negated() {
+        let lobby = lobby();
+        let names = names();
+        assert_eq!(resolve_all(&lobby, &names, &strings(&["Mario,Luigi", "Mario"])).unwrap(), [guid(1), guid(2)]);
+        assert_eq!(resolve_all(&lobby, &names, &strings(&["Peach*", "!Peaches"])).unwrap(), [guid(5)]);
+        // the exact name wins over the negation
+        assert_eq!(resolve_all(&lobby, &names, &strings(&["!C"])).unwrap(), [guid(4)]);
+
+        let mut guids = resolve_all(&lobby, &names, &strings(&["!Mario,!Peach*"])).unwrap();
+        guids.sort();
+        assert_eq!(guids, [guid(2), guid(3), guid(4)]);
+    }
+
+    #[test]
+    fn resolve_filters() {
+        let lobby = lobby();
+        let names = names();
+        assert!(resolve_all(&lobby, &names, &strings(&["@tagged"])).unwrap().is_empty());
+        assert_eq!(error(resolve_all(&lobby, &names, &strings(&["@nope"]))), "Unknown selector @nope");
+        assert_eq!(error(resolve_all(&lobby, &names, &strings(&["@kingdom:Nope"]))), "Unknown kingdom Nope");
+    }
+}
@@ -0,0 +1,313 @@
+//! Host for sandboxed WASM plugins
+//!
+//! A plugin module exports its `memory`, an `alloc(len: i32) -> i32` function and an
+//! `on_event(ptr: i32, len: i32)` function, which gets called with a JSON encoded [`PluginEvent`]
+//! for every event the plugin subscribed to. From within `on_event` the plugin may call the
+//! imported functions `smoo.command(ptr: i32, len: i32) -> i32` with a JSON encoded
+//! [`PluginCommand`] (returns [`COMMAND_QUEUED`], [`COMMAND_DENIED`] or [`COMMAND_INVALID`]) and
+//! `smoo.log(ptr: i32, len: i32)` with an UTF-8 message.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, oneshot};
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::{
+    cmds::{ClientCommand, Command, ExternalCommand, PlayerCommand, Players, ServerWideCommand, ShineCommand},
+    guid::Guid,
+    lobby::Lobby,
+    net::{Packet, PacketData},
+    relay::Relay,
+    settings::PluginSettings,
+    types::Result,
+};
+
+pub const COMMAND_QUEUED: i32 = 0;
+pub const COMMAND_DENIED: i32 = 1;
+pub const COMMAND_INVALID: i32 = 2;
+
+/// Event passed to the `on_event` function of a plugin
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum PluginEvent {
+    #[serde(rename_all = "PascalCase")]
+    Connect { id: Guid, name: String },
+    #[serde(rename_all = "PascalCase")]
+    Disconnect { id: Guid },
+    #[serde(rename_all = "PascalCase")]
+    Game { id: Guid, stage: String, scenario: i8 },
+    #[serde(rename_all = "PascalCase")]
+    Costume { id: Guid, body: String, cap: String },
+    #[serde(rename_all = "PascalCase")]
+    Capture { id: Guid, model: String },
+    #[serde(rename_all = "PascalCase")]
+    Tag { id: Guid, is_it: bool },
+}
+
+impl PluginEvent {
+    fn from_packet(packet: &Packet) -> Option<Self> {
+        let id = packet.id;
+        let event = match &packet.data {
+            PacketData::Connect { client_name, .. } => Self::Connect { id, name: client_name.clone() },
+            PacketData::Disconnect => Self::Disconnect { id },
+            PacketData::Game { stage, scenario_num, .. } => Self::Game { id, stage: stage.clone(), scenario: *scenario_num },
+            PacketData::Costume(costume) => Self::Costume {
+                id,
+                body: costume.body_name.clone(),
+                cap: costume.cap_name.clone(),
+            },
+            PacketData::Capture { model } => Self::Capture { id, model: model.clone() },
+            PacketData::Tag { is_it, .. } => Self::Tag { id, is_it: *is_it },
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    /// Name used to subscribe to this event
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Connect { .. } => "Connect",
+            Self::Disconnect { .. } => "Disconnect",
+            Self::Game { .. } => "Game",
+            Self::Costume { .. } => "Costume",
+            Self::Capture { .. } => "Capture",
+            Self::Tag { .. } => "Tag",
+        }
+    }
+}
+
+/// Command a plugin can issue, targeting all players if `Players` is missing
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum PluginCommand {
+    #[serde(rename_all = "PascalCase")]
+    Send {
+        players: Option<Vec<Guid>>,
+        stage: String,
+        #[serde(default)]
+        id: String,
+        #[serde(default = "default_scenario")]
+        scenario: i8,
+    },
+    #[serde(rename_all = "PascalCase")]
+    Disconnect { players: Option<Vec<Guid>> },
+    #[serde(rename_all = "PascalCase")]
+    Crash { players: Option<Vec<Guid>> },
+    #[serde(rename_all = "PascalCase")]
+    Tag {
+        players: Option<Vec<Guid>>,
+        is_seeking: Option<bool>,
+        time: Option<(u16, u8)>,
+    },
+    #[serde(rename_all = "PascalCase")]
+    SendShine { players: Option<Vec<Guid>>, id: i32 },
+    SyncShines,
+    ClearShines,
+}
+
+fn default_scenario() -> i8 {
+    -1
+}
+
+impl PluginCommand {
+    /// Permission a plugin needs to issue this command
+    pub fn permission(&self) -> &'static str {
+        match self {
+            Self::Send { .. } => "Send",
+            Self::Disconnect { .. } => "Disconnect",
+            Self::Crash { .. } => "Crash",
+            Self::Tag { .. } => "Tag",
+            Self::SendShine { .. } => "SendShine",
+            Self::SyncShines => "SyncShines",
+            Self::ClearShines => "ClearShines",
+        }
+    }
+
+    fn into_external(self) -> ExternalCommand {
+        let players = |p: Option<Vec<Guid>>| p.map(Players::Individual).unwrap_or(Players::All);
+        let player = |players, command| ExternalCommand::Player { players, command };
+        match self {
+            Self::Send { players: p, stage, id, scenario } => player(players(p), PlayerCommand::Send { stage, id, scenario }),
+            Self::Disconnect { players: p } => player(players(p), PlayerCommand::Disconnect {}),
+            Self::Crash { players: p } => player(players(p), PlayerCommand::Crash {}),
+            Self::Tag { players: p, is_seeking, time } => player(players(p), PlayerCommand::Tag { time, is_seeking }),
+            Self::SendShine { players: p, id } => player(players(p), PlayerCommand::SendShine { id }),
+            Self::SyncShines => ExternalCommand::Shine { command: ShineCommand::Sync },
+            Self::ClearShines => ExternalCommand::Shine { command: ShineCommand::Clear },
+        }
+    }
+}
+
+struct PluginState {
+    settings: PluginSettings,
+    queued: Vec<ExternalCommand>,
+    limits: StoreLimits,
+}
+
+struct Plugin {
+    store: Store<PluginState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: TypedFunc<(i32, i32), ()>,
+}
+
+impl Plugin {
+    fn load(engine: &Engine, settings: PluginSettings) -> anyhow::Result<Self> {
+        let module = Module::from_file(engine, &settings.path)?;
+
+        let mut linker = Linker::new(engine);
+        linker.func_wrap("smoo", "command", host_command)?;
+        linker.func_wrap("smoo", "log", host_log)?;
+
+        let fuel = settings.fuel;
+        let limits = StoreLimitsBuilder::new().memory_size(settings.memory_limit).build();
+        let mut store = Store::new(
+            engine,
+            PluginState {
+                settings,
+                queued: Vec::new(),
+                limits,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(fuel)?;
+        let instance: Instance = linker.instantiate(&mut store, &module)?;
+
+        Ok(Self {
+            memory: instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow::anyhow!("Plugin doesn't export its memory"))?,
+            alloc: instance.get_typed_func(&mut store, "alloc")?,
+            on_event: instance.get_typed_func(&mut store, "on_event")?,
+            store,
+        })
+    }
+
+    fn is_subscribed(&self, event: &PluginEvent) -> bool {
+        let events = &self.store.data().settings.events;
+        events.is_empty() || events.contains(event.name())
+    }
+
+    /// Call the event handler with a fresh amount of fuel, returns the commands the plugin issued
+    fn handle_event(&mut self, event: &PluginEvent) -> anyhow::Result<Vec<ExternalCommand>> {
+        let json = serde_json::to_vec(event)?;
+        let fuel = self.store.data().settings.fuel;
+        self.store.set_fuel(fuel)?;
+
+        let ptr = self.alloc.call(&mut self.store, json.len() as i32)?;
+        self.memory.write(&mut self.store, ptr as usize, &json)?;
+        let result = self.on_event.call(&mut self.store, (ptr, json.len() as i32));
+
+        let queued = std::mem::take(&mut self.store.data_mut().queued);
+        result?;
+        Ok(queued)
+    }
+
+    fn name(&self) -> &str {
+        &self.store.data().settings.name
+    }
+}
+
+fn read_guest(caller: &mut Caller<'_, PluginState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let data = memory.data(caller);
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    data.get(start..end).map(|d| d.to_vec())
+}
+
+fn host_command(mut caller: Caller<'_, PluginState>, ptr: i32, len: i32) -> i32 {
+    let command = read_guest(&mut caller, ptr, len)
+        .and_then(|data| serde_json::from_slice::<PluginCommand>(&data).ok());
+    let state = caller.data_mut();
+    match command {
+        None => COMMAND_INVALID,
+        Some(command) if !state.settings.permissions.contains(command.permission()) => {
+            tracing::warn!(
+                "Plugin {} is missing the {} permission",
+                state.settings.name,
+                command.permission()
+            );
+            COMMAND_DENIED
+        }
+        Some(command) => {
+            state.queued.push(command.into_external());
+            COMMAND_QUEUED
+        }
+    }
+}
+
+fn host_log(mut caller: Caller<'_, PluginState>, ptr: i32, len: i32) {
+    if let Some(data) = read_guest(&mut caller, ptr, len) {
+        let name = &caller.data().settings.name;
+        tracing::info!("[{}] {}", name, String::from_utf8_lossy(&data));
+    }
+}
+
+/// Feed the events of the server to all plugins until the server shuts down
+pub async fn run_plugins(mut lobby: Lobby, cli_broadcast: Relay) -> Result<()> {
+    let settings = lobby.settings.read().await.plugins.clone();
+    if !settings.enabled || settings.plugins.is_empty() {
+        return Ok(());
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+
+    let mut plugins = Vec::new();
+    for plugin in settings.plugins {
+        let name = plugin.name.clone();
+        match Plugin::load(&engine, plugin) {
+            Ok(plugin) => {
+                tracing::info!("Loaded plugin {}", name);
+                plugins.push(plugin);
+            }
+            Err(e) => tracing::error!("Failed to load plugin {}: {}", name, e),
+        }
+    }
+
+    let mut cli_recv = cli_broadcast.subscribe();
+    loop {
+        let packet = tokio::select! {
+            cmd = cli_recv.recv() => match cmd {
+                Ok(ClientCommand::Packet(packet)) if !lobby.is_hidden(&packet).await => packet,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(amount)) => {
+                    tracing::warn!("Plugins skipped {} events", amount);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        };
+        let event = match PluginEvent::from_packet(&packet) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        for plugin in plugins.iter_mut().filter(|p| p.is_subscribed(&event)) {
+            let commands = match plugin.handle_event(&event) {
+                Ok(commands) => commands,
+                Err(e) => {
+                    tracing::warn!("Plugin {} failed to handle {} event: {}", plugin.name(), event.name(), e);
+                    continue;
+                }
+            };
+
+            for command in commands {
+                let (sender, recv) = oneshot::channel();
+                lobby.to_coord.send(Command::External(command, sender)).await?;
+                match recv.await? {
+                    Ok(reply) => tracing::debug!("[{}] {}", plugin.name(), reply),
+                    Err(e) => tracing::warn!("Command of plugin {} failed: {}", plugin.name(), e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Races along a route of stages from `Races`, run with `race start`
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::guid::Guid;
+
+/// A running race, the first stage of the route is the start and the last one the finish
+#[derive(Clone, Debug)]
+pub struct Race {
+    pub name: String,
+    pub route: Vec<String>,
+    pub started: Instant,
+    /// Index of the last stage of the route a player reached in order
+    pub reached: BTreeMap<Guid, usize>,
+    /// Players in the order they finished, with their times
+    pub finished: Vec<(Guid, String, Duration)>,
+}
+
+/// A player reaching the next stage of the route
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RaceProgress {
+    Checkpoint(usize),
+    Finished { place: usize, time: Duration },
+}
+
+impl Race {
+    pub fn new(name: &str, route: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            route,
+            started: Instant::now(),
+            reached: Default::default(),
+            finished: Default::default(),
+        }
+    }
+
+    /// Track a player entering a stage, stages out of order are ignored
+    pub fn enter(&mut self, id: Guid, name: &str, stage: &str) -> Option<RaceProgress> {
+        if self.finished.iter().any(|(finished, ..)| *finished == id) {
+            return None;
+        }
+        let reached = self.reached.entry(id).or_default();
+        if self.route.get(*reached + 1).map(String::as_str) != Some(stage) {
+            return None;
+        }
+        *reached += 1;
+        if *reached + 1 < self.route.len() {
+            return Some(RaceProgress::Checkpoint(*reached));
+        }
+
+        let time = self.started.elapsed();
+        self.finished.push((id, name.to_string(), time));
+        Some(RaceProgress::Finished {
+            place: self.finished.len(),
+            time,
+        })
+    }
+
+    /// Players still racing, the furthest first
+    pub fn racing(&self) -> Vec<(Guid, usize)> {
+        let mut racing: Vec<_> = self
+            .reached
+            .iter()
+            .filter(|(id, _)| !self.finished.iter().any(|(finished, ..)| finished == *id))
+            .map(|(id, reached)| (*id, *reached))
+            .collect();
+        racing.sort_by_key(|(_, reached)| std::cmp::Reverse(*reached));
+        racing
+    }
+}
+
+/// Best race times of the players, per race
+#[derive(Clone, Debug, Default)]
+pub struct Leaderboard {
+    best: BTreeMap<String, BTreeMap<Guid, (String, Duration)>>,
+}
+
+impl Leaderboard {
+    /// Remember the time if it's the best of the player, returns whether it was
+    pub fn record(&mut self, race: &str, id: Guid, name: &str, time: Duration) -> bool {
+        let times = self.best.entry(race.to_string()).or_default();
+        match times.get(&id) {
+            Some((_, best)) if *best <= time => false,
+            _ => {
+                times.insert(id, (name.to_string(), time));
+                true
+            }
+        }
+    }
+
+    /// Best times of the race, fastest first
+    pub fn standings(&self, race: &str) -> Vec<(String, Duration)> {
+        let mut times: Vec<_> = self.best.get(race).into_iter().flat_map(|times| times.values().cloned()).collect();
+        times.sort_by_key(|(_, time)| *time);
+        times
+    }
+}
+
+/// `m:ss.mmm`
+pub fn format_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!("{}:{:02}.{:03}", millis / 60_000, millis / 1000 % 60, millis % 1000)
+}
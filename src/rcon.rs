@@ -0,0 +1,208 @@
+//! Source RCON protocol, for the remote admin tools and web panels that speak it
+//!
+//! Authenticated connections run console commands like the console of the server, except that
+//! destructive commands don't wait for a `confirm`.
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::{Buf, BufMut, BytesMut};
+use clap::Parser;
+use ring::constant_time::verify_slices_are_equal;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::broadcast::error::RecvError,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    cmds::ServerWideCommand,
+    console::{redact_command, Cli, Console},
+    json_api::BlockClients,
+    lobby::{Lobby, LobbyView},
+    types::Result,
+};
+
+const SERVERDATA_AUTH: i32 = 3;
+const SERVERDATA_AUTH_RESPONSE: i32 = 2;
+const SERVERDATA_EXECCOMMAND: i32 = 2;
+const SERVERDATA_RESPONSE_VALUE: i32 = 0;
+
+/// Largest body of a response, longer replies are split over multiple packets
+const MAX_BODY_SIZE: usize = 4096;
+/// Largest packet accepted from a client, without the size field
+const MAX_PACKET_SIZE: i32 = 4096 + 10;
+/// How long a connection may take to authenticate
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct RconPacket {
+    id: i32,
+    kind: i32,
+    body: String,
+}
+
+pub struct Rcon {
+    lobby: Lobby,
+}
+
+impl Rcon {
+    pub fn new(lobby: Lobby) -> Self {
+        Self { lobby }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let rcon = settings.rcon.clone();
+        let addr = SocketAddr::new(settings.server.address.primary(), rcon.port);
+        drop(settings);
+
+        if !rcon.enabled {
+            return Ok(());
+        }
+        if rcon.password.is_empty() {
+            tracing::warn!("RCON is enabled without a password, not accepting any connections");
+            return Ok(());
+        }
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Accepting RCON connections on {}", listener.local_addr()?);
+
+        loop {
+            let (socket, addr) = select! {
+                conn = listener.accept() => conn?,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            let rcon = Self::new(self.lobby.clone());
+            tokio::spawn(async move {
+                match rcon.handle(socket, addr).await {
+                    Ok(()) => tracing::debug!("RCON client {} disconnected", addr),
+                    Err(e) => tracing::debug!("RCON client {} failed: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    async fn handle(mut self, mut socket: TcpStream, addr: SocketAddr) -> Result<()> {
+        let blocking = self.lobby.settings.read().await.json_api.blocking.clone();
+        if BlockClients::is_blocked(&addr, &blocking).await {
+            tracing::info!("Rejected blocked RCON client {}", addr.ip());
+            return Ok(());
+        }
+
+        let mut console = Console::new(LobbyView::new(&self.lobby))
+            .with_origin("rcon".to_string())
+            .with_address(addr.ip());
+        let mut authenticated = false;
+        let auth_deadline = Instant::now() + AUTH_TIMEOUT;
+        let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE as usize + 4);
+        loop {
+            let Some(packet) = decode_packet(&mut buff)? else {
+                select! {
+                    read = socket.read_buf(&mut buff) => if read? == 0 {
+                        return Ok(());
+                    },
+                    _ = sleep_until(auth_deadline), if !authenticated => {
+                        tracing::debug!("RCON client {} didn't authenticate in time", addr);
+                        return Ok(());
+                    },
+                    cmd = self.lobby.server_recv.recv() => match cmd {
+                        Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(()),
+                        Err(RecvError::Lagged(_)) => {}
+                    },
+                }
+                continue;
+            };
+
+            match packet.kind {
+                SERVERDATA_AUTH => {
+                    let password = self.lobby.settings.read().await.rcon.password.clone();
+                    if password.is_empty() || verify_slices_are_equal(packet.body.as_bytes(), password.as_bytes()).is_err() {
+                        tracing::warn!("Invalid RCON password from {}", addr.ip());
+                        BlockClients::fail(&addr, &blocking).await;
+                        write_packet(&mut socket, -1, SERVERDATA_AUTH_RESPONSE, "").await?;
+                        return Ok(());
+                    }
+                    BlockClients::redeem(&addr).await;
+                    authenticated = true;
+                    write_packet(&mut socket, packet.id, SERVERDATA_RESPONSE_VALUE, "").await?;
+                    write_packet(&mut socket, packet.id, SERVERDATA_AUTH_RESPONSE, "").await?;
+                }
+                _ if !authenticated => return Ok(()),
+                SERVERDATA_EXECCOMMAND => {
+                    let line = packet.body.trim();
+                    tracing::info!("RCON {}: {}", addr.ip(), redact_command(line));
+                    let reply = match Cli::try_parse_from(std::iter::once(">").chain(line.split_whitespace())) {
+                        Ok(cli) => match console.run_audited(line, cli).await {
+                            Ok(reply) => reply,
+                            Err(e) => format!("Error: {}", e),
+                        },
+                        Err(e) => e.render().to_string(),
+                    };
+                    for chunk in chunks(&reply) {
+                        write_packet(&mut socket, packet.id, SERVERDATA_RESPONSE_VALUE, chunk).await?;
+                    }
+                }
+                // sent after a command to find the end of a reply split over multiple packets
+                SERVERDATA_RESPONSE_VALUE => {
+                    write_packet(&mut socket, packet.id, SERVERDATA_RESPONSE_VALUE, "").await?;
+                }
+                kind => tracing::debug!("Ignoring RCON packet of unknown type {} from {}", kind, addr),
+            }
+        }
+    }
+}
+
+/// Take the next packet out of the received bytes, `None` until it was received completely
+fn decode_packet(buff: &mut BytesMut) -> Result<Option<RconPacket>> {
+    if buff.len() < 4 {
+        return Ok(None);
+    }
+    let size = i32::from_le_bytes([buff[0], buff[1], buff[2], buff[3]]);
+    if !(10..=MAX_PACKET_SIZE).contains(&size) {
+        return Err(anyhow::anyhow!("Invalid RCON packet size {}", size).into());
+    }
+    if buff.len() < size as usize + 4 {
+        return Ok(None);
+    }
+
+    let mut packet = buff.split_to(size as usize + 4);
+    packet.advance(4);
+    let id = packet.get_i32_le();
+    let kind = packet.get_i32_le();
+    // the body and an empty string, both null terminated
+    let body = String::from_utf8_lossy(&packet).trim_end_matches('\0').to_string();
+    Ok(Some(RconPacket { id, kind, body }))
+}
+
+async fn write_packet(socket: &mut TcpStream, id: i32, kind: i32, body: &str) -> Result<()> {
+    let mut buff = BytesMut::with_capacity(body.len() + 14);
+    buff.put_i32_le(body.len() as i32 + 10);
+    buff.put_i32_le(id);
+    buff.put_i32_le(kind);
+    buff.put_slice(body.as_bytes());
+    buff.put_u16(0);
+    socket.write_all(&buff).await?;
+    Ok(())
+}
+
+/// The reply split into bodies that fit into a packet, at least one even for an empty reply
+fn chunks(reply: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = reply;
+    while rest.len() > MAX_BODY_SIZE {
+        let mut end = MAX_BODY_SIZE;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+    chunks
+}
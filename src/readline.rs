@@ -0,0 +1,190 @@
+//! Console input, read on its own thread with line editing, a history and completion of commands, player names and stages
+
+use std::{
+    io::ErrorKind,
+    sync::{mpsc as std_mpsc, Mutex},
+    thread,
+};
+
+use clap::{Command, CommandFactory, Parser};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter, validate::Validator, Context,
+    Editor, Helper,
+};
+use lazy_static::lazy_static;
+use tokio::sync::mpsc;
+
+use crate::{
+    console::Cli,
+    lobby::Lobby,
+    output,
+    stages::Stages,
+    types::{Result, SMOError},
+};
+
+pub type ConsoleEditor = Editor<ConsoleHelper>;
+
+lazy_static! {
+    /// Input released by the last console, to be reused after a restart instead of a second thread reading stdin
+    static ref RELEASED: Mutex<Option<ConsoleInput>> = Mutex::new(None);
+}
+
+pub fn create_editor() -> Result<ConsoleEditor> {
+    let mut editor = Editor::new().map_err(anyhow::Error::new)?;
+    editor.set_helper(Some(ConsoleHelper {
+        lobby: None,
+        cli: Cli::command(),
+    }));
+    Ok(editor)
+}
+
+/// Reads the console on its own thread, which never holds up the shutdown of the runtime
+///
+/// The thread only prompts for a line when asked to, so the reply to a command is printed before the next prompt.
+/// It stops once the `ConsoleInput` is dropped and no line is being read anymore.
+pub struct ConsoleInput {
+    requests: std_mpsc::Sender<Lobby>,
+    lines: mpsc::Receiver<Result<Option<(String, Cli)>>>,
+    pending: bool,
+}
+
+impl ConsoleInput {
+    /// The input released by a previous console or a new one
+    pub fn take() -> Result<Self> {
+        match RELEASED.lock().expect("Console input lock poisoned").take() {
+            Some(input) => Ok(input),
+            None => Self::spawn(),
+        }
+    }
+
+    /// Keep the input around for the next console
+    pub fn release(self) {
+        *RELEASED.lock().expect("Console input lock poisoned") = Some(self);
+    }
+
+    fn spawn() -> Result<Self> {
+        let mut editor = create_editor()?;
+        // not available when stdin or stdout isn't a terminal, then the output can't clobber a prompt anyway
+        if let Ok(printer) = editor.create_external_printer() {
+            output::set_printer(printer);
+        }
+        let (requests, request_recv) = std_mpsc::channel::<Lobby>();
+        let (line_send, lines) = mpsc::channel(1);
+        thread::Builder::new().name("console".to_string()).spawn(move || {
+            while let Ok(lobby) = request_recv.recv() {
+                if let Some(helper) = editor.helper_mut() {
+                    helper.lobby = Some(lobby);
+                }
+                if line_send.blocking_send(read_line(&mut editor)).is_err() {
+                    break;
+                }
+            }
+        })?;
+
+        Ok(Self {
+            requests,
+            lines,
+            pending: false,
+        })
+    }
+
+    /// Next command with the line it was parsed from, `None` for an empty line, a `ServerShutdown` error for Ctrl-C
+    /// and an `UnexpectedEof` error once the input is closed
+    ///
+    /// Cancel safe, a line requested by a dropped call is returned by the next call.
+    /// Player names are completed from the given lobby.
+    pub async fn next(&mut self, lobby: &Lobby) -> Result<Option<(String, Cli)>> {
+        if !self.pending {
+            self.requests.send(lobby.clone()).map_err(|_| closed())?;
+            self.pending = true;
+        }
+        let line = self.lines.recv().await;
+        self.pending = false;
+        line.unwrap_or_else(|| Err(closed()))
+    }
+}
+
+fn closed() -> SMOError {
+    std::io::Error::from(ErrorKind::UnexpectedEof).into()
+}
+
+fn read_line(editor: &mut ConsoleEditor) -> Result<Option<(String, Cli)>> {
+    let line = match editor.readline("> ") {
+        Ok(line) => line,
+        Err(ReadlineError::Interrupted) => return Err(SMOError::ServerShutdown),
+        Err(ReadlineError::Eof) => return Err(closed()),
+        Err(ReadlineError::Io(e)) => return Err(e.into()),
+        Err(e) => return Err(anyhow::Error::new(e).into()),
+    };
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    editor.add_history_entry(line.trim());
+
+    let cli = Cli::try_parse_from(std::iter::once(">").chain(line.split_whitespace()))?;
+    Ok(Some((line, cli)))
+}
+
+pub struct ConsoleHelper {
+    lobby: Option<Lobby>,
+    cli: Command,
+}
+
+impl ConsoleHelper {
+    /// Subcommands while the previous words name a command with subcommands, otherwise players and stages
+    fn candidates(&self, previous: &[&str]) -> Vec<String> {
+        let mut cmd = &self.cli;
+        for word in previous.iter().filter(|w| !w.starts_with('-')) {
+            match cmd.find_subcommand(word) {
+                Some(sub) => cmd = sub,
+                None => return self.arguments(),
+            }
+        }
+
+        if cmd.has_subcommands() {
+            cmd.get_subcommands().map(|c| c.get_name().to_string()).collect()
+        } else {
+            self.arguments()
+        }
+    }
+
+    fn arguments(&self) -> Vec<String> {
+        let mut args: Vec<String> = match &self.lobby {
+            Some(lobby) => lobby.players.iter().map(|p| p.name.clone()).collect(),
+            None => Vec::new(),
+        };
+        args.extend(Stages::aliases());
+        args.extend(Stages::stages().into_iter().map(str::to_string));
+        args
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let (previous, word) = line.split_at(start);
+        let previous: Vec<_> = previous.split_whitespace().collect();
+
+        let mut candidates: Vec<_> = self
+            .candidates(&previous)
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
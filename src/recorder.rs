@@ -0,0 +1,74 @@
+//! Records the packets passing through the coordinator, for analysing desyncs or playing them back later
+//!
+//! A recording starts with [`RECORDING_MAGIC`], followed by one entry per packet: the milliseconds
+//! since the start of the recording as little endian `u64` and the packet in its network encoding.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use bytes::BytesMut;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    time::Instant,
+};
+
+use crate::{
+    net::{encoding::Encodable, Packet, MAX_PACKET_SIZE},
+    sampling::PacketSampler,
+    types::Result,
+};
+
+pub const RECORDING_MAGIC: &[u8; 4] = b"SMOR";
+
+pub struct Recorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    started: Instant,
+    sampler: PacketSampler,
+    packets: u64,
+}
+
+impl Recorder {
+    pub async fn create(path: PathBuf) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut file = BufWriter::new(File::create(&path).await?);
+        file.write_all(RECORDING_MAGIC).await?;
+
+        Ok(Self {
+            path,
+            file,
+            started: Instant::now(),
+            sampler: Default::default(),
+            packets: 0,
+        })
+    }
+
+    /// Append the packet, unless it's skipped by the sampling rates of its packet type
+    pub async fn record(&mut self, packet: &Packet, rates: &BTreeMap<String, u32>) -> Result<()> {
+        if !self.sampler.sample(rates, packet.data.get_type_name()) {
+            return Ok(());
+        }
+
+        let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE + 8);
+        buff.extend_from_slice(&(self.started.elapsed().as_millis() as u64).to_le_bytes());
+        packet.encode(&mut buff)?;
+        self.file.write_all(&buff).await?;
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// Flush the recording, returns the amount of recorded packets
+    pub async fn finish(mut self) -> Result<u64> {
+        self.file.flush().await?;
+        Ok(self.packets)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
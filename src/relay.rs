@@ -0,0 +1,352 @@
+//! Fan-out of the packets and commands for all clients
+//!
+//! Every client has its own bounded queue, so a slow client only loses its own movement packets
+//! instead of making everyone lag behind. Everything else is always delivered, or else the client disconnected.
+//! Packets are encoded once when sent, instead of once per client.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem::{discriminant, Discriminant},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use tokio::sync::{broadcast, Notify};
+
+use crate::{
+    cmds::ClientCommand,
+    guid::Guid,
+    net::{encoding::Encodable, Packet, PacketData, MAX_PACKET_SIZE},
+};
+
+#[derive(Clone, Debug)]
+pub struct Relay {
+    queues: Arc<DashMap<Guid, Arc<SendQueue>>>,
+    taps: broadcast::Sender<ClientCommand>,
+}
+
+impl Relay {
+    pub fn new(tap_capacity: usize) -> Self {
+        Self {
+            queues: Default::default(),
+            taps: broadcast::channel(tap_capacity).0,
+        }
+    }
+
+    /// Queue the command for all clients
+    pub fn send(&self, cmd: ClientCommand) {
+        let outgoing = Outgoing::new(cmd.clone());
+        for queue in self.queues.iter() {
+            queue.push(outgoing.clone());
+        }
+        let _ = self.taps.send(cmd);
+    }
+
+    /// Everything sent to all clients, for tasks that watch or forward it, lagging behind only loses commands of that receiver
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientCommand> {
+        self.taps.subscribe()
+    }
+
+    /// Queue of a newly connected client, replacing that of a previous connection
+    pub fn register(&self, guid: Guid, capacity: usize) -> Arc<SendQueue> {
+        let queue = Arc::new(SendQueue::new(capacity));
+        self.queues.insert(guid, queue.clone());
+        queue
+    }
+
+    pub fn unregister(&self, guid: &Guid, queue: &Arc<SendQueue>) {
+        self.queues.remove_if(guid, |_, registered| Arc::ptr_eq(registered, queue));
+    }
+}
+
+/// A command for all clients, packets come with their encoding shared by all of them
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+    pub command: ClientCommand,
+    pub encoded: Option<Bytes>,
+}
+
+impl Outgoing {
+    fn new(command: ClientCommand) -> Self {
+        let encoded = match &command {
+            ClientCommand::Packet(packet) => {
+                let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+                // clients encode it themselves on errors, to log them
+                packet.encode(&mut buff).ok().map(|_| buff.freeze())
+            }
+            ClientCommand::SelfAddressed(_) => None,
+        };
+        Self { command, encoded }
+    }
+}
+
+#[derive(Debug)]
+pub struct SendQueue {
+    commands: Mutex<VecDeque<Outgoing>>,
+    notify: Notify,
+    capacity: usize,
+    dropped: AtomicU64,
+    /// Set once a command couldn't be queued that mustn't be dropped
+    overflowed: AtomicBool,
+}
+
+impl SendQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            commands: Mutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+            capacity,
+            dropped: Default::default(),
+            overflowed: Default::default(),
+        }
+    }
+
+    /// Movement replaces the queued movement of the same player that it supersedes.
+    /// When full, the oldest movement makes room for other commands, or else the queue overflows and stops delivering.
+    pub fn push(&self, cmd: Outgoing) {
+        let mut commands = self.commands.lock().expect("Send queue lock poisoned");
+        if self.overflowed.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(source) = movement_of(&cmd) {
+            if let Some(older) = commands.iter().position(|queued| movement_of(queued) == Some(source)) {
+                commands.remove(older);
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if commands.len() >= self.capacity {
+            if movement_of(&cmd).is_some() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            match commands.iter().position(|queued| movement_of(queued).is_some()) {
+                Some(oldest) => {
+                    commands.remove(oldest);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    commands.clear();
+                    self.overflowed.store(true, Ordering::Relaxed);
+                    drop(commands);
+                    self.notify.notify_one();
+                    return;
+                }
+            }
+        }
+        commands.push_back(cmd);
+        drop(commands);
+        self.notify.notify_one();
+    }
+
+    /// Next command, `None` once the queue overflowed and the client should be disconnected, cancel safe
+    pub async fn recv(&self) -> Option<Outgoing> {
+        loop {
+            {
+                let mut commands = self.commands.lock().expect("Send queue lock poisoned");
+                if self.overflowed.load(Ordering::Relaxed) {
+                    return None;
+                }
+                if let Some(cmd) = commands.pop_front() {
+                    return Some(cmd);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Amount of movement packets dropped, because the client didn't keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Player and packet type of movement, which is outdated by the next such packet anyway
+fn movement_of(cmd: &Outgoing) -> Option<(Guid, Discriminant<PacketData>)> {
+    match &cmd.command {
+        ClientCommand::Packet(p) if matches!(p.data, PacketData::Player { .. } | PacketData::Cap { .. }) => {
+            Some((p.id, discriminant(&p.data)))
+        }
+        _ => None,
+    }
+}
+
+/// Limits how often the movement of a player gets forwarded, coalescing the packets in between to the latest one
+#[derive(Debug, Default)]
+pub struct Throttle {
+    /// Per packet type, when it was last forwarded and the latest packet since then
+    sent: BTreeMap<String, (Instant, Option<Packet>)>,
+    interval: Duration,
+}
+
+impl Throttle {
+    /// Returns the packet if it may be forwarded now, otherwise it's kept until `due`
+    pub fn offer(&mut self, packet: Packet, max_rate: u32) -> Option<Packet> {
+        if max_rate == 0 {
+            self.sent.clear();
+            return Some(packet);
+        }
+        self.interval = Duration::from_secs(1) / max_rate;
+
+        let now = Instant::now();
+        let name = packet.data.get_type_name();
+        match self.sent.get_mut(&name) {
+            Some((last, pending)) if now.duration_since(*last) < self.interval => {
+                *pending = Some(packet);
+                None
+            }
+            _ => {
+                self.sent.insert(name, (now, None));
+                Some(packet)
+            }
+        }
+    }
+
+    /// When the next kept packet may be forwarded
+    pub fn due(&self) -> Option<Instant> {
+        self.sent
+            .values()
+            .filter(|(_, pending)| pending.is_some())
+            .map(|(last, _)| *last + self.interval)
+            .min()
+    }
+
+    /// Kept packets that may be forwarded now
+    pub fn take_due(&mut self) -> Vec<Packet> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (last, pending) in self.sent.values_mut() {
+            if now.duration_since(*last) >= self.interval {
+                if let Some(packet) = pending.take() {
+                    *last = now;
+                    due.push(packet);
+                }
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::types::{Quaternion, Vector3};
+
+    fn movement(id: u8) -> Packet {
+        let data = PacketData::Player {
+            pos: Vector3::zeros(),
+            rot: Quaternion::identity(),
+            animation_blend_weights: [0.0; 6],
+            act: 0,
+            sub_act: 0,
+        };
+        Packet::new(Guid::from([id; 16]), data)
+    }
+
+    fn game(scenario_num: i8) -> Packet {
+        let data = PacketData::Game {
+            is_2d: false,
+            scenario_num,
+            stage: "CapWorldHomeStage".to_string(),
+        };
+        Packet::new(Guid::NIL, data)
+    }
+
+    fn queued(queue: &SendQueue) -> Vec<String> {
+        let commands = queue.commands.lock().unwrap();
+        commands
+            .iter()
+            .map(|cmd| match &cmd.command {
+                ClientCommand::Packet(p) => p.data.get_type_name(),
+                _ => "other".to_string(),
+            })
+            .collect()
+    }
+
+    fn push(queue: &SendQueue, packet: Packet) {
+        queue.push(Outgoing::new(ClientCommand::Packet(packet)));
+    }
+
+    #[test]
+    fn movement_supersedes_movement_of_same_player() {
+        let queue = SendQueue::new(8);
+        push(&queue, movement(1));
+        push(&queue, movement(2));
+        push(&queue, game(1));
+        push(&queue, movement(1));
+        assert_eq!(queued(&queue), ["player", "game", "player"]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn full_queue_drops_movement() {
+        let queue = SendQueue::new(2);
+        push(&queue, movement(1));
+        push(&queue, game(1));
+        push(&queue, game(2));
+        assert_eq!(queued(&queue), ["game", "game"]);
+        assert_eq!(queue.dropped(), 1);
+
+        // without movement to make room, new movement is dropped
+        push(&queue, movement(2));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queued(&queue), ["game", "game"]);
+    }
+
+    #[tokio::test]
+    async fn full_queue_overflows() {
+        let queue = SendQueue::new(2);
+        push(&queue, game(1));
+        push(&queue, game(2));
+        assert!(queue.recv().await.is_some());
+        push(&queue, game(3));
+
+        push(&queue, game(4));
+        assert!(queued(&queue).is_empty());
+        assert!(queue.recv().await.is_none());
+        // nothing gets queued anymore
+        push(&queue, movement(1));
+        assert!(queue.recv().await.is_none());
+    }
+
+    #[test]
+    fn packets_are_encoded_once() {
+        let outgoing = Outgoing::new(ClientCommand::Packet(game(1)));
+        let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+        game(1).encode(&mut buff).unwrap();
+        assert_eq!(outgoing.encoded, Some(buff.freeze()));
+    }
+
+    #[test]
+    fn throttle_coalesces_to_latest() {
+        let mut throttle = Throttle::default();
+        assert!(throttle.offer(game(1), 10).is_some());
+        assert!(throttle.offer(game(2), 10).is_none());
+        assert!(throttle.offer(game(3), 10).is_none());
+        // other packet types have their own interval
+        assert!(throttle.offer(movement(1), 10).is_some());
+        assert!(throttle.take_due().is_empty());
+        assert!(throttle.due().is_some());
+
+        sleep(Duration::from_millis(110));
+        let due = throttle.take_due();
+        assert_eq!(due.len(), 1);
+        assert!(matches!(due[0].data, PacketData::Game { scenario_num: 3, .. }));
+        assert_eq!(throttle.due(), None);
+    }
+
+    #[test]
+    fn unthrottled() {
+        let mut throttle = Throttle::default();
+        for scenario in 0..5 {
+            assert!(throttle.offer(game(scenario), 0).is_some());
+        }
+        assert_eq!(throttle.due(), None);
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Picks every n-th packet of each packet type, with the rates configured per packet type name
+///
+/// Packet types without a configured rate are always picked, a rate of `0` never picks a packet.
+#[derive(Debug, Default)]
+pub struct PacketSampler {
+    counters: HashMap<String, u64>,
+}
+
+impl PacketSampler {
+    pub fn sample(&mut self, rates: &BTreeMap<String, u32>, packet_type: String) -> bool {
+        let rate = match rates.get(&packet_type) {
+            Some(0) => return false,
+            Some(rate) => u64::from(*rate),
+            None => return true,
+        };
+
+        // count down the packets to skip until the next one gets picked
+        let skip = self.counters.entry(packet_type).or_default();
+        if *skip == 0 {
+            *skip = rate - 1;
+            true
+        } else {
+            *skip -= 1;
+            false
+        }
+    }
+}
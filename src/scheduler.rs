@@ -0,0 +1,213 @@
+//! Runs the console commands of `Scheduler.Tasks` at their configured times
+//!
+//! Changes to the tasks take effect after a restart of the server.
+
+use std::{str::FromStr, time::Duration};
+
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike};
+use clap::Parser;
+use tokio::{select, sync::broadcast::error::RecvError, time::sleep};
+
+use crate::{
+    cmds::ServerWideCommand,
+    console::{Cli, Console},
+    lobby::{Lobby, LobbyView},
+    settings::ScheduledTask,
+    types::{Result, SMOError},
+};
+
+/// Times a task runs at, parsed from a cron expression `minute hour day-of-month month day-of-week`
+///
+/// Every field is `*`, a value, a range `a-b` or a list of them `a,b-c`, each optionally with a step `*/15`.
+/// Days of the week go from 0 (Sunday) to 6, 7 is Sunday as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Both days were restricted, then either of them has to match like in cron
+    either_day: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = SMOError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(SMOError::InvalidConsoleArg(format!("Expected 5 fields in the schedule {}", s)));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is another name for sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+}
+
+/// Bit mask of the values the field allows
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let invalid = || SMOError::InvalidConsoleArg(format!("Invalid schedule field {}", field));
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?),
+            // a single value with a step runs from there to the end
+            None if part.contains('/') => (range.parse().map_err(|_| invalid())?, max),
+            None => {
+                let value = range.parse().map_err(|_| invalid())?;
+                (value, value)
+            }
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl Schedule {
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let has = |mask: u64, value: u32| mask & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = if self.either_day { day || weekday } else { day && weekday };
+        day && has(self.minutes, time.minute()) && has(self.hours, time.hour()) && has(self.months, time.month())
+    }
+}
+
+/// Check the tasks at the start of every minute until the server shuts down
+pub async fn run(mut lobby: Lobby) -> Result<()> {
+    let settings = lobby.settings.read().await.scheduler.clone();
+    if !settings.enabled {
+        return Ok(());
+    }
+
+    let mut tasks = Vec::new();
+    for task in settings.tasks {
+        match task.cron.parse::<Schedule>() {
+            Ok(schedule) => tasks.push((schedule, task)),
+            Err(e) => tracing::warn!("Skipping scheduled task {}: {}", task.name, e),
+        }
+    }
+    if tasks.is_empty() {
+        return Ok(());
+    }
+
+    loop {
+        let millis = Local::now().timestamp_millis().rem_euclid(60_000) as u64;
+        select! {
+            _ = sleep(Duration::from_millis(60_000 - millis)) => {},
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        }
+
+        let now = Local::now();
+        for (schedule, task) in &tasks {
+            if schedule.matches(&now) {
+                execute(&lobby, task.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn execute(lobby: &Lobby, task: ScheduledTask) {
+    tracing::info!("Running scheduled task {}: {}", task.name, task.command);
+    let view = LobbyView::new(lobby);
+    tokio::spawn(async move {
+        let args = std::iter::once(">").chain(task.command.split_whitespace());
+        let result = match Cli::try_parse_from(args) {
+            Ok(cli) => Console::new(view).with_origin(format!("scheduler:{}", task.name)).process_command(cli).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(reply) => tracing::info!("Scheduled task {}: {}", task.name, reply),
+            Err(e) => tracing::warn!("Scheduled task {} failed: {}", task.name, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn at(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2026, month, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn every_minute() {
+        let schedule: Schedule = "* * * * *".parse().unwrap();
+        assert!(schedule.matches(&at(1, 1, 0, 0)));
+        assert!(schedule.matches(&at(10, 14, 23, 59)));
+    }
+
+    #[test]
+    fn values_ranges_and_lists() {
+        assert_eq!(parse_field("5", 0, 59).unwrap(), 1 << 5);
+        assert_eq!(parse_field("1-3", 0, 59).unwrap(), 0b1110);
+        assert_eq!(parse_field("1,4-5", 0, 59).unwrap(), 0b110010);
+
+        let schedule: Schedule = "30 4 * * *".parse().unwrap();
+        assert!(schedule.matches(&at(10, 14, 4, 30)));
+        assert!(!schedule.matches(&at(10, 14, 5, 30)));
+        assert!(!schedule.matches(&at(10, 14, 4, 31)));
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(parse_field("*/15", 0, 59).unwrap(), 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(parse_field("10-20/5", 0, 59).unwrap(), 1 << 10 | 1 << 15 | 1 << 20);
+        // a single value with a step runs to the end of the field
+        assert_eq!(parse_field("20/2", 0, 23).unwrap(), 1 << 20 | 1 << 22);
+    }
+
+    #[test]
+    fn weekdays() {
+        // 2026-10-14 is a wednesday, 2026-10-18 a sunday
+        let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday, "0 0 * * 0".parse().unwrap());
+        assert!(sunday.matches(&at(10, 18, 0, 0)));
+        assert!(!sunday.matches(&at(10, 14, 0, 0)));
+
+        // with both days restricted either of them matches
+        let either: Schedule = "0 0 1 * 3".parse().unwrap();
+        assert!(either.matches(&at(10, 14, 0, 0)));
+        assert!(either.matches(&at(11, 1, 0, 0)));
+        assert!(!either.matches(&at(10, 15, 0, 0)));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for cron in ["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8"] {
+            assert!(cron.parse::<Schedule>().is_err(), "{}", cron);
+        }
+        for field in ["", "a", "5-", "-5", "5-1", "*/0", "*/x", "1,,2"] {
+            assert!(parse_field(field, 0, 59).is_err(), "{}", field);
+        }
+    }
+}
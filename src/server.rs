@@ -1,35 +1,77 @@
 use crate::{
-    cmds::ClientCommand,
     console::Console,
-    coordinator::{load_shines, Coordinator, ShineBag},
+    coordinator::{autosave_shines, load_shines, Coordinator, ShineBag},
+    discord::DiscordNotifier,
+    discovery,
+    events::ServerEvent,
+    failover::Failover,
     json_api::JsonApi,
+    link::Link,
     listener::Listener,
     lobby::{Lobby, LobbyView},
+    master_server,
+    mirror::Mirror,
+    npc::NpcDriver,
+    overlay::Overlay,
+    player_holder::SeenMap,
+    rcon::Rcon,
+    relay::Relay,
+    scheduler,
     settings::Settings,
+    shines::Shines,
+    snapshot,
+    webhooks::Webhooks,
+    websocket::WebSocketBridge,
     types::Result,
 };
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use tokio::sync::{broadcast, mpsc, RwLock};
 
+/// Set when the next shutdown should end the process instead of restarting the server
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn request_exit() {
+    EXIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn exit_requested() -> bool {
+    EXIT_REQUESTED.load(Ordering::SeqCst)
+}
+
 pub struct Server {
     pub lobby: Lobby,
-    pub cli_broadcast: broadcast::Sender<ClientCommand>,
+    pub cli_broadcast: Relay,
     pub listener: Listener,
     pub coord: Coordinator,
+    pub console_enabled: bool,
+    /// Additional lobbies reachable through the `lobby` console command
+    pub lobbies: BTreeMap<String, LobbyView>,
 }
 
 impl Server {
-    pub fn build_server(settings: Settings) -> Server {
+    pub fn build_server(mut settings: Settings) -> Server {
         let (to_coord, from_clients) = mpsc::channel(100);
 
-        let local_bind_addr = SocketAddr::new(settings.server.address, settings.server.port);
+        let local_bind_addrs =
+            settings.server.address.all().into_iter().map(|address| SocketAddr::new(address, settings.server.port)).collect();
 
-        let _shines = if settings.persist_shines.enabled {
+        let shines = if settings.persist_shines.enabled {
             let result = load_shines(&settings.persist_shines.filename);
 
             match result {
-                Ok(bag) => bag,
+                Ok(persisted) => {
+                    // still excluded, even if the settings lost the exclusion
+                    settings.shines.excluded.extend(persisted.excluded);
+                    persisted.shines
+                }
                 Err(e) => {
                     tracing::warn!(
                         "Failed to load shine bag using empty shine bag instead: {}",
@@ -41,21 +83,40 @@ impl Server {
         } else {
             ShineBag::default()
         };
+        // the names are shared by all lobbies
+        if settings.lobby.is_none() {
+            Shines::reload(&settings.shines);
+        }
+        let history = if settings.history.enabled {
+            match SeenMap::load(&settings.history.filename) {
+                Ok(seen) => Some(seen),
+                Err(e) => {
+                    tracing::warn!("Failed to load the player history from {}: {}", settings.history.filename, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
         let udp_ports = Some((settings.udp.base_port, settings.udp.port_count));
 
         let settings = Arc::new(RwLock::new(settings));
-        let (cli_broadcast, _) = broadcast::channel(100);
+        let cli_broadcast = Relay::new(100);
 
         let (serv_send, serv_recv) = broadcast::channel(1);
 
-        let lobby = Lobby::new(settings, to_coord, serv_send);
+        let mut lobby = Lobby::new(settings, to_coord, serv_send);
+        lobby.shines = Arc::new(RwLock::new(shines));
+        if let Some(seen) = history {
+            lobby.seen = seen;
+        }
         let listener = Listener {
             server_broadcast: serv_recv,
             cli_broadcast: cli_broadcast.clone(),
 
-            tcp_bind_addr: local_bind_addr,
+            tcp_bind_addrs: local_bind_addrs,
             udp_port_addrs: udp_ports,
-            listener: None,
+            listeners: Vec::new(),
             lobby: lobby.clone(),
         };
 
@@ -66,6 +127,8 @@ impl Server {
             coord,
             cli_broadcast,
             lobby,
+            console_enabled: true,
+            lobbies: Default::default(),
         }
     }
 
@@ -81,22 +144,74 @@ impl Server {
         Ok(())
     }
 
-    pub async fn spawn_full_server(self) -> Result<()> {
+    pub async fn spawn_full_server(mut self) -> Result<()> {
         let view = LobbyView::new(&self.lobby);
-        let console = Console::new(view.clone());
-        let json_api = JsonApi::create(view).await?;
-        let serv_task = tokio::task::spawn(self.listener.listen_for_clients());
+        // ends once the coordinator is gone, after saving the shines still queued
+        let shine_task = self.coord.take_shine_sync().map(|shine_sync| tokio::task::spawn(shine_sync.run()));
         let coord_task = tokio::task::spawn(self.coord.handle_commands());
-        let parser_task = tokio::task::spawn(console.loop_read_commands());
+        let console_enabled = self.console_enabled;
+        let lobbies = self.lobbies;
+        let parser_task = tokio::task::spawn(async move {
+            if console_enabled {
+                Console::new(view).with_lobbies(lobbies).loop_read_commands().await
+            } else {
+                Ok(())
+            }
+        });
+
+        // a standby server only starts serving clients once its primary is gone
+        let failover = Failover::new(self.lobby.clone(), self.cli_broadcast.clone());
+        if !failover.wait_for_takeover().await? {
+            let _results = tokio::join!(coord_task, parser_task);
+            if let Some(shine_task) = shine_task {
+                let _result = shine_task.await;
+            }
+            return Ok(());
+        }
+
+        let _webhook_task = tokio::task::spawn(Webhooks::new(self.lobby.clone()).run());
+        let _discord_task = tokio::task::spawn(DiscordNotifier::new(self.lobby.clone()).run());
+        let json_api = JsonApi::create(LobbyView::new(&self.lobby)).await?;
+        let serv_task = tokio::task::spawn(self.listener.listen_for_clients());
         if let Some(api) = json_api {
             let _api_task = tokio::task::spawn(api.loop_events());
         }
-
-        let _results = tokio::join!(serv_task, coord_task, parser_task);
+        let _snapshot_task = tokio::task::spawn(snapshot::run(self.lobby.clone()));
+        let _scheduler_task = tokio::task::spawn(scheduler::run(self.lobby.clone()));
+        let autosave_task = tokio::task::spawn(autosave_shines(self.lobby.clone()));
+        let _overlay_task = tokio::task::spawn(Overlay::new(self.lobby.clone()).run());
+        let _discovery_task = tokio::task::spawn(discovery::announce(self.lobby.clone()));
+        let _master_task = tokio::task::spawn(master_server::announce(self.lobby.clone()));
+        let _mirror_task =
+            tokio::task::spawn(Mirror::new(self.lobby.clone(), self.cli_broadcast.clone()).run());
+        let _npc_task =
+            tokio::task::spawn(NpcDriver::new(self.lobby.clone(), self.cli_broadcast.clone()).run());
+        let _failover_task = tokio::task::spawn(
+            Failover::new(self.lobby.clone(), self.cli_broadcast.clone()).run_primary(),
+        );
+        #[cfg(feature = "plugins")]
+        let _plugin_task = tokio::task::spawn(crate::plugin::run_plugins(
+            self.lobby.clone(),
+            self.cli_broadcast.clone(),
+        ));
+        #[cfg(not(feature = "plugins"))]
+        if self.lobby.settings.read().await.plugins.enabled {
+            tracing::warn!("Plugins are enabled, but the server was built without the plugins feature");
+        }
+        let _websocket_task =
+            tokio::task::spawn(WebSocketBridge::new(self.lobby.clone(), self.cli_broadcast.clone()).run());
+        let _rcon_task = tokio::task::spawn(Rcon::new(self.lobby.clone()).run());
+        let _link_task = tokio::task::spawn(Link::new(self.lobby.clone(), self.cli_broadcast).run());
+        self.lobby.emit(ServerEvent::ServerStart);
+
+        let _results = tokio::join!(serv_task, coord_task, parser_task, autosave_task);
+        if let Some(shine_task) = shine_task {
+            let _result = shine_task.await;
+        }
         Ok(())
     }
 
     pub fn get_bind_addr(&self) -> SocketAddr {
-        self.listener.tcp_bind_addr
+        self.listener.tcp_bind_addrs[0]
     }
 }
@@ -0,0 +1,154 @@
+//! Snapshots of the session state, to restore it for the players reconnecting after a restart or crash
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    coordinator::ShineBag,
+    failover::ReplicatedPlayer,
+    guid::Guid,
+    lobby::Lobby,
+    net::{
+        encoding::{Decodable, Encodable},
+        Packet, PacketData, MAX_PACKET_SIZE,
+    },
+    types::{Result, SMOError},
+};
+
+/// Version of the session file format
+pub const SESSION_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionSnapshot {
+    pub version: u32,
+    /// Seconds since the unix epoch when the snapshot was taken
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub shines: ShineBag,
+    /// Seconds since the unix epoch when the current round of hide and seek was started
+    pub tag_round: Option<u64>,
+    pub players: BTreeMap<Guid, SessionPlayer>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionPlayer {
+    pub name: String,
+    pub scenario: i8,
+    pub is_seeking: Option<bool>,
+    /// Hide and seek time in seconds
+    pub time: Option<u64>,
+    /// Hex encoded costume, capture, game and player packets
+    pub packets: Vec<String>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl SessionSnapshot {
+    pub async fn capture(lobby: &Lobby) -> Result<Self> {
+        let mut players = BTreeMap::new();
+        for player in lobby.players.iter() {
+            let cached = [
+                &player.last_costume_packet,
+                &player.last_capture_packet,
+                &player.last_game_packet,
+                &player.last_player_packet,
+            ];
+            let mut packets = Vec::new();
+            for packet in cached.into_iter().flatten() {
+                let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+                packet.encode(&mut buff)?;
+                packets.push(hex::encode(&buff));
+            }
+
+            let session = SessionPlayer {
+                name: player.name.clone(),
+                scenario: player.scenario,
+                is_seeking: player.is_seeking,
+                time: player.time.map(|t| t.as_secs()),
+                packets,
+            };
+            players.insert(*player.key(), session);
+        }
+
+        Ok(Self {
+            version: SESSION_FORMAT_VERSION,
+            timestamp: unix_secs(SystemTime::now()),
+            shines: lobby.shines.read().await.clone(),
+            tag_round: lobby.tag_round.read().await.map(unix_secs),
+            players,
+        })
+    }
+
+    /// Write to a temporary file first and swap it in, so a crash can't leave a truncated file behind
+    pub async fn save(&self, path: &str) -> Result<()> {
+        let temp = format!("{}.tmp", path);
+        tokio::fs::write(&temp, serde_json::to_string(self)?).await?;
+        tokio::fs::rename(&temp, path).await?;
+        Ok(())
+    }
+
+    pub async fn load(path: &str) -> Result<Self> {
+        let snapshot: Self = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        if snapshot.version > SESSION_FORMAT_VERSION {
+            tracing::warn!(
+                "{} was written by a newer server (format version {}), some data may be ignored",
+                path,
+                snapshot.version
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Merge the shines into the lobby and remember the state of the players that aren't connected,
+    /// to restore it once they reconnect, returns the amount of such players
+    pub async fn restore(self, lobby: &Lobby) -> Result<usize> {
+        lobby.shines.write().await.extend(self.shines);
+
+        let mut tag_round = lobby.tag_round.write().await;
+        if tag_round.is_none() {
+            *tag_round = self.tag_round.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        }
+        drop(tag_round);
+
+        let mut restored = 0;
+        for (guid, session) in self.players {
+            if lobby.players.contains_key(&guid) {
+                continue;
+            }
+
+            let mut player = ReplicatedPlayer {
+                scenario: Some(session.scenario),
+                is_seeking: session.is_seeking,
+                time: session.time.map(Duration::from_secs),
+                ..Default::default()
+            };
+            for encoded in &session.packets {
+                let data = hex::decode(encoded)
+                    .map_err(|e| SMOError::InvalidConsoleArg(format!("Invalid packet of {}: {}", session.name, e)))?;
+                let packet = Packet::decode(&mut &data[..])?;
+                let slot = match packet.data {
+                    PacketData::Costume(_) => &mut player.costume,
+                    PacketData::Capture { .. } => &mut player.capture,
+                    PacketData::Game { .. } => &mut player.game,
+                    PacketData::Player { .. } => &mut player.player,
+                    _ => continue,
+                };
+                *slot = Some(packet);
+            }
+
+            lobby.seen.record(guid, &session.name, None).await;
+            lobby.replicated.insert(guid, player);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
@@ -1,27 +1,55 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
+    borrow::Cow,
     fmt::Display,
     fs::File,
     io::{BufReader, BufWriter},
-    net::IpAddr,
+    net::{AddrParseError, IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, RwLock as StdRwLock},
 };
 
 use clap::ValueEnum;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
+use serde_json::Value;
+
 use crate::{
     guid::Guid,
-    types::{Result, SMOError},
+    logbuffer,
+    net::CLIENT_NAME_SIZE,
+    stages::Stages,
+    types::{matches_wildcard, Result, SMOError, VANILLA_COSTUMES},
 };
 
 pub type SyncSettings = Arc<RwLock<Settings>>;
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+lazy_static! {
+    static ref SETTINGS_PATH: StdRwLock<PathBuf> = StdRwLock::new(PathBuf::from("./settings.json"));
+}
+
+/// Change the file used by `load_settings` and `save_settings`
+pub fn set_settings_path(path: PathBuf) {
+    *SETTINGS_PATH.write().expect("Settings path lock poisoned") = path;
+}
+
+pub fn get_settings_path() -> PathBuf {
+    SETTINGS_PATH.read().expect("Settings path lock poisoned").clone()
+}
+
+/// Migration steps of the settings file, the step at index `n` upgrades version `n` to `n + 1`
+const SETTINGS_MIGRATIONS: &[fn(&mut Value)] = &[migrate_v0_to_v1];
+
+/// Version of the settings file written by `save_settings`
+pub const SETTINGS_VERSION: u32 = SETTINGS_MIGRATIONS.len() as u32;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Settings {
+    pub version: u32,
     pub server: ServerSettings,
     pub flip: FlipSettings,
     pub scenario: ScenarioSettings,
@@ -30,17 +58,228 @@ pub struct Settings {
     pub shines: ShineTable,
     pub persist_shines: PersistShine,
     pub udp: Udp,
+    pub relay: RelaySettings,
+    pub unhandled_packets: UnhandledPacketSettings,
+    pub malformed_packets: MalformedPacketSettings,
     pub json_api: JsonApiSettings,
+    pub link: LinkSettings,
+    pub discovery: DiscoverySettings,
+    pub mirror: MirrorSettings,
+    pub failover: FailoverSettings,
+    pub master_server: MasterServerSettings,
+    pub logging: LoggingSettings,
+    pub plugins: PluginsSettings,
+    pub costume_stages: CostumeStageSettings,
+    pub stage_capacity: StageCapacitySettings,
+    pub webhooks: WebhookSettings,
+    pub progression: ProgressionSettings,
+    pub recording: RecordingSettings,
+    pub costume_moderation: CostumeModerationSettings,
+    pub overlay: OverlaySettings,
+    pub websocket: WebSocketSettings,
+    pub rcon: RconSettings,
+    pub emotes: EmoteSettings,
+    pub spectators: SpectatorSettings,
+    pub ghosts: GhostSettings,
+    pub invisible: InvisibleSettings,
+    pub snapshots: SnapshotSettings,
+    pub scheduler: SchedulerSettings,
+    /// Named stage entrances to send players to with `warp <name> <players...>`
+    pub warps: BTreeMap<String, Warp>,
+    /// Routes of stages to race along with `race start <name>`, from the start to the finish
+    pub races: BTreeMap<String, Vec<String>>,
+    /// Additional stage aliases for sub-areas, naming a stage or a group of stages (e.g. for `ban stage`)
+    pub stage_aliases: BTreeMap<String, Vec<String>>,
+    /// Groups of players that only see the movement of each other, players without a party see everyone
+    pub parties: BTreeMap<String, BTreeSet<Guid>>,
+    /// Notes of the moderators about players, added with `note add`
+    pub notes: BTreeMap<Guid, Vec<String>>,
+    pub audit: AuditSettings,
+    pub history: HistorySettings,
+    pub names: NameSettings,
+    pub queue: QueueSettings,
+    pub welcome: WelcomeSettings,
+    pub tag: TagSettings,
+    pub teleport_detection: TeleportDetectionSettings,
+    pub stage_validation: StageValidationSettings,
+    pub limbo: LimboSettings,
+    /// Additional lobbies hosted by this process, mapping a name to its settings file
+    #[serde(default)]
+    pub lobbies: BTreeMap<String, PathBuf>,
+
+    /// Set for the settings of an additional lobby, `None` for the main settings
+    #[serde(skip)]
+    pub lobby: Option<LobbyFile>,
+    #[serde(skip)]
+    pub overrides: Overrides,
+}
+
+#[derive(Clone, Debug)]
+pub struct LobbyFile {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Values of the main server given on the command line, they replace those of the settings file without being saved to it
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub port: Option<u16>,
+    pub address: Option<BindAddress>,
+    pub max_players: Option<u16>,
+    /// The replaced server settings of the file
+    file: Option<ServerSettings>,
+}
+
+impl Overrides {
+    pub fn new(port: Option<u16>, address: Option<BindAddress>, max_players: Option<u16>) -> Self {
+        Self {
+            port,
+            address,
+            max_players,
+            file: None,
+        }
+    }
+
+    /// Replace the values of settings loaded from the file
+    pub fn apply(&self, settings: &mut Settings) {
+        let mut overrides = self.clone();
+        overrides.file = Some(settings.server.clone());
+        if let Some(port) = self.port {
+            settings.server.port = port;
+        }
+        if let Some(address) = &self.address {
+            settings.server.address = address.clone();
+        }
+        if let Some(max_players) = self.max_players {
+            settings.server.max_players = max_players;
+        }
+        settings.overrides = overrides;
+    }
+
+    /// Server settings with the file values for those that are still overridden, changed ones are saved
+    fn saved(&self, server: &ServerSettings) -> Option<ServerSettings> {
+        let file = self.file.as_ref()?;
+        Some(ServerSettings {
+            address: match &self.address {
+                Some(address) if *address == server.address => file.address.clone(),
+                _ => server.address.clone(),
+            },
+            port: match self.port {
+                Some(port) if port == server.port => file.port,
+                _ => server.port,
+            },
+            max_players: match self.max_players {
+                Some(max_players) if max_players == server.max_players => file.max_players,
+                _ => server.max_players,
+            },
+        })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ServerSettings {
-    pub address: IpAddr,
+    pub address: BindAddress,
     pub port: u16,
     pub max_players: u16,
 }
 
+/// One address or a list of them to accept the players on, e.g. `["0.0.0.0", "::"]`.
+/// The other services only bind to the first one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BindAddress {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
+
+impl BindAddress {
+    pub fn primary(&self) -> IpAddr {
+        self.all().first().copied().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    pub fn all(&self) -> Vec<IpAddr> {
+        match self {
+            Self::One(address) => vec![*address],
+            Self::Many(addresses) => addresses.clone(),
+        }
+    }
+}
+
+/// Comma separated addresses
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err("Needs at least one address to bind to".to_string());
+        }
+        let addresses = s
+            .split(',')
+            .map(|address| address.trim().parse().map_err(|e: AddrParseError| format!("{}: {}", e, address.trim())))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(match addresses[..] {
+            [address] => Self::One(address),
+            _ => Self::Many(addresses),
+        })
+    }
+}
+
+/// An address or a range of them in CIDR notation, e.g. `192.168.0.0/16`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => prefix_matches(&range.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => prefix_matches(&range.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(range: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let bits = range.iter().zip(ip).flat_map(|(r, i)| (0..8).rev().map(move |bit| (r >> bit & 1, i >> bit & 1)));
+    bits.take(prefix as usize).all(|(r, i)| r == i)
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let address: IpAddr = address.parse().map_err(|e| format!("Invalid address {}: {}", s, e))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max_prefix).ok_or_else(|| format!("Invalid prefix length in {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        format!("{}/{}", range.address, range.prefix)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct FlipSettings {
@@ -105,6 +344,14 @@ impl Display for FlipPovSettings {
 #[serde(rename_all = "PascalCase")]
 pub struct ScenarioSettings {
     pub merge_enabled: bool,
+    /// Per kingdom alias (e.g. `sand`), the first scenario that only gets merged into players who already reached it
+    pub merge_thresholds: BTreeMap<String, i8>,
+    /// `true` never merges scenarios into the player, `false` always does (ignoring the thresholds)
+    pub protection_overrides: BTreeMap<Guid, bool>,
+    /// Per stage, the scenario all players are pinned to, instead of merging
+    pub locks: BTreeMap<String, i8>,
+    /// Per player, the scenario the other players see them in
+    pub player_overrides: BTreeMap<Guid, i8>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -115,6 +362,60 @@ pub struct BanListSettings {
     pub ip_addresses: BTreeSet<IpAddr>,
     pub stages: BTreeSet<String>,
     pub game_modes: BTreeSet<i8>,
+    /// Banned body and cap names
+    pub costumes: BTreeSet<String>,
+    /// Banned capture models
+    pub captures: BTreeSet<String>,
+    /// Ban every stage that isn't in `allowed_stages`
+    pub allow_list: bool,
+    pub allowed_stages: BTreeSet<String>,
+    /// How connections of banned players are dealt with
+    pub enforcement: BanEnforcement,
+    /// How banned costumes and captures are dealt with
+    pub model_enforcement: ModelEnforcement,
+    /// Why and by whom the profile ids and ip addresses got banned
+    pub details: BTreeMap<String, BanDetails>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BanDetails {
+    pub reason: Option<String>,
+    pub moderator: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl BanListSettings {
+    pub fn is_stage_banned(&self, stage: &str) -> bool {
+        // the game sends an empty stage while loading
+        let not_allowed = self.allow_list && !stage.is_empty() && !self.allowed_stages.contains(stage);
+        self.enabled && (self.stages.contains(stage) || not_allowed)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum ModelEnforcement {
+    /// Don't relay the packet, the other players keep seeing the previous costume or capture
+    Strip,
+    /// Relay the default costume or no capture instead
+    Replace,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum BanEnforcement {
+    /// Crash the client once it enters a stage
+    CrashOnStage,
+    /// Close the connection right away
+    Disconnect,
+    /// Crash the client after some seconds
+    #[serde(rename_all = "PascalCase")]
+    TimedCrash { seconds: u64 },
+    /// Keep the connection open without ever answering, only reading a few bytes per second
+    #[serde(rename_all = "PascalCase")]
+    Tarpit { bytes_per_second: usize },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -123,6 +424,10 @@ pub struct DiscordSettings {
     pub token: Option<String>,
     pub prefix: String,
     pub log_channel: Option<String>,
+    /// Webhook to post join/leave, moon and ban notifications to, works without a bot token
+    pub webhook_url: Option<String>,
+    /// Events to notify about, all of them if empty
+    pub webhook_events: BTreeSet<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -131,6 +436,10 @@ pub struct ShineTable {
     pub enabled: bool,
     pub excluded: BTreeSet<i32>,
     pub clear_on_new_saves: bool,
+    /// File with the names of the shines, see [`crate::shines`], lobbies use the one of the main settings
+    pub names_filename: Option<String>,
+    /// Players that never get the shines of the others synced
+    pub excluded_players: BTreeSet<Guid>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -138,14 +447,24 @@ pub struct ShineTable {
 pub struct PersistShine {
     pub enabled: bool,
     pub filename: String,
+    /// Seconds between saves of changed shines, `0` only saves on changes by commands and on shutdown
+    pub autosave_interval: u64,
+    /// Amount of previous versions to keep as `<filename>.1`, `<filename>.2`, ...
+    pub backups: u8,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Udp {
+    /// Without UDP, all packets are sent over TCP
+    pub enabled: bool,
     pub initiate_handshake: bool,
     pub base_port: u16,
     pub port_count: u16,
+    /// Milliseconds without UDP packets of a client before its movement is sent over TCP again, `0` to never fall back
+    pub fallback_timeout_ms: u64,
+    /// Milliseconds between hole punches keeping the udp path through NATs open, `0` to not send any
+    pub keepalive_interval_ms: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -154,108 +473,1273 @@ pub struct JsonApiSettings {
     pub enabled: bool,
     pub port: u16,
     pub tokens: BTreeMap<String, BTreeSet<String>>,
+    /// Named permission sets, granted to a token with a `Role/<name>` permission
+    pub roles: BTreeMap<String, BTreeSet<String>>,
+    pub blocking: ApiBlockSettings,
+    /// Serve a live map of the players at `http://host:port/map?token=...` for tokens with the `Map` permission,
+    /// the positions come from the `Websocket` bridge
+    pub map: bool,
+    /// Only accept requests from these addresses or ranges (e.g. `10.0.0.0/8`), from everywhere if empty
+    pub allowed_ips: Vec<IpRange>,
 }
 
-impl Default for ServerSettings {
-    fn default() -> Self {
-        Self {
-            address: "0.0.0.0".parse().unwrap(),
-            port: 1027,
-            max_players: 8,
-        }
-    }
+
+/// Blocking of ip addresses that send too many invalid requests
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApiBlockSettings {
+    pub max_failures: u8,
+    /// How long a client stays blocked, `0` until the block is cleared with `api blocks clear`
+    pub block_seconds: u64,
+    /// Ip addresses that never get blocked
+    pub whitelist: BTreeSet<IpAddr>,
+    /// File to keep the blocks in across restarts, if set
+    pub persist_filename: Option<String>,
 }
 
-impl Default for FlipSettings {
-    fn default() -> Self {
-        Self {
-            enabled: false,
-            players: Default::default(),
-            pov: Default::default(),
-        }
+impl JsonApiSettings {
+    /// Whether requests from the address are accepted at all
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.allowed_ips.is_empty() || self.allowed_ips.iter().any(|range| range.contains(ip))
     }
-}
 
-impl Default for ScenarioSettings {
-    fn default() -> Self {
-        Self {
-            merge_enabled: false,
-        }
+    /// Permissions of the token, including the ones of its roles
+    pub fn permissions(&self, token: &str) -> BTreeSet<String> {
+        let mut permissions = self.tokens.get(token).cloned().unwrap_or_default();
+        let roles: Vec<_> = permissions
+            .iter()
+            .filter_map(|perm| perm.strip_prefix("Role/"))
+            .filter_map(|role| self.roles.get(role))
+            .collect();
+        let granted: BTreeSet<_> = roles.into_iter().flatten().cloned().collect();
+        permissions.extend(granted);
+        permissions
     }
 }
 
-impl Default for BanListSettings {
-    fn default() -> Self {
-        Self {
-            enabled: false,
-            players: Default::default(),
-            ip_addresses: Default::default(),
-            stages: Default::default(),
-            game_modes: Default::default(),
-        }
-    }
+/// Stream of the relayed packets for browser viewers, at `ws://host:port/?token=...&format=json`,
+/// the format defaults to binary frames of encoded packets
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WebSocketSettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Packet types each token receives, e.g. `player`, `game`, or `*` for all of them
+    pub tokens: BTreeMap<String, BTreeSet<String>>,
 }
 
-impl Default for DiscordSettings {
-    fn default() -> Self {
-        Self {
-            token: Default::default(),
-            prefix: "$".to_string(),
-            log_channel: Default::default(),
+/// Remote console over the Source RCON protocol, failed logins are blocked like those of the JSON API
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RconSettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Grants access to all console commands, RCON stays off while it's empty
+    pub password: String,
+}
+
+/// Public HTTP endpoint with the player count and timers, for stream overlays
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct OverlaySettings {
+    pub enabled: bool,
+    pub port: u16,
+    /// Require `?token=...` in the request, if set
+    pub token: Option<String>,
+    /// How long the generated status gets reused
+    pub cache_ms: u64,
+    /// Minimal time between two requests of the same ip address
+    pub min_interval_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnapshotSettings {
+    /// How often the lobby state shared with dashboards and overlays is refreshed
+    pub interval_ms: u64,
+}
+
+/// Append-only log of the moderation actions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditSettings {
+    pub enabled: bool,
+    /// File with one JSON entry per line
+    pub filename: String,
+}
+
+/// Cleanup of the names the players connect with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NameSettings {
+    pub strip_control_characters: bool,
+    /// Longest name in characters, longer names are cut off
+    pub max_length: usize,
+    pub duplicates: DuplicateNames,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum DuplicateNames {
+    /// Refuse players with the name of a connected player
+    Reject,
+    /// Rename them by appending a number, e.g. `Mario(2)`
+    Suffix,
+}
+
+impl NameSettings {
+    pub fn sanitize(&self, name: &str) -> String {
+        let name: String = name
+            .trim_matches(char::from(0))
+            .chars()
+            .filter(|c| !self.strip_control_characters || !c.is_control())
+            .collect();
+        let name: String = name.trim().chars().take(self.max_length).collect();
+        if name.is_empty() {
+            "Player".to_string()
+        } else {
+            name
         }
     }
-}
 
-impl Default for PersistShine {
-    fn default() -> Self {
-        Self {
-            enabled: false,
-            filename: "./moons.json".into(),
+    /// The name or the first free name with a number appended, cut off to still fit the maximum length
+    /// and the bytes of a name in the packets
+    pub fn deduplicate(&self, name: &str, is_taken: impl Fn(&str) -> bool) -> String {
+        if !is_taken(name) {
+            return name.to_string();
         }
+        (2..)
+            .map(|n| {
+                let suffix = format!("({})", n);
+                let base: String = name.chars().take(self.max_length.saturating_sub(suffix.len())).collect();
+                let mut end = base.len().min(CLIENT_NAME_SIZE.saturating_sub(suffix.len()));
+                while !base.is_char_boundary(end) {
+                    end -= 1;
+                }
+                base[..end].to_string() + &suffix
+            })
+            .find(|candidate| !is_taken(candidate))
+            .expect("Ran out of numbers for duplicate names")
     }
 }
 
-impl Default for ShineTable {
+/// Connections that wait for a free slot while the server is full, instead of being refused
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct QueueSettings {
+    pub enabled: bool,
+    /// Connections beyond this are refused
+    pub max_length: usize,
+    pub check_interval_ms: u64,
+}
+
+/// Names and addresses of the players of past connections, to target them while they are offline
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HistorySettings {
+    pub enabled: bool,
+    pub filename: String,
+}
+
+/// Forwarding of the packets of a player to the other players
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RelaySettings {
+    /// Only forward movement to the players in the same stage, who are the only ones that can see it
+    pub same_stage_only: bool,
+    /// Commands queued per client, beyond it movement packets get dropped and clients that still fall behind get disconnected
+    pub queue_capacity: usize,
+    /// Movement updates forwarded per player and second, in between only the latest one is kept, `0` for no limit
+    pub max_update_rate: u32,
+    /// Distance within the same stage beyond which movement is only forwarded `DistantUpdateRate` times a second, `0` to always forward it
+    pub interest_radius: f32,
+    pub distant_update_rate: u32,
+}
+
+/// Packets that fail to decode, e.g. with a size too small for their type or names that aren't UTF-8, they're always
+/// counted and dropped
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MalformedPacketSettings {
+    /// Disconnect clients after `Limit` malformed packets
+    pub strict: bool,
+    pub limit: u64,
+}
+
+impl Default for MalformedPacketSettings {
     fn default() -> Self {
-        Self {
-          enabled: true,
-          excluded: BTreeSet::from([ 496 ]),
-          clear_on_new_saves: false,
-        }
+        Self { strict: false, limit: 10 }
     }
 }
 
-impl Default for FlipPovSettings {
-    fn default() -> Self {
-        Self::Both
+/// What to do with packets of types this server doesn't know, like those of newer mods
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UnhandledPacketSettings {
+    pub default: UnhandledAction,
+    /// Actions per packet tag, overriding the default
+    pub tags: BTreeMap<u16, UnhandledAction>,
+}
+
+impl UnhandledPacketSettings {
+    pub fn action(&self, tag: u16) -> UnhandledAction {
+        self.tags.get(&tag).copied().unwrap_or(self.default)
     }
 }
 
-impl Default for Udp {
-    fn default() -> Self {
-        Self {
-            initiate_handshake: false,
-            base_port: 0,
-            port_count: 1,
-        }
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum UnhandledAction {
+    /// Forward the packets to the other players as they are
+    #[default]
+    Forward,
+    /// Silently drop the packets
+    Drop,
+    /// Drop the packets and log them
+    Log,
+}
+
+/// Links to other servers, whose players get mirrored into this server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LinkSettings {
+    pub enabled: bool,
+    /// Port to accept links from other servers on, `0` to only connect to the peers
+    pub port: u16,
+    /// Addresses (`host:port`) of the servers to link to
+    pub peers: Vec<String>,
+    /// Shared secret that both sides of a link need to agree on
+    pub secret: String,
+}
+
+/// Broadcast beacons announcing this server on the local network
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DiscoverySettings {
+    pub enabled: bool,
+    /// UDP port the beacons get broadcast to
+    pub port: u16,
+    /// Seconds between two beacons
+    pub interval: u64,
+    pub name: String,
+}
+
+/// Secondary server that receives a copy of all relayed packets
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MirrorSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+/// Hot-standby pairing, the standby takes over the server address once the primary is gone
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FailoverSettings {
+    pub enabled: bool,
+    pub role: FailoverRole,
+    /// Port the primary replicates its state on
+    pub port: u16,
+    /// Replication address (`host:port`) of the primary, used by the standby
+    pub primary: String,
+    /// Seconds without contact to the primary before the standby takes over
+    pub timeout: u64,
+    /// Shared secret that the primary and the standby need to agree on
+    #[serde(default)]
+    pub secret: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum FailoverRole {
+    Primary,
+    Standby,
+}
+
+/// Public server list this server periodically reports to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MasterServerSettings {
+    pub enabled: bool,
+    pub url: String,
+    pub name: String,
+    /// Seconds between two heartbeats
+    pub interval: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LoggingSettings {
+    /// Only trace 1 in N packets of a packet type (e.g. `"player": 100`), `0` to never trace them
+    pub trace_sampling: BTreeMap<String, u32>,
+    /// Recent log lines kept in memory for `log` and the `Status/Log` of the JSON API
+    pub buffer_lines: usize,
+}
+
+/// Hold back costumes that aren't in the game, until they are approved with `costume approve`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CostumeModerationSettings {
+    pub enabled: bool,
+    /// Approved body and cap names of modded costumes
+    pub approved: BTreeSet<String>,
+}
+
+impl CostumeModerationSettings {
+    pub fn allows(&self, name: &str) -> bool {
+        !self.enabled || VANILLA_COSTUMES.contains(&name) || self.approved.contains(name)
     }
 }
 
-pub fn load_settings() -> Result<Settings> {
-    let file = File::open("./settings.json")?;
-    let reader = BufReader::new(file);
-    let settings = serde_json::from_reader(reader)?;
-    tracing::debug!("Loading settings");
+/// Packet recordings started with `record start`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RecordingSettings {
+    /// Directory for recordings started without an explicit path
+    pub directory: PathBuf,
+    /// Only record 1 in N packets of a packet type (e.g. `"player": 2`), `0` to never record them
+    pub sampling: BTreeMap<String, u32>,
+}
 
-    Ok(settings)
+/// WASM plugins, only loaded if the server was built with the `plugins` feature
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PluginsSettings {
+    pub enabled: bool,
+    pub plugins: Vec<PluginSettings>,
 }
 
-pub fn save_settings(settings: &Settings) -> Result<()> {
-    tracing::debug!("Saving settings");
-    let file = File::create("./settings.json")?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, settings)?;
-    Ok(())
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct PluginSettings {
+    pub name: String,
+    pub path: PathBuf,
+    /// Events passed to the plugin, all events if empty
+    pub events: BTreeSet<String>,
+    /// Commands the plugin may issue
+    pub permissions: BTreeSet<String>,
+    /// Amount of fuel (roughly WASM instructions) the plugin may burn per event
+    pub fuel: u64,
+    /// Bytes the memory of the plugin may grow to
+    pub memory_limit: usize,
+}
+
+/// Stages that can only be entered while wearing certain costumes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CostumeStageSettings {
+    pub enabled: bool,
+    pub rules: BTreeMap<String, CostumeRule>,
+    /// Stage to send violators to, if their previous stage is unknown
+    pub fallback_stage: String,
+}
+
+/// Stages that only fit a limited amount of players at once
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StageCapacitySettings {
+    pub enabled: bool,
+    /// Maximum amount of players per stage name
+    pub stages: BTreeMap<String, usize>,
+    /// Stage to send players to who enter a full stage, if their previous stage is unknown
+    pub fallback_stage: String,
+}
+
+/// Allowed body and cap costumes, an empty set allows any costume
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct CostumeRule {
+    pub bodies: BTreeSet<String>,
+    pub caps: BTreeSet<String>,
+}
+
+impl CostumeRule {
+    pub fn allows(&self, body: &str, cap: &str) -> bool {
+        (self.bodies.is_empty() || self.bodies.contains(body))
+            && (self.caps.is_empty() || self.caps.contains(cap))
+    }
+}
+
+/// Stages that stay locked until every connected player reached their prerequisite
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProgressionSettings {
+    pub enabled: bool,
+    pub gates: BTreeMap<String, ProgressionGate>,
+    /// Stage to send players to who enter a locked stage, if their previous stage is unknown
+    pub fallback_stage: String,
+}
+
+/// Stage and scenario a player has to have reached, e.g. `MoonWorldHomeStage` and `2`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProgressionGate {
+    pub stage: String,
+    pub scenario: i8,
+}
+
+/// Players whose own position and cap aren't shown to the others
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SpectatorSettings {
+    pub players: BTreeSet<Guid>,
+}
+
+/// Players whose packets are accepted but not relayed to the others, set with `ghost`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GhostSettings {
+    pub players: BTreeSet<Guid>,
+    /// Whether the ghosts still see the other players, to not notice that they're hidden
+    pub see_others: bool,
+}
+
+impl Default for GhostSettings {
+    fn default() -> Self {
+        Self {
+            players: BTreeSet::new(),
+            see_others: true,
+        }
+    }
+}
+
+/// Players without a puppet for the others, e.g. moderators observing a round, set with `invisible`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct InvisibleSettings {
+    pub players: BTreeSet<Guid>,
+}
+
+/// Console commands that run at given times
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SchedulerSettings {
+    pub enabled: bool,
+    pub tasks: Vec<ScheduledTask>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ScheduledTask {
+    pub name: String,
+    /// When to run in local time, as `minute hour day-of-month month day-of-week`, e.g. `0 4 * * *` or `*/15 * * * *`
+    pub cron: String,
+    pub command: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Warp {
+    pub stage: String,
+    /// Entrance id within the stage, empty for the default entrance
+    pub id: String,
+    /// Scenario to send to, `-1` keeps the current scenario
+    pub scenario: i8,
+}
+
+/// Rounds of hide and seek started with `tag start`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TagSettings {
+    /// Start the next round once all hiders are tagged, with the first tagged hider as seeker
+    pub auto_restart: bool,
+    /// Seconds before the next round starts
+    pub countdown: u8,
+}
+
+/// Actions for players joining the server, reconnecting players are left alone
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WelcomeSettings {
+    /// Shown to the player in game and announced as `Welcome` event, with `{name}` replaced by the player's name, nothing if empty
+    pub message: String,
+    /// Make new players seekers or hiders
+    pub is_seeking: Option<bool>,
+    /// Send new players to this stage entrance, e.g. a lobby stage
+    pub warp: Option<Warp>,
+}
+
+/// Players moving impossibly fast within a stage, e.g. by hacking their position
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TeleportDetectionSettings {
+    pub enabled: bool,
+    /// Units per second
+    pub max_speed: f32,
+    /// Shorter jumps are never flagged
+    pub min_distance: f32,
+    /// Times a player has to be flagged within the window before the action is taken, checkpoints and pipes
+    /// legitimately teleport within a stage as well
+    pub strikes: usize,
+    pub window_ms: u64,
+    pub action: TeleportAction,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum TeleportAction {
+    /// Only log the flagged movement
+    Log,
+    /// Announce a `Teleport` event as well
+    Notify,
+    /// Announce it and kick the player
+    Kick,
+}
+
+/// Stage names of game packets that aren't a known stage, e.g. garbage from broken or malicious clients
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StageValidationSettings {
+    pub enabled: bool,
+    /// Stages of mods to accept as well, `*` matches any characters (e.g. `Custom*Stage`)
+    pub allowed: Vec<String>,
+    pub action: StageValidationAction,
+}
+
+impl StageValidationSettings {
+    pub fn allows(&self, stage: &str) -> bool {
+        !self.enabled
+            || stage.is_empty()
+            || Stages::is_stage(stage)
+            || self.allowed.iter().any(|pattern| matches_wildcard(pattern, stage))
+    }
+}
+
+/// New players stay hidden from the others until their first game packet with a valid stage, so connections that only
+/// spoof a handshake don't end up as puppets
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LimboSettings {
+    pub enabled: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub enum StageValidationAction {
+    /// Only log the unknown stage, the packet is handled as usual
+    Log,
+    /// Drop the packet, the other players keep seeing the last known stage
+    Drop,
+    /// Drop the packet and kick the player
+    Kick,
+}
+
+/// Animations of the players that trigger server features
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmoteSettings {
+    pub enabled: bool,
+    pub triggers: Vec<EmoteTrigger>,
+}
+
+/// Fires when a player starts the `act` animation `count` times within `window_ms` milliseconds
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmoteTrigger {
+    pub name: String,
+    pub act: u16,
+    /// Any sub act if missing
+    pub sub_act: Option<u16>,
+    pub count: usize,
+    pub window_ms: u64,
+    /// Console command to run, `{name}` is replaced with the name of the player
+    pub command: Option<String>,
+}
+
+impl EmoteTrigger {
+    pub fn matches(&self, act: u16, sub_act: u16) -> bool {
+        self.act == act && self.sub_act.unwrap_or(sub_act) == sub_act
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    /// URLs to post to, per event (`ServerStart`, `ServerStop`, `PlayerJoin`, `PlayerLeave`, `MoonCollected`, `StageBanned`, `StageFull`, `StageUnlocked`, `BannedClient`, `Emote`, `Welcome`, `Teleport`, `RaceFinished`, `TagRoundEnd`)
+    pub hooks: BTreeMap<String, Vec<String>>,
+    /// Attempts after the first failed one
+    pub retries: u32,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            address: BindAddress::One("0.0.0.0".parse().unwrap()),
+            port: 1027,
+            max_players: 8,
+        }
+    }
+}
+
+impl Default for FlipSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            players: Default::default(),
+            pov: Default::default(),
+        }
+    }
+}
+
+impl Default for ScenarioSettings {
+    fn default() -> Self {
+        Self {
+            merge_enabled: false,
+            merge_thresholds: Default::default(),
+            protection_overrides: Default::default(),
+            player_overrides: Default::default(),
+            locks: Default::default(),
+        }
+    }
+}
+
+impl Default for BanListSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            players: Default::default(),
+            ip_addresses: Default::default(),
+            stages: Default::default(),
+            game_modes: Default::default(),
+            costumes: Default::default(),
+            captures: Default::default(),
+            allow_list: false,
+            allowed_stages: Default::default(),
+            enforcement: BanEnforcement::CrashOnStage,
+            model_enforcement: ModelEnforcement::Replace,
+            details: Default::default(),
+        }
+    }
+}
+
+impl Default for DiscordSettings {
+    fn default() -> Self {
+        Self {
+            token: Default::default(),
+            prefix: "$".to_string(),
+            log_channel: Default::default(),
+            webhook_url: Default::default(),
+            webhook_events: Default::default(),
+        }
+    }
+}
+
+impl Default for PersistShine {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            filename: "./moons.json".into(),
+            autosave_interval: 60,
+            backups: 0,
+        }
+    }
+}
+
+impl Default for ShineTable {
+    fn default() -> Self {
+        Self {
+          enabled: true,
+          excluded: BTreeSet::from([ 496 ]),
+          clear_on_new_saves: false,
+          names_filename: Some("./shine_names.json".into()),
+          excluded_players: Default::default(),
+        }
+    }
+}
+
+impl Default for FlipPovSettings {
+    fn default() -> Self {
+        Self::Both
+    }
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1032,
+            tokens: Default::default(),
+        }
+    }
+}
+
+impl Default for RconSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 27015,
+            password: String::new(),
+        }
+    }
+}
+
+impl Default for OverlaySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1031,
+            token: None,
+            cache_ms: 1000,
+            min_interval_ms: 500,
+        }
+    }
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            filename: "./audit.log".to_string(),
+        }
+    }
+}
+
+impl Default for NameSettings {
+    fn default() -> Self {
+        Self {
+            strip_control_characters: true,
+            max_length: 32,
+            duplicates: DuplicateNames::Suffix,
+        }
+    }
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_length: 16,
+            check_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for TeleportDetectionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_speed: 3000.0,
+            min_distance: 1000.0,
+            strikes: 3,
+            window_ms: 60_000,
+            action: TeleportAction::Log,
+        }
+    }
+}
+
+impl Default for StageValidationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allowed: Vec::new(),
+            action: StageValidationAction::Log,
+        }
+    }
+}
+
+impl Default for TagSettings {
+    fn default() -> Self {
+        Self {
+            auto_restart: false,
+            countdown: 10,
+        }
+    }
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        Self {
+            same_stage_only: false,
+            queue_capacity: 256,
+            max_update_rate: 0,
+            interest_radius: 0.0,
+            distant_update_rate: 2,
+        }
+    }
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            filename: "./history.json".to_string(),
+        }
+    }
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        Self { interval_ms: 500 }
+    }
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1028,
+            interval: 5,
+            name: "smo-rs".to_string(),
+        }
+    }
+}
+
+impl Default for MirrorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".to_string(),
+            port: 1029,
+        }
+    }
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            role: FailoverRole::Primary,
+            port: 1030,
+            primary: "127.0.0.1:1030".to_string(),
+            timeout: 10,
+            secret: String::new(),
+        }
+    }
+}
+
+impl Default for MasterServerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: Default::default(),
+            name: "smo-rs".to_string(),
+            interval: 60,
+        }
+    }
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            trace_sampling: BTreeMap::from([("player".to_string(), 0), ("cap".to_string(), 0)]),
+            buffer_lines: logbuffer::DEFAULT_CAPACITY,
+        }
+    }
+}
+
+impl Default for RecordingSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("./recordings"),
+            sampling: Default::default(),
+        }
+    }
+}
+
+impl Default for PluginSettings {
+    fn default() -> Self {
+        Self {
+            name: Default::default(),
+            path: Default::default(),
+            events: Default::default(),
+            permissions: Default::default(),
+            fuel: 1_000_000,
+            memory_limit: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for StageCapacitySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stages: Default::default(),
+            fallback_stage: "HomeShipInsideStage".to_string(),
+        }
+    }
+}
+
+impl Default for CostumeStageSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: Default::default(),
+            fallback_stage: "HomeShipInsideStage".to_string(),
+        }
+    }
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hooks: Default::default(),
+            retries: 3,
+        }
+    }
+}
+
+impl Default for ProgressionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            gates: Default::default(),
+            fallback_stage: "HomeShipInsideStage".to_string(),
+        }
+    }
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initiate_handshake: false,
+            base_port: 0,
+            port_count: 1,
+            fallback_timeout_ms: 5000,
+            keepalive_interval_ms: 10000,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            server: Default::default(),
+            flip: Default::default(),
+            scenario: Default::default(),
+            ban_list: Default::default(),
+            discord: Default::default(),
+            shines: Default::default(),
+            persist_shines: Default::default(),
+            udp: Default::default(),
+            relay: Default::default(),
+            unhandled_packets: Default::default(),
+            malformed_packets: Default::default(),
+            json_api: Default::default(),
+            link: Default::default(),
+            discovery: Default::default(),
+            mirror: Default::default(),
+            failover: Default::default(),
+            master_server: Default::default(),
+            logging: Default::default(),
+            plugins: Default::default(),
+            costume_stages: Default::default(),
+            stage_capacity: Default::default(),
+            webhooks: Default::default(),
+            progression: Default::default(),
+            recording: Default::default(),
+            costume_moderation: Default::default(),
+            overlay: Default::default(),
+            websocket: Default::default(),
+            rcon: Default::default(),
+            emotes: Default::default(),
+            spectators: Default::default(),
+            ghosts: Default::default(),
+            invisible: Default::default(),
+            snapshots: Default::default(),
+            scheduler: Default::default(),
+            warps: Default::default(),
+            races: Default::default(),
+            stage_aliases: Default::default(),
+            parties: Default::default(),
+            notes: Default::default(),
+            audit: Default::default(),
+            history: Default::default(),
+            names: Default::default(),
+            queue: Default::default(),
+            welcome: Default::default(),
+            tag: Default::default(),
+            teleport_detection: Default::default(),
+            stage_validation: Default::default(),
+            limbo: Default::default(),
+            lobbies: Default::default(),
+            lobby: None,
+            overrides: Default::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Name of the party the player belongs to
+    pub fn party_of(&self, player: &Guid) -> Option<&str> {
+        self.parties
+            .iter()
+            .find(|(_, members)| members.contains(player))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The settings as saved to the file, without the command line overrides
+    pub fn without_overrides(&self) -> Cow<'_, Settings> {
+        match self.overrides.saved(&self.server) {
+            Some(server) => {
+                let mut settings = self.clone();
+                settings.server = server;
+                settings.overrides = Overrides::default();
+                Cow::Owned(settings)
+            }
+            None => Cow::Borrowed(self),
+        }
+    }
+}
+
+pub fn load_settings() -> Result<Settings> {
+    let path = get_settings_path();
+    let file = File::open(&path)?;
+    let reader = BufReader::new(file);
+    let mut json: Value = serde_json::from_reader(reader)?;
+    tracing::debug!("Loading settings");
+    migrate_settings(&mut json, &path)?;
+
+    // fill in everything missing from the file with the defaults
+    let mut settings = serde_json::to_value(Settings::default())?;
+    merge_json(&mut settings, json);
+    let mut settings: Settings = serde_json::from_value(settings)?;
+    settings.version = settings.version.max(SETTINGS_VERSION);
+    check_bind_address(&settings)?;
+    check_merge_thresholds(&settings)?;
+
+    Ok(settings)
+}
+
+fn check_bind_address(settings: &Settings) -> Result<()> {
+    if settings.server.address.all().is_empty() {
+        let e = <serde_json::Error as serde::de::Error>::custom("Server.Address needs at least one address to bind to");
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn check_merge_thresholds(settings: &Settings) -> Result<()> {
+    if let Some(kingdom) = settings.scenario.merge_thresholds.keys().find(|k| !Stages::is_alias(k)) {
+        let msg = format!("Scenario.MergeThresholds: {} is not a kingdom alias", kingdom);
+        return Err(<serde_json::Error as serde::de::Error>::custom(msg).into());
+    }
+    Ok(())
+}
+
+/// Bring the settings read from the file up to the current version, backing up the file first
+fn migrate_settings(json: &mut Value, path: &Path) -> Result<()> {
+    let version = json
+        .get("Version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32;
+    if version < SETTINGS_VERSION {
+        let mut backup = path.as_os_str().to_os_string();
+        backup.push(format!(".v{}.bak", version));
+        std::fs::copy(path, &backup)?;
+        tracing::info!(
+            "Migrating settings from version {} to {}, backup written to {}",
+            version,
+            SETTINGS_VERSION,
+            backup.to_string_lossy()
+        );
+
+        for migration in &SETTINGS_MIGRATIONS[version as usize..] {
+            migration(json);
+        }
+    } else if version > SETTINGS_VERSION {
+        tracing::warn!(
+            "Settings were written by a newer server (version {}), unknown values will be dropped",
+            version
+        );
+    }
+    Ok(())
+}
+
+/// Version 0 are the unversioned settings, which used `Ips` for the banned ip addresses
+fn migrate_v0_to_v1(json: &mut Value) {
+    if let Some(Value::Object(ban_list)) = json.get_mut("BanList") {
+        for old_key in ["Ips", "IPs", "IpAddrs"] {
+            if let Some(ips) = ban_list.remove(old_key) {
+                ban_list.entry("IpAddresses").or_insert(ips);
+            }
+        }
+    }
+    if let Value::Object(map) = json {
+        map.insert("Version".to_string(), Value::from(1));
+    }
+}
+
+pub fn save_settings(settings: &Settings) -> Result<()> {
+    tracing::debug!("Saving settings");
+    let path = match &settings.lobby {
+        Some(lobby) => lobby.path.clone(),
+        None => get_settings_path(),
+    };
+    let file = File::create(path)?;
+    let writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(writer, &*settings.without_overrides())?;
+    Ok(())
+}
+
+/// Load the settings of an additional lobby.
+///
+/// The lobby file only needs to contain the values that differ from the main
+/// settings, but once the lobby saves its settings the file contains all of them.
+pub fn load_lobby_settings(base: &Settings, name: &str, path: &PathBuf) -> Result<Settings> {
+    // the command line overrides are only meant for the main server
+    let mut json = serde_json::to_value(&*base.without_overrides())?;
+    match File::open(path) {
+        Ok(file) => {
+            let mut overrides: Value = serde_json::from_reader(BufReader::new(file))?;
+            migrate_settings(&mut overrides, path)?;
+            merge_json(&mut json, overrides);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("No settings file for lobby {} found, using the main settings", name);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut settings: Settings = serde_json::from_value(json)?;
+    check_bind_address(&settings)?;
+    check_merge_thresholds(&settings)?;
+    settings.lobbies.clear();
+    settings.lobby = Some(LobbyFile {
+        name: name.to_string(),
+        path: path.clone(),
+    });
+
+    if settings.server.port == base.server.port {
+        return Err(anyhow::anyhow!(
+            "Lobby {} uses the same port as the main server ({})",
+            name,
+            base.server.port
+        )
+        .into());
+    }
+    // don't fight over ports or files with the main server
+    if settings.json_api.port == base.json_api.port || settings.json_api.port == base.server.port {
+        settings.json_api.port = settings.server.port;
+    }
+    if settings.shines.names_filename != base.shines.names_filename {
+        tracing::warn!("Lobby {} uses the shine names of the main settings, they're shared by all lobbies", name);
+        settings.shines.names_filename = base.shines.names_filename.clone();
+    }
+    if settings.persist_shines.filename == base.persist_shines.filename {
+        settings.persist_shines.filename = format!("./moons.{}.json", name);
+    }
+    if settings.recording.directory == base.recording.directory {
+        settings.recording.directory = base.recording.directory.join(name);
+    }
+    if settings.discovery.name == base.discovery.name {
+        settings.discovery.name = format!("{} ({})", base.discovery.name, name);
+    }
+    if settings.master_server.name == base.master_server.name {
+        settings.master_server.name = format!("{} ({})", base.master_server.name, name);
+    }
+    if settings.overlay.enabled && settings.overlay.port == base.overlay.port {
+        tracing::warn!("Lobby {} uses the same overlay port as the main server, disabling the overlay", name);
+        settings.overlay.enabled = false;
+    }
+    if settings.websocket.enabled && settings.websocket.port == base.websocket.port {
+        tracing::warn!("Lobby {} uses the same websocket port as the main server, disabling the websocket bridge", name);
+        settings.websocket.enabled = false;
+    }
+    if settings.rcon.enabled && settings.rcon.port == base.rcon.port {
+        tracing::warn!("Lobby {} uses the same RCON port as the main server, disabling RCON", name);
+        settings.rcon.enabled = false;
+    }
+    if settings.link.enabled
+        && settings.link.port == base.link.port
+        && settings.link.peers == base.link.peers
+    {
+        tracing::warn!("Lobby {} uses the same link settings as the main server, disabling links", name);
+        settings.link.enabled = false;
+    }
+
+    Ok(settings)
+}
+
+/// Reload the settings from the file they were loaded from
+pub fn reload_settings(current: &Settings) -> Result<Settings> {
+    let mut base = load_settings()?;
+    match &current.lobby {
+        Some(lobby) => load_lobby_settings(&base, &lobby.name, &lobby.path),
+        None => {
+            current.overrides.apply(&mut base);
+            Ok(base)
+        }
+    }
+}
+
+fn merge_json(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_json(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn default_roles() -> BTreeMap<String, BTreeSet<String>> {
+    let players = [
+        "Status/Server",
+        "Status/Shines",
+        "Status/Tag",
+        "Status/Players",
+        "Status/Players/Name",
+        "Status/Players/Kingdom",
+        "Status/Players/Stage",
+        "Status/Players/Scenario",
+        "Status/Players/Costume",
+        "Status/Players/Capture",
+        "Status/Players/Tagged",
+        "Status/Players/MissingShines",
+    ];
+    let admin = [
+        "Commands",
+        "Commands/*",
+        "Status/Players/ID",
+        "Status/Players/GameMode",
+        "Status/Players/Position",
+        "Status/Players/Rotation",
+        "Status/Players/Is2D",
+        "Status/Players/IPv4",
+        "Status/Players/Alts",
+        "Status/Players/Transport",
+        "Status/Players/Connection",
+        "Status/Log",
+    ];
+    let moderator = [
+        "Commands",
+        "Commands/list",
+        "Commands/send",
+        "Commands/sendto",
+        "Commands/warp",
+        "Commands/sendall",
+        "Commands/crash",
+        "Commands/kick",
+        "Commands/audit/list",
+        "Commands/history",
+        "Commands/rejoin",
+        "Commands/ban",
+        "Commands/unban",
+        "Commands/allowlist",
+        "Commands/tag",
+        "Commands/filter",
+        "Commands/spectate",
+        "Status/Players/ID",
+    ];
+    let viewer = ["Commands", "Commands/list"];
+
+    let role = |perms: &[&str]| perms.iter().chain(players.iter()).map(ToString::to_string).collect();
+    BTreeMap::from([
+        ("Admin".to_string(), role(&admin)),
+        ("Moderator".to_string(), role(&moderator)),
+        ("Viewer".to_string(), role(&viewer)),
+    ])
 }
 
 impl Default for JsonApiSettings {
@@ -264,6 +1748,170 @@ impl Default for JsonApiSettings {
             enabled: false,
             port: 1027,
             tokens: Default::default(),
+            roles: default_roles(),
+            blocking: Default::default(),
+            map: false,
+            allowed_ips: Vec::new(),
         }
     }
 }
+
+impl Default for ApiBlockSettings {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            block_seconds: 0,
+            whitelist: Default::default(),
+            persist_filename: Some("./api_blocks.json".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn limited(max_length: usize) -> NameSettings {
+        NameSettings {
+            max_length,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sanitize_names() {
+        let names = NameSettings::default();
+        assert_eq!(names.sanitize("Mario\0\0\0"), "Mario");
+        assert_eq!(names.sanitize("  Ma\u{7}rio\n "), "Mario");
+        assert_eq!(names.sanitize("\0\0"), "Player");
+        assert_eq!(names.sanitize(" \t "), "Player");
+        assert_eq!(limited(3).sanitize("Mario"), "Mar");
+        assert_eq!(limited(3).sanitize("Äöü€x"), "Äöü");
+
+        let keep = NameSettings {
+            strip_control_characters: false,
+            ..Default::default()
+        };
+        assert_eq!(keep.sanitize("Ma\u{7}rio"), "Ma\u{7}rio");
+    }
+
+    #[test]
+    fn deduplicate_names() {
+        let names = NameSettings::default();
+        assert_eq!(names.deduplicate("Mario", |_| false), "Mario");
+        assert_eq!(names.deduplicate("Mario", |n| n == "Mario"), "Mario(2)");
+        assert_eq!(names.deduplicate("Mario", |n| ["Mario", "Mario(2)"].contains(&n)), "Mario(3)");
+        assert_eq!(limited(6).deduplicate("Mario", |n| n == "Mario"), "Mar(2)");
+
+        // cut at a char boundary to fit the bytes of a name packet
+        let long = "é".repeat(CLIENT_NAME_SIZE / 2);
+        let renamed = names.deduplicate(&long, |n| n == long);
+        assert_eq!(renamed, "é".repeat(CLIENT_NAME_SIZE / 2 - 2) + "(2)");
+        assert!(renamed.len() <= CLIENT_NAME_SIZE);
+    }
+
+    #[test]
+    fn bind_addresses() {
+        assert_eq!("0.0.0.0".parse(), Ok(BindAddress::One(ip("0.0.0.0"))));
+        assert_eq!("::, 0.0.0.0".parse(), Ok(BindAddress::Many(vec![ip("::"), ip("0.0.0.0")])));
+        for address in ["", " ", "0.0.0.0,", "localhost"] {
+            assert!(address.parse::<BindAddress>().is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn overrides_are_not_saved() {
+        let mut settings = Settings::default();
+        settings.server.port = 1027;
+        settings.server.max_players = 8;
+        let overrides = Overrides::new(Some(1127), Some("127.0.0.1".parse().unwrap()), Some(4));
+        overrides.apply(&mut settings);
+        assert_eq!(settings.server.port, 1127);
+        assert_eq!(settings.server.max_players, 4);
+
+        // changed at runtime, e.g. with `maxplayers`
+        settings.server.max_players = 10;
+        let saved = settings.without_overrides();
+        assert_eq!(saved.server.port, 1027);
+        assert_eq!(saved.server.address, Settings::default().server.address);
+        assert_eq!(saved.server.max_players, 10);
+
+        assert!(matches!(Settings::default().without_overrides(), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn lobby_settings_round_trip() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("smoo-lobby-{}.json", std::process::id()));
+        // unversioned, with the old name of the banned ip addresses
+        std::fs::write(&path, r#"{"Server": {"Port": 1130}, "BanList": {"Ips": ["10.0.0.1"]}}"#).unwrap();
+
+        let mut base = Settings::default();
+        base.shines.names_filename = Some("names.json".to_string());
+        let settings = load_lobby_settings(&base, "test", &path).unwrap();
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.server.port, 1130);
+        assert!(settings.ban_list.ip_addresses.contains(&ip("10.0.0.1")));
+        assert_eq!(settings.shines.names_filename.as_deref(), Some("names.json"));
+
+        save_settings(&settings).unwrap();
+        let reloaded = load_lobby_settings(&base, "test", &path).unwrap();
+        assert_eq!(serde_json::to_value(&reloaded).unwrap(), serde_json::to_value(&settings).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        let mut backup = path.into_os_string();
+        backup.push(".v0.bak");
+        std::fs::remove_file(backup).unwrap();
+    }
+
+    #[test]
+    fn lobbies_share_the_shine_names() {
+        let path = std::env::temp_dir().join(format!("smoo-lobby-names-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"Version": 1, "Server": {"Port": 1130}, "Shines": {"NamesFilename": "own.json"}}"#).unwrap();
+        let base = Settings::default();
+        let settings = load_lobby_settings(&base, "test", &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(settings.shines.names_filename, base.shines.names_filename);
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let range: IpRange = "192.168.0.0/16".parse().unwrap();
+        assert!(range.contains(&ip("192.168.0.1")));
+        assert!(range.contains(&ip("192.168.255.255")));
+        assert!(!range.contains(&ip("192.169.0.1")));
+        // mapped addresses of dual stack sockets
+        assert!(range.contains(&ip("::ffff:192.168.1.2")));
+        assert!(!range.contains(&ip("fe80::1")));
+
+        let single: IpRange = "10.0.0.1".parse().unwrap();
+        assert!(single.contains(&ip("10.0.0.1")));
+        assert!(!single.contains(&ip("10.0.0.2")));
+
+        let all: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("8.8.8.8")));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(&ip("2001:db8:1::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn invalid_ip_ranges() {
+        for range in ["", "10.0.0", "10.0.0.0/33", "::/129", "10.0.0.0/x", "10.0.0.0/"] {
+            assert!(range.parse::<IpRange>().is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn ip_range_round_trip() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(String::from(range), "10.0.0.0/8");
+        assert_eq!(String::from("10.0.0.1".parse::<IpRange>().unwrap()), "10.0.0.1/32");
+        assert_eq!(IpRange::try_from(String::from(range)), Ok(range));
+    }
+}
@@ -0,0 +1,187 @@
+//! Task that owns collecting, syncing and saving the shines of a lobby
+//!
+//! The coordinator only hands the work over with a `ShineRequest`, so bursts of moons or slow clients don't hold up
+//! the processing of unrelated packets.
+
+use std::time::Duration;
+
+use tokio::{
+    select,
+    sync::mpsc,
+    time::{sleep_until, timeout, Instant},
+};
+
+use crate::{
+    cmds::{reply::ReplyChannel, ClientCommand, ShineRequest},
+    coordinator::save_shines,
+    events::ServerEvent,
+    guid::Guid,
+    lobby::Lobby,
+    net::{Packet, PacketData},
+    shines::Shines,
+    types::Result,
+};
+
+/// How long shine syncs are collected before sending them, so that bursts of moons or costume changes sync only once
+const SHINE_SYNC_DELAY: Duration = Duration::from_millis(250);
+/// How long a sync waits for a client to take its shines
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ShineSync {
+    lobby: Lobby,
+    requests: mpsc::Receiver<ShineRequest>,
+    /// When the next debounced sync is due
+    sync_due: Option<Instant>,
+}
+
+impl ShineSync {
+    pub fn new(lobby: Lobby) -> (Self, mpsc::Sender<ShineRequest>) {
+        let (to_sync, requests) = mpsc::channel(100);
+        let sync = Self {
+            lobby,
+            requests,
+            sync_due: None,
+        };
+        (sync, to_sync)
+    }
+
+    /// Handle requests until all senders are gone, then save the shines a last time
+    pub async fn run(mut self) {
+        loop {
+            let sync_due = self.sync_due;
+            let request = select! {
+                request = self.requests.recv() => match request {
+                    Some(request) => request,
+                    None => break,
+                },
+                _ = sleep_until(sync_due.unwrap_or_else(Instant::now)), if sync_due.is_some() => ShineRequest::SyncNow(None),
+            };
+            if let Err(e) = self.handle(request).await {
+                tracing::warn!("Shine sync error: {e}");
+            }
+        }
+        self.persist().await;
+    }
+
+    async fn handle(&mut self, request: ShineRequest) -> Result<()> {
+        match request {
+            ShineRequest::Collected { id, shine_id, is_grand } => self.collected(id, shine_id, is_grand).await?,
+            ShineRequest::Sync => {
+                self.sync_due.get_or_insert_with(|| Instant::now() + SHINE_SYNC_DELAY);
+            }
+            ShineRequest::SyncNow(reply) => {
+                self.sync_due = None;
+                self.sync(None, reply).await;
+            }
+            ShineRequest::SyncPlayer(guid) => self.sync(Some(guid), None).await,
+            ShineRequest::Clear { players } => {
+                self.lobby.shines.write().await.clear();
+                if players {
+                    for mut player in self.lobby.players.iter_mut() {
+                        player.shine_sync.clear();
+                    }
+                }
+                self.persist().await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn collected(&mut self, id: Guid, shine_id: i32, is_grand: bool) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let is_excluded = settings.shines.excluded.contains(&shine_id);
+        let is_ghost = settings.ghosts.players.contains(&id);
+        drop(settings);
+
+        if is_ghost {
+            tracing::info!("Got moon {} (ghost)", Shines::describe(shine_id));
+        } else if is_excluded {
+            tracing::info!("Got moon {} (excluded)", Shines::describe(shine_id));
+        } else {
+            let is_new = self.lobby.shines.write().await.insert(shine_id, is_grand);
+            tracing::info!("Got moon {}", Shines::describe(shine_id));
+            if is_new {
+                self.lobby.emit(ServerEvent::MoonCollected {
+                    id,
+                    name: self.lobby.get_client(&id)?.name.clone(),
+                    shine_id,
+                });
+            }
+            self.sync_due.get_or_insert_with(|| Instant::now() + SHINE_SYNC_DELAY);
+        }
+        Ok(())
+    }
+
+    /// Send the missing shines to all players, or only to the given one, answering the reply once they are sent.
+    ///
+    /// The sending happens in the background, for slow clients to not hold up the next requests.
+    async fn sync(&self, only: Option<Guid>, reply: Option<ReplyChannel<Result<String>>>) {
+        let settings = self.lobby.settings.read().await;
+        if !settings.shines.enabled {
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok("Shine sync is disabled".to_string()));
+            }
+            return;
+        }
+        let excluded_shines = settings.shines.excluded.clone();
+        let excluded_players = settings.shines.excluded_players.clone();
+        drop(settings);
+        let server_shines = self.lobby.shines.read().await.clone();
+
+        // the missing shines of every player, to not hold any locks while sending them
+        let missing: Vec<_> = self
+            .lobby
+            .players
+            .iter()
+            .filter(|player| !player.disable_shine_sync && !excluded_players.contains(player.key()))
+            .filter(|player| only.is_none_or(|guid| *player.key() == guid))
+            .map(|player| {
+                let shines: Vec<_> = server_shines
+                    .iter()
+                    .filter(|id| !player.shine_sync.contains(id) && !excluded_shines.contains(id))
+                    .map(|&id| (id, server_shines.is_grand(id)))
+                    .collect();
+                (player.channel.clone(), shines)
+            })
+            .filter(|(_, shines)| !shines.is_empty())
+            .collect();
+
+        tokio::spawn(async move {
+            let sends = missing.into_iter().map(|(channel, shines)| async move {
+                let send = async {
+                    for (shine_id, is_grand) in shines {
+                        let data = PacketData::Shine { shine_id, is_grand };
+                        channel.send(ClientCommand::SelfAddressed(Packet::new(Guid::NIL, data))).await?;
+                    }
+                    Ok(())
+                };
+                // the shines missing for a stuck client are sent again with the next sync
+                timeout(SEND_TIMEOUT, send).await.unwrap_or(Ok(()))
+            });
+            let result: Result<()> = futures::future::join_all(sends).await.into_iter().collect();
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(result.map(|()| "Synced shine bags".to_string()));
+                }
+                None => {
+                    if let Err(e) = result {
+                        tracing::warn!("Shine sync error: {e}");
+                    }
+                }
+            }
+        });
+    }
+
+    async fn persist(&self) {
+        let settings = self.lobby.settings.read().await;
+        if settings.persist_shines.enabled {
+            let persist = settings.persist_shines.clone();
+            let excluded = settings.shines.excluded.clone();
+            drop(settings);
+            let shines = self.lobby.shines.read().await.clone();
+            if let Err(e) = save_shines(&persist, shines, excluded).await {
+                tracing::error!("Error saving shines: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Names of the shines, to show them instead of bare ids
+//!
+//! The names are read from the file in `Shines.NamesFilename`, mapping the shine ids to their names
+//! and kingdoms, e.g. `{"155": {"Name": "Moon Name", "Kingdom": "Sand Kingdom"}}`.
+//! The server doesn't ship such a file, without one the shines are only shown by their id and
+//! `shine missing` lists all of them under `Unknown`. The names are shared by all lobbies, so only the file
+//! of the main settings is read, again by `loadsettings`.
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::RwLock,
+};
+
+use crate::{settings::ShineTable, types::Result};
+
+lazy_static! {
+    static ref SHINES: RwLock<BTreeMap<i32, ShineInfo>> = RwLock::new(BTreeMap::new());
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ShineInfo {
+    pub name: String,
+    pub kingdom: Option<String>,
+}
+
+pub struct Shines {}
+
+impl Shines {
+    /// Replace the known shine names with the ones in the file, a missing file is fine
+    pub fn load(filename: &str) -> Result<usize> {
+        let shines: BTreeMap<i32, ShineInfo> = match std::fs::File::open(filename) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let count = shines.len();
+        *SHINES.write().expect("Shine names lock poisoned") = shines;
+        Ok(count)
+    }
+
+    /// Load the names from the file in the settings, or forget them if there is none
+    pub fn reload(settings: &ShineTable) {
+        let Some(filename) = &settings.names_filename else {
+            SHINES.write().expect("Shine names lock poisoned").clear();
+            return;
+        };
+        match Self::load(filename) {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Loaded the names of {} shines", count),
+            Err(e) => tracing::warn!("Failed to load the shine names from {}: {}", filename, e),
+        }
+    }
+
+    pub fn info(id: i32) -> Option<ShineInfo> {
+        SHINES.read().expect("Shine names lock poisoned").get(&id).cloned()
+    }
+
+    /// The id followed by the kingdom and name of the shine, if known, e.g. `155 (Sand Kingdom: Moon Name)`
+    pub fn describe(id: i32) -> String {
+        match Self::info(id) {
+            Some(ShineInfo { name, kingdom: Some(kingdom) }) => format!("{} ({}: {})", id, kingdom, name),
+            Some(ShineInfo { name, kingdom: None }) => format!("{} ({})", id, name),
+            None => id.to_string(),
+        }
+    }
+
+    /// Shines of the server the player doesn't have yet, grouped by their kingdom
+    pub fn missing(server: &BTreeSet<i32>, player: &BTreeSet<i32>) -> BTreeMap<String, Vec<i32>> {
+        let shines = SHINES.read().expect("Shine names lock poisoned");
+        let mut missing: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+        for id in server.difference(player) {
+            let kingdom = shines.get(id).and_then(|s| s.kingdom.clone());
+            missing.entry(kingdom.unwrap_or_else(|| "Unknown".to_string())).or_default().push(*id);
+        }
+        missing
+    }
+}
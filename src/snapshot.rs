@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time::interval,
+};
+
+use crate::{
+    cmds::ServerWideCommand,
+    guid::Guid,
+    lobby::Lobby,
+    net::{Packet, PacketData},
+    types::Result,
+};
+
+/// State of a player that dashboards are interested in
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PlayerSnapshot {
+    pub name: String,
+    pub stage: Option<String>,
+    pub scenario: i8,
+    pub body: Option<String>,
+    pub cap: Option<String>,
+    pub capture: Option<String>,
+    pub is_seeking: Option<bool>,
+    /// Hide and seek time in seconds
+    pub time: Option<u64>,
+}
+
+/// State of the whole lobby at one tick
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LobbySnapshot {
+    pub tick: u64,
+    pub players: BTreeMap<Guid, PlayerSnapshot>,
+    pub shines: usize,
+}
+
+/// Changes between two consecutive snapshots
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SnapshotDiff {
+    pub tick: u64,
+    pub joined: BTreeMap<Guid, PlayerSnapshot>,
+    pub changed: BTreeMap<Guid, PlayerSnapshot>,
+    pub left: Vec<Guid>,
+    /// Amount of collected shines, if it changed
+    pub shines: Option<usize>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.joined.is_empty() && self.changed.is_empty() && self.left.is_empty() && self.shines.is_none()
+    }
+}
+
+impl LobbySnapshot {
+    async fn capture(lobby: &Lobby, tick: u64) -> Self {
+        let players = lobby
+            .players
+            .iter()
+            .map(|p| {
+                let (stage, scenario) = match &p.last_game_packet {
+                    Some(Packet { data: PacketData::Game { stage, scenario_num, .. }, .. }) => {
+                        (Some(stage.clone()), *scenario_num)
+                    }
+                    _ => (None, p.scenario),
+                };
+                let (body, cap) = match &p.last_costume_packet {
+                    Some(Packet { data: PacketData::Costume(costume), .. }) => {
+                        (Some(costume.body_name.clone()), Some(costume.cap_name.clone()))
+                    }
+                    _ => (None, None),
+                };
+                let capture = match &p.last_capture_packet {
+                    Some(Packet { data: PacketData::Capture { model }, .. }) if !model.is_empty() => Some(model.clone()),
+                    _ => None,
+                };
+                let snapshot = PlayerSnapshot {
+                    name: p.name.clone(),
+                    stage,
+                    scenario,
+                    body,
+                    cap,
+                    capture,
+                    is_seeking: p.is_seeking,
+                    time: p.time.map(|t| t.as_secs()),
+                };
+                (*p.key(), snapshot)
+            })
+            .collect();
+
+        Self {
+            tick,
+            players,
+            shines: lobby.shines.read().await.len(),
+        }
+    }
+
+    pub fn diff(&self, newer: &LobbySnapshot) -> SnapshotDiff {
+        let mut joined = BTreeMap::new();
+        let mut changed = BTreeMap::new();
+        for (guid, player) in &newer.players {
+            match self.players.get(guid) {
+                None => {
+                    joined.insert(*guid, player.clone());
+                }
+                Some(old) if old != player => {
+                    changed.insert(*guid, player.clone());
+                }
+                Some(_) => {}
+            }
+        }
+        let left = self
+            .players
+            .keys()
+            .filter(|guid| !newer.players.contains_key(guid))
+            .copied()
+            .collect();
+
+        SnapshotDiff {
+            tick: newer.tick,
+            joined,
+            changed,
+            left,
+            shines: (self.shines != newer.shines).then_some(newer.shines),
+        }
+    }
+}
+
+/// Latest snapshot and the stream of diffs, shared by every consumer of the lobby state
+#[derive(Clone, Debug)]
+pub struct Snapshots {
+    latest: Arc<RwLock<Arc<LobbySnapshot>>>,
+    diffs: broadcast::Sender<Arc<SnapshotDiff>>,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self {
+            latest: Default::default(),
+            diffs: broadcast::channel(16).0,
+        }
+    }
+}
+
+impl Snapshots {
+    pub fn latest(&self) -> Arc<LobbySnapshot> {
+        self.latest.read().expect("Snapshot lock poisoned").clone()
+    }
+
+    /// Diffs of the following ticks, apply them on top of [`Snapshots::latest`]
+    #[allow(dead_code)] // used by dashboards embedding the library, the overlay only needs the latest snapshot
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<SnapshotDiff>> {
+        self.diffs.subscribe()
+    }
+}
+
+/// Take a snapshot of the lobby every tick and publish what changed
+pub async fn run(mut lobby: Lobby) -> Result<()> {
+    let interval_ms = lobby.settings.read().await.snapshots.interval_ms;
+    let mut ticks = interval(Duration::from_millis(interval_ms.max(1)));
+    let mut tick = 0;
+    loop {
+        select! {
+            _ = ticks.tick() => {},
+            cmd = lobby.server_recv.recv() => match cmd {
+                Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break,
+                Err(RecvError::Lagged(_)) => continue,
+            },
+        }
+
+        tick += 1;
+        let snapshot = Arc::new(LobbySnapshot::capture(&lobby, tick).await);
+        let previous = std::mem::replace(
+            &mut *lobby.snapshots.latest.write().expect("Snapshot lock poisoned"),
+            snapshot.clone(),
+        );
+        let diff = previous.diff(&snapshot);
+        if !diff.is_empty() {
+            // nobody listening is fine
+            let _ = lobby.snapshots.diffs.send(Arc::new(diff));
+        }
+    }
+    Ok(())
+}
@@ -1,8 +1,13 @@
 use lazy_static::lazy_static;
 
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
 
 lazy_static! {
+    /// Aliases of `StageAliases` in the settings, naming one stage or a group of them
+    static ref CUSTOM_ALIASES: RwLock<HashMap<String, Vec<String>>> = RwLock::new(HashMap::new());
     static ref ALIAS2STAGE: HashMap<&'static str, &'static str> = HashMap::from([
         ("cap", "CapWorldHomeStage"),
         ("cascade", "WaterfallWorldHomeStage"),
@@ -226,7 +231,31 @@ lazy_static! {
 pub struct Stages {}
 
 impl Stages {
+    /// Replace the aliases from the settings, aliases of kingdoms can't be redefined
+    pub fn set_custom_aliases(aliases: &BTreeMap<String, Vec<String>>) {
+        let mut custom = HashMap::new();
+        for (alias, stages) in aliases {
+            if ALIAS2STAGE.contains_key(alias.as_str()) {
+                tracing::warn!("Stage alias {} is already the alias of a kingdom, ignoring it", alias);
+                continue;
+            }
+            if stages.is_empty() {
+                tracing::warn!("Stage alias {} doesn't name any stages, ignoring it", alias);
+                continue;
+            }
+            for stage in stages.iter().filter(|stage| !Self::is_stage(stage)) {
+                tracing::warn!("Stage alias {} names the unknown stage {}", alias, stage);
+            }
+            custom.insert(alias.clone(), stages.clone());
+        }
+        *CUSTOM_ALIASES.write().expect("Stage alias lock poisoned") = custom;
+    }
+
     pub fn input2stage(input: &str) -> Option<String> {
+        // alias from the settings, of the first stage of a group
+        if let Some(stages) = CUSTOM_ALIASES.read().expect("Stage alias lock poisoned").get(input) {
+            return stages.first().cloned();
+        }
         // alias value
         if Self::is_alias(input) {
             return match ALIAS2STAGE.get(&input) {
@@ -255,6 +284,11 @@ impl Stages {
         }
     }
 
+    /// Alias of the kingdom the stage belongs to
+    pub fn stage2alias(stage: &str) -> Option<String> {
+        STAGE2ALIAS.get(&stage).map(|alias| alias.to_string())
+    }
+
     pub fn is_alias(input: &str) -> bool {
         return ALIAS2STAGE.contains_key(&input);
     }
@@ -263,7 +297,25 @@ impl Stages {
         return STAGE2ALIAS.contains_key(&input);
     }
 
+    pub fn aliases() -> Vec<String> {
+        let custom = CUSTOM_ALIASES.read().expect("Stage alias lock poisoned");
+        ALIAS2STAGE.keys().map(ToString::to_string).chain(custom.keys().cloned()).collect()
+    }
+
+    pub fn stages() -> Vec<&'static str> {
+        STAGE2ALIAS.keys().copied().collect()
+    }
+
+    /// All stages of the kingdom that the stage or alias belongs to
+    pub fn kingdom_stages(input: &str) -> Option<Vec<String>> {
+        let alias = Self::input2stage(input).and_then(|stage| Self::stage2alias(&stage))?;
+        Some(Self::stages_by_input(&alias))
+    }
+
     pub fn stages_by_input(input: &str) -> Vec<String> {
+        if let Some(stages) = CUSTOM_ALIASES.read().expect("Stage alias lock poisoned").get(input) {
+            return stages.clone();
+        }
         if Self::is_alias(input) {
             return STAGE2ALIAS.iter().filter(|(_k,v)| **v == input).map(|(k,_v)| k.to_string()).collect::<Vec<_>>();
         }
@@ -0,0 +1,76 @@
+//! Notifications for systemd services with `Type=notify` and `WatchdogSec=`
+//!
+//! Everything is a no-op unless systemd passed a `NOTIFY_SOCKET` to the server.
+
+use std::{future::pending, time::Duration};
+
+use tokio::time::{interval, Interval};
+
+/// Tell systemd that the server accepts clients now
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Keepalives for the systemd watchdog, to be sent from a loop that stops when the server is wedged
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            interval: watchdog_interval().map(interval),
+        }
+    }
+}
+
+impl Watchdog {
+    /// Wait for and send the next keepalive, never completes if systemd doesn't expect any
+    pub async fn keepalive(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+                notify("WATCHDOG=1");
+            }
+            None => pending().await,
+        }
+    }
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // twice as often as required, to not miss it because of a slow tick
+    Some(Duration::from_micros(usec / 2)).filter(|d| !d.is_zero())
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+    if let Err(e) = result {
+        tracing::warn!("Failed to notify systemd with {}: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
@@ -0,0 +1,80 @@
+//! Scoreboard of the hide and seek rounds started with `tag start`
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::guid::Guid;
+
+#[derive(Clone, Debug, Default)]
+pub struct TagScoreboard {
+    /// Hiders of the current round that weren't tagged yet
+    pub hiding: BTreeSet<Guid>,
+    /// Hiders of the current round in the order they got tagged
+    pub tagged: Vec<Guid>,
+    /// Amount of finished rounds
+    pub rounds: u32,
+    pub scores: BTreeMap<Guid, TagScore>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TagScore {
+    pub name: String,
+    pub rounds: u32,
+    pub wins: u32,
+    /// Seconds hidden over all rounds
+    pub hide_time: u64,
+}
+
+impl TagScoreboard {
+    pub fn start_round(&mut self, hiders: impl IntoIterator<Item = Guid>) {
+        self.hiding = hiders.into_iter().collect();
+        self.tagged.clear();
+    }
+
+    /// Whether a round is running that still has hiders
+    pub fn is_running(&self) -> bool {
+        !self.hiding.is_empty()
+    }
+
+    /// Note a hider as tagged, returns whether that ended the round
+    pub fn tag(&mut self, id: Guid) -> bool {
+        if !self.hiding.remove(&id) {
+            return false;
+        }
+        self.tagged.push(id);
+        self.hiding.is_empty()
+    }
+
+    /// A hider left the server, returns whether that ended the round
+    pub fn leave(&mut self, id: Guid) -> bool {
+        self.hiding.remove(&id) && self.hiding.is_empty() && !self.tagged.is_empty()
+    }
+
+    /// Count the round with the hide times of the players, the hider tagged last wins
+    pub fn finish_round(&mut self, players: &[(Guid, String, Duration)]) -> Option<Guid> {
+        self.rounds += 1;
+        let winner = self.tagged.last().copied();
+        for (id, name, time) in players {
+            let score = self.scores.entry(*id).or_default();
+            score.name = name.clone();
+            score.rounds += 1;
+            score.hide_time += time.as_secs();
+            if winner == Some(*id) {
+                score.wins += 1;
+            }
+        }
+        winner
+    }
+
+    /// Most wins first, then the longest hide time
+    pub fn standings(&self) -> Vec<TagScore> {
+        let mut scores: Vec<_> = self.scores.values().cloned().collect();
+        scores.sort_by(|a, b| b.wins.cmp(&a.wins).then(b.hide_time.cmp(&a.hide_time)));
+        scores
+    }
+}
@@ -37,16 +37,6 @@ impl MockClient {
             .expect("Couldn't bind udp port");
         let udp = UdpConnection::new(udp_sock, "127.0.0.1".parse().unwrap());
 
-        let init_packet = timeout(Duration::from_millis(100), tcp.read_packet())
-            .await
-            .expect("Init packet timed out")
-            .expect("Init packet recv failed");
-
-        match init_packet.data {
-            PacketData::Init { max_players } => assert!(max_players > 0),
-            _ => panic!("First packet not init packet"),
-        }
-
         let data = PacketData::Connect {
             c_type: ConnectionType::FirstConnection,
             max_player: u16::MAX,
@@ -59,6 +49,17 @@ impl MockClient {
             .await
             .expect("Failed to send connect packet");
 
+        // the server answers the connect packet with its init packet
+        let init_packet = timeout(Duration::from_millis(100), tcp.read_packet())
+            .await
+            .expect("Init packet timed out")
+            .expect("Init packet recv failed");
+
+        match init_packet.data {
+            PacketData::Init { max_players } => assert!(max_players > 0),
+            _ => panic!("First packet not init packet"),
+        }
+
         Self { guid, tcp, udp }
     }
 
@@ -12,3 +12,15 @@ impl Default for Costume {
         }
     }
 }
+
+/// Body and cap names of the costumes in the unmodded game
+pub const VANILLA_COSTUMES: &[&str] = &[
+    "Mario", "Mario3D", "Mario64", "Mario64Metal", "MarioAloha", "MarioArmor", "MarioBone",
+    "MarioClown", "MarioColorClassic", "MarioColorGold", "MarioColorLuigi", "MarioColorWaluigi",
+    "MarioColorWario", "MarioCook", "MarioDiddyKong", "MarioDoctor", "MarioExplorer",
+    "MarioFootball", "MarioGolf", "MarioGunman", "MarioHakama", "MarioHappi", "MarioInvisible",
+    "MarioKing", "MarioKoopa", "MarioMaker", "MarioNew3DS", "MarioPainter", "MarioPeach",
+    "MarioPilot", "MarioPirate", "MarioPoncho", "MarioPrimitiveMan", "MarioSailor",
+    "MarioScientist", "MarioShopman", "MarioSnowSuit", "MarioSpaceSuit", "MarioSuit",
+    "MarioSwimwear", "MarioTailCoat", "MarioTuxedo", "MarioUnderwear",
+];
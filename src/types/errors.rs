@@ -1,7 +1,7 @@
 use std::{num::TryFromIntError, str::Utf8Error};
 
 use crate::{
-    cmds::{ClientCommand, Command, ServerWideCommand},
+    cmds::{ClientCommand, Command, ServerWideCommand, ShineRequest},
     guid::Guid,
 };
 use hex::FromHexError;
@@ -58,6 +58,8 @@ pub enum ChannelError {
     SendChannel(#[from] SendError<Command>),
     #[error("Sending client channel error")]
     SendClientChannel(#[from] SendError<ClientCommand>),
+    #[error("Sending shine channel error")]
+    SendShineChannel(#[from] SendError<ShineRequest>),
 
     #[error("Client broadcast channel sending error")]
     SendClientBroadcastChannel(#[from] broadcast::error::SendError<ClientCommand>),
@@ -76,6 +78,8 @@ pub enum ChannelError {
 pub enum EncodingError {
     #[error("Not enough data")]
     NotEnoughData,
+    #[error("Packet smaller than its layout")]
+    SmallerThanLayout,
     #[error("Invalid string data")]
     BadUtf8(#[from] Utf8Error),
     #[error("Invalid integer conversion")]
@@ -105,6 +109,19 @@ pub enum ClientInitError {
 }
 
 impl SMOError {
+    /// Whether a received packet failed to decode, the connection itself is fine
+    pub fn is_malformed_packet(&self) -> bool {
+        matches!(
+            self,
+            Self::Encoding(
+                EncodingError::NotEnoughData
+                    | EncodingError::SmallerThanLayout
+                    | EncodingError::BadUtf8(_)
+                    | EncodingError::IntConversion(_)
+            )
+        )
+    }
+
     pub fn severity(&self) -> ErrorSeverity {
         match self {
             Self::Encoding(EncodingError::ConnectionClose)
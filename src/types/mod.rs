@@ -1,9 +1,11 @@
 mod costume;
 mod errors;
+mod pattern;
 mod quat;
 mod vec;
 
 pub use costume::*;
 pub use errors::*;
+pub use pattern::*;
 pub use quat::*;
 pub use vec::*;
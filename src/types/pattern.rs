@@ -0,0 +1,40 @@
+/// Whether the pattern matches the whole input, with `*` matching any characters
+pub fn matches_wildcard(pattern: &str, input: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = input.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches_wildcard("Stage", "Stage"));
+        assert!(!matches_wildcard("Stage", "Stages"));
+        assert!(matches_wildcard("*", ""));
+        assert!(matches_wildcard("Custom*", "CustomStage"));
+        assert!(matches_wildcard("*Stage", "CustomStage"));
+        assert!(matches_wildcard("Custom*Stage", "CustomStage"));
+        assert!(matches_wildcard("Custom*Stage", "CustomHomeStage"));
+        assert!(!matches_wildcard("Custom*Stage", "CustomStageZone"));
+        assert!(matches_wildcard("a*b*c", "abbc"));
+        assert!(!matches_wildcard("a*b*c", "acb"));
+        // the parts can't overlap
+        assert!(!matches_wildcard("ab*ba", "aba"));
+    }
+}
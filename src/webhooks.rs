@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+
+use crate::{
+    cmds::ServerWideCommand,
+    events::ServerEvent,
+    lobby::Lobby,
+    settings::WebhookSettings,
+    types::Result,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Posts server events as JSON to the URLs configured for them
+pub struct Webhooks {
+    lobby: Lobby,
+    events: broadcast::Receiver<ServerEvent>,
+}
+
+impl Webhooks {
+    /// Subscribes right away, so no event emitted after this is missed
+    pub fn new(lobby: Lobby) -> Self {
+        Self {
+            events: lobby.events.subscribe(),
+            lobby,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await.webhooks.clone();
+        if !settings.enabled {
+            return Ok(());
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(anyhow::Error::from)?;
+
+        loop {
+            let event = select! {
+                event = self.events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(amount)) => {
+                        tracing::warn!("Webhooks skipped {} events", amount);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => {
+                        // deliver the stop event before going down
+                        let settings = self.lobby.settings.read().await.webhooks.clone();
+                        dispatch(client, settings, ServerEvent::ServerStop).await;
+                        break;
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            let settings = self.lobby.settings.read().await.webhooks.clone();
+            tokio::spawn(dispatch(client.clone(), settings, event));
+        }
+        Ok(())
+    }
+}
+
+async fn dispatch(client: reqwest::Client, settings: WebhookSettings, event: ServerEvent) {
+    let urls = match settings.hooks.get(event.name()) {
+        Some(urls) => urls,
+        None => return,
+    };
+
+    for url in urls {
+        for attempt in 0..=settings.retries {
+            if attempt > 0 {
+                sleep(RETRY_DELAY * attempt).await;
+            }
+
+            let result = client
+                .post(url)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => break,
+                Err(e) if attempt == settings.retries => {
+                    tracing::warn!("Webhook {} for {} failed, giving up: {}", url, event.name(), e)
+                }
+                Err(e) => tracing::debug!("Webhook {} for {} failed, retrying: {}", url, event.name(), e),
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Relayed packets over WebSockets, for browser based map viewers without a native client
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+};
+
+use async_tungstenite::tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message,
+};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::broadcast::error::RecvError,
+};
+
+use crate::{
+    cmds::{ClientCommand, ServerWideCommand},
+    lobby::Lobby,
+    net::{encoding::Encodable, Packet, PacketData, MAX_PACKET_SIZE},
+    relay::Relay,
+    stages::Stages,
+    types::Result,
+};
+
+/// Packet types the live map of the JSON API needs
+const MAP_PACKETS: &[&str] = &["connect", "disconnect", "game", "player", "tag"];
+
+/// How the packets are sent to a viewer, chosen with `?format=...`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Binary frames of encoded packets, as a client would receive them
+    Binary,
+    /// Text frames of JSON objects
+    Json,
+}
+
+pub struct WebSocketBridge {
+    lobby: Lobby,
+    cli_broadcast: Relay,
+}
+
+impl WebSocketBridge {
+    pub fn new(lobby: Lobby, cli_broadcast: Relay) -> Self {
+        Self { lobby, cli_broadcast }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let websocket = settings.websocket.clone();
+        let addr = SocketAddr::new(settings.server.address.primary(), websocket.port);
+        drop(settings);
+
+        if !websocket.enabled {
+            return Ok(());
+        }
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Streaming packets on ws://{}/", listener.local_addr()?);
+
+        loop {
+            let (socket, addr) = select! {
+                conn = listener.accept() => conn?,
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => break Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+
+            let bridge = Self::new(self.lobby.clone(), self.cli_broadcast.clone());
+            tokio::spawn(async move {
+                match bridge.stream(socket).await {
+                    Ok(()) => tracing::debug!("WebSocket viewer {} disconnected", addr),
+                    Err(e) => tracing::debug!("WebSocket viewer {} failed: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    async fn stream(mut self, socket: TcpStream) -> Result<()> {
+        let settings = self.lobby.settings.read().await;
+        let mut tokens = settings.websocket.tokens.clone();
+        // viewers of the live map of the JSON API
+        if settings.json_api.map {
+            for token in settings.json_api.tokens.keys() {
+                if settings.json_api.permissions(token).contains("Map") {
+                    tokens.entry(token.clone()).or_default().extend(MAP_PACKETS.iter().map(ToString::to_string));
+                }
+            }
+        }
+        drop(settings);
+        let mut viewer = None;
+        let handshake = Handshake {
+            tokens: &tokens,
+            viewer: &mut viewer,
+        };
+        let mut ws = async_tungstenite::tokio::accept_hdr_async(socket, handshake)
+            .await
+            .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {}", e))?;
+        let (types, format) = viewer.expect("Accepted viewers are authorized");
+        let mut cli_recv = self.cli_broadcast.subscribe();
+
+        // show the players that are already connected
+        let packets = self.lobby.sync_packets().await;
+        for packet in packets {
+            if let Some(message) = to_message(&packet, &types, format)? {
+                ws.send(message).await.map_err(|e| anyhow::anyhow!(e))?;
+            }
+        }
+
+        loop {
+            let packet = select! {
+                cmd = cli_recv.recv() => match cmd {
+                    Ok(ClientCommand::Packet(packet)) if !self.lobby.is_hidden(&packet).await => packet,
+                    Ok(_) => continue,
+                    // a slow viewer only loses packets, the clients don't wait on it
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                msg = ws.next() => match msg {
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(anyhow::anyhow!(e).into()),
+                },
+                cmd = self.lobby.server_recv.recv() => match cmd {
+                    Ok(ServerWideCommand::Shutdown) | Err(RecvError::Closed) => return Ok(()),
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+            };
+            if let Some(message) = to_message(&packet, &types, format)? {
+                ws.send(message).await.map_err(|e| anyhow::anyhow!(e))?;
+            }
+        }
+    }
+}
+
+/// Refuses viewers without a known token
+struct Handshake<'a> {
+    tokens: &'a BTreeMap<String, BTreeSet<String>>,
+    /// Packet types the token may receive, and the requested format
+    viewer: &'a mut Option<(BTreeSet<String>, Format)>,
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(self, request: &Request, response: Response) -> std::result::Result<Response, ErrorResponse> {
+        let query = request.uri().query().unwrap_or("");
+        let params: BTreeMap<_, _> = query.split('&').filter_map(|param| param.split_once('=')).collect();
+        let Some(types) = params.get("token").and_then(|token| self.tokens.get(*token)) else {
+            let mut error = ErrorResponse::new(Some("Unknown token".to_string()));
+            *error.status_mut() = StatusCode::UNAUTHORIZED;
+            return Err(error);
+        };
+        let format = match params.get("format") {
+            Some(&"json") => Format::Json,
+            _ => Format::Binary,
+        };
+        *self.viewer = Some((types.clone(), format));
+        Ok(response)
+    }
+}
+
+fn to_message(packet: &Packet, types: &BTreeSet<String>, format: Format) -> Result<Option<Message>> {
+    if !types.contains("*") && !types.contains(&packet.data.get_type_name()) {
+        return Ok(None);
+    }
+    let message = match format {
+        Format::Binary => {
+            let mut buff = BytesMut::with_capacity(MAX_PACKET_SIZE);
+            packet.encode(&mut buff)?;
+            Message::Binary(buff.to_vec())
+        }
+        Format::Json => Message::Text(to_json(packet).to_string()),
+    };
+    Ok(Some(message))
+}
+
+fn to_json(packet: &Packet) -> Value {
+    let mut value = json!({
+        "ID": packet.id.to_string(),
+        "Type": packet.data.get_type_name(),
+    });
+    let data = match &packet.data {
+        PacketData::Player { pos, rot, .. } => json!({
+            "Position": [pos.x, pos.y, pos.z],
+            "Rotation": [rot.w, rot.i, rot.j, rot.k],
+        }),
+        PacketData::Cap { pos, rot, cap_out, cap_anim } => json!({
+            "Position": [pos.x, pos.y, pos.z],
+            "Rotation": [rot.w, rot.i, rot.j, rot.k],
+            "CapOut": cap_out,
+            "CapAnim": cap_anim,
+        }),
+        PacketData::Game { is_2d, scenario_num, stage } => json!({
+            "Stage": stage,
+            "Kingdom": Stages::stage2kingdom(stage),
+            "Scenario": scenario_num,
+            "Is2D": is_2d,
+        }),
+        PacketData::Tag { is_it, seconds, minutes, .. } => json!({
+            "Tagged": is_it,
+            "Seconds": *minutes as u32 * 60 + *seconds as u32,
+        }),
+        PacketData::Connect { client_name, .. } => json!({ "Name": client_name }),
+        PacketData::Costume(costume) => json!({
+            "Body": costume.body_name,
+            "Cap": costume.cap_name,
+        }),
+        PacketData::Capture { model } => json!({ "Capture": model }),
+        _ => return value,
+    };
+    if let (Value::Object(value), Value::Object(data)) = (&mut value, data) {
+        value.extend(data);
+    }
+    value
+}
@@ -0,0 +1,143 @@
+use std::{io::Cursor, time::Duration};
+
+use bytes::BytesMut;
+use smoo::{
+    net::{encoding::Decodable, encoding::Encodable, Packet, PacketData},
+    server::Server,
+    settings::Settings,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+const DEFAULT_TIMEOUT_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    Client,
+    Server,
+}
+
+/// Read a fixture file with one hex encoded packet per line
+fn load_fixture(content: &str) -> Vec<(Origin, Vec<u8>)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (origin, data) = line.split_once(' ').expect("Malformed fixture line");
+            let origin = match origin {
+                "C" => Origin::Client,
+                "S" => Origin::Server,
+                _ => panic!("Unknown fixture origin {}", origin),
+            };
+            (origin, hex::decode(data).expect("Invalid hex in fixture"))
+        })
+        .collect()
+}
+
+fn session_fixture() -> Vec<(Origin, Vec<u8>)> {
+    load_fixture(include_str!("fixtures/session.txt"))
+}
+
+fn find_packet(fixture: &[(Origin, Vec<u8>)], origin: Origin, type_id: u16) -> Vec<u8> {
+    fixture
+        .iter()
+        .find(|(o, bytes)| *o == origin && u16::from_le_bytes([bytes[16], bytes[17]]) == type_id)
+        .map(|(_, bytes)| bytes.clone())
+        .expect("Packet missing from fixture")
+}
+
+#[test_log::test]
+fn fixture_packets_round_trip() {
+    for (origin, bytes) in session_fixture() {
+        let mut cursor = Cursor::new(&bytes[..]);
+        let len = Packet::check(&mut cursor).expect("Packet check failed");
+        assert_eq!(len as usize, bytes.len(), "{:?} packet length mismatch", origin);
+
+        let packet = Packet::decode(&mut &bytes[..]).expect("Packet decode failed");
+        assert!(
+            !matches!(packet.data, PacketData::Unhandled { .. }),
+            "Fixture packet not recognized: {:?}",
+            packet
+        );
+
+        let mut buff = BytesMut::with_capacity(bytes.len());
+        packet.encode(&mut buff).expect("Packet encode failed");
+        assert_eq!(&buff[..], &bytes[..], "Re-encoding changed {:?}", packet.data);
+    }
+}
+
+#[test_log::test]
+fn fixture_stream_parsing() {
+    let fixture = session_fixture();
+    let stream: Vec<u8> = fixture
+        .iter()
+        .filter(|(origin, _)| *origin == Origin::Client)
+        .flat_map(|(_, bytes)| bytes.clone())
+        .collect();
+    let expected = fixture.iter().filter(|(origin, _)| *origin == Origin::Client).count();
+
+    // parse the packets the same way a connection parses its read buffer
+    let mut buff = &stream[..];
+    let mut packets = Vec::new();
+    while !buff.is_empty() {
+        let len = Packet::check(&mut Cursor::new(buff)).expect("Packet check failed") as usize;
+        packets.push(Packet::decode(&mut &buff[..len]).expect("Packet decode failed"));
+        buff = &buff[len..];
+    }
+
+    assert_eq!(packets.len(), expected);
+    assert!(matches!(packets.first().map(|p| &p.data), Some(PacketData::Connect { .. })));
+    assert!(matches!(packets.last().map(|p| &p.data), Some(PacketData::Disconnect)));
+}
+
+async fn create_server() -> Server {
+    let mut settings = Settings::default();
+    settings.server.address = "127.0.0.1".parse().unwrap();
+    settings.server.port = 0;
+    settings.history.enabled = false;
+    settings.server.max_players = 8;
+
+    let mut server = Server::build_server(settings);
+    server.listener.udp_port_addrs = None;
+    server.bind_addresses().await.unwrap();
+    server
+}
+
+async fn read_exact(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+    let mut buff = vec![0; len];
+    timeout(Duration::from_millis(DEFAULT_TIMEOUT_MS), stream.read_exact(&mut buff))
+        .await
+        .expect("Server response timed out")
+        .expect("Failed to read server response");
+    buff
+}
+
+#[test_log::test(tokio::test)]
+async fn fixture_handshake() {
+    let fixture = session_fixture();
+    let connect = find_packet(&fixture, Origin::Client, 6);
+    let init = find_packet(&fixture, Origin::Server, 1);
+
+    let server = create_server().await;
+    let addr = server.get_bind_addr();
+    let _serv_task = tokio::task::spawn(server.spawn_minimal_server());
+    sleep(Duration::from_millis(100)).await;
+
+    // the server answers a connect packet with a byte identical init packet
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    first.write_all(&connect).await.unwrap();
+    assert_eq!(read_exact(&mut first, init.len()).await, init);
+
+    // the connect packet of the next client gets relayed unchanged
+    let mut second_connect = connect.clone();
+    second_connect[0] ^= 0xff;
+    second_connect[26] = b'L';
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    second.write_all(&second_connect).await.unwrap();
+    assert_eq!(read_exact(&mut second, init.len()).await, init);
+    assert_eq!(read_exact(&mut first, second_connect.len()).await, second_connect);
+}
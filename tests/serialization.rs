@@ -1,8 +1,10 @@
 use bytes::{Buf, BufMut, BytesMut};
 
+use smoo::coordinator::{load_shines, save_shines, PersistedShines, ShineBag, SHINE_FORMAT_VERSION};
 use smoo::guid::Guid;
 use smoo::net::encoding::{Decodable, Encodable};
-use smoo::net::{Packet, PacketData, TagUpdate};
+use smoo::net::{GameMode, Packet, PacketData, TagUpdate};
+use smoo::settings::PersistShine;
 
 // Used to test any bad packet decodes
 #[ignore]
@@ -43,6 +45,7 @@ fn bad_packet() {
         },
         data_size: 6,
         data: PacketData::Tag {
+            game_mode: GameMode::HideAndSeek,
             update_type: TagUpdate::State,
             is_it: true,
             seconds: 115,
@@ -58,3 +61,75 @@ fn bad_packet() {
     let new_pack = Packet::decode(&mut buff).expect("Decode error");
     assert_eq!(bad_packet, new_pack);
 }
+
+fn moons_path(test: &str) -> String {
+    let path = std::env::temp_dir().join(format!("smoo-{}-{}.json", test, std::process::id()));
+    path.to_str().unwrap().to_string()
+}
+
+/// Write the content to a moons file of the test and load it
+fn load_moons(test: &str, content: &str) -> PersistedShines {
+    let path = moons_path(test);
+    std::fs::write(&path, content).unwrap();
+    let shines = load_shines(&path);
+    std::fs::remove_file(&path).unwrap();
+    shines.unwrap()
+}
+
+#[test]
+fn load_legacy_moons() {
+    let persisted = load_moons("legacy", "[155, 496, 1000]");
+    assert_eq!(persisted.version, 0);
+    assert!(persisted.excluded.is_empty());
+    let shines = persisted.shines;
+    assert_eq!(shines.iter().copied().collect::<Vec<_>>(), vec![155, 496, 1000]);
+    assert!(shines.iter().all(|&id| !shines.is_grand(id)));
+}
+
+#[test]
+fn load_v1_moons() {
+    let persisted = load_moons("v1", r#"{"Version": 1, "Timestamp": 0, "Excluded": [496], "Shines": [155, 1000]}"#);
+    assert_eq!(persisted.excluded.into_iter().collect::<Vec<_>>(), vec![496]);
+    let shines = persisted.shines;
+    assert_eq!(shines.iter().copied().collect::<Vec<_>>(), vec![155, 1000]);
+    assert!(shines.iter().all(|&id| !shines.is_grand(id)));
+}
+
+#[test]
+fn grand_moons_round_trip() {
+    let mut shines = ShineBag::default();
+    shines.insert(155, false);
+    shines.insert(1000, true);
+    let persisted = PersistedShines {
+        version: SHINE_FORMAT_VERSION,
+        timestamp: 0,
+        excluded: Default::default(),
+        shines: shines.clone(),
+    };
+
+    let loaded = load_moons("grand", &serde_json::to_string(&persisted).unwrap()).shines;
+    assert_eq!(loaded, shines);
+    assert!(loaded.is_grand(1000));
+    assert!(!loaded.is_grand(155));
+}
+
+#[tokio::test]
+async fn saved_moons_round_trip() {
+    let mut shines = ShineBag::default();
+    shines.insert(155, false);
+    shines.insert(1000, true);
+    let settings = PersistShine {
+        enabled: true,
+        filename: moons_path("saved"),
+        autosave_interval: 0,
+        backups: 0,
+    };
+    save_shines(&settings, shines.clone(), [496].into()).await.unwrap();
+
+    let loaded = load_shines(&settings.filename);
+    std::fs::remove_file(&settings.filename).unwrap();
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.version, SHINE_FORMAT_VERSION);
+    assert_eq!(loaded.shines, shines);
+    assert_eq!(loaded.excluded.into_iter().collect::<Vec<_>>(), vec![496]);
+}
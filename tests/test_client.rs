@@ -1,6 +1,8 @@
 use std::time::Duration;
 
 use smoo::{
+    client_sdk::{SmoClient, SmoEvent},
+    guid::Guid,
     net::{Packet, PacketData},
     server::Server,
     settings::Settings,
@@ -15,6 +17,7 @@ async fn create_server() -> Server {
     let mut settings = Settings::default();
     settings.server.address = "127.0.0.1".parse().unwrap();
     settings.server.port = 0;
+    settings.history.enabled = false;
 
     let mut server = Server::build_server(settings);
     server.listener.udp_port_addrs = None;
@@ -37,7 +40,7 @@ async fn test_client_join() {
 async fn test_two_client_handshake() {
     let server = create_server().await;
     let addr = server.get_bind_addr();
-    let perform_udp_handshake = server.settings.read().await.udp.initiate_handshake;
+    let perform_udp_handshake = server.lobby.settings.read().await.udp.initiate_handshake;
     let _serv_task = tokio::task::spawn(server.spawn_minimal_server());
 
     sleep(Duration::from_secs(1)).await;
@@ -76,16 +79,22 @@ async fn test_two_client_handshake() {
         _ => panic!("Join 2 has wrong packet type"),
     }
 
-    // First client waits till second sends costume normally
-    // So first client left out of recving
+    // First client gets told to reset its puppet cache for the new player
+    let tag_reset = timeout(
+        Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        mock1.get_packet(),
+    )
+    .await
+    .expect("Tag reset packet timed out");
+    assert!(matches!(tag_reset.data, PacketData::Tag { .. }));
 
-    // let _costume_1 = mock1.get_packet().await;
-    let _costume_2 = timeout(
+    let capture_reset = timeout(
         Duration::from_millis(DEFAULT_TIMEOUT_MS),
-        mock2.get_packet(),
+        mock1.get_packet(),
     )
     .await
-    .expect("Costume handshake packet timed out");
+    .expect("Capture reset packet timed out");
+    assert!(matches!(capture_reset.data, PacketData::Capture { .. }));
 }
 
 async fn finish_mock_handshake(mock1: &mut MockClient, mock2: &mut MockClient, perform_udp: bool) {
@@ -121,22 +130,27 @@ async fn finish_mock_handshake(mock1: &mut MockClient, mock2: &mut MockClient, p
     .await
     .expect("Connect handshake packet timed out");
 
-    // Receive other players custume packets
-    tracing::debug!("Finishing costume handshake");
-    // let _costume_1 = mock1.get_packet().await;
-    let _costume_2 = timeout(
+    // Receive the puppet cache reset packets for the second player
+    tracing::debug!("Finishing puppet reset handshake");
+    let _tag_reset = timeout(
         Duration::from_millis(DEFAULT_TIMEOUT_MS),
-        mock2.get_packet(),
+        mock1.get_packet(),
+    )
+    .await
+    .expect("Tag reset packet timed out");
+    let _capture_reset = timeout(
+        Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        mock1.get_packet(),
     )
     .await
-    .expect("Costume handshake packet timed out");
+    .expect("Capture reset packet timed out");
 }
 
 #[test_log::test(tokio::test)]
 async fn test_movement() {
     let server = create_server().await;
     let addr = server.get_bind_addr();
-    let perform_udp_handshake = server.settings.read().await.udp.initiate_handshake;
+    let perform_udp_handshake = server.lobby.settings.read().await.udp.initiate_handshake;
     let _serv_task = tokio::task::spawn(server.spawn_minimal_server());
     sleep(Duration::from_secs(1)).await;
 
@@ -188,7 +202,7 @@ async fn test_movement() {
 async fn test_cap_movement() {
     let server = create_server().await;
     let addr = server.get_bind_addr();
-    let perform_udp_handshake = server.settings.read().await.udp.initiate_handshake;
+    let perform_udp_handshake = server.lobby.settings.read().await.udp.initiate_handshake;
     let _serv_task = tokio::task::spawn(server.spawn_minimal_server());
     sleep(Duration::from_secs(1)).await;
 
@@ -233,3 +247,28 @@ async fn test_cap_movement() {
     let recv_packet = mock_client_1.get_packet().await;
     assert_eq!(packet, recv_packet);
 }
+
+#[test_log::test(tokio::test)]
+async fn test_sdk_client_sees_other_player() {
+    let server = create_server().await;
+    let addr = server.get_bind_addr();
+    let _serv_task = tokio::task::spawn(server.spawn_minimal_server());
+
+    sleep(Duration::from_secs(1)).await;
+
+    let mut bot1 = SmoClient::connect(addr, Guid::new_random(), "Bot1").await.unwrap();
+    let bot2 = SmoClient::connect(addr, Guid::new_random(), "Bot2").await.unwrap();
+    assert!(bot1.max_players > 0);
+
+    let event = timeout(Duration::from_millis(DEFAULT_TIMEOUT_MS), bot1.next_event())
+        .await
+        .expect("Connect packet timed out")
+        .unwrap();
+    match event {
+        SmoEvent::PlayerConnected { id, name } => {
+            assert_eq!(id, bot2.guid);
+            assert_eq!(name, "Bot2");
+        }
+        e => panic!("Expected the connect of the other bot, got {:?}", e),
+    }
+}